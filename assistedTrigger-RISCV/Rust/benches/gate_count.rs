@@ -0,0 +1,106 @@
+//! Gate count per instruction row before and after the `AluGate`: the old `is_equal`/`select`
+//! cascade, kept here as the baseline, and the `AluGate` rows that `circuit::prove_rows` adds
+//! through `circuit::add_instruction_row`. Also prints the size of the whole proof circuit.
+//!
+//! Run with `cargo +nightly bench --bench gate_count`.
+
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::Field;
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::CircuitConfig;
+use riscv_trace_reader::circuit::add_instruction_row;
+use riscv_trace_reader::lookup::LookupTables;
+use riscv_trace_reader::{convert_trace_to_rows_with, parse_trace, prove_rows, ConversionMode, ConvertOptions, InstructionRow};
+
+type F = GoldilocksField;
+
+/// Per-row constraints as generated before the `AluGate`: one `is_equal` per opcode
+/// followed by a chain of `select`s.
+fn add_cascade_row(builder: &mut CircuitBuilder<F, 2>) {
+    let opcode = builder.add_virtual_target();
+    let rs1 = builder.add_virtual_target();
+    let rs2 = builder.add_virtual_target();
+    let imm_flag = builder.add_virtual_target();
+    let imm_val = builder.add_virtual_target();
+    let rd = builder.add_virtual_target();
+
+    let zero = builder.zero();
+    let one = builder.one();
+    let imm_bool = builder.is_equal(imm_flag, one);
+    let rs2_or_imm = builder.select(imm_bool, imm_val, rs2);
+
+    let is_op: Vec<_> = (1..=17u64)
+        .map(|v| {
+            let c = builder.constant(F::from_canonical_u64(v));
+            builder.is_equal(opcode, c)
+        })
+        .collect();
+
+    let add_res = builder.add(rs1, rs2_or_imm);
+    let sub_res = builder.sub(rs1, rs2_or_imm);
+    let mul_res = builder.mul(rs1, rs2_or_imm);
+    let is_rs2_zero = builder.is_equal(rs2_or_imm, zero);
+    let safe_divisor = builder.select(is_rs2_zero, one, rs2_or_imm);
+    let raw_div = builder.div(rs1, safe_divisor);
+    let div_res = builder.select(is_rs2_zero, zero, raw_div);
+
+    let mut result = builder.select(is_op[0], add_res, zero);
+    result = builder.select(is_op[1], sub_res, result);
+    result = builder.select(is_op[2], mul_res, result);
+    result = builder.select(is_op[3], add_res, result);
+    result = builder.select(is_op[4], div_res, result);
+    result = builder.select(is_op[7], imm_val, result);
+    result = builder.select(is_op[16], rs1, result);
+    for k in [9, 10, 11, 12, 13, 14, 15] {
+        result = builder.select(is_op[k], rs1, result);
+    }
+
+    let no_output = builder.or(is_op[5], is_op[6]);
+    let no_output = builder.or(no_output, is_op[8]);
+    result = builder.select(no_output, zero, result);
+
+    let is_result_correct = builder.is_equal(result, rd);
+    builder.assert_one(is_result_correct.target);
+}
+
+fn gates_for(num_rows: usize, mut add_row: impl FnMut(&mut CircuitBuilder<F, 2>)) -> usize {
+    let mut builder = CircuitBuilder::<F, 2>::new(CircuitConfig::standard_recursion_config());
+    let before = builder.num_gates();
+    for _ in 0..num_rows {
+        add_row(&mut builder);
+    }
+    builder.num_gates() - before
+}
+
+/// Whether `circuit::prove_rows` proves the row with `add_instruction_row`.
+fn is_alu_row(row: &InstructionRow<F>) -> bool {
+    row.csr.is_none()
+        && row.syscall.is_none()
+        && row.atomic.is_none()
+        && row.fp.is_none()
+        && row.bitmanip.is_none()
+        && row.memory.is_none()
+        && row.unproven.is_none()
+}
+
+fn main() {
+    let trace = concat!(env!("CARGO_MANIFEST_DIR"), "/traces/sample_trace.log");
+    let options = ConvertOptions { mode: ConversionMode::Lenient, ..Default::default() };
+    let rows = convert_trace_to_rows_with(&parse_trace(trace).unwrap(), &options).unwrap();
+    let n = rows.iter().filter(|row| is_alu_row(row)).count();
+
+    let cascade = gates_for(n, add_cascade_row);
+    let mut tables = LookupTables::new();
+    let alu_gate = gates_for(n, |b| {
+        add_instruction_row(b, &mut tables);
+    });
+    let (_, data) = prove_rows(&rows, None, None).unwrap();
+
+    println!("trace: {} ({} rows, {} ALU rows)", trace, rows.len(), n);
+    println!("{:<20} {:>8} {:>12}", "layout", "gates", "gates/row");
+    for (name, gates) in [("is_equal cascade", cascade), ("AluGate", alu_gate)] {
+        println!("{:<20} {:>8} {:>12.2}", name, gates, gates as f64 / n as f64);
+    }
+    // Dominated by the lookup tables on short traces, and padded to a power of two.
+    println!("proof circuit: {} rows", data.common.degree());
+}
@@ -0,0 +1,547 @@
+use std::ops::{Add, Mul, Sub};
+
+use anyhow::bail;
+use plonky2::field::extension::Extendable;
+use plonky2::field::packed::PackedField;
+use plonky2::field::types::Field;
+use plonky2::gates::gate::Gate;
+use plonky2::gates::packed_util::PackedEvaluableBase;
+use plonky2::gates::util::StridedConstraintConsumer;
+use plonky2::hash::hash_types::RichField;
+use plonky2::iop::ext_target::ExtensionTarget;
+use plonky2::iop::generator::{GeneratedValues, SimpleGenerator, WitnessGeneratorRef};
+use plonky2::iop::target::Target;
+use plonky2::iop::witness::{PartitionWitness, Witness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::{CircuitConfig, CommonCircuitData};
+use plonky2::plonk::vars::{
+    EvaluationTargets, EvaluationVars, EvaluationVarsBase, EvaluationVarsBaseBatch,
+    EvaluationVarsBasePacked,
+};
+use plonky2::util::serialization::{Buffer, IoResult, Read, Write};
+
+use crate::zk::NUM_OPCODES;
+
+/// What an opcode writes to `rd`.
+#[derive(Debug, Clone, Copy)]
+enum AluResult {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Imm,
+    UpperImm,
+    Rs1,
+    NoResult,
+}
+
+/// Encoding of an opcode's immediate, exported on routed wires for range checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImmKind {
+    /// 12-bit signed immediate of I- and S-type instructions.
+    I12,
+    /// 20-bit immediate of U-type instructions.
+    U20,
+    None,
+}
+
+/// Semantics of opcode id `k + 1` (see `zk::opcode_to_id`).
+const OPS: [(AluResult, ImmKind); NUM_OPCODES] = [
+    (AluResult::Add, ImmKind::None),      // add
+    (AluResult::Sub, ImmKind::None),      // sub
+    (AluResult::Mul, ImmKind::None),      // mul
+    (AluResult::Add, ImmKind::I12),       // addi: rs1 + imm
+    (AluResult::Div, ImmKind::None),      // div
+    (AluResult::NoResult, ImmKind::I12),  // sd
+    (AluResult::NoResult, ImmKind::I12),  // ld
+    (AluResult::Imm, ImmKind::I12),       // li
+    (AluResult::NoResult, ImmKind::I12),  // lw
+    // Shifts and xor/or/and are not implemented yet and forward rs1
+    (AluResult::Rs1, ImmKind::None),      // addw
+    (AluResult::Rs1, ImmKind::None),      // subw
+    (AluResult::Rs1, ImmKind::I12),       // slliw
+    (AluResult::Rs1, ImmKind::I12),       // sraiw
+    (AluResult::Rs1, ImmKind::None),      // xor
+    (AluResult::Rs1, ImmKind::None),      // or
+    (AluResult::Rs1, ImmKind::None),      // and
+    (AluResult::Rs1, ImmKind::None),      // mv
+    (AluResult::UpperImm, ImmKind::U20),  // lui: imm << 12
+];
+
+/// Offset added to I-type immediates so that the valid range becomes `[0, 2^12)`.
+pub const IMM12_BIAS: u64 = 1 << 11;
+
+/// Wire values of one ALU operation, in whatever type the constraints are evaluated in.
+struct AluOpVars<T> {
+    opcode: T,
+    rs1: T,
+    rs2: T,
+    imm_flag: T,
+    imm_val: T,
+    rd: T,
+    imm_i: T,
+    imm_u: T,
+    selectors: Vec<T>,
+    operand: T,
+    inv: T,
+    quot: T,
+}
+
+impl<T: Copy> AluOpVars<T> {
+    fn read(gate: &AluGate, i: usize, wire: impl Fn(usize) -> T) -> Self {
+        Self {
+            opcode: wire(AluGate::wire_opcode(i)),
+            rs1: wire(AluGate::wire_rs1(i)),
+            rs2: wire(AluGate::wire_rs2(i)),
+            imm_flag: wire(AluGate::wire_imm_flag(i)),
+            imm_val: wire(AluGate::wire_imm_val(i)),
+            rd: wire(AluGate::wire_rd(i)),
+            imm_i: wire(AluGate::wire_imm_i(i)),
+            imm_u: wire(AluGate::wire_imm_u(i)),
+            selectors: (0..NUM_OPCODES).map(|k| wire(gate.wire_selector(i, k))).collect(),
+            operand: wire(gate.wire_operand(i)),
+            inv: wire(gate.wire_inv(i)),
+            quot: wire(gate.wire_quot(i)),
+        }
+    }
+}
+
+impl AluResult {
+    fn eval<T>(self, v: &AluOpVars<T>, constant: impl Fn(u64) -> T) -> Option<T>
+    where
+        T: Copy + Add<Output = T> + Sub<Output = T> + Mul<Output = T>,
+    {
+        match self {
+            AluResult::Add => Some(v.rs1 + v.operand),
+            AluResult::Sub => Some(v.rs1 - v.operand),
+            AluResult::Mul => Some(v.rs1 * v.operand),
+            AluResult::Div => Some(v.quot),
+            AluResult::Imm => Some(v.imm_val),
+            AluResult::UpperImm => Some(v.imm_val * constant(1 << 12)),
+            AluResult::Rs1 => Some(v.rs1),
+            AluResult::NoResult => None,
+        }
+    }
+
+    fn eval_circuit<F: RichField + Extendable<D>, const D: usize>(
+        self,
+        builder: &mut CircuitBuilder<F, D>,
+        v: &AluOpVars<ExtensionTarget<D>>,
+    ) -> Option<ExtensionTarget<D>> {
+        match self {
+            AluResult::Add => Some(builder.add_extension(v.rs1, v.operand)),
+            AluResult::Sub => Some(builder.sub_extension(v.rs1, v.operand)),
+            AluResult::Mul => Some(builder.mul_extension(v.rs1, v.operand)),
+            AluResult::Div => Some(v.quot),
+            AluResult::Imm => Some(v.imm_val),
+            AluResult::UpperImm => {
+                Some(builder.mul_const_extension(F::from_canonical_u64(1 << 12), v.imm_val))
+            }
+            AluResult::Rs1 => Some(v.rs1),
+            AluResult::NoResult => None,
+        }
+    }
+}
+
+/// Constraints of one ALU operation. `constant` lifts small integers into `T`.
+fn eval_op<T>(v: &AluOpVars<T>, constant: impl Fn(u64) -> T) -> Vec<T>
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<Output = T>,
+{
+    let zero = constant(0);
+    let one = constant(1);
+    let mut constraints = Vec::with_capacity(NUM_OPCODES + AluGate::CONSTRAINTS_PER_SELECTOR_SET);
+
+    // Selectors are boolean and at most one is set; all-zero is the padding op (opcode 0).
+    let mut selector_sum = zero;
+    let mut opcode_sum = zero;
+    for (k, &sel) in v.selectors.iter().enumerate() {
+        constraints.push(sel * (sel - one));
+        selector_sum = selector_sum + sel;
+        opcode_sum = opcode_sum + constant(k as u64 + 1) * sel;
+    }
+    constraints.push(selector_sum * (selector_sum - one));
+    constraints.push(opcode_sum - v.opcode);
+
+    // operand = imm_flag ? imm_val : rs2
+    constraints.push(v.imm_flag * (v.imm_flag - one));
+    constraints.push(v.operand - (v.rs2 + v.imm_flag * (v.imm_val - v.rs2)));
+
+    // Safe division: quot = rs1 / operand, or 0 when operand == 0.
+    let nonzero = v.operand * v.inv;
+    constraints.push(v.operand * (one - nonzero));
+    constraints.push(v.quot * v.operand - v.rs1 * nonzero);
+    constraints.push(v.quot * (one - nonzero));
+
+    // rd is the selector-weighted result; the immediate is exported per encoding.
+    let bias = constant(IMM12_BIAS);
+    let mut result = zero;
+    let mut imm_i = zero;
+    let mut imm_u = zero;
+    for (&sel, (kind, imm)) in v.selectors.iter().zip(OPS) {
+        if let Some(res) = kind.eval(v, &constant) {
+            result = result + sel * res;
+        }
+        match imm {
+            ImmKind::I12 => imm_i = imm_i + sel * (v.imm_val + bias),
+            ImmKind::U20 => imm_u = imm_u + sel * v.imm_val,
+            ImmKind::None => {}
+        }
+    }
+    constraints.push(v.rd - result);
+    constraints.push(v.imm_i - imm_i);
+    constraints.push(v.imm_u - imm_u);
+
+    constraints
+}
+
+/// A gate evaluating whole instruction rows: opcode selectors, operands and result.
+///
+/// Each operation uses eight routed wires: the row inputs `opcode`, `rs1`, `rs2`, `imm_flag`,
+/// `imm_val`, `rd`, and two outputs exporting the immediate for lookup range checks, `imm_i`
+/// (`imm + 2^11` for I/S-type opcodes, 0 otherwise) and `imm_u` (the immediate of U-type
+/// opcodes, 0 otherwise). After all routed wires of the gate come the advice wires of each
+/// operation: one selector per opcode, the selected operand, the operand's inverse and the
+/// division quotient. Everything but the row inputs is filled in by [`AluGenerator`].
+#[derive(Debug, Clone)]
+pub struct AluGate {
+    /// Number of instruction rows evaluated by one gate.
+    pub num_ops: usize,
+}
+
+impl AluGate {
+    pub const ROUTED_WIRES_PER_OP: usize = 8;
+    pub const ADVICE_WIRES_PER_OP: usize = NUM_OPCODES + 3;
+    /// Constraints per operation besides the per-selector booleanity checks.
+    const CONSTRAINTS_PER_SELECTOR_SET: usize = 10;
+
+    pub const fn new_from_config(config: &CircuitConfig) -> Self {
+        Self {
+            num_ops: Self::num_ops(config),
+        }
+    }
+
+    /// Determine the maximum number of operations that can fit in one gate for the given config.
+    pub const fn num_ops(config: &CircuitConfig) -> usize {
+        let by_wires =
+            config.num_wires / (Self::ROUTED_WIRES_PER_OP + Self::ADVICE_WIRES_PER_OP);
+        let by_routed = config.num_routed_wires / Self::ROUTED_WIRES_PER_OP;
+        if by_wires < by_routed {
+            by_wires
+        } else {
+            by_routed
+        }
+    }
+
+    pub const fn wire_opcode(i: usize) -> usize {
+        Self::ROUTED_WIRES_PER_OP * i
+    }
+    pub const fn wire_rs1(i: usize) -> usize {
+        Self::ROUTED_WIRES_PER_OP * i + 1
+    }
+    pub const fn wire_rs2(i: usize) -> usize {
+        Self::ROUTED_WIRES_PER_OP * i + 2
+    }
+    pub const fn wire_imm_flag(i: usize) -> usize {
+        Self::ROUTED_WIRES_PER_OP * i + 3
+    }
+    pub const fn wire_imm_val(i: usize) -> usize {
+        Self::ROUTED_WIRES_PER_OP * i + 4
+    }
+    pub const fn wire_rd(i: usize) -> usize {
+        Self::ROUTED_WIRES_PER_OP * i + 5
+    }
+    pub const fn wire_imm_i(i: usize) -> usize {
+        Self::ROUTED_WIRES_PER_OP * i + 6
+    }
+    pub const fn wire_imm_u(i: usize) -> usize {
+        Self::ROUTED_WIRES_PER_OP * i + 7
+    }
+
+    fn advice_start(&self, i: usize) -> usize {
+        Self::ROUTED_WIRES_PER_OP * self.num_ops + Self::ADVICE_WIRES_PER_OP * i
+    }
+    pub fn wire_selector(&self, i: usize, k: usize) -> usize {
+        debug_assert!(k < NUM_OPCODES);
+        self.advice_start(i) + k
+    }
+    pub fn wire_operand(&self, i: usize) -> usize {
+        self.advice_start(i) + NUM_OPCODES
+    }
+    pub fn wire_inv(&self, i: usize) -> usize {
+        self.advice_start(i) + NUM_OPCODES + 1
+    }
+    pub fn wire_quot(&self, i: usize) -> usize {
+        self.advice_start(i) + NUM_OPCODES + 2
+    }
+}
+
+impl<F: RichField + Extendable<D>, const D: usize> Gate<F, D> for AluGate {
+    fn id(&self) -> String {
+        format!("{self:?}")
+    }
+
+    fn serialize(&self, dst: &mut Vec<u8>, _common_data: &CommonCircuitData<F, D>) -> IoResult<()> {
+        dst.write_usize(self.num_ops)
+    }
+
+    fn deserialize(src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+        let num_ops = src.read_usize()?;
+        Ok(Self { num_ops })
+    }
+
+    fn eval_unfiltered(&self, vars: EvaluationVars<F, D>) -> Vec<F::Extension> {
+        (0..self.num_ops)
+            .flat_map(|i| {
+                let v = AluOpVars::read(self, i, |w| vars.local_wires[w]);
+                eval_op(&v, F::Extension::from_canonical_u64)
+            })
+            .collect()
+    }
+
+    fn eval_unfiltered_base_one(
+        &self,
+        _vars: EvaluationVarsBase<F>,
+        _yield_constr: StridedConstraintConsumer<F>,
+    ) {
+        panic!("use eval_unfiltered_base_packed instead");
+    }
+
+    fn eval_unfiltered_base_batch(&self, vars_base: EvaluationVarsBaseBatch<F>) -> Vec<F> {
+        self.eval_unfiltered_base_batch_packed(vars_base)
+    }
+
+    fn eval_unfiltered_circuit(
+        &self,
+        builder: &mut CircuitBuilder<F, D>,
+        vars: EvaluationTargets<D>,
+    ) -> Vec<ExtensionTarget<D>> {
+        let mut constraints = Vec::with_capacity(Gate::<F, D>::num_constraints(self));
+        for i in 0..self.num_ops {
+            let v = AluOpVars::read(self, i, |w| vars.local_wires[w]);
+            let one = builder.one_extension();
+
+            let mut selector_sum = builder.zero_extension();
+            let mut opcode_sum = builder.zero_extension();
+            for (k, &sel) in v.selectors.iter().enumerate() {
+                constraints.push(builder.mul_sub_extension(sel, sel, sel));
+                selector_sum = builder.add_extension(selector_sum, sel);
+                opcode_sum = builder.arithmetic_extension(
+                    F::from_canonical_usize(k + 1),
+                    F::ONE,
+                    sel,
+                    one,
+                    opcode_sum,
+                );
+            }
+            constraints.push(builder.mul_sub_extension(selector_sum, selector_sum, selector_sum));
+            constraints.push(builder.sub_extension(opcode_sum, v.opcode));
+
+            constraints.push(builder.mul_sub_extension(v.imm_flag, v.imm_flag, v.imm_flag));
+            let imm_minus_rs2 = builder.sub_extension(v.imm_val, v.rs2);
+            let selected = builder.mul_add_extension(v.imm_flag, imm_minus_rs2, v.rs2);
+            constraints.push(builder.sub_extension(v.operand, selected));
+
+            let nonzero = builder.mul_extension(v.operand, v.inv);
+            let is_zero = builder.sub_extension(one, nonzero);
+            constraints.push(builder.mul_extension(v.operand, is_zero));
+            let rs1_nonzero = builder.mul_extension(v.rs1, nonzero);
+            constraints.push(builder.mul_sub_extension(v.quot, v.operand, rs1_nonzero));
+            constraints.push(builder.mul_extension(v.quot, is_zero));
+
+            let bias = builder.constant_extension(F::Extension::from_canonical_u64(IMM12_BIAS));
+            let biased_imm = builder.add_extension(v.imm_val, bias);
+            let mut result = builder.zero_extension();
+            let mut imm_i = builder.zero_extension();
+            let mut imm_u = builder.zero_extension();
+            for (&sel, (kind, imm)) in v.selectors.iter().zip(OPS) {
+                if let Some(res) = kind.eval_circuit(builder, &v) {
+                    result = builder.mul_add_extension(sel, res, result);
+                }
+                match imm {
+                    ImmKind::I12 => imm_i = builder.mul_add_extension(sel, biased_imm, imm_i),
+                    ImmKind::U20 => imm_u = builder.mul_add_extension(sel, v.imm_val, imm_u),
+                    ImmKind::None => {}
+                }
+            }
+            constraints.push(builder.sub_extension(v.rd, result));
+            constraints.push(builder.sub_extension(v.imm_i, imm_i));
+            constraints.push(builder.sub_extension(v.imm_u, imm_u));
+        }
+        constraints
+    }
+
+    fn generators(&self, row: usize, _local_constants: &[F]) -> Vec<WitnessGeneratorRef<F, D>> {
+        (0..self.num_ops)
+            .map(|i| {
+                WitnessGeneratorRef::new(
+                    AluGenerator {
+                        row,
+                        num_ops: self.num_ops,
+                        i,
+                    }
+                    .adapter(),
+                )
+            })
+            .collect()
+    }
+
+    fn num_wires(&self) -> usize {
+        self.num_ops * (Self::ROUTED_WIRES_PER_OP + Self::ADVICE_WIRES_PER_OP)
+    }
+
+    fn num_constants(&self) -> usize {
+        0
+    }
+
+    fn degree(&self) -> usize {
+        3
+    }
+
+    fn num_constraints(&self) -> usize {
+        self.num_ops * (NUM_OPCODES + Self::CONSTRAINTS_PER_SELECTOR_SET)
+    }
+}
+
+impl<F: RichField + Extendable<D>, const D: usize> PackedEvaluableBase<F, D> for AluGate {
+    fn eval_unfiltered_base_packed<P: PackedField<Scalar = F>>(
+        &self,
+        vars: EvaluationVarsBasePacked<P>,
+        mut yield_constr: StridedConstraintConsumer<P>,
+    ) {
+        for i in 0..self.num_ops {
+            let v = AluOpVars::read(self, i, |w| vars.local_wires[w]);
+            yield_constr.many(eval_op(&v, |c| P::from(F::from_canonical_u64(c))));
+        }
+    }
+}
+
+/// Fills the advice wires and the result of one ALU operation from its routed inputs.
+#[derive(Debug, Clone, Default)]
+pub struct AluGenerator {
+    row: usize,
+    num_ops: usize,
+    i: usize,
+}
+
+impl<F: RichField + Extendable<D>, const D: usize> SimpleGenerator<F, D> for AluGenerator {
+    fn id(&self) -> String {
+        "AluGenerator".to_string()
+    }
+
+    fn dependencies(&self) -> Vec<Target> {
+        [
+            AluGate::wire_opcode(self.i),
+            AluGate::wire_rs1(self.i),
+            AluGate::wire_rs2(self.i),
+            AluGate::wire_imm_flag(self.i),
+            AluGate::wire_imm_val(self.i),
+        ]
+        .iter()
+        .map(|&w| Target::wire(self.row, w))
+        .collect()
+    }
+
+    fn run_once(
+        &self,
+        witness: &PartitionWitness<F>,
+        out_buffer: &mut GeneratedValues<F>,
+    ) -> anyhow::Result<()> {
+        let gate = AluGate { num_ops: self.num_ops };
+        let get_wire = |wire: usize| -> F { witness.get_target(Target::wire(self.row, wire)) };
+
+        let opcode_id = get_wire(AluGate::wire_opcode(self.i)).to_canonical_u64() as usize;
+        if opcode_id > NUM_OPCODES {
+            bail!("AluGate: unknown opcode id {}", opcode_id);
+        }
+        let imm_flag = get_wire(AluGate::wire_imm_flag(self.i));
+        if imm_flag != F::ZERO && imm_flag != F::ONE {
+            bail!("AluGate: imm_flag must be 0 or 1, got {}", imm_flag);
+        }
+
+        let rs1 = get_wire(AluGate::wire_rs1(self.i));
+        let imm_val = get_wire(AluGate::wire_imm_val(self.i));
+        let operand = if imm_flag == F::ONE {
+            imm_val
+        } else {
+            get_wire(AluGate::wire_rs2(self.i))
+        };
+        let inv = operand.try_inverse().unwrap_or(F::ZERO);
+        let quot = rs1 * inv;
+
+        let v = AluOpVars {
+            opcode: F::from_canonical_usize(opcode_id),
+            rs1,
+            rs2: F::ZERO,
+            imm_flag,
+            imm_val,
+            rd: F::ZERO,
+            imm_i: F::ZERO,
+            imm_u: F::ZERO,
+            selectors: (1..=NUM_OPCODES)
+                .map(|id| F::from_bool(id == opcode_id))
+                .collect(),
+            operand,
+            inv,
+            quot,
+        };
+        let op = opcode_id.checked_sub(1).map(|k| OPS[k]);
+        let rd = op
+            .and_then(|(kind, _)| kind.eval(&v, F::from_canonical_u64))
+            .unwrap_or(F::ZERO);
+        let imm_kind = op.map_or(ImmKind::None, |(_, imm)| imm);
+        let imm_i = if imm_kind == ImmKind::I12 {
+            imm_val + F::from_canonical_u64(IMM12_BIAS)
+        } else {
+            F::ZERO
+        };
+        let imm_u = if imm_kind == ImmKind::U20 { imm_val } else { F::ZERO };
+
+        let set_wire = |out: &mut GeneratedValues<F>, wire: usize, value: F| {
+            out.set_target(Target::wire(self.row, wire), value)
+        };
+        for (k, &sel) in v.selectors.iter().enumerate() {
+            set_wire(out_buffer, gate.wire_selector(self.i, k), sel)?;
+        }
+        set_wire(out_buffer, gate.wire_operand(self.i), operand)?;
+        set_wire(out_buffer, gate.wire_inv(self.i), inv)?;
+        set_wire(out_buffer, gate.wire_quot(self.i), quot)?;
+        set_wire(out_buffer, AluGate::wire_imm_i(self.i), imm_i)?;
+        set_wire(out_buffer, AluGate::wire_imm_u(self.i), imm_u)?;
+        set_wire(out_buffer, AluGate::wire_rd(self.i), rd)
+    }
+
+    fn serialize(&self, dst: &mut Vec<u8>, _common_data: &CommonCircuitData<F, D>) -> IoResult<()> {
+        dst.write_usize(self.row)?;
+        dst.write_usize(self.num_ops)?;
+        dst.write_usize(self.i)
+    }
+
+    fn deserialize(src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+        let row = src.read_usize()?;
+        let num_ops = src.read_usize()?;
+        let i = src.read_usize()?;
+        Ok(Self { row, num_ops, i })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use plonky2::field::goldilocks_field::GoldilocksField;
+    use plonky2::gates::gate_testing::{test_eval_fns, test_low_degree};
+    use plonky2::plonk::config::PoseidonGoldilocksConfig;
+
+    use super::*;
+
+    #[test]
+    fn low_degree() {
+        let gate = AluGate::new_from_config(&CircuitConfig::standard_recursion_config());
+        test_low_degree::<GoldilocksField, _, 4>(gate);
+    }
+
+    #[test]
+    fn eval_fns() -> anyhow::Result<()> {
+        let gate = AluGate::new_from_config(&CircuitConfig::standard_recursion_config());
+        test_eval_fns::<GoldilocksField, PoseidonGoldilocksConfig, _, 2>(gate)
+    }
+}
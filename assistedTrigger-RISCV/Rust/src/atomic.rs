@@ -0,0 +1,465 @@
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::Field;
+use plonky2::iop::target::{BoolTarget, Target};
+use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+
+use crate::circuit::RowTargets;
+use crate::lookup::{ByteOp, LookupTables};
+use crate::memory::MemoryLog;
+
+/// Read-modify-write operation of an `amo*` instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmoOp {
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu,
+}
+
+const AMO_OPS: [(&str, AmoOp); 9] = [
+    ("swap", AmoOp::Swap),
+    ("add", AmoOp::Add),
+    ("xor", AmoOp::Xor),
+    ("and", AmoOp::And),
+    ("or", AmoOp::Or),
+    ("min", AmoOp::Min),
+    ("max", AmoOp::Max),
+    ("minu", AmoOp::Minu),
+    ("maxu", AmoOp::Maxu),
+];
+
+/// Kind of an A-extension instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtomicKind {
+    /// `lr`: loads and reserves the address.
+    LoadReserved,
+    /// `sc`: stores if the reservation still holds; `rd` is 0 on success and 1 on failure.
+    StoreConditional,
+    Amo(AmoOp),
+}
+
+/// An A-extension instruction: its kind and whether it works on words (`.w`) or
+/// doublewords (`.d`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtomicOp {
+    pub kind: AtomicKind,
+    pub double: bool,
+}
+
+/// Opcode id of `lr.w`; the other atomics follow, `.w` and `.d` alternating.
+const FIRST_OPCODE_ID: u64 = 26;
+
+impl AtomicOp {
+    /// Parses a mnemonic such as `amoadd.w` or `lr.d`, without `.aq`/`.rl` suffixes.
+    pub fn parse(mnemonic: &str) -> Option<AtomicOp> {
+        let (name, width) = mnemonic.split_once('.')?;
+        let double = match width {
+            "w" => false,
+            "d" => true,
+            _ => return None,
+        };
+        let kind = match name {
+            "lr" => AtomicKind::LoadReserved,
+            "sc" => AtomicKind::StoreConditional,
+            _ => {
+                let op = name.strip_prefix("amo")?;
+                AtomicKind::Amo(AMO_OPS.iter().find(|(n, _)| *n == op)?.1)
+            }
+        };
+        Some(AtomicOp { kind, double })
+    }
+
+    pub fn opcode_id(self) -> u64 {
+        let index = match self.kind {
+            AtomicKind::LoadReserved => 0,
+            AtomicKind::StoreConditional => 1,
+            AtomicKind::Amo(op) => 2 + AMO_OPS.iter().position(|(_, o)| *o == op).unwrap() as u64,
+        };
+        FIRST_OPCODE_ID + 2 * index + self.double as u64
+    }
+
+    /// Width of the memory operand in bits.
+    pub fn width(self) -> usize {
+        if self.double { 64 } else { 32 }
+    }
+
+    /// Mask selecting the memory operand's bits.
+    pub fn mask(self) -> u64 {
+        if self.double { u64::MAX } else { u32::MAX as u64 }
+    }
+
+    /// Sign-extends a memory operand to the 64-bit value written to `rd`.
+    pub fn sign_extend(self, value: u64) -> u64 {
+        if self.double { value } else { value as u32 as i32 as i64 as u64 }
+    }
+
+    /// Value left in memory by an AMO that read `old` and got `src` from `rs2`.
+    pub fn apply(self, op: AmoOp, old: u64, src: u64) -> u64 {
+        let (old, src) = (old & self.mask(), src & self.mask());
+        let signed = |v: u64| self.sign_extend(v) as i64;
+        let new = match op {
+            AmoOp::Swap => src,
+            AmoOp::Add => old.wrapping_add(src),
+            AmoOp::Xor => old ^ src,
+            AmoOp::And => old & src,
+            AmoOp::Or => old | src,
+            AmoOp::Min => if signed(old) <= signed(src) { old } else { src },
+            AmoOp::Max => if signed(old) >= signed(src) { old } else { src },
+            AmoOp::Minu => old.min(src),
+            AmoOp::Maxu => old.max(src),
+        };
+        new & self.mask()
+    }
+}
+
+/// A-extension access of an instruction row, whose `rs1` is the address and `rs2` the source.
+#[derive(Debug, Clone)]
+pub struct AtomicRow<F: Field> {
+    pub op: AtomicOp,
+    /// Memory operand before the instruction, zero-extended.
+    pub old: F,
+}
+
+/// Reservation set and memory state threaded through the atomic rows of a single hart.
+///
+/// A reservation taken by `lr` holds until an `sc` consumes it, or an AMO or a plain store
+/// (see [`AtomicUnit::add_store`]) writes to the reserved address. Plain loads and stores
+/// share the [`MemoryLog`], so atomics read the values they leave.
+#[derive(Debug, Default)]
+pub struct AtomicUnit {
+    reservation: Option<(BoolTarget, Target)>,
+    witness: Vec<(Target, GoldilocksField)>,
+}
+
+impl AtomicUnit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the constraints of an A-extension row and its read-modify-write to `memory`.
+    pub fn add_atomic_row(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        tables: &mut LookupTables,
+        memory: &mut MemoryLog,
+        targets: &RowTargets,
+        row: &AtomicRow<GoldilocksField>,
+    ) {
+        let op = row.op;
+        let opcode = builder.constant(GoldilocksField::from_canonical_u64(op.opcode_id()));
+        builder.connect(targets.opcode, opcode);
+        builder.assert_zero(targets.imm_flag.target);
+        builder.assert_zero(targets.imm_val);
+        let addr = targets.rs1;
+
+        let old = builder.add_virtual_target();
+        self.witness.push((old, row.old));
+        let old_halves = halves(builder, tables, old, op.double);
+
+        let (valid, reserved) = self.reservation.unwrap_or_else(|| (builder._false(), builder.zero()));
+        let at_reservation = builder.is_equal(reserved, addr);
+
+        match op.kind {
+            AtomicKind::LoadReserved => {
+                builder.assert_zero(targets.rs2);
+                sign_extend_into(builder, tables, &old_halves, targets.rd);
+                memory.access(builder, tables, addr, &old_halves, &old_halves);
+                self.reservation = Some((builder._true(), addr));
+            }
+            AtomicKind::StoreConditional => {
+                let src_halves = source_halves(builder, tables, targets.rs2, op.double);
+                let success = builder.and(valid, at_reservation);
+                let failure = builder.not(success);
+                builder.connect(targets.rd, failure.target);
+                let written: Vec<Target> =
+                    src_halves.iter().zip(&old_halves).map(|(&src, &old)| builder.select(success, src, old)).collect();
+                memory.access(builder, tables, addr, &old_halves, &written);
+                self.reservation = Some((builder._false(), reserved));
+            }
+            AtomicKind::Amo(amo) => {
+                let src_halves = source_halves(builder, tables, targets.rs2, op.double);
+                let new = amo_result(builder, tables, amo, &old_halves, &src_halves);
+                sign_extend_into(builder, tables, &old_halves, targets.rd);
+                let new_halves = halves(builder, tables, new, op.double);
+                memory.access(builder, tables, addr, &old_halves, &new_halves);
+                self.add_store(builder, addr);
+            }
+        }
+    }
+
+    /// Ends the reservation if it is at `addr`, where a plain store or an AMO writes.
+    pub fn add_store(&mut self, builder: &mut CircuitBuilder<GoldilocksField, 2>, addr: Target) {
+        if let Some((valid, reserved)) = self.reservation {
+            let at_reservation = builder.is_equal(reserved, addr);
+            let broken = builder.and(valid, at_reservation);
+            let still_valid = builder.sub(valid.target, broken.target);
+            self.reservation = Some((BoolTarget::new_unsafe(still_valid), reserved));
+        }
+    }
+
+    /// Assigns the memory operands read by the atomic rows.
+    pub fn set_witness(&self, pw: &mut PartialWitness<GoldilocksField>) -> Result<(), anyhow::Error> {
+        for &(target, value) in &self.witness {
+            pw.set_target(target, value)?;
+        }
+        Ok(())
+    }
+}
+
+const TWO_POW_16: u64 = 1 << 16;
+const TWO_POW_31: u64 = 1 << 31;
+const TWO_POW_32: u64 = 1 << 32;
+
+/// Splits `x < 2^33` into its low 32 bits and bit 32.
+fn split_carry(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    x: Target,
+) -> (Target, BoolTarget) {
+    let limbs = tables.split_limbs(builder, x, 16, 3);
+    let carry = BoolTarget::new_unsafe(limbs[2]);
+    builder.assert_bool(carry);
+    let low = builder.mul_const_add(GoldilocksField::from_canonical_u64(TWO_POW_16), limbs[1], limbs[0]);
+    (low, carry)
+}
+
+/// The 32-bit halves of a memory operand, least significant first; range checks them.
+fn halves(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    x: Target,
+    double: bool,
+) -> Vec<Target> {
+    if !double {
+        tables.range_check_bits(builder, x, 32);
+        return vec![x];
+    }
+    let limbs = tables.split_u64_limbs(builder, x);
+    let shift = GoldilocksField::from_canonical_u64(TWO_POW_16);
+    vec![
+        builder.mul_const_add(shift, limbs[1], limbs[0]),
+        builder.mul_const_add(shift, limbs[3], limbs[2]),
+    ]
+}
+
+/// The halves of `rs2` that a `.w` or `.d` instruction stores.
+fn source_halves(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    rs2: Target,
+    double: bool,
+) -> Vec<Target> {
+    let mut rs2_halves = halves(builder, tables, rs2, true);
+    rs2_halves.truncate(if double { 2 } else { 1 });
+    rs2_halves
+}
+
+fn join(builder: &mut CircuitBuilder<GoldilocksField, 2>, halves: &[Target]) -> Target {
+    match *halves {
+        [lo] => lo,
+        [lo, hi] => builder.mul_const_add(GoldilocksField::from_canonical_u64(TWO_POW_32), hi, lo),
+        _ => unreachable!("operands have one or two halves"),
+    }
+}
+
+/// Constrains `rd` to the sign extension of a memory operand.
+///
+/// For words, `sext(v) = v + b31·(2^64 - 2^32)`, which is `v - b31` in the Goldilocks field.
+pub(crate) fn sign_extend_into(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    halves: &[Target],
+    rd: Target,
+) {
+    let value = join(builder, halves);
+    let value = if halves.len() == 1 {
+        let biased = builder.add_const(halves[0], GoldilocksField::from_canonical_u64(TWO_POW_31));
+        let (_, sign) = split_carry(builder, tables, biased);
+        builder.sub(value, sign.target)
+    } else {
+        value
+    };
+    builder.connect(rd, value);
+}
+
+/// `a < b` for operands given as 32-bit halves, least significant first.
+fn less_than(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    a: &[Target],
+    b: &[Target],
+) -> BoolTarget {
+    let mut lt = builder._false();
+    for (&x, &y) in a.iter().zip(b) {
+        // x + 2^32 - y carries out of 32 bits exactly when x >= y.
+        let shifted = builder.add_const(x, GoldilocksField::from_canonical_u64(TWO_POW_32));
+        let diff = builder.sub(shifted, y);
+        let (_, ge) = split_carry(builder, tables, diff);
+        let half_lt = builder.not(ge);
+        let eq = builder.is_equal(x, y);
+        // A more significant half decides unless it is equal.
+        let keep = builder.and(eq, lt);
+        lt = builder.or(half_lt, keep);
+    }
+    lt
+}
+
+/// Maps signed operands to unsigned ones with the same order by flipping the sign bit.
+fn flip_sign(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    halves: &[Target],
+) -> Vec<Target> {
+    let mut flipped = halves.to_vec();
+    let top = flipped.last_mut().unwrap();
+    let biased = builder.add_const(*top, GoldilocksField::from_canonical_u64(TWO_POW_31));
+    let (low, _) = split_carry(builder, tables, biased);
+    *top = low;
+    flipped
+}
+
+/// Value an AMO leaves in memory, as a single target.
+fn amo_result(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    op: AmoOp,
+    old: &[Target],
+    src: &[Target],
+) -> Target {
+    // Bytes come from the 32-bit halves, which are range checked, so they cannot be the
+    // bytes of a value plus p.
+    let bitwise = |builder: &mut CircuitBuilder<GoldilocksField, 2>, tables: &mut LookupTables, byte_op| {
+        let mut result = Vec::with_capacity(old.len());
+        for (&a, &b) in old.iter().zip(src) {
+            let a_bytes = tables.split_limbs(builder, a, 8, 4);
+            let b_bytes = tables.split_limbs(builder, b, 8, 4);
+            let mut acc = builder.zero();
+            for (&x, &y) in a_bytes.iter().zip(&b_bytes).rev() {
+                let byte = tables.byte_op(builder, byte_op, x, y);
+                acc = builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << 8), acc, byte);
+            }
+            result.push(acc);
+        }
+        join(builder, &result)
+    };
+    match op {
+        AmoOp::Swap => join(builder, src),
+        AmoOp::Add => {
+            let mut carry = builder.zero();
+            let mut sum = Vec::with_capacity(old.len());
+            for (&a, &b) in old.iter().zip(src) {
+                let s = builder.add_many([a, b, carry]);
+                let (low, c) = split_carry(builder, tables, s);
+                sum.push(low);
+                carry = c.target;
+            }
+            join(builder, &sum)
+        }
+        AmoOp::Xor => bitwise(builder, tables, ByteOp::Xor),
+        AmoOp::And => bitwise(builder, tables, ByteOp::And),
+        AmoOp::Or => bitwise(builder, tables, ByteOp::Or),
+        AmoOp::Min | AmoOp::Max | AmoOp::Minu | AmoOp::Maxu => {
+            let lt = if matches!(op, AmoOp::Min | AmoOp::Max) {
+                let (a, b) = (flip_sign(builder, tables, old), flip_sign(builder, tables, src));
+                less_than(builder, tables, &a, &b)
+            } else {
+                less_than(builder, tables, old, src)
+            };
+            let (a, b) = (join(builder, old), join(builder, src));
+            if matches!(op, AmoOp::Min | AmoOp::Minu) {
+                builder.select(lt, a, b)
+            } else {
+                builder.select(lt, b, a)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::prove_multi_instruction_constraint;
+    use crate::convert_trace_to_rows;
+    use crate::trace_parser::{RegisterFile, TraceEntry};
+
+    fn atomic(opcode: &str, addr: u64, src: Option<u64>, rd_after: u64) -> TraceEntry {
+        let mut before = RegisterFile::default().with(&[("x10", addr)]);
+        if let Some(src) = src {
+            before = before.with(&[("x14", src)]);
+        }
+        TraceEntry {
+            opcode: opcode.to_string(),
+            rd: Some("x15".to_string()),
+            rs1: Some("x10".to_string()),
+            rs2: src.map(|_| "x14".to_string()),
+            regs_before: before,
+            regs_after: before.with(&[("x15", rd_after)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_atomics_follow_memory_and_reservations() {
+        let (a, b) = (0x2000, 0x3000);
+        let trace = vec![
+            atomic("lr.w", a, None, 5),
+            atomic("sc.w", a, Some(7), 0),
+            atomic("amoadd.w", a, Some(0xffff_ffff), 7),
+            atomic("amomin.w", a, Some(0xffff_fffe), 6),
+            atomic("lr.w", a, None, (-2i64) as u64),
+            atomic("amoswap.d", b, Some(9), 0x1234),
+            atomic("amoor.w", a, Some(1), (-2i64) as u64), // breaks the reservation
+            atomic("sc.w", a, Some(3), 1),
+            atomic("amomaxu.d", b, Some(3), 9),
+            atomic("lr.w", a, None, (-1i64) as u64),
+        ];
+        let rows = convert_trace_to_rows(&trace).unwrap();
+        assert_eq!(rows.len(), trace.len());
+        let (proof, data) = prove_multi_instruction_constraint(&rows).unwrap();
+        data.verify(proof).unwrap();
+
+        // amoadd must read what sc stored.
+        let mut bad = rows.clone();
+        bad[2].atomic.as_mut().unwrap().old = GoldilocksField::from_canonical_u64(8);
+        bad[2].rd_val = GoldilocksField::from_canonical_u64(8);
+        assert!(prove_multi_instruction_constraint(&bad).is_err());
+
+        // sc cannot succeed once amoor has written to the reserved address.
+        let mut bad = rows.clone();
+        bad[7].rd_val = GoldilocksField::ZERO;
+        assert!(prove_multi_instruction_constraint(&bad).is_err());
+    }
+
+    #[test]
+    fn test_amo_bitwise_ops_on_small_operands() {
+        let (a, b) = (0x4000, 0x4008);
+        let trace = vec![
+            atomic("amoswap.w", a, Some(0x0f), 0),
+            atomic("amoxor.w", a, Some(0x3c), 0x0f),
+            atomic("amoand.w", a, Some(0x27), 0x33),
+            atomic("amoor.w", a, Some(0x40), 0x23),
+            atomic("lr.w", a, None, 0x63),
+            atomic("amoxor.d", b, Some(5), 0),
+            atomic("amoand.d", b, Some(6), 5),
+            atomic("amoor.d", b, Some(3), 4),
+            atomic("lr.d", b, None, 7),
+        ];
+        let rows = convert_trace_to_rows(&trace).unwrap();
+        let (proof, data) = prove_multi_instruction_constraint(&rows).unwrap();
+        data.verify(proof).unwrap();
+
+        // The values the bitwise AMOs leave are fixed by their operands.
+        for (i, value) in [(4, 0x62), (8, 6)] {
+            let mut bad = rows.clone();
+            bad[i].atomic.as_mut().unwrap().old = GoldilocksField::from_canonical_u64(value);
+            bad[i].rd_val = GoldilocksField::from_canonical_u64(value);
+            assert!(prove_multi_instruction_constraint(&bad).is_err());
+        }
+    }
+}
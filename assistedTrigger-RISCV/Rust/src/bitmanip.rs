@@ -0,0 +1,379 @@
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::{Field, Field64};
+use plonky2::iop::target::{BoolTarget, Target};
+use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+
+use crate::circuit::RowTargets;
+use crate::lookup::{ByteOp, LookupTables};
+
+/// Zba/Zbb instructions proven by [`BitmanipUnit::add_bitmanip_row`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOp {
+    /// `sh1add`/`sh2add`/`sh3add`: `rd = (rs1 << n) + rs2`.
+    ShiftAdd(u32),
+    Andn,
+    Orn,
+    Xnor,
+    Clz,
+    Ctz,
+    Cpop,
+    Rev8,
+    SextB,
+    SextH,
+    ZextH,
+}
+
+/// Supported mnemonics; opcode ids follow the floating-point ones in this order.
+const BIT_OPS: [(&str, BitOp); 13] = [
+    ("sh1add", BitOp::ShiftAdd(1)),
+    ("sh2add", BitOp::ShiftAdd(2)),
+    ("sh3add", BitOp::ShiftAdd(3)),
+    ("andn", BitOp::Andn),
+    ("orn", BitOp::Orn),
+    ("xnor", BitOp::Xnor),
+    ("clz", BitOp::Clz),
+    ("ctz", BitOp::Ctz),
+    ("cpop", BitOp::Cpop),
+    ("rev8", BitOp::Rev8),
+    ("sext.b", BitOp::SextB),
+    ("sext.h", BitOp::SextH),
+    ("zext.h", BitOp::ZextH),
+];
+
+const FIRST_OPCODE_ID: u64 = 64;
+
+impl BitOp {
+    pub fn parse(mnemonic: &str) -> Option<BitOp> {
+        BIT_OPS.iter().find(|(m, _)| *m == mnemonic).map(|&(_, op)| op)
+    }
+
+    pub fn opcode_id(self) -> u64 {
+        let index = BIT_OPS.iter().position(|&(_, op)| op == self).expect("every op is in the table");
+        FIRST_OPCODE_ID + index as u64
+    }
+
+    /// Whether the instruction reads `rs2`.
+    pub fn is_binary(self) -> bool {
+        matches!(self, BitOp::ShiftAdd(_) | BitOp::Andn | BitOp::Orn | BitOp::Xnor)
+    }
+
+    /// Value written to `rd`.
+    pub fn apply(self, a: u64, b: u64) -> u64 {
+        match self {
+            BitOp::ShiftAdd(n) => (a << n).wrapping_add(b),
+            BitOp::Andn => a & !b,
+            BitOp::Orn => a | !b,
+            BitOp::Xnor => !(a ^ b),
+            BitOp::Clz => a.leading_zeros() as u64,
+            BitOp::Ctz => a.trailing_zeros() as u64,
+            BitOp::Cpop => a.count_ones() as u64,
+            BitOp::Rev8 => a.swap_bytes(),
+            BitOp::SextB => a as i8 as i64 as u64,
+            BitOp::SextH => a as i16 as i64 as u64,
+            BitOp::ZextH => a as u16 as u64,
+        }
+    }
+}
+
+/// A Zba/Zbb row, with its operands as the 64-bit register values rather than field elements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitmanipRow {
+    pub op: BitOp,
+    pub rs1: u64,
+    pub rs2: u64,
+}
+
+impl BitmanipRow {
+    /// The row's `imm_val`: bits 0, 1 and 2 are set when `rs1`, `rs2` and `rd` are
+    /// `2^64 - 2^32 + 1` or more, which tells such a value from its reduction in the field.
+    pub fn wrapped_flags(&self) -> u64 {
+        let rs2 = if self.op.is_binary() { self.rs2 } else { 0 };
+        [self.rs1, rs2, self.op.apply(self.rs1, rs2)]
+            .iter()
+            .enumerate()
+            .map(|(i, &v)| ((v >= GoldilocksField::ORDER) as u64) << i)
+            .sum()
+    }
+}
+
+/// Proves Zba/Zbb rows, and holds the limbs of their operands until the witness is set.
+#[derive(Debug, Default)]
+pub struct BitmanipUnit {
+    witness: Vec<(Target, GoldilocksField)>,
+}
+
+impl BitmanipUnit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the constraints of a Zba/Zbb row.
+    ///
+    /// Operands are witnessed as 16-bit limbs of the register values, then split into bytes or
+    /// bits depending on the instruction, and the result is built as 32-bit halves. The
+    /// limbs and halves recombine to the row's field elements, and `imm_val` carries
+    /// [`BitmanipRow::wrapped_flags`], so that values of `2^64 - 2^32 + 1` or more are proven
+    /// as they are rather than reduced.
+    pub fn add_bitmanip_row(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        tables: &mut LookupTables,
+        targets: &RowTargets,
+        row: &BitmanipRow,
+    ) {
+        let op = row.op;
+        let opcode = builder.constant(GoldilocksField::from_canonical_u64(op.opcode_id()));
+        builder.connect(targets.opcode, opcode);
+        builder.assert_zero(targets.imm_flag.target);
+
+        let (a, a_wrapped) = self.operand(builder, tables, targets.rs1, row.rs1);
+        let (b, b_wrapped) = if op.is_binary() {
+            self.operand(builder, tables, targets.rs2, row.rs2)
+        } else {
+            builder.assert_zero(targets.rs2);
+            ([builder.zero(); 4], builder._false())
+        };
+
+        let [lo, hi] = match op {
+            BitOp::ShiftAdd(n) => shift_add(builder, tables, &a, &b, n),
+            BitOp::Andn | BitOp::Orn | BitOp::Xnor => {
+                let a = bytes(builder, tables, &a);
+                let b = bytes(builder, tables, &b);
+                let bytes: Vec<Target> = a
+                    .iter()
+                    .zip(&b)
+                    .map(|(&x, &y)| match op {
+                        BitOp::Andn => {
+                            let not_y = complement_byte(builder, y);
+                            tables.byte_op(builder, ByteOp::And, x, not_y)
+                        }
+                        BitOp::Orn => {
+                            let not_y = complement_byte(builder, y);
+                            tables.byte_op(builder, ByteOp::Or, x, not_y)
+                        }
+                        _ => {
+                            let xor = tables.byte_op(builder, ByteOp::Xor, x, y);
+                            complement_byte(builder, xor)
+                        }
+                    })
+                    .collect();
+                [recompose(builder, &bytes[..4], 8), recompose(builder, &bytes[4..], 8)]
+            }
+            BitOp::Clz | BitOp::Ctz => {
+                let mut bits = bits(builder, &a);
+                if op == BitOp::Clz {
+                    bits.reverse();
+                }
+                // Count the positions before the first set bit, scanning from the counted end.
+                let mut seen = builder._false();
+                let mut count = builder.zero();
+                for bit in bits {
+                    seen = builder.or(seen, bit);
+                    let unseen = builder.not(seen);
+                    count = builder.add(count, unseen.target);
+                }
+                [count, builder.zero()]
+            }
+            BitOp::Cpop => {
+                let bits = bits(builder, &a);
+                [builder.add_many(bits.iter().map(|b| b.target)), builder.zero()]
+            }
+            BitOp::Rev8 => {
+                let mut bytes = bytes(builder, tables, &a);
+                bytes.reverse();
+                [recompose(builder, &bytes[..4], 8), recompose(builder, &bytes[4..], 8)]
+            }
+            BitOp::SextB | BitOp::SextH => {
+                let width = if op == BitOp::SextB { 8 } else { 16 };
+                let low = if width == 8 { tables.split_limbs(builder, a[0], 8, 2)[0] } else { a[0] };
+                let sign = builder.split_le(low, width)[width - 1];
+                // The sign fills the bits above `width`.
+                let fill = GoldilocksField::from_canonical_u64((1 << 32) - (1 << width));
+                let lo = builder.mul_const_add(fill, sign.target, low);
+                let hi = builder.mul_const(GoldilocksField::from_canonical_u64(u32::MAX as u64), sign.target);
+                [lo, hi]
+            }
+            BitOp::ZextH => [a[0], builder.zero()],
+        };
+        let rd = recompose(builder, &[lo, hi], 32);
+        builder.connect(targets.rd, rd);
+
+        let rd_wrapped = wrapped(builder, [lo, hi]);
+        let flags = [a_wrapped, b_wrapped, rd_wrapped]
+            .iter()
+            .rev()
+            .fold(builder.zero(), |acc, flag| builder.mul_const_add(GoldilocksField::TWO, acc, flag.target));
+        builder.connect(targets.imm_val, flags);
+    }
+
+    /// Witnesses the 16-bit limbs of the register value `value`, whose reduction is `x`, and
+    /// returns them with whether `value` is `p` or more.
+    fn operand(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        tables: &mut LookupTables,
+        x: Target,
+        value: u64,
+    ) -> ([Target; 4], BoolTarget) {
+        let limbs: [Target; 4] = std::array::from_fn(|_| builder.add_virtual_target());
+        for (i, &limb) in limbs.iter().enumerate() {
+            tables.range_check_16(builder, limb);
+            self.witness.push((limb, GoldilocksField::from_canonical_u64((value >> (16 * i)) & 0xffff)));
+        }
+        let reduced = recompose(builder, &limbs, 16);
+        builder.connect(reduced, x);
+        let halves = halves(builder, &limbs);
+        (limbs, wrapped(builder, halves))
+    }
+
+    pub fn set_witness(&self, pw: &mut PartialWitness<GoldilocksField>) -> Result<(), anyhow::Error> {
+        for &(target, value) in &self.witness {
+            pw.set_target(target, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the value with 32-bit halves `[lo, hi]` is `p = 2^64 - 2^32 + 1` or more.
+///
+/// The field elements `x` and `x + p` are equal, and this flag tells them apart.
+pub(crate) fn wrapped(builder: &mut CircuitBuilder<GoldilocksField, 2>, [lo, hi]: [Target; 2]) -> BoolTarget {
+    let max = builder.constant(GoldilocksField::from_canonical_u64(u32::MAX as u64));
+    let hi_is_max = builder.is_equal(hi, max);
+    let zero = builder.zero();
+    let lo_is_zero = builder.is_equal(lo, zero);
+    let lo_is_nonzero = builder.not(lo_is_zero);
+    builder.and(hi_is_max, lo_is_nonzero)
+}
+
+/// `(a << n) + b` modulo `2^64`, computed on 32-bit halves.
+fn shift_add(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    a: &[Target; 4],
+    b: &[Target; 4],
+    n: u32,
+) -> [Target; 2] {
+    let a_halves = halves(builder, a);
+    let b_halves = halves(builder, b);
+    let scale = GoldilocksField::from_canonical_u64(1 << n);
+
+    // The low half shifted by n spills its top n bits into the high half.
+    let shifted_lo = builder.mul_const(scale, a_halves[0]);
+    let (shifted_lo, spill) = low_32(builder, tables, shifted_lo);
+    let sum_lo = builder.add(shifted_lo, b_halves[0]);
+    let (lo, carry) = low_32(builder, tables, sum_lo);
+
+    let shifted_hi = builder.mul_const_add(scale, a_halves[1], spill);
+    let sum_hi = builder.add_many([shifted_hi, b_halves[1], carry]);
+    let (hi, _) = low_32(builder, tables, sum_hi);
+    [lo, hi]
+}
+
+/// Splits `x < 2^48` into its low 32 bits and the bits above.
+fn low_32(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    x: Target,
+) -> (Target, Target) {
+    let limbs = tables.split_limbs(builder, x, 16, 3);
+    (recompose(builder, &limbs[..2], 16), limbs[2])
+}
+
+fn halves(builder: &mut CircuitBuilder<GoldilocksField, 2>, limbs: &[Target; 4]) -> [Target; 2] {
+    [recompose(builder, &limbs[..2], 16), recompose(builder, &limbs[2..], 16)]
+}
+
+/// Bytes of a value given as 16-bit limbs, least significant first.
+fn bytes(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    limbs: &[Target; 4],
+) -> Vec<Target> {
+    limbs.iter().flat_map(|&limb| tables.split_limbs(builder, limb, 8, 2)).collect()
+}
+
+/// Bits of a value given as 16-bit limbs, least significant first.
+fn bits(builder: &mut CircuitBuilder<GoldilocksField, 2>, limbs: &[Target; 4]) -> Vec<BoolTarget> {
+    limbs.iter().flat_map(|&limb| builder.split_le(limb, 16)).collect()
+}
+
+fn complement_byte(builder: &mut CircuitBuilder<GoldilocksField, 2>, byte: Target) -> Target {
+    let max = builder.constant(GoldilocksField::from_canonical_u64(0xff));
+    builder.sub(max, byte)
+}
+
+/// Recombines little-endian limbs of `limb_bits` bits.
+fn recompose(builder: &mut CircuitBuilder<GoldilocksField, 2>, limbs: &[Target], limb_bits: usize) -> Target {
+    let shift = GoldilocksField::from_canonical_u64(1 << limb_bits);
+    let mut acc = builder.zero();
+    for &limb in limbs.iter().rev() {
+        acc = builder.mul_const_add(shift, acc, limb);
+    }
+    acc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::prove_multi_instruction_constraint;
+    use crate::trace_parser::{RegisterFile, TraceEntry};
+    use crate::zk::convert_trace_to_rows;
+
+    fn bitmanip(opcode: &str, a: u64, b: Option<u64>) -> TraceEntry {
+        let op = BitOp::parse(opcode).unwrap();
+        let before = RegisterFile::default().with(&[("x6", a), ("x7", b.unwrap_or(0))]);
+        TraceEntry {
+            opcode: opcode.to_string(),
+            rd: Some("x5".to_string()),
+            rs1: Some("x6".to_string()),
+            rs2: b.map(|_| "x7".to_string()),
+            regs_before: before,
+            regs_after: before.with(&[("x5", op.apply(a, b.unwrap_or(0)))]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_bitmanip_rows_prove_full_u64_values() {
+        let x = 0x00f0_1234_8000_0080;
+        let trace = vec![
+            bitmanip("sh1add", 0x7fff_ffff_ffff, Some(5)),
+            bitmanip("sh3add", 0x1fff_0000_0000_0001, Some(0x0000_ffff_0000_0000)),
+            bitmanip("andn", x, Some(0xff00)),
+            bitmanip("orn", x, Some(0x00ff_ffff_ffff_ff00)),
+            bitmanip("xnor", x, Some(0x00ff_ffff_0000_0000)),
+            bitmanip("clz", x, None),
+            bitmanip("clz", 0, None),
+            bitmanip("ctz", x, None),
+            bitmanip("cpop", x, None),
+            bitmanip("rev8", x, None),
+            bitmanip("sext.b", x, None),
+            bitmanip("sext.h", 0x7fff, None),
+            bitmanip("zext.h", x, None),
+            // Values of p or more, which equal smaller ones in the field.
+            bitmanip("clz", 3 + GoldilocksField::ORDER, None),
+            bitmanip("clz", 3, None),
+            bitmanip("andn", u64::MAX, Some(0xffff_ffff_0000_0001)),
+            bitmanip("sh2add", u64::MAX, Some(u64::MAX)),
+            bitmanip("sext.b", 0x80, None),
+            bitmanip("rev8", 0x0100_0000_ffff_ffff, None),
+        ];
+        let rows = convert_trace_to_rows(&trace).unwrap();
+        assert_eq!(rows.len(), 19);
+        assert_eq!(rows[13].rs1_val, rows[14].rs1_val);
+        assert_eq!([rows[13].imm_val, rows[14].imm_val], [GoldilocksField::ONE, GoldilocksField::ZERO]);
+        assert_eq!(rows[17].imm_val, GoldilocksField::from_canonical_u64(4));
+        let (proof, data) = prove_multi_instruction_constraint(&rows).unwrap();
+        data.verify(proof).unwrap();
+
+        // The flags tell which of the two values a row read.
+        let mut bad = rows[13..14].to_vec();
+        bad[0].imm_val = GoldilocksField::ZERO;
+        assert!(prove_multi_instruction_constraint(&bad).is_err());
+        let mut bad = rows[5..6].to_vec();
+        bad[0].rd_val = GoldilocksField::from_canonical_u64(7);
+        assert!(prove_multi_instruction_constraint(&bad).is_err());
+    }
+}
@@ -3,264 +3,304 @@ use plonky2::field::types::Field;
 use plonky2::plonk::circuit_builder::CircuitBuilder;
 use plonky2::plonk::circuit_data::{CircuitConfig, CircuitData};
 use plonky2::plonk::config::PoseidonGoldilocksConfig;
+use plonky2::iop::target::{BoolTarget, Target};
 use plonky2::iop::witness::{PartialWitness, WitnessWrite};
 use plonky2::plonk::prover::prove;
 use plonky2::util::timing::TimingTree;
 use log::Level;
 use plonky2::plonk::proof::ProofWithPublicInputs;
-use crate::zk::InstructionRow;
+use crate::alu_gate::AluGate;
+use crate::csr::{add_csr_row, InputVisibility};
+use crate::error::Error;
+use crate::syscall::IoChannel;
+use crate::atomic::AtomicUnit;
+use crate::fp::FpUnit;
+use crate::inputs::ProgramInputs;
+use crate::bitmanip::BitmanipUnit;
+use crate::memory::MemoryLog;
+use crate::lookup::LookupTables;
+use crate::window::{WindowBoundary, WindowTargets};
+use crate::zk::{InstructionRow, UNPROVEN_SPAN_OPCODE};
 use plonky2::field::types::Field64;
 
-pub fn prove_multi_instruction_constraint(
-    rows: &[InstructionRow<GoldilocksField>],
-) -> Result<
-    (
-        ProofWithPublicInputs<GoldilocksField, PoseidonGoldilocksConfig, 2>,
-        CircuitData<GoldilocksField, PoseidonGoldilocksConfig, 2>,
-    ),
-    anyhow::Error,
-> {
-    assert!(!rows.is_empty(), "Instruction row trace is empty!");
+pub type InstructionProof = ProofWithPublicInputs<GoldilocksField, PoseidonGoldilocksConfig, 2>;
+pub type InstructionCircuit = CircuitData<GoldilocksField, PoseidonGoldilocksConfig, 2>;
+
+/// Targets allocated for one instruction row.
+///
+/// All targets are routed wires of an [`AluGate`] slot, which also holds the row's
+/// opcode selectors and division witnesses.
+#[derive(Debug, Clone)]
+pub struct RowTargets {
+    pub opcode: Target,
+    pub rs1: Target,
+    pub rs2: Target,
+    pub imm_flag: BoolTarget,
+    pub imm_val: Target,
+    pub rd: Target,
+}
 
-    println!("Parsed {} instruction rows", rows.len());
-    for row in rows {
-        println!(
-            "opcode: {}, rs1: {}, rs2: {}, imm_flag: {}, imm_val: {}, rd: {}",
-            row.opcode, row.rs1_val, row.rs2_val, row.imm_flag, row.imm_val, row.rd_val
-        );
+/// Adds the constraints for a single instruction row.
+///
+/// Rows are packed into [`AluGate`]s, several per gate, which enforce the one-hot opcode
+/// selectors and the selector-weighted result in a single custom gate row. The immediates
+/// exported by the gate are range checked through `tables`: 12 bits signed for I/S-type
+/// and 20 bits for U-type opcodes.
+pub fn add_instruction_row(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+) -> RowTargets {
+    let gate = AluGate::new_from_config(&builder.config);
+    let (row, i) = builder.find_slot(gate, &[], &[]);
+    let wire = |w| Target::wire(row, w);
+
+    tables.range_check_bits(builder, wire(AluGate::wire_imm_i(i)), 12);
+    tables.range_check_bits(builder, wire(AluGate::wire_imm_u(i)), 20);
+
+    RowTargets {
+        opcode: wire(AluGate::wire_opcode(i)),
+        rs1: wire(AluGate::wire_rs1(i)),
+        rs2: wire(AluGate::wire_rs2(i)),
+        imm_flag: BoolTarget::new_unsafe(wire(AluGate::wire_imm_flag(i))),
+        imm_val: wire(AluGate::wire_imm_val(i)),
+        rd: wire(AluGate::wire_rd(i)),
     }
+}
 
-    let config = CircuitConfig::standard_recursion_config();
-    let mut builder = CircuitBuilder::<GoldilocksField, 2>::new(config);
-
-    let mut opcode_targets = vec![];
-    let mut rs1_targets = vec![];
-    let mut rs2_targets = vec![];
-    let mut imm_flag_targets = vec![];
-    let mut imm_val_targets = vec![];
-    let mut rd_targets = vec![];
-
-    for _ in rows {
-        opcode_targets.push(builder.add_virtual_target());
-        rs1_targets.push(builder.add_virtual_target());
-        rs2_targets.push(builder.add_virtual_target());
-        imm_flag_targets.push(builder.add_virtual_target());
-        imm_val_targets.push(builder.add_virtual_target());
-        rd_targets.push(builder.add_virtual_target());
+/// Allocates the targets of a row proven by a dedicated gadget rather than an [`AluGate`].
+fn add_gadget_row(builder: &mut CircuitBuilder<GoldilocksField, 2>) -> RowTargets {
+    RowTargets {
+        opcode: builder.add_virtual_target(),
+        rs1: builder.add_virtual_target(),
+        rs2: builder.add_virtual_target(),
+        imm_flag: builder.add_virtual_bool_target_unsafe(),
+        imm_val: builder.add_virtual_target(),
+        rd: builder.add_virtual_target(),
     }
+}
 
-    let zero = builder.zero();
-    let one = builder.one();
-
-    for i in 0..rows.len() {
-        let opcode = &opcode_targets[i];
-        let rs1 = &rs1_targets[i];
-        let rs2 = &rs2_targets[i];
-        let imm_flag = &imm_flag_targets[i];
-        let imm_val = &imm_val_targets[i];
-        let rd = &rd_targets[i];
-
-        // let imm_bool = builder.is_equal(*imm_flag, one);
-
-        let one_const = builder.one();
-        let imm_bool = builder.is_equal(*imm_flag, one_const);
-        let rs2_or_imm = builder.select(imm_bool, *imm_val, *rs2);
-
-        // // Precompute constants for opcodes
-        // let c1 = builder.constant(GoldilocksField::from_canonical_u64(1)); // add
-        // let c2 = builder.constant(GoldilocksField::from_canonical_u64(2)); // sub
-        // let c3 = builder.constant(GoldilocksField::from_canonical_u64(3)); // mul
-        // let c4 = builder.constant(GoldilocksField::from_canonical_u64(4)); // addi
-        // let c5 = builder.constant(GoldilocksField::from_canonical_u64(5)); // div
-        // let c6 = builder.constant(GoldilocksField::from_canonical_u64(6)); // sd
-        // let c7 = builder.constant(GoldilocksField::from_canonical_u64(7)); // ld
-        // let c8 = builder.constant(GoldilocksField::from_canonical_u64(8)); // li
-        // let c9 = builder.constant(GoldilocksField::from_canonical_u64(9)); // lw
-        // let c10 = builder.constant(GoldilocksField::from_canonical_u64(10)); // addw
-        // let c11 = builder.constant(GoldilocksField::from_canonical_u64(11)); // subw
-        // let c12 = builder.constant(GoldilocksField::from_canonical_u64(12)); // slliw
-        // let c13 = builder.constant(GoldilocksField::from_canonical_u64(13)); // sraiw
-        // let c14 = builder.constant(GoldilocksField::from_canonical_u64(14)); // xor
-        // let c15 = builder.constant(GoldilocksField::from_canonical_u64(15)); // or
-        // let c16 = builder.constant(GoldilocksField::from_canonical_u64(16)); // and
-        // let c17 = builder.constant(GoldilocksField::from_canonical_u64(17)); // mv
-
-        // === Opcode constants ===
-        let mut c = |v| builder.constant(GoldilocksField::from_canonical_u64(v));
-        let c1 = c(1);  let c2 = c(2);  let c3 = c(3);  let c4 = c(4);
-        let c5 = c(5);  let c6 = c(6);  let c7 = c(7);  let c8 = c(8);
-        let c9 = c(9);  let c10 = c(10); let c11 = c(11); let c12 = c(12);
-        let c13 = c(13); let c14 = c(14); let c15 = c(15); let c16 = c(16);
-        let c17 = c(17);
-
-
-        // // Match opcodes
-        // let is_add = builder.is_equal(*opcode, c1);
-        // let is_sub = builder.is_equal(*opcode, c2);
-        // let is_mul = builder.is_equal(*opcode, c3);
-        // let is_addi = builder.is_equal(*opcode, c4);
-        // let is_div = builder.is_equal(*opcode, c5);
-        // let is_sd = builder.is_equal(*opcode, c6);
-        // let is_ld = builder.is_equal(*opcode, c7);
-        // let is_li = builder.is_equal(*opcode, c8);
-        // let is_lw = builder.is_equal(*opcode, c9);
-        // let is_addw = builder.is_equal(*opcode, c10);
-        // let is_subw = builder.is_equal(*opcode, c11);
-        // let is_slliw = builder.is_equal(*opcode, c12);
-        // let is_sraiw = builder.is_equal(*opcode, c13);
-        // let is_xor = builder.is_equal(*opcode, c14);
-        // let is_or = builder.is_equal(*opcode, c15);
-        // let is_and = builder.is_equal(*opcode, c16);
-        // let is_mv = builder.is_equal(*opcode, c17);
-
-        // === Match opcodes ===
-        let is_add    = builder.is_equal(*opcode, c1);
-        let is_sub    = builder.is_equal(*opcode, c2);
-        let is_mul    = builder.is_equal(*opcode, c3);
-        let is_addi   = builder.is_equal(*opcode, c4);
-        let is_div    = builder.is_equal(*opcode, c5);
-        let is_sd     = builder.is_equal(*opcode, c6);
-        let is_ld     = builder.is_equal(*opcode, c7);
-        let is_li     = builder.is_equal(*opcode, c8);
-        let is_lw     = builder.is_equal(*opcode, c9);
-        let is_addw   = builder.is_equal(*opcode, c10);
-        let is_subw   = builder.is_equal(*opcode, c11);
-        let is_slliw  = builder.is_equal(*opcode, c12);
-        let is_sraiw  = builder.is_equal(*opcode, c13);
-        let is_xor    = builder.is_equal(*opcode, c14);
-        let is_or     = builder.is_equal(*opcode, c15);
-        let is_and    = builder.is_equal(*opcode, c16);
-        let is_mv     = builder.is_equal(*opcode, c17);
-
-        // Operations
-        // let add_res = builder.add(*rs1, rs2_or_imm);
-        // let sub_res = builder.sub(*rs1, rs2_or_imm);
-        // let mul_res = builder.mul(*rs1, rs2_or_imm);
-        // let addi_res = builder.add(*rs1, rs2_or_imm);
-        // let is_rs2_zero = builder.is_equal(rs2_or_imm, zero);
-        // let safe_divisor = builder.select(is_rs2_zero, one, rs2_or_imm);
-        // let raw_div = builder.div(*rs1, safe_divisor);
-        // let div_res = builder.select(is_rs2_zero, zero, raw_div);
-        
-        // let is_sd_or_ld = builder.or(is_sd, is_ld);
-        // let is_li_or_lw = builder.or(is_li, is_lw);
-        // let is_addw_or_subw = builder.or(is_addw, is_subw);
-        // let is_slliw_or_sraiw = builder.or(is_slliw, is_sraiw);
-        // let is_xor_or_or = builder.or(is_xor, is_or);
-        // let is_mv = builder.is_equal(*opcode, c17); 
-
-        // let and_res = builder.mul(*rs1, rs2_or_imm);
-        // let or_res = {
-        //     let sum = builder.add(*rs1, rs2_or_imm);
-        //     let product = builder.mul(*rs1, rs2_or_imm);
-        //     builder.sub(sum, product)
-        // };
-
-        
-
-        // === Arithmetic operations ===
-        let add_res = builder.add(*rs1, rs2_or_imm);
-        let sub_res = builder.sub(*rs1, rs2_or_imm);
-        let mul_res = builder.mul(*rs1, rs2_or_imm);
-
-        // Safe division
-        let is_rs2_zero = builder.is_equal(rs2_or_imm, zero);
-        let safe_divisor = builder.select(is_rs2_zero, one, rs2_or_imm);
-        let raw_div = builder.div(*rs1, safe_divisor);
-        let div_res = builder.select(is_rs2_zero, zero, raw_div);
-
-        // Placeholder logic for unimplemented ops
-        let dummy_shift = *rs1; // shift ops not implemented yet
-        let dummy_bitwise = *rs1; // xor/or/and not supported on Target
-
-
-
-
-        // // Conditional result based on opcode
-        // let mut result = builder.select(is_add, add_res, zero);
-        // result = builder.select(is_sub, sub_res, result);
-        // result = builder.select(is_mul, mul_res, result);
-        // result = builder.select(is_addi, addi_res, result);
-        // result = builder.select(is_div, div_res, result);
-        // result = builder.select(is_sd_or_ld, zero, result); // sd/ld do not produce a result
-        // result = builder.select(is_li_or_lw, *imm_val, result); // li produces imm_val, lw does not produce a result
-        // result = builder.select(is_addw_or_subw, result, zero); // addw/subw do not produce a result
-        // result = builder.select(is_slliw_or_sraiw, *rs1, result); // slliw/sraiw do not produce a result
-        // result = builder.select(is_xor_or_or, or_res, result); // xor/or produce or_res
-        // result = builder.select(is_and, and_res, result); // and produces and_res
-        // result = builder.select(is_mv, *rs1, result); // mv produces rs1
-
-        // Result logic: step-by-step cascade
-        let mut result = builder.select(is_add, add_res, zero);
-        result = builder.select(is_sub, sub_res, result);
-        result = builder.select(is_mul, mul_res, result);
-        result = builder.select(is_addi, add_res, result); // rs1 + imm
-        result = builder.select(is_div, div_res, result);
-        result = builder.select(is_li, *imm_val, result);
-        result = builder.select(is_mv, *rs1, result);
-
-        // Temporarily forward rs1 for unsupported ops
-        result = builder.select(is_addw, dummy_shift, result);
-        result = builder.select(is_subw, dummy_shift, result);
-        result = builder.select(is_slliw, dummy_shift, result);
-        result = builder.select(is_sraiw, dummy_shift, result);
-        result = builder.select(is_xor, dummy_bitwise, result);
-        result = builder.select(is_or, dummy_bitwise, result);
-        result = builder.select(is_and, dummy_bitwise, result);
+/// Pins the shape of an [`crate::zk::UnprovenSpan`] marker row. Its pcs and count are
+/// public values that nothing else constrains.
+fn add_unproven_span_row(builder: &mut CircuitBuilder<GoldilocksField, 2>, targets: &RowTargets) {
+    let opcode = builder.constant(GoldilocksField::from_canonical_u64(UNPROVEN_SPAN_OPCODE));
+    builder.connect(targets.opcode, opcode);
+    builder.assert_zero(targets.imm_flag.target);
+    builder.assert_zero(targets.rd);
+}
 
+/// Registers the six public inputs of a row; `rd` is passed separately so that private
+/// inputs can be hidden.
+fn register_row_public_inputs(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    targets: &RowTargets,
+    rd: Target,
+) {
+    builder.register_public_input(targets.opcode);
+    builder.register_public_input(targets.rs1);
+    builder.register_public_input(targets.rs2);
+    builder.register_public_input(targets.imm_flag.target);
+    builder.register_public_input(targets.imm_val);
+    builder.register_public_input(rd);
+}
 
+/// Current `fcsr`, tracked as (value before the first row using it, its witness, current
+/// value). The first row allocates the initial value from its own `fcsr_before`; the caller
+/// stores the row's output in the last field.
+fn fcsr_input<'a>(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    fcsr: &'a mut Option<(Target, GoldilocksField, Target)>,
+    before: GoldilocksField,
+) -> &'a mut (Target, GoldilocksField, Target) {
+    fcsr.get_or_insert_with(|| {
+        let initial = builder.add_virtual_target();
+        (initial, before, initial)
+    })
+}
 
+/// Assigns the witness for one instruction row.
+///
+/// The selectors are derived from the opcode by the gate's generator, which also recomputes
+/// `rd` so a row whose result does not match its opcode fails during witness generation.
+pub fn set_instruction_row(
+    pw: &mut PartialWitness<GoldilocksField>,
+    targets: &RowTargets,
+    row: &InstructionRow<GoldilocksField>,
+) -> Result<(), anyhow::Error> {
+    let to_field = |v: u128| {
+        anyhow::ensure!(v < GoldilocksField::ORDER as u128, "Value too large for GoldilocksField: {}", v);
+        Ok(GoldilocksField::from_canonical_u64(v as u64))
+    };
 
+    pw.set_target(targets.opcode, to_field(row.opcode.0 as u128)?)?;
+    pw.set_target(targets.rs1, to_field(row.rs1_val.0 as u128)?)?;
+    pw.set_target(targets.rs2, to_field(row.rs2_val.0 as u128)?)?;
+    pw.set_target(targets.imm_flag.target, to_field(row.imm_flag.0 as u128)?)?;
+    pw.set_target(targets.imm_val, to_field(row.imm_val.0 as u128)?)?;
+    pw.set_target(targets.rd, to_field(row.rd_val.0 as u128)?)
+}
 
-        // // Enforce result == rd
-        // let is_eq = builder.is_equal(result, *rd);
-        
-        // No output ops
-        let no_output = builder.or(is_sd, is_ld);
-        let no_output = builder.or(no_output, is_lw);
-        result = builder.select(no_output, zero, result);
+/// Proves a trace of instruction rows.
+///
+/// Each row contributes six public inputs (opcode, rs1, rs2, imm_flag, imm_val, rd) in row
+/// order; entries skipped by a lenient conversion show up as [`crate::zk::UnprovenSpan`]
+/// rows. CSR rows are proven by [`add_csr_row`]; the value read by a counter whose input is
+/// [`InputVisibility::Private`] is replaced by zero in its row's public inputs. Floating-point
+/// rows are proven by [`FpUnit`] and Zba/Zbb rows by [`BitmanipUnit`]. If the trace
+/// contains CSR or floating-point rows, the `fcsr` value before the first and after the last
+/// of them follow as two more public inputs. If it contains `ecall` rows, the I/O block
+/// described in [`IoChannel::register_public_inputs`] comes last.
+pub fn prove_multi_instruction_constraint(
+    rows: &[InstructionRow<GoldilocksField>],
+) -> crate::error::Result<(InstructionProof, InstructionCircuit)> {
+    prove_rows(rows, None, None)
+}
 
+/// Like [`prove_multi_instruction_constraint`], also committing the program's inputs and the
+/// boundary of a window of the trace. With `inputs`, the four elements of
+/// [`ProgramInputs::public_digest`] follow the I/O block; with a `boundary`, the block of
+/// [`WindowTargets::register_public_inputs`] comes last.
+///
+/// Fails with [`Error::EmptyTrace`] when there are no rows, and with [`Error::Prover`] when
+/// a row's values do not satisfy its constraints.
+pub fn prove_rows(
+    rows: &[InstructionRow<GoldilocksField>],
+    inputs: Option<&ProgramInputs>,
+    boundary: Option<&WindowBoundary>,
+) -> crate::error::Result<(InstructionProof, InstructionCircuit)> {
+    if rows.is_empty() {
+        return Err(Error::EmptyTrace);
+    }
+    build_and_prove(rows, inputs, boundary).map_err(Error::Prover)
+}
 
-        // // Register the equality check as a public input instead of asserting
-        // builder.assert_one(is_eq.target);
+fn build_and_prove(
+    rows: &[InstructionRow<GoldilocksField>],
+    inputs: Option<&ProgramInputs>,
+    boundary: Option<&WindowBoundary>,
+) -> Result<(InstructionProof, InstructionCircuit), anyhow::Error> {
+    println!("Parsed {} instruction rows", rows.len());
+    if log::log_enabled!(Level::Debug) {
+        for row in rows {
+            log::debug!(
+                "opcode: {}, rs1: {}, rs2: {}, imm_flag: {}, imm_val: {}, rd: {}",
+                row.opcode, row.rs1_val, row.rs2_val, row.imm_flag, row.imm_val, row.rd_val
+            );
+        }
+    }
 
-        // === Enforce output ===
-        let is_result_correct = builder.is_equal(result, *rd);
-        builder.assert_one(is_result_correct.target);
+    let config = CircuitConfig::standard_recursion_config();
+    let mut builder = CircuitBuilder::<GoldilocksField, 2>::new(config);
 
-        // Register public inputs
-        builder.register_public_input(*opcode);
-        builder.register_public_input(*rs1);
-        builder.register_public_input(*rs2);
-        builder.register_public_input(*imm_flag);
-        builder.register_public_input(*imm_val);
-        builder.register_public_input(*rd);
+    let mut tables = LookupTables::new();
+    let mut row_targets = Vec::with_capacity(rows.len());
+    let mut fcsr: Option<(Target, GoldilocksField, Target)> = None;
+    let mut io = IoChannel::new();
+    let mut memory = MemoryLog::new();
+    let mut atomics = AtomicUnit::new();
+    let mut fp = FpUnit::new();
+    let mut bitmanip = BitmanipUnit::new();
+    for row in rows {
+        if let Some(atomic) = &row.atomic {
+            let targets = add_gadget_row(&mut builder);
+            atomics.add_atomic_row(&mut builder, &mut tables, &mut memory, &targets, atomic);
+            register_row_public_inputs(&mut builder, &targets, targets.rd);
+            row_targets.push(targets);
+            continue;
+        }
+        if row.unproven.is_some() {
+            let targets = add_gadget_row(&mut builder);
+            add_unproven_span_row(&mut builder, &targets);
+            register_row_public_inputs(&mut builder, &targets, targets.rd);
+            row_targets.push(targets);
+            continue;
+        }
+        if let Some(bitmanip_row) = &row.bitmanip {
+            let targets = add_gadget_row(&mut builder);
+            bitmanip.add_bitmanip_row(&mut builder, &mut tables, &targets, bitmanip_row);
+            register_row_public_inputs(&mut builder, &targets, targets.rd);
+            row_targets.push(targets);
+            continue;
+        }
+        if let Some(fp_row) = &row.fp {
+            let targets = add_gadget_row(&mut builder);
+            let state = fcsr_input(&mut builder, &mut fcsr, fp_row.fcsr_before);
+            let values = (row.rs1_val, row.rs2_val);
+            state.2 = fp.add_fp_row(&mut builder, &mut tables, &targets, fp_row, values, state.2)?;
+            register_row_public_inputs(&mut builder, &targets, targets.rd);
+            row_targets.push(targets);
+            continue;
+        }
+        let targets = match (&row.csr, &row.syscall) {
+            (_, Some(syscall)) => {
+                let targets = add_gadget_row(&mut builder);
+                io.add_syscall_row(&mut builder, &mut tables, &mut memory, &mut atomics, &targets, syscall)?;
+                targets
+            }
+            (Some(csr), None) => {
+                let targets = add_gadget_row(&mut builder);
+                let state = fcsr_input(&mut builder, &mut fcsr, csr.fcsr_before);
+                state.2 = add_csr_row(&mut builder, &mut tables, &targets, csr, state.2)?;
+                targets
+            }
+            (None, None) => match &row.memory {
+                Some(access) => {
+                    let targets = add_gadget_row(&mut builder);
+                    let addr = memory.add_load_store_row(&mut builder, &mut tables, &targets, access);
+                    if access.store {
+                        atomics.add_store(&mut builder, addr);
+                    }
+                    targets
+                }
+                None => add_instruction_row(&mut builder, &mut tables),
+            },
+        };
+        let rd_public = match row.csr.as_ref().and_then(|csr| csr.nondet) {
+            Some(InputVisibility::Private) => builder.zero(),
+            _ => targets.rd,
+        };
+
+        register_row_public_inputs(&mut builder, &targets, rd_public);
+        row_targets.push(targets);
+    }
+    if let Some((initial, _, last)) = fcsr {
+        builder.register_public_input(initial);
+        builder.register_public_input(last);
+    }
+    if rows.iter().any(|row| row.syscall.is_some()) {
+        io.register_public_inputs(&mut builder);
+    }
+    if let Some(inputs) = inputs {
+        let digest = builder.constant_hash(inputs.public_digest());
+        builder.register_public_inputs(&digest.elements);
+    }
+    let window = boundary.map(|b| WindowTargets::register_public_inputs(&mut builder, &mut tables, b));
+    if let Some(window) = &window {
+        let proven = |(row, _): &(&InstructionRow<GoldilocksField>, &RowTargets)| row.unproven.is_none();
+        let mut pairs = rows.iter().zip(&row_targets);
+        if let Some((row, targets)) = pairs.clone().next().filter(proven) {
+            window.connect_entry(&mut builder, targets, row.x_regs);
+        }
+        if let Some((row, targets)) = pairs.next_back().filter(proven) {
+            window.connect_exit(&mut builder, targets, row.x_regs);
+        }
     }
 
     let data = builder.build::<PoseidonGoldilocksConfig>();
     let mut pw = PartialWitness::new();
 
-    // for (i, row) in rows.iter().enumerate() {
-    //     pw.set_target(opcode_targets[i], row.opcode).unwrap();
-    //     pw.set_target(rs1_targets[i], row.rs1_val).unwrap();
-    //     pw.set_target(rs2_targets[i], row.rs2_val).unwrap();
-    //     pw.set_target(imm_flag_targets[i], row.imm_flag).unwrap();
-    //     pw.set_target(imm_val_targets[i], row.imm_val).unwrap();
-    //     pw.set_target(rd_targets[i], row.rd_val).unwrap();
-    // }
-
-    let to_field = |v: u128| {
-        assert!(v < GoldilocksField::ORDER as u128, "Value too large for GoldilocksField: {}", v);
-        GoldilocksField::from_canonical_u64(v as u64)
-    };
-
-    for (i, row) in rows.iter().enumerate() {
-        pw.set_target(opcode_targets[i], to_field(row.opcode.0 as u128));
-        pw.set_target(rs1_targets[i], to_field(row.rs1_val.0 as u128));
-        pw.set_target(rs2_targets[i], to_field(row.rs2_val.0 as u128));
-        pw.set_target(imm_flag_targets[i], to_field(row.imm_flag.0 as u128));
-        pw.set_target(imm_val_targets[i], to_field(row.imm_val.0 as u128));
-        pw.set_target(rd_targets[i], to_field(row.rd_val.0 as u128));
+    for (targets, row) in row_targets.iter().zip(rows) {
+        set_instruction_row(&mut pw, targets, row)?;
+    }
+    if let Some((initial, before, _)) = fcsr {
+        pw.set_target(initial, before)?;
+    }
+    io.set_witness(&mut pw)?;
+    atomics.set_witness(&mut pw)?;
+    memory.set_witness(&mut pw)?;
+    fp.set_witness(&mut pw)?;
+    bitmanip.set_witness(&mut pw)?;
+    if let Some(window) = &window {
+        window.set_witness(&mut pw)?;
     }
 
     let mut timing = TimingTree::new("prove", Level::Info);
@@ -268,3 +308,54 @@ pub fn prove_multi_instruction_constraint(
     let proof = prove(&data.prover_only, &data.common, pw, &mut timing)?;
     Ok((proof, data))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(opcode: u64, rs1: u64, rs2: u64, imm: Option<i64>, rd: u64) -> InstructionRow<GoldilocksField> {
+        let f = GoldilocksField::from_canonical_u64;
+        InstructionRow {
+            pc: GoldilocksField::ZERO,
+            opcode: f(opcode),
+            rs1_val: f(rs1),
+            rs2_val: f(rs2),
+            imm_flag: if imm.is_some() { GoldilocksField::ONE } else { GoldilocksField::ZERO },
+            imm_val: imm.map(GoldilocksField::from_canonical_i64).unwrap_or(GoldilocksField::ZERO),
+            rd_val: f(rd),
+            x_regs: [None; 3],
+            csr: None,
+            syscall: None,
+            atomic: None,
+            fp: None,
+            bitmanip: None,
+            memory: None,
+            unproven: None,
+        }
+    }
+
+    #[test]
+    fn test_selector_rows_prove_and_reject_bad_result() {
+        let rows = vec![
+            row(4, 0, 0, Some(5), 5),  // addi
+            row(1, 5, 10, None, 15),   // add
+            row(3, 5, 10, None, 50),   // mul
+            row(6, 7, 0, Some(8), 0),  // sd
+        ];
+        let (proof, data) = prove_multi_instruction_constraint(&rows).unwrap();
+        data.verify(proof.clone()).unwrap();
+
+        // The custom gate must survive a save/load round trip.
+        let dir = std::env::temp_dir();
+        let proof_path = dir.join("alu_gate_proof.bin");
+        let circuit_path = dir.join("alu_gate_circuit.bin");
+        let (proof_path, circuit_path) = (proof_path.to_str().unwrap(), circuit_path.to_str().unwrap());
+        crate::store::save_proof_and_circuit(&proof, &data, proof_path, circuit_path).unwrap();
+        let (proof_loaded, data_loaded) = crate::store::load_proof_and_circuit(proof_path, circuit_path).unwrap();
+        data_loaded.verify(proof_loaded).unwrap();
+
+        let bad = vec![row(2, 10, 5, None, 6)]; // sub with wrong rd
+        assert!(matches!(prove_multi_instruction_constraint(&bad), Err(Error::Prover(_))));
+        assert!(matches!(prove_multi_instruction_constraint(&[]), Err(Error::EmptyTrace)));
+    }
+}
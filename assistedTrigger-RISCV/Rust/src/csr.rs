@@ -0,0 +1,302 @@
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::Field;
+use plonky2::iop::target::{BoolTarget, Target};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use serde::{Deserialize, Serialize};
+
+use crate::circuit::RowTargets;
+use crate::lookup::LookupTables;
+use crate::zk::opcode_to_id;
+
+/// CSRs known to the trace parser, by name and address.
+const CSRS: [(&str, u16); 9] = [
+    ("fflags", 0x001),
+    ("frm", 0x002),
+    ("fcsr", 0x003),
+    ("cycle", 0xc00),
+    ("time", 0xc01),
+    ("instret", 0xc02),
+    ("cycleh", 0xc80),
+    ("timeh", 0xc81),
+    ("instreth", 0xc82),
+];
+
+/// Address of a CSR given by name or as a number (`0xc00`, `3072`).
+pub fn csr_address(name: &str) -> Option<u16> {
+    if let Some(&(_, addr)) = CSRS.iter().find(|(n, _)| *n == name) {
+        return Some(addr);
+    }
+    match name.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => name.parse::<u16>().ok(),
+    }
+    .filter(|&addr| addr < 1 << 12)
+}
+
+/// Canonical name of a CSR address, if it is one we know.
+pub fn csr_name(addr: u16) -> Option<&'static str> {
+    CSRS.iter().find(|(_, a)| *a == addr).map(|(n, _)| *n)
+}
+
+/// How a modeled CSR is proven.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrKind {
+    /// Read-only counter (`cycle`, `time`, `instret`): reads are nondeterministic inputs.
+    Counter,
+    /// A bit window `[lo, lo + width)` of the 8-bit floating-point control/status register.
+    Fcsr { lo: usize, width: usize },
+}
+
+/// The CSRs whose semantics the circuit enforces.
+pub fn csr_kind(addr: u16) -> Option<CsrKind> {
+    match addr {
+        0x001 => Some(CsrKind::Fcsr { lo: 0, width: 5 }),
+        0x002 => Some(CsrKind::Fcsr { lo: 5, width: 3 }),
+        0x003 => Some(CsrKind::Fcsr { lo: 0, width: 8 }),
+        0xc00..=0xc02 | 0xc80..=0xc82 => Some(CsrKind::Counter),
+        _ => None,
+    }
+}
+
+/// Read-modify-write operation of a CSR instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrOp {
+    Write,
+    Set,
+    Clear,
+}
+
+impl CsrOp {
+    /// Operation and immediate flag of a canonical CSR mnemonic (`csrrw` ... `csrrci`).
+    pub fn from_mnemonic(op: &str) -> Option<(CsrOp, bool)> {
+        match op {
+            "csrrw" => Some((CsrOp::Write, false)),
+            "csrrs" => Some((CsrOp::Set, false)),
+            "csrrc" => Some((CsrOp::Clear, false)),
+            "csrrwi" => Some((CsrOp::Write, true)),
+            "csrrsi" => Some((CsrOp::Set, true)),
+            "csrrci" => Some((CsrOp::Clear, true)),
+            _ => None,
+        }
+    }
+
+    /// Canonical mnemonic of the operation.
+    pub fn mnemonic(self, uimm: bool) -> &'static str {
+        match (self, uimm) {
+            (CsrOp::Write, false) => "csrrw",
+            (CsrOp::Set, false) => "csrrs",
+            (CsrOp::Clear, false) => "csrrc",
+            (CsrOp::Write, true) => "csrrwi",
+            (CsrOp::Set, true) => "csrrsi",
+            (CsrOp::Clear, true) => "csrrci",
+        }
+    }
+
+    /// New CSR value after applying `src` to `old`.
+    pub fn apply(self, old: u64, src: u64) -> u64 {
+        match self {
+            CsrOp::Write => src,
+            CsrOp::Set => old | src,
+            CsrOp::Clear => old & !src,
+        }
+    }
+
+    /// Whether the instruction writes the CSR at all; set/clear with a zero source only read.
+    pub fn writes(self, src: u64) -> bool {
+        self == CsrOp::Write || src != 0
+    }
+}
+
+/// Whether a nondeterministic input is exposed to the verifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum InputVisibility {
+    /// The value appears in the row's `rd` public input.
+    #[default]
+    Public,
+    /// The row's `rd` public input is replaced by zero. Later rows that read the value
+    /// still expose it through their own operands.
+    Private,
+}
+
+/// CSR access of an instruction row.
+#[derive(Debug, Clone)]
+pub struct CsrRow<F: Field> {
+    pub addr: u16,
+    pub op: CsrOp,
+    /// `true` for the `csrr?i` forms, whose source is the 5-bit immediate in `rs1_val`.
+    pub uimm: bool,
+    /// `true` when `rd` is `x0`, so the old value is discarded.
+    pub rd_is_x0: bool,
+    /// Set for counter reads, whose value is a declared nondeterministic input.
+    pub nondet: Option<InputVisibility>,
+    /// `fcsr` before the instruction. Only the first CSR row of a trace needs it in the
+    /// witness; later rows chain the value computed by the previous one.
+    pub fcsr_before: F,
+}
+
+/// Number of bits of the modeled `fcsr` (`frm` in bits 5..8, `fflags` in bits 0..5).
+pub const FCSR_BITS: usize = 8;
+
+/// Adds the constraints of a CSR instruction row and returns the `fcsr` value after it.
+///
+/// Fails for rows that [`crate::zk`] would not produce: an unmodeled CSR, or a write to a
+/// counter. `targets` holds the row's public values: `imm_val` is the CSR address, `imm_flag` marks
+/// the immediate forms and `rs1` is the source (register value or 5-bit immediate).
+pub fn add_csr_row(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    targets: &RowTargets,
+    csr: &CsrRow<GoldilocksField>,
+    fcsr_in: Target,
+) -> anyhow::Result<Target> {
+    let constant = |b: &mut CircuitBuilder<GoldilocksField, 2>, v: u64| {
+        b.constant(GoldilocksField::from_canonical_u64(v))
+    };
+
+    // The row's shape is fixed by the trace, so pin the public values that select it.
+    let opcode_id = opcode_to_id(csr.op.mnemonic(csr.uimm))
+        .ok_or_else(|| anyhow::anyhow!("{} has no opcode id", csr.op.mnemonic(csr.uimm)))?;
+    let opcode = constant(builder, opcode_id);
+    builder.connect(targets.opcode, opcode);
+    let addr = constant(builder, csr.addr as u64);
+    builder.connect(targets.imm_val, addr);
+    let uimm = builder.constant_bool(csr.uimm);
+    builder.connect(targets.imm_flag.target, uimm.target);
+    builder.assert_zero(targets.rs2);
+
+    let kind = csr_kind(csr.addr).ok_or_else(|| anyhow::anyhow!("CSR 0x{:03x} is not modeled", csr.addr))?;
+    Ok(match kind {
+        CsrKind::Counter => {
+            // Counters are read-only: the source must not write, and the value read is a
+            // free witness (the declared nondeterministic input) that lands in rd.
+            anyhow::ensure!(csr.op != CsrOp::Write, "counter CSR 0x{:03x} cannot be written", csr.addr);
+            builder.assert_zero(targets.rs1);
+            if csr.rd_is_x0 {
+                builder.assert_zero(targets.rd);
+            }
+            fcsr_in
+        }
+        CsrKind::Fcsr { lo, width } => {
+            let old_bits = builder.split_le(fcsr_in, FCSR_BITS);
+            let src_bits = source_bits(builder, tables, targets.rs1, csr.uimm, width);
+
+            let zero = builder.zero();
+            let mut old = zero;
+            let mut fcsr_out = zero;
+            for (i, &bit) in old_bits.iter().enumerate().rev() {
+                let new_bit = if (lo..lo + width).contains(&i) {
+                    old = builder.mul_const_add(GoldilocksField::TWO, old, bit.target);
+                    apply_bit(builder, csr.op, bit, src_bits[i - lo])
+                } else {
+                    bit.target
+                };
+                fcsr_out = builder.mul_const_add(GoldilocksField::TWO, fcsr_out, new_bit);
+            }
+
+            if csr.rd_is_x0 {
+                builder.assert_zero(targets.rd);
+            } else {
+                builder.connect(targets.rd, old);
+            }
+            fcsr_out
+        }
+    })
+}
+
+/// The low `width` bits of the CSR source operand.
+fn source_bits(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    tables: &mut LookupTables,
+    rs1: Target,
+    uimm: bool,
+    width: usize,
+) -> Vec<BoolTarget> {
+    let low = if uimm {
+        // The immediate forms carry a 5-bit zero-extended immediate.
+        tables.range_check_bits(builder, rs1, 5);
+        rs1
+    } else {
+        let limbs = tables.split_u64_limbs(builder, rs1);
+        tables.split_limbs(builder, limbs[0], 8, 2)[0]
+    };
+    let mut bits = builder.split_le(low, 8);
+    bits.truncate(width);
+    bits
+}
+
+fn apply_bit(
+    builder: &mut CircuitBuilder<GoldilocksField, 2>,
+    op: CsrOp,
+    old: BoolTarget,
+    src: BoolTarget,
+) -> Target {
+    match op {
+        CsrOp::Write => src.target,
+        CsrOp::Set => builder.or(old, src).target,
+        CsrOp::Clear => {
+            let not_src = builder.not(src);
+            builder.and(old, not_src).target
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::prove_multi_instruction_constraint;
+    use crate::trace_parser::{RegisterFile, TraceEntry};
+    use crate::zk::{convert_trace_to_rows_with, ConversionMode, ConvertOptions, UnprovenSpan};
+
+    fn entry(opcode: &str, rd: &str, csr: &str, rs1: Option<(&str, u64)>, imm: Option<i64>, rd_after: u64) -> TraceEntry {
+        let before = RegisterFile::default().with(rs1.as_slice());
+        TraceEntry {
+            opcode: opcode.to_string(),
+            rd: Some(rd.to_string()),
+            rs1: rs1.map(|(r, _)| r.to_string()),
+            imm,
+            regs_before: before,
+            regs_after: before.with(&[(rd, rd_after)]),
+            csr: Some(csr.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_csr_rows_chain_fcsr_and_hide_private_counters() {
+        let options = ConvertOptions {
+            counter_inputs: InputVisibility::Private,
+            initial_fcsr: 0b001_00001,
+            mode: ConversionMode::Lenient,
+            ..Default::default()
+        };
+        let trace = vec![
+            entry("csrrs", "x5", "cycle", Some(("x0", 0)), None, 1234),
+            entry("csrrwi", "x6", "frm", None, Some(3), 0b001),
+            entry("csrrc", "x7", "fflags", Some(("x5", 0x1f)), None, 0b00001),
+            entry("csrrs", "x8", "fcsr", Some(("x0", 0)), None, 0b011_00000),
+            entry("csrrw", "x0", "time", Some(("x5", 1)), None, 0), // skipped
+        ];
+        let rows = convert_trace_to_rows_with(&trace, &options).unwrap();
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[4].unproven, Some(UnprovenSpan { first_pc: 0, last_pc: 0, count: 1 }));
+
+        let (proof, data) = prove_multi_instruction_constraint(&rows).unwrap();
+        let inputs = &proof.public_inputs;
+        assert_eq!(inputs[5], GoldilocksField::ZERO); // private cycle read
+        assert_eq!(inputs[4 * 6 + 4], GoldilocksField::ONE); // one unproven entry
+        assert_eq!(inputs[5 * 6], GoldilocksField::from_canonical_u64(0b001_00001));
+        assert_eq!(inputs[5 * 6 + 1], GoldilocksField::from_canonical_u64(0b011_00000));
+        data.verify(proof).unwrap();
+
+        // frm reads back the value written by fsrmi, so a stale read must be rejected.
+        let mut bad = rows.clone();
+        bad[3].rd_val = GoldilocksField::from_canonical_u64(0b001_00001);
+        assert!(prove_multi_instruction_constraint(&bad).is_err());
+
+        // Rows the conversion never builds are reported rather than panicking.
+        let mut write = rows.clone();
+        write[0].csr.as_mut().unwrap().op = CsrOp::Write;
+        let err = prove_multi_instruction_constraint(&write).unwrap_err();
+        assert!(err.to_string().contains("cannot be written"), "{}", err);
+    }
+}
@@ -0,0 +1,473 @@
+use crate::csr::csr_name;
+use crate::instruction::{CsrSource, Instruction, Reg, RA, ZERO};
+
+/// Rounding modes by `rm` field; 5 and 6 are reserved.
+const ROUNDING: [Option<&str>; 8] =
+    [Some("rne"), Some("rtz"), Some("rdn"), Some("rup"), Some("rmm"), None, None, Some("dyn")];
+
+pub(crate) fn bits(x: u32, hi: u32, lo: u32) -> u32 {
+    (x >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+pub(crate) fn sext(value: u32, width: u32) -> i64 {
+    let shift = 64 - width;
+    ((value as i64) << shift) >> shift
+}
+
+/// Length in bytes of the instruction whose low bits are `bits`: 4, or 2 if compressed.
+pub fn instruction_len(bits: u32) -> u8 {
+    if bits & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+/// Decodes the instruction at `pc` whose encoding starts with `bits`, compressed or not.
+///
+/// Covers RV64GC with Zicsr, Zifencei, Zba and Zbb. The result is in the canonical form of
+/// [`Instruction::parse`]: compressed instructions are expanded, branch and jump targets are
+/// absolute, and no pseudo-instructions are formed, so `li` comes out as `addi rd, x0, imm`.
+pub fn decode(pc: u64, bits: u32) -> Option<Instruction> {
+    match instruction_len(bits) {
+        4 => decode_word(pc, bits),
+        _ => decode_half(pc, bits as u16),
+    }
+}
+
+/// Data memory operand of a load, store or atomic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryOperand {
+    /// Number of the x register holding the base address.
+    pub base: u8,
+    pub offset: i64,
+    /// Access width in bytes.
+    pub width: u8,
+    /// Whether the instruction may write memory: stores, `sc` and AMOs.
+    pub writes: bool,
+}
+
+impl MemoryOperand {
+    /// Address accessed with the integer registers `x`.
+    pub fn addr(&self, x: &[u64; 32]) -> u64 {
+        x[self.base as usize].wrapping_add(self.offset as u64)
+    }
+}
+
+/// The memory operand of `inst`, if it accesses data memory.
+pub fn memory_operand(inst: &Instruction) -> Option<MemoryOperand> {
+    // The width is the last letter of `lwu`-style mnemonics and the suffix of `amoadd.w.aq` ones.
+    let width = |op: &str| {
+        let name = if op.contains('.') { op.split('.').nth(1)? } else { op.trim_end_matches('u') };
+        match name.chars().last()? {
+            'b' => Some(1),
+            'h' => Some(2),
+            'w' => Some(4),
+            'd' => Some(8),
+            _ => None,
+        }
+    };
+    let (base, offset, op, writes) = match inst {
+        Instruction::Load { op, base, offset, .. } => (base, *offset, op, false),
+        Instruction::Store { op, base, offset, .. } => (base, *offset, op, true),
+        Instruction::Atomic { op, base, .. } => (base, 0, op, !op.starts_with("lr")),
+        _ => return None,
+    };
+    let Reg::X(base) = *base else { return None };
+    Some(MemoryOperand { base, offset, width: width(op)?, writes })
+}
+
+fn x(r: u32) -> Reg {
+    Reg::X(r as u8)
+}
+
+fn f(r: u32) -> Reg {
+    Reg::F(r as u8)
+}
+
+/// Picks the mnemonic at `index`, `""` marking reserved encodings.
+fn pick(table: &[&str], index: u32) -> Option<String> {
+    table.get(index as usize).filter(|op| !op.is_empty()).map(|op| op.to_string())
+}
+
+fn csr(addr: u32) -> String {
+    csr_name(addr as u16).map_or_else(|| format!("0x{:x}", addr), str::to_string)
+}
+
+/// `fence` ordering set, from the `iorw` bits of its predecessor or successor field.
+fn fence_set(set: u32) -> String {
+    "iorw".chars().enumerate().filter(|(i, _)| set & (8 >> i) != 0).map(|(_, c)| c).collect()
+}
+
+fn decode_word(pc: u64, w: u32) -> Option<Instruction> {
+    use Instruction::*;
+    let (rd, rs1, rs2, rs3) = (bits(w, 11, 7), bits(w, 19, 15), bits(w, 24, 20), bits(w, 31, 27));
+    let funct3 = bits(w, 14, 12);
+    let funct7 = bits(w, 31, 25);
+    let imm12 = bits(w, 31, 20);
+    let i_imm = sext(imm12, 12);
+    let s_imm = sext(funct7 << 5 | rd, 12);
+    let b_imm = sext(bits(w, 31, 31) << 12 | bits(w, 7, 7) << 11 | bits(w, 30, 25) << 5 | bits(w, 11, 8) << 1, 13);
+    let j_imm = sext(bits(w, 31, 31) << 20 | bits(w, 19, 12) << 12 | bits(w, 20, 20) << 11 | bits(w, 30, 21) << 1, 21);
+    let target = |imm: i64| pc.wrapping_add(imm as u64);
+    let shamt = bits(w, 25, 20) as i64;
+    let reg_reg = |op: &str| Some(RegReg { op: op.to_string(), rd: x(rd), rs1: x(rs1), rs2: x(rs2) });
+    let reg_imm = |op: &str, imm: i64| Some(RegImm { op: op.to_string(), rd: x(rd), rs1: x(rs1), imm });
+    let unary = |op: &str| Some(Unary { op: op.to_string(), rd: x(rd), rs1: x(rs1) });
+    // Floating-point formats: `.s` and `.d` only.
+    let fmt = |code: u32| ["s", "d"].get(code as usize).copied();
+    let rm = ROUNDING[funct3 as usize];
+
+    match w & 0x7f {
+        0x37 => Some(Upper { op: "lui".into(), rd: x(rd), imm: bits(w, 31, 12) as i64 }),
+        0x17 => Some(Upper { op: "auipc".into(), rd: x(rd), imm: bits(w, 31, 12) as i64 }),
+        0x6f => Some(Jal { rd: x(rd), target: target(j_imm) }),
+        0x67 if funct3 == 0 => Some(Jalr { rd: x(rd), base: x(rs1), offset: i_imm }),
+        0x63 => {
+            let op = pick(&["beq", "bne", "", "", "blt", "bge", "bltu", "bgeu"], funct3)?;
+            Some(Branch { op, rs1: x(rs1), rs2: x(rs2), target: target(b_imm) })
+        }
+        0x03 => {
+            let op = pick(&["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu"], funct3)?;
+            Some(Load { op, rd: x(rd), base: x(rs1), offset: i_imm })
+        }
+        0x07 => {
+            let op = pick(&["", "", "flw", "fld"], funct3)?;
+            Some(Load { op, rd: f(rd), base: x(rs1), offset: i_imm })
+        }
+        0x23 => {
+            let op = pick(&["sb", "sh", "sw", "sd"], funct3)?;
+            Some(Store { op, src: x(rs2), base: x(rs1), offset: s_imm })
+        }
+        0x27 => {
+            let op = pick(&["", "", "fsw", "fsd"], funct3)?;
+            Some(Store { op, src: f(rs2), base: x(rs1), offset: s_imm })
+        }
+        0x13 => match (funct3, bits(w, 31, 26)) {
+            (0, _) => reg_imm("addi", i_imm),
+            (2, _) => reg_imm("slti", i_imm),
+            (3, _) => reg_imm("sltiu", i_imm),
+            (4, _) => reg_imm("xori", i_imm),
+            (6, _) => reg_imm("ori", i_imm),
+            (7, _) => reg_imm("andi", i_imm),
+            (1, 0x00) => reg_imm("slli", shamt),
+            (1, 0x18) => unary(["clz", "ctz", "cpop", "", "sext.b", "sext.h"].get(rs2 as usize).filter(|op| !op.is_empty())?),
+            (5, 0x00) => reg_imm("srli", shamt),
+            (5, 0x10) => reg_imm("srai", shamt),
+            (5, 0x18) => reg_imm("rori", shamt),
+            (5, _) if imm12 == 0x287 => unary("orc.b"),
+            (5, _) if imm12 == 0x6b8 => unary("rev8"),
+            _ => None,
+        },
+        0x1b => match (funct3, funct7) {
+            (0, _) => reg_imm("addiw", i_imm),
+            (1, 0x00) => reg_imm("slliw", rs2 as i64),
+            (1, 0x04 | 0x05) => reg_imm("slli.uw", shamt),
+            (1, 0x30) => unary(["clzw", "ctzw", "cpopw"].get(rs2 as usize)?),
+            (5, 0x00) => reg_imm("srliw", rs2 as i64),
+            (5, 0x20) => reg_imm("sraiw", rs2 as i64),
+            (5, 0x30) => reg_imm("roriw", rs2 as i64),
+            _ => None,
+        },
+        0x33 => match (funct7, funct3) {
+            (0x00, _) => reg_reg(["add", "sll", "slt", "sltu", "xor", "srl", "or", "and"][funct3 as usize]),
+            (0x20, 0) => reg_reg("sub"),
+            (0x20, 5) => reg_reg("sra"),
+            (0x20, 4) => reg_reg("xnor"),
+            (0x20, 6) => reg_reg("orn"),
+            (0x20, 7) => reg_reg("andn"),
+            (0x01, _) => reg_reg(["mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu"][funct3 as usize]),
+            (0x10, 2 | 4 | 6) => reg_reg(&format!("sh{}add", funct3 / 2)),
+            (0x05, _) => reg_reg(&pick(&["", "", "", "", "min", "minu", "max", "maxu"], funct3)?),
+            (0x30, 1) => reg_reg("rol"),
+            (0x30, 5) => reg_reg("ror"),
+            _ => None,
+        },
+        0x3b => match (funct7, funct3) {
+            (0x00, 0) => reg_reg("addw"),
+            (0x20, 0) => reg_reg("subw"),
+            (0x00, 1) => reg_reg("sllw"),
+            (0x00, 5) => reg_reg("srlw"),
+            (0x20, 5) => reg_reg("sraw"),
+            (0x01, _) => reg_reg(&pick(&["mulw", "", "", "", "divw", "divuw", "remw", "remuw"], funct3)?),
+            (0x04, 0) => reg_reg("add.uw"),
+            (0x04, 4) if rs2 == 0 => unary("zext.h"),
+            (0x10, 2 | 4 | 6) => reg_reg(&format!("sh{}add.uw", funct3 / 2)),
+            (0x30, 1) => reg_reg("rolw"),
+            (0x30, 5) => reg_reg("rorw"),
+            _ => None,
+        },
+        0x2f => {
+            let width = pick(&["", "", "w", "d"], funct3)?;
+            let name = match rs3 {
+                0x02 if rs2 == 0 => "lr",
+                0x03 => "sc",
+                0x01 => "amoswap",
+                0x00 => "amoadd",
+                0x04 => "amoxor",
+                0x0c => "amoand",
+                0x08 => "amoor",
+                0x10 => "amomin",
+                0x14 => "amomax",
+                0x18 => "amominu",
+                0x1c => "amomaxu",
+                _ => return None,
+            };
+            let ordering = ["", "rl", "aq", "aqrl"][bits(w, 26, 25) as usize];
+            Some(Atomic {
+                op: format!("{}.{}", name, width),
+                ordering: (!ordering.is_empty()).then(|| ordering.to_string()),
+                rd: x(rd),
+                src: (name != "lr").then(|| x(rs2)),
+                base: x(rs1),
+            })
+        }
+        0x0f => match funct3 {
+            0 if bits(w, 31, 20) == 0x833 => Some(System { op: "fence.tso".into(), args: String::new() }),
+            0 => {
+                let args = format!("{},{}", fence_set(bits(w, 27, 24)), fence_set(bits(w, 23, 20)));
+                Some(System { op: "fence".into(), args })
+            }
+            1 => Some(System { op: "fence.i".into(), args: String::new() }),
+            _ => None,
+        },
+        0x73 => {
+            let op = match (funct3, w) {
+                (0, 0x0000_0073) => "ecall",
+                (0, 0x0010_0073) => "ebreak",
+                (0, 0x1050_0073) => "wfi",
+                (0, 0x3020_0073) => "mret",
+                (0, 0x1020_0073) => "sret",
+                (0, _) => return None,
+                _ => {
+                    let op = pick(&["", "csrrw", "csrrs", "csrrc", "", "csrrwi", "csrrsi", "csrrci"], funct3)?;
+                    let src = if funct3 < 4 { CsrSource::Reg(x(rs1)) } else { CsrSource::Imm(rs1 as i64) };
+                    return Some(Csr { op, rd: x(rd), csr: csr(imm12), src });
+                }
+            };
+            Some(System { op: op.into(), args: String::new() })
+        }
+        opcode @ (0x43 | 0x47 | 0x4b | 0x4f) => {
+            let name = ["fmadd", "fmsub", "fnmsub", "fnmadd"][(opcode as usize - 0x43) / 4];
+            Some(Fp {
+                op: format!("{}.{}", name, fmt(bits(w, 26, 25))?),
+                rd: f(rd),
+                rs1: f(rs1),
+                rs2: Some(f(rs2)),
+                rs3: Some(f(rs3)),
+                rounding: rounding(rm?),
+            })
+        }
+        0x53 => {
+            let suffix = fmt(funct7 & 3)?;
+            let fp = |op: String, rd: Reg, rs1: Reg, rs2: Option<Reg>, rounding: Option<String>| {
+                Some(Fp { op, rd, rs1, rs2, rs3: None, rounding })
+            };
+            let int = ["w", "wu", "l", "lu"];
+            match funct7 >> 2 {
+                op @ 0x00..=0x03 => {
+                    let name = ["fadd", "fsub", "fmul", "fdiv"][op as usize];
+                    fp(format!("{}.{}", name, suffix), f(rd), f(rs1), Some(f(rs2)), rounding(rm?))
+                }
+                0x0b if rs2 == 0 => fp(format!("fsqrt.{}", suffix), f(rd), f(rs1), None, rounding(rm?)),
+                0x04 => fp(format!("{}.{}", pick(&["fsgnj", "fsgnjn", "fsgnjx"], funct3)?, suffix), f(rd), f(rs1), Some(f(rs2)), None),
+                0x05 => fp(format!("{}.{}", pick(&["fmin", "fmax"], funct3)?, suffix), f(rd), f(rs1), Some(f(rs2)), None),
+                0x14 => fp(format!("{}.{}", pick(&["fle", "flt", "feq"], funct3)?, suffix), x(rd), f(rs1), Some(f(rs2)), None),
+                // fcvt.s.d rounds; fcvt.d.s is exact.
+                0x08 => match (suffix, rs2) {
+                    ("s", 1) => fp("fcvt.s.d".into(), f(rd), f(rs1), None, rounding(rm?)),
+                    ("d", 0) => fp("fcvt.d.s".into(), f(rd), f(rs1), None, None),
+                    _ => None,
+                },
+                0x18 => fp(format!("fcvt.{}.{}", int.get(rs2 as usize)?, suffix), x(rd), f(rs1), None, rounding(rm?)),
+                0x1a => {
+                    let from = int.get(rs2 as usize)?;
+                    // Every 32-bit integer is a double, so those conversions are exact.
+                    let exact = suffix == "d" && from.starts_with('w');
+                    let rounding = if exact { None } else { rounding(rm?) };
+                    fp(format!("fcvt.{}.{}", suffix, from), f(rd), x(rs1), None, rounding)
+                }
+                0x1c if rs2 == 0 && funct3 == 0 => {
+                    fp(format!("fmv.x.{}", if suffix == "s" { "w" } else { "d" }), x(rd), f(rs1), None, None)
+                }
+                0x1c if rs2 == 0 && funct3 == 1 => fp(format!("fclass.{}", suffix), x(rd), f(rs1), None, None),
+                0x1e if rs2 == 0 && funct3 == 0 => {
+                    fp(format!("fmv.{}.x", if suffix == "s" { "w" } else { "d" }), f(rd), x(rs1), None, None)
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Static rounding mode operand; `dyn`, the default, is left out as in disassembly.
+fn rounding(mode: &str) -> Option<String> {
+    (mode != "dyn").then(|| mode.to_string())
+}
+
+/// Expands a 16-bit RV64C instruction, including the floating-point loads and stores.
+fn decode_half(pc: u64, half: u16) -> Option<Instruction> {
+    use Instruction::*;
+    let c = half as u32;
+    let rd = bits(c, 11, 7);
+    let rs2 = bits(c, 6, 2);
+    // The x8-x15 register fields of the CIW/CL/CS/CB formats.
+    let rd_p = bits(c, 4, 2) + 8;
+    let rs1_p = bits(c, 9, 7) + 8;
+    let ci_imm = sext(bits(c, 12, 12) << 5 | bits(c, 6, 2), 6);
+    let lw_off = (bits(c, 12, 10) << 3 | bits(c, 6, 6) << 2 | bits(c, 5, 5) << 6) as i64;
+    let ld_off = (bits(c, 12, 10) << 3 | bits(c, 6, 5) << 6) as i64;
+    let lwsp_off = (bits(c, 12, 12) << 5 | bits(c, 6, 4) << 2 | bits(c, 3, 2) << 6) as i64;
+    let ldsp_off = (bits(c, 12, 12) << 5 | bits(c, 6, 5) << 3 | bits(c, 4, 2) << 6) as i64;
+    let swsp_off = (bits(c, 12, 9) << 2 | bits(c, 8, 7) << 6) as i64;
+    let sdsp_off = (bits(c, 12, 10) << 3 | bits(c, 9, 7) << 6) as i64;
+    let shamt = (bits(c, 12, 12) << 5 | bits(c, 6, 2)) as i64;
+    let load = |op: &str, rd: Reg, base: u32, offset: i64| Some(Load { op: op.into(), rd, base: x(base), offset });
+    let store = |op: &str, src: Reg, base: u32, offset: i64| Some(Store { op: op.into(), src, base: x(base), offset });
+    let reg_imm = |op: &str, rd: u32, rs1: u32, imm: i64| Some(RegImm { op: op.into(), rd: x(rd), rs1: x(rs1), imm });
+    let reg_reg = |op: &str, rd: u32, rs1: u32, rs2: u32| Some(RegReg { op: op.into(), rd: x(rd), rs1: x(rs1), rs2: x(rs2) });
+
+    if half == 0 {
+        return Some(System { op: "unimp".into(), args: String::new() });
+    }
+    match (c & 0b11, bits(c, 15, 13)) {
+        (0b00, 0b000) => {
+            let imm = bits(c, 12, 11) << 4 | bits(c, 10, 7) << 6 | bits(c, 6, 6) << 2 | bits(c, 5, 5) << 3;
+            if imm == 0 {
+                return None;
+            }
+            reg_imm("addi", rd_p, 2, imm as i64)
+        }
+        (0b00, 0b001) => load("fld", f(rd_p), rs1_p, ld_off),
+        (0b00, 0b010) => load("lw", x(rd_p), rs1_p, lw_off),
+        (0b00, 0b011) => load("ld", x(rd_p), rs1_p, ld_off),
+        (0b00, 0b101) => store("fsd", f(rd_p), rs1_p, ld_off),
+        (0b00, 0b110) => store("sw", x(rd_p), rs1_p, lw_off),
+        (0b00, 0b111) => store("sd", x(rd_p), rs1_p, ld_off),
+        (0b01, 0b000) => reg_imm("addi", rd, rd, ci_imm),
+        (0b01, 0b001) if rd != 0 => reg_imm("addiw", rd, rd, ci_imm),
+        (0b01, 0b010) => reg_imm("addi", rd, 0, ci_imm),
+        (0b01, 0b011) if rd == 2 => {
+            let imm = bits(c, 12, 12) << 9 | bits(c, 4, 3) << 7 | bits(c, 5, 5) << 6 | bits(c, 2, 2) << 5 | bits(c, 6, 6) << 4;
+            match sext(imm, 10) {
+                0 => None,
+                imm => reg_imm("addi", 2, 2, imm),
+            }
+        }
+        (0b01, 0b011) => match ci_imm {
+            0 => None,
+            imm => Some(Upper { op: "lui".into(), rd: x(rd), imm: imm & 0xf_ffff }),
+        },
+        (0b01, 0b100) => match (bits(c, 11, 10), bits(c, 12, 12), bits(c, 6, 5)) {
+            (0b00, _, _) => reg_imm("srli", rs1_p, rs1_p, shamt),
+            (0b01, _, _) => reg_imm("srai", rs1_p, rs1_p, shamt),
+            (0b10, _, _) => reg_imm("andi", rs1_p, rs1_p, ci_imm),
+            (0b11, 0, op) => reg_reg(["sub", "xor", "or", "and"][op as usize], rs1_p, rs1_p, rd_p),
+            (0b11, 1, 0b00) => reg_reg("subw", rs1_p, rs1_p, rd_p),
+            (0b11, 1, 0b01) => reg_reg("addw", rs1_p, rs1_p, rd_p),
+            _ => None,
+        },
+        (0b01, 0b101) => {
+            let imm = bits(c, 12, 12) << 11 | bits(c, 11, 11) << 4 | bits(c, 10, 9) << 8 | bits(c, 8, 8) << 10
+                | bits(c, 7, 7) << 6 | bits(c, 6, 6) << 7 | bits(c, 5, 3) << 1 | bits(c, 2, 2) << 5;
+            Some(Jal { rd: ZERO, target: pc.wrapping_add(sext(imm, 12) as u64) })
+        }
+        (0b01, op @ (0b110 | 0b111)) => {
+            let imm = bits(c, 12, 12) << 8 | bits(c, 11, 10) << 3 | bits(c, 6, 5) << 6 | bits(c, 4, 3) << 1 | bits(c, 2, 2) << 5;
+            let op = if op == 0b110 { "beq" } else { "bne" };
+            Some(Branch { op: op.into(), rs1: x(rs1_p), rs2: ZERO, target: pc.wrapping_add(sext(imm, 9) as u64) })
+        }
+        (0b10, 0b000) => reg_imm("slli", rd, rd, shamt),
+        (0b10, 0b001) => load("fld", f(rd), 2, ldsp_off),
+        (0b10, 0b010) if rd != 0 => load("lw", x(rd), 2, lwsp_off),
+        (0b10, 0b011) if rd != 0 => load("ld", x(rd), 2, ldsp_off),
+        (0b10, 0b100) => match (bits(c, 12, 12), rd, rs2) {
+            (0, 0, _) => None,
+            (0, _, 0) => Some(Jalr { rd: ZERO, base: x(rd), offset: 0 }),
+            (0, _, _) => reg_reg("add", rd, 0, rs2),
+            (1, 0, 0) => Some(System { op: "ebreak".into(), args: String::new() }),
+            (1, _, 0) => Some(Jalr { rd: RA, base: x(rd), offset: 0 }),
+            _ => reg_reg("add", rd, rd, rs2),
+        },
+        (0b10, 0b101) => store("fsd", f(rs2), 2, sdsp_off),
+        (0b10, 0b110) => store("sw", x(rs2), 2, swsp_off),
+        (0b10, 0b111) => store("sd", x(rs2), 2, sdsp_off),
+        _ => None,
+    }
+}
+
+/// Whether a disassembled and a decoded instruction are the same operation.
+///
+/// Disassemblers print some encodings under other names: `li` for `addi rd, x0, imm`, `mv`
+/// for `addi rd, rs, 0` and `add rd, x0, rs`, `fmv.x.s` for `fmv.x.w`, CSRs by number, and
+/// `fence` without its ordering sets. Those spellings compare equal.
+pub fn equivalent(disassembly: &Instruction, decoded: &Instruction) -> bool {
+    normalize(disassembly) == normalize(decoded)
+}
+
+fn normalize(inst: &Instruction) -> Instruction {
+    use Instruction::*;
+    let mv = |rd: Reg, rs1: Reg| RegImm { op: "addi".into(), rd, rs1, imm: 0 };
+    match inst.clone() {
+        Upper { op, rd, imm } if op == "li" => RegImm { op: "addi".into(), rd, rs1: ZERO, imm },
+        Upper { op, rd, imm } => Upper { op, rd, imm: imm & 0xf_ffff },
+        Unary { op, rd, rs1 } if op == "mv" => mv(rd, rs1),
+        RegReg { op, rd, rs1: ZERO, rs2 } if op == "add" => mv(rd, rs2),
+        RegReg { op, rd, rs1, rs2: ZERO } if op == "add" => mv(rd, rs1),
+        Csr { op, rd, csr, src } => {
+            let csr = crate::csr::csr_address(&csr).map_or(csr, |addr| self::csr(addr as u32));
+            Csr { op, rd, csr, src }
+        }
+        Fp { op, rd, rs1, rs2, rs3, rounding } => {
+            let op = match op.as_str() {
+                "fmv.x.s" => "fmv.x.w".to_string(),
+                "fmv.s.x" => "fmv.w.x".to_string(),
+                _ => op,
+            };
+            Fp { op, rd, rs1, rs2, rs3, rounding: rounding.filter(|mode| mode != "dyn") }
+        }
+        System { op, .. } => System { op, args: String::new() },
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_matches_disassembly_and_emulator() {
+        let parse = |text: &str| Instruction::parse(text).unwrap();
+        let cases: [(u64, u32, &str); 16] = [
+            (0x101dc, 0x7139, "addi sp,sp,-64"),
+            (0x101de, 0xfc06, "sd ra,56(sp)"),
+            (0x101e2, 0x0080, "addi s0,sp,64"),
+            (0x101f4, 0x0007871b, "sext.w a4,a5"),
+            (0x101f0, 0xfec42783, "lw a5,-20(s0)"),
+            (0x101e6, 0xfef42623, "sw a5,-20(s0)"),
+            (0x1000, 0xc111, "beqz a0,0x1004 <f+4>"),
+            (0x1000, 0x852e, "mv a0,a1"),
+            (0x1000, 0x4515, "li a0,5"),
+            (0x1000, 0x8082, "ret"),
+            (0x1000, 0x0000_0073, "ecall"),
+            (0x1000, 0x06e5_37af, "amoadd.d.aqrl a5,a4,(a0)"),
+            (0x1000, 0x02f7_07d3, "fadd.d fa5,fa4,fa5,rne"),
+            (0x1000, 0xc000_22f3, "rdcycle t0"),
+            (0x1000, 0x6005_9513, "clz a0,a1"),
+            (0x1000, 0x2588, "fld fa0,8(a1)"),
+        ];
+        for (pc, bits, text) in cases {
+            let decoded = decode(pc, bits).unwrap_or_else(|| panic!("cannot decode {:x}", bits));
+            assert!(equivalent(&parse(text), &decoded), "{:x}: {} vs {}", bits, text, decoded);
+        }
+        assert_eq!(decode(0x1000, 0x20a5_c6b3).unwrap().to_string(), "sh2add x13, x11, x10");
+        assert_eq!(decode(0x1000, 0x0ff0_000f).unwrap().to_string(), "fence iorw,iorw");
+        assert!(!equivalent(&parse("sd ra,48(sp)"), &decode(0x101de, 0xfc06).unwrap()));
+        assert_eq!(decode(0x1000, 0xffff_ffff), None);
+        assert_eq!(instruction_len(0xfc06), 2);
+
+        let operand = |bits: u32| memory_operand(&decode(0x1000, bits).unwrap());
+        assert_eq!(operand(0xfef42623), Some(MemoryOperand { base: 8, offset: -20, width: 4, writes: true }));
+        assert_eq!(operand(0x2588), Some(MemoryOperand { base: 11, offset: 8, width: 8, writes: false }));
+        assert_eq!(operand(0x06e5_37af), Some(MemoryOperand { base: 10, offset: 0, width: 8, writes: true }));
+        assert_eq!(operand(0x1005_262f), Some(MemoryOperand { base: 10, offset: 0, width: 4, writes: false })); // lr.w
+        assert_eq!(operand(0x852e), None);
+    }
+}
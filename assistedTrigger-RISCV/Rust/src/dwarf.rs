@@ -0,0 +1,310 @@
+use crate::elf::ElfError;
+
+/// One row of a DWARF line-number program: the instructions from `address` up to the next
+/// row's address come from `line` of `file`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineRow {
+    pub address: u64,
+    pub file: String,
+    pub line: u64,
+    /// Marks the first address past a contiguous sequence; the row itself has no code.
+    pub end_sequence: bool,
+}
+
+const DW_LNCT_PATH: u64 = 1;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+fn malformed(reason: &str) -> ElfError {
+    ElfError::Malformed(format!(".debug_line: {}", reason))
+}
+
+/// Cursor over little-endian DWARF data.
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ElfError> {
+        let end = self.pos.checked_add(n).ok_or_else(|| malformed("truncated"))?;
+        let bytes = self.data.get(self.pos..end).ok_or_else(|| malformed("truncated"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ElfError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64, ElfError> {
+        Ok(self.take(n)?.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64))
+    }
+
+    fn uleb(&mut self) -> Result<u64, ElfError> {
+        let (mut value, mut shift) = (0u64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, ElfError> {
+        let (mut value, mut shift) = (0i64, 0);
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Result<String, ElfError> {
+        let rest = self.data.get(self.pos..).ok_or_else(|| malformed("string offset is out of range"))?;
+        let len = rest.iter().position(|&b| b == 0).ok_or_else(|| malformed("unterminated string"))?;
+        let s = String::from_utf8_lossy(self.take(len)?).into_owned();
+        self.pos += 1;
+        Ok(s)
+    }
+}
+
+fn string_at(table: Option<&[u8]>, offset: u64) -> Result<String, ElfError> {
+    let table = table.ok_or_else(|| malformed("string section is missing"))?;
+    let pos = usize::try_from(offset).map_err(|_| malformed("string offset is out of range"))?;
+    Cursor { data: table, pos }.cstr()
+}
+
+/// Reads an attribute of a DWARF 5 directory or file entry; only paths are kept.
+fn read_form(
+    cursor: &mut Cursor,
+    form: u64,
+    strings: (Option<&[u8]>, Option<&[u8]>),
+) -> Result<Option<String>, ElfError> {
+    match form {
+        DW_FORM_STRING => return cursor.cstr().map(Some),
+        DW_FORM_LINE_STRP => return string_at(strings.0, cursor.uint(4)?).map(Some),
+        DW_FORM_STRP => return string_at(strings.1, cursor.uint(4)?).map(Some),
+        DW_FORM_UDATA => drop(cursor.uleb()?),
+        DW_FORM_DATA1 => drop(cursor.take(1)?),
+        DW_FORM_DATA2 => drop(cursor.take(2)?),
+        DW_FORM_DATA4 => drop(cursor.take(4)?),
+        DW_FORM_DATA8 => drop(cursor.take(8)?),
+        DW_FORM_DATA16 => drop(cursor.take(16)?),
+        DW_FORM_BLOCK => {
+            let len = cursor.uleb()? as usize;
+            cursor.take(len)?;
+        }
+        _ => return Err(malformed(&format!("unsupported form 0x{:x}", form))),
+    }
+    Ok(None)
+}
+
+/// DWARF 5 directory or file name table: the path of each entry.
+fn read_entry_table(cursor: &mut Cursor, strings: (Option<&[u8]>, Option<&[u8]>)) -> Result<Vec<String>, ElfError> {
+    let format_count = cursor.u8()?;
+    let formats = (0..format_count)
+        .map(|_| Ok((cursor.uleb()?, cursor.uleb()?)))
+        .collect::<Result<Vec<_>, ElfError>>()?;
+    let count = cursor.uleb()?;
+    let mut paths = Vec::new();
+    for _ in 0..count {
+        let mut path = String::new();
+        for &(content, form) in &formats {
+            if let Some(s) = read_form(cursor, form, strings)? {
+                if content == DW_LNCT_PATH {
+                    path = s;
+                }
+            }
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Runs the line-number programs of a `.debug_line` section (DWARF 2 to 5, 32-bit format).
+///
+/// `line_str` and `str` are the `.debug_line_str` and `.debug_str` sections that DWARF 5
+/// file names may point into. File names are returned as written, without their directory.
+pub fn line_rows(debug_line: &[u8], line_str: Option<&[u8]>, str: Option<&[u8]>) -> Result<Vec<LineRow>, ElfError> {
+    let mut rows = Vec::new();
+    let mut unit = Cursor { data: debug_line, pos: 0 };
+    while unit.pos < debug_line.len() {
+        let length = unit.uint(4)? as usize;
+        if length >= 0xffff_fff0 {
+            return Err(malformed("64-bit DWARF is not supported"));
+        }
+        let end = unit.pos + length;
+        let mut c = Cursor { data: debug_line.get(..end).ok_or_else(|| malformed("truncated unit"))?, pos: unit.pos };
+        unit.pos = end;
+
+        let version = c.uint(2)?;
+        if !(2..=5).contains(&version) {
+            return Err(malformed(&format!("unsupported version {}", version)));
+        }
+        if version >= 5 {
+            c.take(2)?; // address and segment selector sizes
+        }
+        let header_length = c.uint(4)? as usize;
+        let program = c.pos + header_length;
+        let min_inst_length = c.u8()? as u64;
+        if version >= 4 {
+            c.u8()?; // maximum operations per instruction, always 1 on RISC-V
+        }
+        c.u8()?; // default_is_stmt
+        let line_base = c.u8()? as i8 as i64;
+        let line_range = c.u8()? as u64;
+        let opcode_base = c.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return Err(malformed("bad header"));
+        }
+        let opcode_lengths = c.take(opcode_base as usize - 1)?.to_vec();
+
+        let files = if version >= 5 {
+            read_entry_table(&mut c, (line_str, str))?; // directories
+            read_entry_table(&mut c, (line_str, str))?
+        } else {
+            while !c.cstr()?.is_empty() {} // include directories
+            // File numbers start at 1 before DWARF 5.
+            let mut files = vec![String::new()];
+            loop {
+                let name = c.cstr()?;
+                if name.is_empty() {
+                    break;
+                }
+                for _ in 0..3 {
+                    c.uleb()?; // directory, mtime, length
+                }
+                files.push(name);
+            }
+            files
+        };
+        let file_name = |index: u64| files.get(index as usize).cloned().unwrap_or_default();
+
+        c.pos = program;
+        let (mut address, mut file, mut line) = (0u64, 1u64, 1u64);
+        while c.pos < c.data.len() {
+            let opcode = c.u8()?;
+            let mut emit = |address: u64, file: u64, line: u64, end_sequence: bool| {
+                rows.push(LineRow { address, file: file_name(file), line, end_sequence });
+            };
+            if opcode >= opcode_base {
+                let adjusted = (opcode - opcode_base) as u64;
+                address = address.wrapping_add(adjusted / line_range * min_inst_length);
+                line = line.wrapping_add_signed(line_base + (adjusted % line_range) as i64);
+                emit(address, file, line, false);
+                continue;
+            }
+            match opcode {
+                0 => {
+                    let len = c.uleb()? as usize;
+                    let next = c.pos.checked_add(len).ok_or_else(|| malformed("truncated"))?;
+                    match c.u8()? {
+                        1 => {
+                            emit(address, file, line, true);
+                            (address, file, line) = (0, 1, 1);
+                        }
+                        2 => {
+                            let size = len.checked_sub(1).filter(|&n| n <= 8).ok_or_else(|| malformed("bad set_address"))?;
+                            address = c.uint(size)?;
+                        }
+                        _ => {} // define_file, set_discriminator and vendor extensions
+                    }
+                    c.pos = next;
+                }
+                1 => emit(address, file, line, false),
+                2 => address = address.wrapping_add(c.uleb()?.wrapping_mul(min_inst_length)),
+                3 => line = line.wrapping_add_signed(c.sleb()?),
+                4 => file = c.uleb()?,
+                8 => address = address.wrapping_add((255 - opcode_base as u64) / line_range * min_inst_length),
+                9 => address = address.wrapping_add(c.uint(2)?),
+                _ => {
+                    // set_column, negate_stmt, basic_block, prologue_end, ...: skip operands.
+                    for _ in 0..opcode_lengths[opcode as usize - 1] {
+                        c.uleb()?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/// Address ranges `[start, end)` whose code comes from lines `first..=last` of a file whose
+/// name ends with `file`, or of any file when `file` is `None`.
+pub fn line_ranges(rows: &[LineRow], file: Option<&str>, first: u64, last: u64) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for pair in rows.windows(2) {
+        let (row, next) = (&pair[0], &pair[1]);
+        let wanted = !row.end_sequence
+            && (first..=last).contains(&row.line)
+            && file.is_none_or(|f| row.file.ends_with(f))
+            && next.address > row.address;
+        if !wanted {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(range) if range.1 == row.address => range.1 = next.address,
+            _ => ranges.push((row.address, next.address)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::ElfFile;
+
+    #[test]
+    fn test_line_table_of_newlib_binary() {
+        let elf = ElfFile::load("test.bin").unwrap();
+        let rows = elf.line_rows().unwrap();
+        let main = elf.symbol("main").unwrap();
+        assert_eq!(rows[0], LineRow { address: main.value, file: "test.cpp".to_string(), line: 4, end_sequence: false });
+        assert!(rows.last().unwrap().end_sequence);
+        assert_eq!(rows.last().unwrap().address, main.value + main.size);
+
+        // `volatile int a = 3;` and `volatile int b = 4;` follow the prologue.
+        assert_eq!(line_ranges(&rows, Some("test.cpp"), 5, 6), vec![(main.value + 8, main.value + 20)]);
+        assert_eq!(line_ranges(&rows, None, 4, 100), vec![(main.value, main.value + main.size)]);
+        assert!(line_ranges(&rows, Some("other.c"), 4, 100).is_empty());
+    }
+
+    #[test]
+    fn test_rejects_out_of_range_offsets() {
+        assert_eq!(string_at(Some(b"main\0"), 0), Ok("main".to_string()));
+        assert!(matches!(string_at(Some(b"main\0"), 64), Err(ElfError::Malformed(_))));
+
+        // A DWARF 2 unit whose program is a zero-length `set_address`.
+        let mut header = vec![2, 0, 0, 0, 0, 0, 1, 1, -5i8 as u8, 14, 13];
+        header.extend([0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]); // standard opcode lengths
+        header.extend([0, 0]); // no include directories, no files
+        header[2] = (header.len() - 6) as u8;
+        header.extend([0, 0, 2]);
+        let mut unit = (header.len() as u32).to_le_bytes().to_vec();
+        unit.extend(header);
+        assert!(matches!(line_rows(&unit, None, None), Err(ElfError::Malformed(_))));
+    }
+}
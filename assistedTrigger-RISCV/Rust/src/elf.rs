@@ -0,0 +1,332 @@
+use std::fmt;
+
+use crate::dwarf::{self, LineRow};
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const STT_FUNC: u8 = 2;
+const EM_RISCV: u16 = 0xf3;
+const PHDR_SIZE: u64 = 56;
+const SHDR_SIZE: u64 = 64;
+/// Largest segment we load. Memory is allocated up front, so a corrupt `p_memsz` must not
+/// reach the allocator.
+const MAX_SEGMENT_SIZE: u64 = 1 << 30;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ElfError {
+    Io(String),
+    /// The file is not a little-endian RISC-V ELF64 image, or a table points outside it.
+    Malformed(String),
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Io(e) => write!(f, "cannot read ELF file: {}", e),
+            ElfError::Malformed(reason) => write!(f, "malformed ELF file: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+/// A `PT_LOAD` program header with its bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u64,
+    /// Bytes in memory: the file contents followed by `memsz - filesz` zeros (`.bss`).
+    pub data: Vec<u8>,
+    /// `PF_X` (1), `PF_W` (2) and `PF_R` (4).
+    pub flags: u32,
+}
+
+impl Segment {
+    pub fn end(&self) -> u64 {
+        self.vaddr + self.data.len() as u64
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & 1 != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+    /// `sh_type`, e.g. 1 for `SHT_PROGBITS` and 8 for `SHT_NOBITS`.
+    pub kind: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    pub is_function: bool,
+}
+
+impl Symbol {
+    /// Whether `addr` lies within the symbol's `[value, value + size)` range.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr.checked_sub(self.value).is_some_and(|offset| offset < self.size)
+    }
+}
+
+/// A parsed RISC-V ELF64 executable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfFile {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    /// The whole file, for sections that are not loaded such as debug information.
+    image: Vec<u8>,
+}
+
+/// Little-endian field reader that reports out-of-bounds reads as malformed input.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes(&self, offset: u64, len: u64, what: &str) -> Result<&[u8], ElfError> {
+        let start = usize::try_from(offset).ok();
+        let end = start.zip(usize::try_from(len).ok()).and_then(|(s, l)| s.checked_add(l));
+        start
+            .zip(end)
+            .and_then(|(s, e)| self.0.get(s..e))
+            .ok_or_else(|| ElfError::Malformed(format!("{} is outside the file", what)))
+    }
+
+    fn u16(&self, offset: u64, what: &str) -> Result<u16, ElfError> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2, what)?.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: u64, what: &str) -> Result<u32, ElfError> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4, what)?.try_into().unwrap()))
+    }
+
+    fn u64(&self, offset: u64, what: &str) -> Result<u64, ElfError> {
+        Ok(u64::from_le_bytes(self.bytes(offset, 8, what)?.try_into().unwrap()))
+    }
+
+    /// Offset of entry `index` of a table of `count` entries of `entsize` bytes at `base`,
+    /// checking that the entry's first `len` bytes lie in the file.
+    fn entry(&self, base: u64, index: u64, entsize: u64, len: u64, what: &str) -> Result<u64, ElfError> {
+        let offset = index
+            .checked_mul(entsize)
+            .and_then(|o| o.checked_add(base))
+            .ok_or_else(|| ElfError::Malformed(format!("{} is outside the file", what)))?;
+        self.bytes(offset, len, what)?;
+        Ok(offset)
+    }
+
+    /// NUL-terminated string at `offset` in the string table starting at `table`.
+    fn str(&self, table: u64, offset: u64) -> Result<String, ElfError> {
+        let rest = table
+            .checked_add(offset)
+            .and_then(|start| usize::try_from(start).ok())
+            .and_then(|start| self.0.get(start..))
+            .ok_or_else(|| ElfError::Malformed("string table is outside the file".to_string()))?;
+        let len = rest.iter().position(|&b| b == 0).ok_or_else(|| ElfError::Malformed("unterminated string".to_string()))?;
+        Ok(String::from_utf8_lossy(&rest[..len]).into_owned())
+    }
+}
+
+impl ElfFile {
+    pub fn load(path: &str) -> Result<Self, ElfError> {
+        let bytes = std::fs::read(path).map_err(|e| ElfError::Io(format!("{}: {}", path, e)))?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, ElfError> {
+        let r = Reader(bytes);
+        if bytes.get(..6) != Some(b"\x7fELF\x02\x01") {
+            return Err(ElfError::Malformed("not a little-endian ELF64 file".to_string()));
+        }
+        if r.u16(18, "header")? != EM_RISCV {
+            return Err(ElfError::Malformed("not a RISC-V executable".to_string()));
+        }
+        let entry = r.u64(24, "header")?;
+        let phoff = r.u64(32, "header")?;
+        let shoff = r.u64(40, "header")?;
+        let phentsize = r.u16(54, "header")? as u64;
+        let phnum = r.u16(56, "header")? as u64;
+        let shentsize = r.u16(58, "header")? as u64;
+        let shnum = r.u16(60, "header")? as u64;
+        let shstrndx = r.u16(62, "header")? as u64;
+
+        let mut segments = Vec::new();
+        for i in 0..phnum {
+            let ph = r.entry(phoff, i, phentsize, PHDR_SIZE, "program header")?;
+            if r.u32(ph, "program header")? != PT_LOAD {
+                continue;
+            }
+            let flags = r.u32(ph + 4, "program header")?;
+            let offset = r.u64(ph + 8, "program header")?;
+            let vaddr = r.u64(ph + 16, "program header")?;
+            let filesz = r.u64(ph + 32, "program header")?;
+            let memsz = r.u64(ph + 40, "program header")?;
+            if filesz > memsz {
+                return Err(ElfError::Malformed("segment is larger in the file than in memory".to_string()));
+            }
+            if memsz > MAX_SEGMENT_SIZE || vaddr.checked_add(memsz).is_none() {
+                return Err(ElfError::Malformed(format!("segment at 0x{:x} of 0x{:x} bytes is too large", vaddr, memsz)));
+            }
+            let mut data = r.bytes(offset, filesz, "segment")?.to_vec();
+            data.resize(memsz as usize, 0);
+            segments.push(Segment { vaddr, data, flags });
+        }
+
+        // Section headers: (name offset, type, addr, offset, size, link).
+        let mut headers = Vec::new();
+        for i in 0..shnum {
+            let sh = r.entry(shoff, i, shentsize, SHDR_SIZE, "section header")?;
+            headers.push((
+                r.u32(sh, "section header")? as u64,
+                r.u32(sh + 4, "section header")?,
+                r.u64(sh + 16, "section header")?,
+                r.u64(sh + 24, "section header")?,
+                r.u64(sh + 32, "section header")?,
+                r.u32(sh + 40, "section header")? as usize,
+            ));
+        }
+        let names = headers.get(shstrndx as usize).map(|&(_, _, _, offset, _, _)| offset);
+        let sections = headers
+            .iter()
+            .map(|&(name, kind, addr, offset, size, _)| {
+                Ok(Section {
+                    name: names.map(|table| r.str(table, name)).transpose()?.unwrap_or_default(),
+                    addr,
+                    offset,
+                    size,
+                    kind,
+                })
+            })
+            .collect::<Result<Vec<_>, ElfError>>()?;
+
+        let mut symbols = Vec::new();
+        for &(_, kind, _, offset, size, link) in &headers {
+            if kind != SHT_SYMTAB {
+                continue;
+            }
+            let strtab = headers.get(link).ok_or_else(|| ElfError::Malformed("symbol table without strings".to_string()))?.3;
+            let end = offset.checked_add(size).ok_or_else(|| ElfError::Malformed("symbol table is outside the file".to_string()))?;
+            // Entry 0 is the reserved null symbol.
+            for sym in (offset..end).step_by(24).skip(1) {
+                let name = r.str(strtab, r.u32(sym, "symbol")? as u64)?;
+                let info = r.bytes(sym + 4, 1, "symbol")?[0];
+                let shndx = r.u16(sym + 6, "symbol")?;
+                if name.is_empty() || shndx == 0 {
+                    continue; // undefined or unnamed
+                }
+                symbols.push(Symbol {
+                    name,
+                    value: r.u64(sym + 8, "symbol")?,
+                    size: r.u64(sym + 16, "symbol")?,
+                    is_function: info & 0xf == STT_FUNC,
+                });
+            }
+        }
+
+        Ok(ElfFile { entry, segments, sections, symbols, image: bytes.to_vec() })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// File contents of a section; `None` for missing and `SHT_NOBITS` sections.
+    pub fn section_data(&self, name: &str) -> Option<&[u8]> {
+        let section = self.section(name).filter(|s| s.kind != SHT_NOBITS)?;
+        let start = usize::try_from(section.offset).ok()?;
+        self.image.get(start..start.checked_add(usize::try_from(section.size).ok()?)?)
+    }
+
+    /// Rows of the `.debug_line` program, or an empty table without debug information.
+    pub fn line_rows(&self) -> Result<Vec<LineRow>, ElfError> {
+        match self.section_data(".debug_line") {
+            Some(data) => dwarf::line_rows(data, self.section_data(".debug_line_str"), self.section_data(".debug_str")),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// `[start, end)` addresses of `.text`.
+    pub fn text_bounds(&self) -> Option<(u64, u64)> {
+        self.section(".text").map(|s| (s.addr, s.addr.saturating_add(s.size)))
+    }
+
+    /// Address of a symbol such as `main`.
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    /// Function containing `addr`, for naming trace locations.
+    pub fn function_at(&self, addr: u64) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.is_function && s.contains(addr))
+    }
+
+    /// Initial memory image as (address, bytes) pairs, one per loadable segment.
+    pub fn memory_image(&self) -> Vec<(u64, &[u8])> {
+        self.segments.iter().map(|s| (s.vaddr, s.data.as_slice())).collect()
+    }
+
+    /// Bytes at `[addr, addr + len)` in the initial image, if they lie in one segment.
+    pub fn read(&self, addr: u64, len: usize) -> Option<&[u8]> {
+        self.segments.iter().find_map(|s| {
+            let start = addr.checked_sub(s.vaddr)? as usize;
+            s.data.get(start..start.checked_add(len)?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_newlib_binary() {
+        let elf = ElfFile::load("test.bin").unwrap();
+        let (start, end) = elf.text_bounds().unwrap();
+        assert!(start <= elf.entry && elf.entry < end);
+        assert!(elf.segments.iter().any(|s| s.is_executable() && s.vaddr <= elf.entry && elf.entry < s.end()));
+
+        let main = elf.symbol("main").unwrap();
+        assert!(main.is_function && main.size > 0);
+        assert!(start <= main.value && main.value + main.size <= end);
+        assert_eq!(elf.function_at(main.value + 4).unwrap().name, "main");
+        assert_eq!(elf.function_at(elf.entry).unwrap().name, "_start");
+
+        // The first instruction of main is in the image and decodes.
+        let first = elf.read(main.value, 2).unwrap();
+        assert_ne!(first, [0, 0]);
+        assert!(elf.read(end + 0x1000_0000, 4).is_none());
+    }
+
+    #[test]
+    fn test_rejects_truncated_and_foreign_files() {
+        let bytes = std::fs::read("test.bin").unwrap();
+        assert!(matches!(ElfFile::parse(&bytes[..100]), Err(ElfError::Malformed(_))));
+
+        let mut x86 = bytes.clone();
+        x86[18] = 0x3e;
+        assert_eq!(ElfFile::parse(&x86), Err(ElfError::Malformed("not a RISC-V executable".to_string())));
+        assert!(matches!(ElfFile::load("missing.bin"), Err(ElfError::Io(_))));
+
+        // Offsets and sizes that overflow or would exhaust memory are rejected, not followed.
+        let u64_at = |bytes: &mut Vec<u8>, at: usize, v: u64| bytes[at..at + 8].copy_from_slice(&v.to_le_bytes());
+        let phoff = u64::from_le_bytes(bytes[32..40].try_into().unwrap()) as usize;
+        let mut far = bytes.clone();
+        u64_at(&mut far, 32, u64::MAX - 8);
+        assert!(matches!(ElfFile::parse(&far), Err(ElfError::Malformed(_))));
+        let first_load = (0..).map(|i| phoff + i * 56).find(|&ph| bytes[ph..ph + 4] == [1, 0, 0, 0]).unwrap();
+        let mut huge = bytes.clone();
+        u64_at(&mut huge, first_load + 40, u64::MAX);
+        assert!(matches!(ElfFile::parse(&huge), Err(ElfError::Malformed(_))));
+        let mut wrapping = bytes.clone();
+        u64_at(&mut wrapping, 40, u64::MAX - 63);
+        assert!(matches!(ElfFile::parse(&wrapping), Err(ElfError::Malformed(_))));
+    }
+}
@@ -0,0 +1,694 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::decoder::{self, instruction_len};
+use crate::elf::ElfFile;
+use crate::inputs::ProgramInputs;
+use crate::instruction::{Instruction, Reg};
+use crate::trace_parser::{MemoryAccess, RegisterFile, TraceEntry};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Initial stack pointer. The stack below it is zeroed, so `_start` sees `argc = 0` and empty
+/// `argv`/`envp`/auxv vectors unless [`Emulator::set_inputs`] lays them out.
+pub const STACK_TOP: u64 = 0x7fff_f000;
+
+/// Most bytes one `read` or `write` transfers, so a guest count cannot exhaust host memory.
+pub const MAX_SYSCALL_DATA: u64 = 1 << 20;
+
+/// Sparse little-endian byte memory. Unmapped bytes read as zero.
+#[derive(Debug, Clone, Default)]
+pub struct Memory {
+    pages: HashMap<u64, Box<[u8; PAGE_SIZE as usize]>>,
+}
+
+impl Memory {
+    pub fn read_byte(&self, addr: u64) -> u8 {
+        self.pages
+            .get(&(addr / PAGE_SIZE))
+            .map_or(0, |page| page[(addr % PAGE_SIZE) as usize])
+    }
+
+    pub fn write_byte(&mut self, addr: u64, value: u8) {
+        let page = self.pages.entry(addr / PAGE_SIZE).or_insert_with(|| Box::new([0; PAGE_SIZE as usize]));
+        page[(addr % PAGE_SIZE) as usize] = value;
+    }
+
+    pub fn read_bytes(&self, addr: u64, len: usize) -> Vec<u8> {
+        (0..len as u64).map(|i| self.read_byte(addr.wrapping_add(i))).collect()
+    }
+
+    pub fn write_bytes(&mut self, addr: u64, bytes: &[u8]) {
+        for (i, &b) in bytes.iter().enumerate() {
+            self.write_byte(addr.wrapping_add(i as u64), b);
+        }
+    }
+
+    /// Reads `width` bytes (at most 8), zero-extended.
+    pub fn load(&self, addr: u64, width: u8) -> u64 {
+        (0..width as u64).fold(0, |acc, i| acc | (self.read_byte(addr.wrapping_add(i)) as u64) << (8 * i))
+    }
+
+    /// Writes the low `width` bytes of `value`.
+    pub fn store(&mut self, addr: u64, width: u8, value: u64) {
+        for i in 0..width as u64 {
+            self.write_byte(addr.wrapping_add(i), (value >> (8 * i)) as u8);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmulatorError {
+    /// The bytes at `pc` are not an RV64IMC instruction.
+    IllegalInstruction { pc: u64, bits: u32 },
+    /// An `ecall` with a number outside `read`/`write`/`exit`/`brk`.
+    UnsupportedSyscall { pc: u64, number: u64 },
+    /// `step` was called after the program exited.
+    Exited,
+    /// The program was still running after this many steps.
+    StepLimit(usize),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::IllegalInstruction { pc, bits } => {
+                write!(f, "illegal instruction 0x{:08x} at 0x{:x}", bits, pc)
+            }
+            EmulatorError::UnsupportedSyscall { pc, number } => {
+                write!(f, "unsupported syscall {} at 0x{:x}", number, pc)
+            }
+            EmulatorError::Exited => write!(f, "the program has already exited"),
+            EmulatorError::StepLimit(max) => write!(f, "the program did not exit within {} steps", max),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
+/// Decoded RV64IMC operation. Compressed instructions decode to their base equivalent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Lui, Auipc, Jal, Jalr,
+    Beq, Bne, Blt, Bge, Bltu, Bgeu,
+    Lb, Lh, Lw, Ld, Lbu, Lhu, Lwu,
+    Sb, Sh, Sw, Sd,
+    Addi, Slti, Sltiu, Xori, Ori, Andi, Slli, Srli, Srai,
+    Addiw, Slliw, Srliw, Sraiw,
+    Add, Sub, Sll, Slt, Sltu, Xor, Srl, Sra, Or, And,
+    Addw, Subw, Sllw, Srlw, Sraw,
+    Mul, Mulh, Mulhsu, Mulhu, Div, Divu, Rem, Remu,
+    Mulw, Divw, Divuw, Remw, Remuw,
+    Fence, Ecall, Ebreak,
+}
+
+impl Op {
+    pub fn mnemonic(self) -> &'static str {
+        use Op::*;
+        match self {
+            Lui => "lui", Auipc => "auipc", Jal => "jal", Jalr => "jalr",
+            Beq => "beq", Bne => "bne", Blt => "blt", Bge => "bge", Bltu => "bltu", Bgeu => "bgeu",
+            Lb => "lb", Lh => "lh", Lw => "lw", Ld => "ld", Lbu => "lbu", Lhu => "lhu", Lwu => "lwu",
+            Sb => "sb", Sh => "sh", Sw => "sw", Sd => "sd",
+            Addi => "addi", Slti => "slti", Sltiu => "sltiu", Xori => "xori", Ori => "ori",
+            Andi => "andi", Slli => "slli", Srli => "srli", Srai => "srai",
+            Addiw => "addiw", Slliw => "slliw", Srliw => "srliw", Sraiw => "sraiw",
+            Add => "add", Sub => "sub", Sll => "sll", Slt => "slt", Sltu => "sltu",
+            Xor => "xor", Srl => "srl", Sra => "sra", Or => "or", And => "and",
+            Addw => "addw", Subw => "subw", Sllw => "sllw", Srlw => "srlw", Sraw => "sraw",
+            Mul => "mul", Mulh => "mulh", Mulhsu => "mulhsu", Mulhu => "mulhu",
+            Div => "div", Divu => "divu", Rem => "rem", Remu => "remu",
+            Mulw => "mulw", Divw => "divw", Divuw => "divuw", Remw => "remw", Remuw => "remuw",
+            Fence => "fence", Ecall => "ecall", Ebreak => "ebreak",
+        }
+    }
+
+    /// Access width in bytes of a load or store.
+    fn width(self) -> Option<u8> {
+        use Op::*;
+        match self {
+            Lb | Lbu | Sb => Some(1),
+            Lh | Lhu | Sh => Some(2),
+            Lw | Lwu | Sw => Some(4),
+            Ld | Sd => Some(8),
+            _ => None,
+        }
+    }
+}
+
+impl Op {
+    const ALL: [Op; 65] = {
+        use Op::*;
+        [
+            Lui, Auipc, Jal, Jalr,
+            Beq, Bne, Blt, Bge, Bltu, Bgeu,
+            Lb, Lh, Lw, Ld, Lbu, Lhu, Lwu,
+            Sb, Sh, Sw, Sd,
+            Addi, Slti, Sltiu, Xori, Ori, Andi, Slli, Srli, Srai,
+            Addiw, Slliw, Srliw, Sraiw,
+            Add, Sub, Sll, Slt, Sltu, Xor, Srl, Sra, Or, And,
+            Addw, Subw, Sllw, Srlw, Sraw,
+            Mul, Mulh, Mulhsu, Mulhu, Div, Divu, Rem, Remu,
+            Mulw, Divw, Divuw, Remw, Remuw,
+            Fence, Ecall, Ebreak,
+        ]
+    };
+
+    fn from_mnemonic(mnemonic: &str) -> Option<Op> {
+        match mnemonic {
+            // Every MISC-MEM instruction orders nothing in a single-hart interpreter.
+            "fence" | "fence.i" | "fence.tso" => Some(Op::Fence),
+            _ => Op::ALL.into_iter().find(|op| op.mnemonic() == mnemonic),
+        }
+    }
+}
+
+/// An instruction in the form the interpreter executes. `imm` is sign-extended, except for
+/// `lui`/`auipc` where it is the 20-bit upper immediate as written in assembly; branch and
+/// jump offsets are relative to the instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoded {
+    pub op: Op,
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub imm: i64,
+    /// Instruction length in bytes: 2 for compressed instructions, 4 otherwise.
+    pub len: u8,
+}
+
+impl Decoded {
+    /// Execution form of `inst`, decoded by [`decoder::decode`] from `len` bytes at `pc`, or
+    /// `None` outside RV64IM.
+    pub fn from_instruction(pc: u64, inst: &Instruction, len: u8) -> Option<Decoded> {
+        let x = |reg: &Reg| match *reg {
+            Reg::X(n) => Some(n),
+            Reg::F(_) => None,
+        };
+        let op = Op::from_mnemonic(inst.mnemonic())?;
+        let relative = |target: u64| target.wrapping_sub(pc) as i64;
+        let d = |rd, rs1, rs2, imm| Some(Decoded { op, rd, rs1, rs2, imm, len });
+        match inst {
+            Instruction::RegReg { rd, rs1, rs2, .. } => d(x(rd)?, x(rs1)?, x(rs2)?, 0),
+            Instruction::RegImm { rd, rs1, imm, .. } => d(x(rd)?, x(rs1)?, 0, *imm),
+            Instruction::Upper { rd, imm, .. } => d(x(rd)?, 0, 0, *imm),
+            Instruction::Load { rd, base, offset, .. } => d(x(rd)?, x(base)?, 0, *offset),
+            Instruction::Store { src, base, offset, .. } => d(0, x(base)?, x(src)?, *offset),
+            Instruction::Branch { rs1, rs2, target, .. } => d(0, x(rs1)?, x(rs2)?, relative(*target)),
+            Instruction::Jal { rd, target } => d(x(rd)?, 0, 0, relative(*target)),
+            Instruction::Jalr { rd, base, offset } => d(x(rd)?, x(base)?, 0, *offset),
+            Instruction::System { .. } => d(0, 0, 0, 0),
+            _ => None,
+        }
+    }
+}
+
+/// RV64IMC interpreter producing [`TraceEntry`]s.
+///
+/// Every entry carries the whole integer register file before and after the instruction,
+/// canonical mnemonics (compressed instructions are expanded, pseudo-instructions are not
+/// formed) and the [`MemoryAccess`] of loads and stores. `ecall`s follow the Linux ABI for
+/// `read`, `write`, `exit`, `exit_group` and `brk`; stdin is taken from [`Emulator::stdin`]
+/// and stdout and stderr are collected in [`Emulator::stdout`] and [`Emulator::stderr`].
+#[derive(Debug, Clone)]
+pub struct Emulator {
+    pub regs: [u64; 32],
+    pub pc: u64,
+    pub memory: Memory,
+    pub stdin: VecDeque<u8>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    brk: u64,
+    exit_code: Option<u64>,
+}
+
+impl Emulator {
+    /// Maps `segments` (address, bytes) and starts at `entry` with `sp = STACK_TOP`. The
+    /// program break starts at the first page boundary after the highest segment.
+    pub fn new(entry: u64, segments: &[(u64, &[u8])]) -> Self {
+        let mut memory = Memory::default();
+        let mut end = 0;
+        for &(addr, bytes) in segments {
+            memory.write_bytes(addr, bytes);
+            end = end.max(addr + bytes.len() as u64);
+        }
+        let mut regs = [0; 32];
+        regs[2] = STACK_TOP;
+        Emulator {
+            regs,
+            pc: entry,
+            memory,
+            stdin: VecDeque::new(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            brk: end.div_ceil(PAGE_SIZE) * PAGE_SIZE,
+            exit_code: None,
+        }
+    }
+
+    /// Starts at the entry point of `elf` with its loadable segments mapped.
+    pub fn from_elf(elf: &ElfFile) -> Self {
+        Emulator::new(elf.entry, &elf.memory_image())
+    }
+
+    /// Gives the program `inputs`: `argv` (with `program` as `argv[0]`) and `envp` are laid out
+    /// below [`STACK_TOP`] the way Linux does, followed by an empty auxv, stdin is queued and
+    /// the sensor blob is written to memory. Call before the first step.
+    pub fn set_inputs(&mut self, program: &str, inputs: &ProgramInputs) {
+        let mut top = STACK_TOP;
+        let mut push_string = |memory: &mut Memory, text: &str| {
+            top -= text.len() as u64 + 1;
+            memory.write_bytes(top, text.as_bytes());
+            memory.write_byte(top + text.len() as u64, 0);
+            top
+        };
+        let argv: Vec<u64> = std::iter::once(program)
+            .chain(inputs.args.iter().map(String::as_str))
+            .map(|arg| push_string(&mut self.memory, arg))
+            .collect();
+        let envp: Vec<u64> =
+            inputs.env.iter().map(|(key, value)| push_string(&mut self.memory, &format!("{}={}", key, value))).collect();
+        // argc, argv and its null, envp and its null, then the AT_NULL auxv pair.
+        let words: Vec<u64> = std::iter::once(argv.len() as u64)
+            .chain(argv.iter().copied())
+            .chain([0])
+            .chain(envp.iter().copied())
+            .chain([0, 0, 0])
+            .collect();
+        let sp = (top - 8 * words.len() as u64) & !15;
+        for (i, &word) in words.iter().enumerate() {
+            self.memory.store(sp + 8 * i as u64, 8, word);
+        }
+        self.regs[2] = sp;
+        self.stdin = VecDeque::from(inputs.stdin.clone());
+        if let Some(sensor) = &inputs.sensor {
+            self.memory.write_bytes(sensor.addr, &sensor.data);
+        }
+    }
+
+    /// Exit code, once the program has called `exit`.
+    pub fn exit_code(&self) -> Option<u64> {
+        self.exit_code
+    }
+
+    /// Fetches and decodes the instruction at `pc`, in its canonical and its executable form.
+    pub fn fetch(&self) -> Result<(Instruction, Decoded), EmulatorError> {
+        let bits = self.memory.load(self.pc, 4) as u32;
+        let illegal = || EmulatorError::IllegalInstruction { pc: self.pc, bits };
+        let inst = decoder::decode(self.pc, bits).ok_or_else(illegal)?;
+        let decoded = Decoded::from_instruction(self.pc, &inst, instruction_len(bits)).ok_or_else(illegal)?;
+        Ok((inst, decoded))
+    }
+
+    /// Executes one instruction and returns its trace entry.
+    pub fn step(&mut self) -> Result<TraceEntry, EmulatorError> {
+        if self.exit_code.is_some() {
+            return Err(EmulatorError::Exited);
+        }
+        let pc = self.pc;
+        let (inst, decoded) = self.fetch()?;
+        let before = self.regs;
+        let (memory, syscall_data) = self.execute(decoded)?;
+        self.regs[0] = 0;
+
+        Ok(TraceEntry {
+            regs_before: RegisterFile { x: before, ..Default::default() },
+            regs_after: RegisterFile { x: self.regs, ..Default::default() },
+            memory,
+            syscall_data,
+            ..TraceEntry::from_instruction(pc, &inst)
+        })
+    }
+
+    /// Runs until the program exits. `max_steps` bounds the number of instructions; reaching
+    /// it first is a [`EmulatorError::StepLimit`] error, not a truncated trace.
+    pub fn run(&mut self, max_steps: usize) -> Result<Vec<TraceEntry>, EmulatorError> {
+        let mut entries = Vec::new();
+        while self.exit_code.is_none() {
+            if entries.len() == max_steps {
+                return Err(EmulatorError::StepLimit(max_steps));
+            }
+            entries.push(self.step()?);
+        }
+        Ok(entries)
+    }
+
+    fn execute(&mut self, inst: Decoded) -> Result<(Option<MemoryAccess>, Option<Vec<u8>>), EmulatorError> {
+        use Op::*;
+        let a = self.regs[inst.rs1 as usize];
+        let b = self.regs[inst.rs2 as usize];
+        let imm = inst.imm as u64;
+        let next = self.pc.wrapping_add(inst.len as u64);
+        let word = |x: u64| x as i32 as i64 as u64;
+        let mut pc = next;
+        let mut access = None;
+        let mut data = None;
+
+        let rd_val = match inst.op {
+            Lui => Some(word(imm << 12)),
+            Auipc => Some(self.pc.wrapping_add(word(imm << 12))),
+            Jal => {
+                pc = self.pc.wrapping_add(imm);
+                Some(next)
+            }
+            Jalr => {
+                pc = a.wrapping_add(imm) & !1;
+                Some(next)
+            }
+            Beq | Bne | Blt | Bge | Bltu | Bgeu => {
+                let taken = match inst.op {
+                    Beq => a == b,
+                    Bne => a != b,
+                    Blt => (a as i64) < (b as i64),
+                    Bge => (a as i64) >= (b as i64),
+                    Bltu => a < b,
+                    _ => a >= b,
+                };
+                if taken {
+                    pc = self.pc.wrapping_add(imm);
+                }
+                None
+            }
+            Lb | Lh | Lw | Ld | Lbu | Lhu | Lwu => {
+                let width = inst.op.width().unwrap();
+                let addr = a.wrapping_add(imm);
+                let raw = self.memory.load(addr, width);
+                access = Some(MemoryAccess { addr, width, read: raw, written: None });
+                Some(match inst.op {
+                    Lb => raw as i8 as i64 as u64,
+                    Lh => raw as i16 as i64 as u64,
+                    Lw => word(raw),
+                    _ => raw,
+                })
+            }
+            Sb | Sh | Sw | Sd => {
+                let width = inst.op.width().unwrap();
+                let addr = a.wrapping_add(imm);
+                let value = if width == 8 { b } else { b & ((1 << (8 * width)) - 1) };
+                access = Some(MemoryAccess { addr, width, read: self.memory.load(addr, width), written: Some(value) });
+                self.memory.store(addr, width, value);
+                None
+            }
+            Addi => Some(a.wrapping_add(imm)),
+            Slti => Some(((a as i64) < inst.imm) as u64),
+            Sltiu => Some((a < imm) as u64),
+            Xori => Some(a ^ imm),
+            Ori => Some(a | imm),
+            Andi => Some(a & imm),
+            Slli => Some(a << (imm & 63)),
+            Srli => Some(a >> (imm & 63)),
+            Srai => Some(((a as i64) >> (imm & 63)) as u64),
+            Addiw => Some(word(a.wrapping_add(imm))),
+            Slliw => Some(word(a << (imm & 31))),
+            Srliw => Some(word((a as u32 >> (imm & 31)) as u64)),
+            Sraiw => Some((a as i32 >> (imm & 31)) as i64 as u64),
+            Add => Some(a.wrapping_add(b)),
+            Sub => Some(a.wrapping_sub(b)),
+            Sll => Some(a << (b & 63)),
+            Slt => Some(((a as i64) < (b as i64)) as u64),
+            Sltu => Some((a < b) as u64),
+            Xor => Some(a ^ b),
+            Srl => Some(a >> (b & 63)),
+            Sra => Some(((a as i64) >> (b & 63)) as u64),
+            Or => Some(a | b),
+            And => Some(a & b),
+            Addw => Some(word(a.wrapping_add(b))),
+            Subw => Some(word(a.wrapping_sub(b))),
+            Sllw => Some(word(a << (b & 31))),
+            Srlw => Some(word((a as u32 >> (b & 31)) as u64)),
+            Sraw => Some((a as i32 >> (b & 31)) as i64 as u64),
+            Mul => Some(a.wrapping_mul(b)),
+            Mulh => Some(((a as i64 as i128 * b as i64 as i128) >> 64) as u64),
+            Mulhsu => Some(((a as i64 as i128 * b as i128) >> 64) as u64),
+            Mulhu => Some(((a as u128 * b as u128) >> 64) as u64),
+            Div => Some(match b {
+                0 => u64::MAX,
+                _ => (a as i64).wrapping_div(b as i64) as u64,
+            }),
+            Divu => Some(a.checked_div(b).unwrap_or(u64::MAX)),
+            Rem => Some(match b {
+                0 => a,
+                _ => (a as i64).wrapping_rem(b as i64) as u64,
+            }),
+            Remu => Some(a.checked_rem(b).unwrap_or(a)),
+            Mulw => Some(word(a.wrapping_mul(b))),
+            Divw => Some(match b as i32 {
+                0 => u64::MAX,
+                d => (a as i32).wrapping_div(d) as i64 as u64,
+            }),
+            Divuw => Some(word((a as u32).checked_div(b as u32).unwrap_or(u32::MAX) as u64)),
+            Remw => Some(match b as i32 {
+                0 => word(a),
+                d => (a as i32).wrapping_rem(d) as i64 as u64,
+            }),
+            Remuw => Some(word((a as u32).checked_rem(b as u32).unwrap_or(a as u32) as u64)),
+            Fence | Ebreak => None,
+            Ecall => {
+                data = self.syscall()?;
+                None
+            }
+        };
+        if let Some(value) = rd_val {
+            self.regs[inst.rd as usize] = value;
+        }
+        self.pc = pc;
+        Ok((access, data))
+    }
+
+    /// Performs the system call in `a7`, returning the bytes transferred by `read`/`write`.
+    fn syscall(&mut self) -> Result<Option<Vec<u8>>, EmulatorError> {
+        let [a0, a1, a2] = [self.regs[10], self.regs[11], self.regs[12]];
+        let number = self.regs[17];
+        let (ret, data) = match number {
+            63 => {
+                let count = if a0 == 0 { (a2 as usize).min(self.stdin.len()) } else { 0 };
+                let bytes: Vec<u8> = self.stdin.drain(..count).collect();
+                self.memory.write_bytes(a1, &bytes);
+                (count as u64, (count > 0).then_some(bytes))
+            }
+            64 => {
+                // Larger writes complete partially, as they may on Linux.
+                let count = a2.min(MAX_SYSCALL_DATA);
+                let bytes = self.memory.read_bytes(a1, count as usize);
+                match a0 {
+                    1 => self.stdout.extend_from_slice(&bytes),
+                    2 => self.stderr.extend_from_slice(&bytes),
+                    _ => {}
+                }
+                (count, (count > 0).then_some(bytes))
+            }
+            93 | 94 => {
+                self.exit_code = Some(a0);
+                (a0, None)
+            }
+            214 => {
+                if a0 != 0 {
+                    self.brk = a0;
+                }
+                (self.brk, None)
+            }
+            _ => return Err(EmulatorError::UnsupportedSyscall { pc: self.pc, number }),
+        };
+        self.regs[10] = ret;
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::bits;
+    use crate::circuit::prove_multi_instruction_constraint;
+    use crate::zk::{convert_trace_to_rows_with, ConversionMode, ConvertOptions};
+
+    fn i_type(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+        ((imm as u32 & 0xfff) << 20) | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+    }
+
+    fn r_type(funct7: u32, funct3: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+        funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | 0x33
+    }
+
+    fn s_type(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+        let imm = imm as u32 & 0xfff;
+        (imm >> 5) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | 0x23
+    }
+
+    fn b_type(funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+        let imm = imm as u32;
+        bits(imm, 12, 12) << 31 | bits(imm, 10, 5) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12
+            | bits(imm, 4, 1) << 8 | bits(imm, 11, 11) << 7 | 0x63
+    }
+
+    fn assemble(code: &[Vec<u8>]) -> Vec<u8> {
+        code.concat()
+    }
+
+    fn w(word: u32) -> Vec<u8> {
+        word.to_le_bytes().to_vec()
+    }
+
+    fn c(half: u16) -> Vec<u8> {
+        half.to_le_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_decoded_instructions_get_an_execution_form() {
+        use Op::*;
+        let expand = |half: u16| {
+            let inst = decoder::decode(0x1000, half as u32).unwrap();
+            let d = Decoded::from_instruction(0x1000, &inst, 2).unwrap();
+            (d.op, d.rd, d.rs1, d.rs2, d.imm)
+        };
+        assert_eq!(expand(0x1101), (Addi, 2, 2, 0, -32)); // c.addi16sp sp,-32
+        assert_eq!(expand(0xec06), (Sd, 0, 2, 1, 24)); // c.sdsp ra,24(sp)
+        assert_eq!(expand(0x60e2), (Ld, 1, 2, 0, 24)); // c.ldsp ra,24(sp)
+        assert_eq!(expand(0x4515), (Addi, 10, 0, 0, 5)); // c.li a0,5
+        assert_eq!(expand(0x852e), (Add, 10, 0, 11, 0)); // c.mv a0,a1
+        assert_eq!(expand(0x8082), (Jalr, 0, 1, 0, 0)); // c.jr ra (ret)
+        assert_eq!(expand(0x67c1), (Lui, 15, 0, 0, 0x10)); // c.lui a5,0x10
+        assert_eq!(expand(0x8d89), (Sub, 11, 11, 10, 0)); // c.sub a1,a0
+        assert_eq!(expand(0xa001), (Jal, 0, 0, 0, 0)); // c.j .
+        assert_eq!(expand(0xc111), (Beq, 0, 10, 0, 4)); // c.beqz a0,.+4
+
+        // FP and atomic instructions decode, but are outside what the interpreter runs.
+        let fld = decoder::decode(0x1000, 0x2588).unwrap();
+        assert_eq!(Decoded::from_instruction(0x1000, &fld, 2), None);
+
+        // Every RV64IM instruction of a newlib binary has one.
+        let elf = ElfFile::load("test.bin").unwrap();
+        let (start, end) = elf.text_bounds().unwrap();
+        let mut executable = 0;
+        for pc in (start..end).step_by(2) {
+            let Some(bytes) = elf.read(pc, 4) else { continue };
+            let bits = u32::from_le_bytes(bytes.try_into().unwrap());
+            let Some(inst) = decoder::decode(pc, bits) else { continue };
+            if Op::ALL.iter().any(|op| op.mnemonic() == inst.mnemonic()) {
+                assert!(Decoded::from_instruction(pc, &inst, instruction_len(bits)).is_some(), "{} at 0x{:x}", inst, pc);
+                executable += 1;
+            }
+        }
+        assert!(executable > 1000);
+    }
+
+    #[test]
+    fn test_emulator_traces_program_and_proves_it() {
+        // sum = 1 + 2 + ... + 10, kept on the stack, then written to stdout and used as the
+        // exit code.
+        let code = assemble(&[
+            c(0x1101),                                // addi sp,sp,-32
+            w(i_type(0x13, 0, 5, 0, 0)),              // addi t0,x0,0 (sum)
+            c(0x4329),                                // addi t1,x0,10 (c.li t1,10)
+            w(r_type(0, 0, 5, 5, 6)),                 // loop: add t0,t0,t1
+            w(i_type(0x13, 0, 6, 6, -1)),             // addi t1,t1,-1
+            w(b_type(1, 6, 0, -8)),                   // bne t1,x0,loop
+            w(s_type(3, 2, 5, 8)),                    // sd t0,8(sp)
+            w(i_type(0x03, 3, 10, 2, 8)),             // ld a0,8(sp)
+            w(i_type(0x13, 0, 7, 0, 7)),              // addi t2,x0,7
+            w(r_type(1, 0, 28, 10, 7)),               // mul t3,a0,t2
+            w(r_type(1, 4, 29, 28, 7)),               // div t4,t3,t2
+            w(i_type(0x13, 0, 11, 0, 0x48)),          // addi a1,x0,'H'
+            w(s_type(0, 2, 11, 0)),                   // sb a1,0(sp)
+            w(i_type(0x13, 0, 10, 0, 1)),             // addi a0,x0,1
+            w(i_type(0x13, 0, 11, 2, 0)),             // addi a1,sp,0
+            w(i_type(0x13, 0, 12, 0, 1)),             // addi a2,x0,1
+            w(i_type(0x13, 0, 17, 0, 64)),            // addi a7,x0,64
+            w(0x0000_0073),                           // ecall (write)
+            w(i_type(0x03, 3, 10, 2, 8)),             // ld a0,8(sp)
+            w(i_type(0x13, 0, 17, 0, 93)),            // addi a7,x0,93
+            w(0x0000_0073),                           // ecall (exit)
+        ]);
+        let mut emu = Emulator::new(0x1_0000, &[(0x1_0000, &code)]);
+        let trace = emu.run(1000).unwrap();
+        assert_eq!(emu.exit_code(), Some(55));
+        assert_eq!(emu.stdout, b"H");
+        assert_eq!(trace.len(), 2 + 1 + 3 * 10 + 15);
+        assert_eq!(emu.step(), Err(EmulatorError::Exited));
+
+        assert_eq!(trace[0].opcode, "addi");
+        assert_eq!(trace[0].after("x2"), Some(STACK_TOP - 32));
+        assert_eq!(trace[2].opcode, "addi");
+        assert_eq!(trace[2].pc, 0x1_0006);
+        let store = trace.iter().find(|e| e.opcode == "sd").unwrap();
+        assert_eq!((store.rs1.as_deref(), store.rs2.as_deref(), store.imm), (Some("x2"), Some("x5"), Some(8)));
+        assert_eq!(store.memory, Some(MemoryAccess { addr: STACK_TOP - 24, width: 8, read: 0, written: Some(55) }));
+        let load = trace.iter().find(|e| e.opcode == "ld").unwrap();
+        assert_eq!(load.memory.unwrap().read, 55);
+        assert_eq!(load.after("x10"), Some(55));
+        let div = trace.iter().find(|e| e.opcode == "div").unwrap();
+        assert_eq!(div.after("x29"), Some(55));
+        let write = trace.iter().find(|e| e.opcode == "ecall").unwrap();
+        assert_eq!(write.syscall_data.as_deref(), Some(&b"H"[..]));
+
+        // Branches and stores of bytes are not modeled by the circuit, so they become unproven
+        // spans. The loads read what sd left, through the memory log.
+        let options = ConvertOptions { mode: ConversionMode::Lenient, ..Default::default() };
+        let rows = convert_trace_to_rows_with(&trace, &options).unwrap();
+        assert_eq!(rows.iter().filter(|row| row.memory.is_some_and(|m| !m.store)).count(), 2);
+        let (proof, data) = prove_multi_instruction_constraint(&rows).unwrap();
+        data.verify(proof).unwrap();
+    }
+
+    #[test]
+    fn test_emulator_rejects_illegal_instructions_and_syscalls() {
+        let mut emu = Emulator::new(0x1000, &[(0x1000, &[0xff, 0xff, 0xff, 0xff])]);
+        assert_eq!(emu.step(), Err(EmulatorError::IllegalInstruction { pc: 0x1000, bits: 0xffff_ffff }));
+
+        let code = assemble(&[w(i_type(0x13, 0, 17, 0, 1)), w(0x0000_0073)]);
+        let mut emu = Emulator::new(0x1000, &[(0x1000, &code)]);
+        assert_eq!(emu.run(10), Err(EmulatorError::UnsupportedSyscall { pc: 0x1004, number: 1 }));
+
+        // An endless loop: `jal x0, 0`.
+        let mut emu = Emulator::new(0x1000, &[(0x1000, &[0x6f, 0x00, 0x00, 0x00])]);
+        assert_eq!(emu.run(100), Err(EmulatorError::StepLimit(100)));
+
+        // `write(2, sp, -1)` goes to stderr and completes partially.
+        let code = assemble(&[
+            w(i_type(0x13, 0, 10, 0, 2)),  // addi a0,x0,2
+            w(i_type(0x13, 0, 11, 2, 0)),  // addi a1,sp,0
+            w(i_type(0x13, 0, 12, 0, -1)), // addi a2,x0,-1
+            w(i_type(0x13, 0, 17, 0, 64)), // addi a7,x0,64
+            w(0x0000_0073),                // ecall (write)
+        ]);
+        let mut emu = Emulator::new(0x1000, &[(0x1000, &code)]);
+        for _ in 0..5 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.regs[10], MAX_SYSCALL_DATA);
+        assert_eq!(emu.stderr.len() as u64, MAX_SYSCALL_DATA);
+        assert!(emu.stdout.is_empty());
+    }
+
+    #[test]
+    fn test_emulator_runs_newlib_binary_to_exit() {
+        let elf = ElfFile::load("test.bin").unwrap();
+        let main = elf.symbol("main").unwrap();
+        let mut emu = Emulator::from_elf(&elf);
+        let trace = emu.run(100_000).unwrap();
+        assert_eq!(emu.exit_code(), Some(0));
+        assert_eq!(trace[0].pc, elf.entry);
+        assert!(trace.iter().any(|e| e.pc == main.value));
+        assert!(trace.iter().any(|e| e.opcode == "sw" && e.memory.is_some_and(|m| m.written == Some(7))));
+
+        // `_start` hands argc and argv to main.
+        let inputs = ProgramInputs {
+            args: vec!["--rate".to_string()],
+            env: vec![("MODE".to_string(), "fast".to_string())],
+            sensor: Some(crate::inputs::SensorInput { addr: 0x2000_0000, data: vec![7, 8] }),
+            ..Default::default()
+        };
+        let mut emu = Emulator::from_elf(&elf);
+        emu.set_inputs("test.bin", &inputs);
+        assert_eq!(emu.regs[2] % 16, 0);
+        assert_eq!(emu.memory.read_bytes(0x2000_0000, 2), [7, 8]);
+        let trace = emu.run(100_000).unwrap();
+        let at_main = trace.iter().find(|e| e.pc == main.value).unwrap();
+        assert_eq!(at_main.before("x10"), Some(2));
+        let argv = at_main.before("x11").unwrap();
+        let arg1 = emu.memory.load(argv + 8, 8);
+        assert_eq!(emu.memory.read_bytes(arg1, 7), b"--rate\0");
+        // envp follows argv's null.
+        let env0 = emu.memory.load(argv + 24, 8);
+        assert_eq!(emu.memory.read_bytes(env0, 9), b"MODE=fast");
+    }
+}
@@ -0,0 +1,99 @@
+use std::fmt;
+
+use crate::elf::ElfError;
+use crate::emulator::EmulatorError;
+use crate::program_runner::RunError;
+use crate::qemu_log::QemuLogError;
+use crate::trace_format::TraceFormatError;
+use crate::window::WindowError;
+use crate::zk::UnsupportedInstructions;
+
+/// Error of the crate's public API, which returns it instead of panicking on bad input.
+#[derive(Debug)]
+pub enum Error {
+    /// A trace line that does not parse, numbered from 1.
+    Parse { path: String, line: usize, message: String },
+    Io(String),
+    /// Instructions a strict conversion cannot prove.
+    Unsupported(UnsupportedInstructions),
+    /// A proof or circuit that cannot be written or read back.
+    Serialization(String),
+    /// There are no rows to prove, e.g. because the window matched nothing.
+    EmptyTrace,
+    /// Witness generation or proving failed, typically because a row's values do not satisfy
+    /// its constraints.
+    Prover(anyhow::Error),
+    Verification(anyhow::Error),
+    Run(RunError),
+    Emulator(EmulatorError),
+    QemuLog(QemuLogError),
+    Elf(ElfError),
+    TraceFormat(TraceFormatError),
+    Window(WindowError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse { path, line, message } => write!(f, "{}:{}: {}", path, line, message),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Unsupported(e) => write!(f, "{}", e),
+            Error::Serialization(e) => write!(f, "serialization failed: {}", e),
+            Error::EmptyTrace => write!(f, "there are no instructions to prove"),
+            Error::Prover(e) => write!(f, "proving failed: {}", e),
+            Error::Verification(e) => write!(f, "verification failed: {}", e),
+            Error::Run(e) => write!(f, "{}", e),
+            Error::Emulator(e) => write!(f, "{}", e),
+            Error::QemuLog(e) => write!(f, "{}", e),
+            Error::Elf(e) => write!(f, "{}", e),
+            Error::TraceFormat(e) => write!(f, "{}", e),
+            Error::Window(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<UnsupportedInstructions> for Error {
+    fn from(e: UnsupportedInstructions) -> Self {
+        Error::Unsupported(e)
+    }
+}
+
+impl From<RunError> for Error {
+    fn from(e: RunError) -> Self {
+        Error::Run(e)
+    }
+}
+
+impl From<EmulatorError> for Error {
+    fn from(e: EmulatorError) -> Self {
+        Error::Emulator(e)
+    }
+}
+
+impl From<QemuLogError> for Error {
+    fn from(e: QemuLogError) -> Self {
+        Error::QemuLog(e)
+    }
+}
+
+impl From<ElfError> for Error {
+    fn from(e: ElfError) -> Self {
+        Error::Elf(e)
+    }
+}
+
+impl From<TraceFormatError> for Error {
+    fn from(e: TraceFormatError) -> Self {
+        Error::TraceFormat(e)
+    }
+}
+
+impl From<WindowError> for Error {
+    fn from(e: WindowError) -> Self {
+        Error::Window(e)
+    }
+}
@@ -0,0 +1,1028 @@
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::{Field, PrimeField64};
+use plonky2::iop::target::{BoolTarget, Target};
+use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+
+use crate::circuit::RowTargets;
+use crate::lookup::LookupTables;
+
+/// IEEE-754 binary32 (`.s`) or binary64 (`.d`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FpFormat {
+    Single,
+    Double,
+}
+
+impl FpFormat {
+    /// Significand width including the hidden bit.
+    pub fn mant_bits(self) -> usize {
+        match self {
+            FpFormat::Single => 24,
+            FpFormat::Double => 53,
+        }
+    }
+
+    pub fn exp_bits(self) -> usize {
+        match self {
+            FpFormat::Single => 8,
+            FpFormat::Double => 11,
+        }
+    }
+
+    fn frac_bits(self) -> usize {
+        self.mant_bits() - 1
+    }
+
+    fn bias(self) -> u64 {
+        (1 << (self.exp_bits() - 1)) - 1
+    }
+
+    fn max_exp(self) -> u64 {
+        (1 << self.exp_bits()) - 1
+    }
+
+    /// Register value holding `bits`. Singles are NaN-boxed, and since the box
+    /// `0xffffffff_00000000` is `-1` in the Goldilocks field, a boxed single is `bits - 1`.
+    pub fn to_register(self, bits: u64) -> u64 {
+        match self {
+            FpFormat::Single => 0xffff_ffff_0000_0000 | bits,
+            FpFormat::Double => bits,
+        }
+    }
+
+    /// Inverse of [`FpFormat::to_register`] on field values.
+    fn bits_of(self, reg: GoldilocksField) -> u64 {
+        match self {
+            FpFormat::Single => (reg + GoldilocksField::ONE).to_canonical_u64(),
+            FpFormat::Double => reg.to_canonical_u64(),
+        }
+    }
+
+    fn unpack(self, bits: u64) -> (u64, u64, u64) {
+        let f = self.frac_bits();
+        (
+            bits >> (f + self.exp_bits()) & 1,
+            bits >> f & self.max_exp(),
+            bits & ((1 << f) - 1),
+        )
+    }
+
+    fn pack(self, sign: u64, exp: u64, frac: u64) -> u64 {
+        let f = self.frac_bits();
+        sign << (f + self.exp_bits()) | exp << f | frac
+    }
+
+    /// Normal numbers and zeros; infinities, NaNs and subnormals are not modeled.
+    fn is_modeled(self, bits: u64) -> bool {
+        let (_, exp, frac) = self.unpack(bits);
+        exp != self.max_exp() && (exp != 0 || frac == 0)
+    }
+}
+
+/// Floating-point instructions proven by [`FpUnit`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FpOp {
+    Add,
+    Sub,
+    Mul,
+    /// `fmv.x.w`/`fmv.x.d`: moves the bits of an f-register to an x-register.
+    MoveToInt,
+    /// `fmv.w.x`/`fmv.d.x`: moves the bits of an x-register to an f-register.
+    MoveFromInt,
+    /// `fcvt.d.w`: exact conversion of a 32-bit signed integer.
+    ConvertFromWord,
+    /// `fcvt.d.s`: exact widening of a single.
+    ConvertFromSingle,
+    /// `flw`/`fld`: the loaded value is not constrained until memory is part of the circuit.
+    Load,
+    /// `fsw`/`fsd`.
+    Store,
+}
+
+/// Supported mnemonics; opcode ids follow the atomics in this order.
+const FP_OPS: [(&str, FpOp, FpFormat); 16] = [
+    ("fadd.s", FpOp::Add, FpFormat::Single),
+    ("fsub.s", FpOp::Sub, FpFormat::Single),
+    ("fmul.s", FpOp::Mul, FpFormat::Single),
+    ("fadd.d", FpOp::Add, FpFormat::Double),
+    ("fsub.d", FpOp::Sub, FpFormat::Double),
+    ("fmul.d", FpOp::Mul, FpFormat::Double),
+    ("fmv.x.w", FpOp::MoveToInt, FpFormat::Single),
+    ("fmv.w.x", FpOp::MoveFromInt, FpFormat::Single),
+    ("fmv.x.d", FpOp::MoveToInt, FpFormat::Double),
+    ("fmv.d.x", FpOp::MoveFromInt, FpFormat::Double),
+    ("fcvt.d.w", FpOp::ConvertFromWord, FpFormat::Double),
+    ("fcvt.d.s", FpOp::ConvertFromSingle, FpFormat::Double),
+    ("flw", FpOp::Load, FpFormat::Single),
+    ("fsw", FpOp::Store, FpFormat::Single),
+    ("fld", FpOp::Load, FpFormat::Double),
+    ("fsd", FpOp::Store, FpFormat::Double),
+];
+
+const FIRST_OPCODE_ID: u64 = 48;
+
+/// Operation and format of a floating-point mnemonic.
+pub fn parse_fp_op(mnemonic: &str) -> Option<(FpOp, FpFormat)> {
+    FP_OPS.iter().find(|(m, _, _)| *m == mnemonic).map(|&(_, op, fmt)| (op, fmt))
+}
+
+pub fn fp_opcode_id(mnemonic: &str) -> Option<u64> {
+    FP_OPS.iter().position(|(m, _, _)| *m == mnemonic).map(|i| FIRST_OPCODE_ID + i as u64)
+}
+
+/// Floating-point access of an instruction row.
+///
+/// Sources and destination live in the row's `rs1`, `rs2` and `rd`: f-registers hold the
+/// (NaN-boxed) bits of the value and x-registers their usual value.
+#[derive(Debug, Clone)]
+pub struct FpRow<F: Field> {
+    pub op: FpOp,
+    pub fmt: FpFormat,
+    /// The rounding mode comes from `frm`, which must then be round-to-nearest-even.
+    pub dynamic_rm: bool,
+    /// `fcsr` before the instruction, for traces whose first `fcsr` access is this row.
+    pub fcsr_before: F,
+}
+
+/// Intermediate values of a rounded addition, following [`FpUnit`]'s constraints.
+#[derive(Debug, Default)]
+struct AddAdvice {
+    swap: bool,
+    far: bool,
+    hi: u64,
+    lo: u64,
+    hi_half: u64,
+    carry: bool,
+    k: u64,
+    u: u64,
+    s0: bool,
+    lost: bool,
+    round: Rounding,
+    bits: u64,
+}
+
+/// Significand with guard, round and sticky bits, split for round-to-nearest-even.
+#[derive(Debug, Default)]
+struct Rounding {
+    mant_half: u64,
+    lsb: bool,
+    g: bool,
+    r: bool,
+    s: bool,
+}
+
+impl Rounding {
+    fn new(n: u64) -> Self {
+        Rounding {
+            mant_half: n >> 4,
+            lsb: n >> 3 & 1 == 1,
+            g: n >> 2 & 1 == 1,
+            r: n >> 1 & 1 == 1,
+            s: n & 1 == 1,
+        }
+    }
+
+    fn inexact(&self) -> bool {
+        self.g || self.r || self.s
+    }
+
+    /// Rounded significand and whether rounding carried into the next binade.
+    fn apply(&self, m: usize) -> (u64, bool) {
+        let mant = self.mant_half << 1 | self.lsb as u64;
+        let up = self.g && (self.r || self.s || self.lsb);
+        let rounded = mant + up as u64;
+        if rounded == 1 << m {
+            (1 << (m - 1), true)
+        } else {
+            (rounded, false)
+        }
+    }
+}
+
+fn unmodeled(row: &FpRow<GoldilocksField>, a: u64, b: u64) -> anyhow::Error {
+    anyhow::anyhow!("{:?} {:?} of 0x{:x} and 0x{:x} is outside the modeled range", row.fmt, row.op, a, b)
+}
+
+/// Rounded `a + b` (or `a - b`), or `None` outside the modeled range.
+fn add_advice(fmt: FpFormat, a: u64, b: u64, subtract: bool) -> Option<AddAdvice> {
+    if !fmt.is_modeled(a) || !fmt.is_modeled(b) {
+        return None;
+    }
+    let (m, e) = (fmt.mant_bits(), fmt.exp_bits());
+    let d_max = m as u64 + 3;
+    let mant = |exp: u64, frac: u64| if exp == 0 { 0 } else { frac | 1 << (m - 1) };
+    let (sa, ea, fa) = fmt.unpack(a);
+    let (sb, eb, fb) = fmt.unpack(b);
+    let sb = sb ^ subtract as u64;
+
+    let mut adv = AddAdvice { swap: (eb, fb) > (ea, fa), ..Default::default() };
+    let ((sx, ex, mx), (sy, ey, my)) = if adv.swap {
+        ((sb, eb, mant(eb, fb)), (sa, ea, mant(ea, fa)))
+    } else {
+        ((sa, ea, mant(ea, fa)), (sb, eb, mant(eb, fb)))
+    };
+
+    adv.far = ex - ey >= d_max;
+    let d = (ex - ey).min(d_max);
+    adv.hi = (my << 3) >> d;
+    adv.lo = (my << 3) & ((1 << d) - 1);
+    adv.hi_half = adv.hi >> 1;
+    let aligned = adv.hi | (adv.lo != 0) as u64;
+
+    let sum = if sx != sy { (mx << 3) - aligned } else { (mx << 3) + aligned };
+    if sum == 0 {
+        // Exact cancellation gives +0, and -0 only when both operands are -0.
+        adv.bits = fmt.pack(sa & sb, 0, 0);
+        let dummy = 1 << (d_max - 1);
+        adv.u = dummy >> 2;
+        adv.round = Rounding::new(dummy);
+        return Some(adv);
+    }
+
+    adv.carry = sum >> d_max != 0;
+    adv.u = sum >> 2;
+    adv.s0 = sum >> 1 & 1 == 1;
+    adv.lost = sum & 1 == 1;
+    let n = if adv.carry {
+        adv.u << 1 | (adv.s0 || adv.lost) as u64
+    } else {
+        adv.k = d_max - 1 - (63 - sum.leading_zeros() as u64);
+        sum << adv.k
+    };
+    adv.round = Rounding::new(n);
+    let (rounded, ovf) = adv.round.apply(m);
+    let exp = ex as i64 + adv.carry as i64 - adv.k as i64 + ovf as i64;
+    if exp < 1 || exp > (1 << e) - 2 {
+        return None;
+    }
+    adv.bits = fmt.pack(sx, exp as u64, rounded - (1 << (m - 1)));
+    Some(adv)
+}
+
+/// Limb width of the multiplication's schoolbook product.
+const LIMB_BITS: usize = 18;
+
+/// Intermediate values of a rounded multiplication, following [`FpUnit`]'s constraints.
+#[derive(Debug, Default)]
+struct MulAdvice {
+    a_limbs: Vec<u64>,
+    b_limbs: Vec<u64>,
+    hi: bool,
+    q_limbs: Vec<u64>,
+    q_half: u64,
+    lsb: bool,
+    r_limbs: Vec<u64>,
+    g: bool,
+    r_top: u64,
+    bits: u64,
+    inexact: bool,
+}
+
+fn limbs(x: u128, n: usize) -> Vec<u64> {
+    (0..n).map(|i| (x >> (LIMB_BITS * i)) as u64 & ((1 << LIMB_BITS) - 1)).collect()
+}
+
+/// Rounded `a * b`, or `None` outside the modeled range.
+fn mul_advice(fmt: FpFormat, a: u64, b: u64) -> Option<MulAdvice> {
+    if !fmt.is_modeled(a) || !fmt.is_modeled(b) {
+        return None;
+    }
+    let (m, e) = (fmt.mant_bits(), fmt.exp_bits());
+    let num_limbs = m.div_ceil(LIMB_BITS);
+    let k0 = (m - 1) / LIMB_BITS;
+    let (sa, ea, fa) = fmt.unpack(a);
+    let (sb, eb, fb) = fmt.unpack(b);
+    let zero = ea == 0 || eb == 0;
+    // Zero operands take the dummy significand 1.0 so the rounding constraints still hold.
+    let mant = |exp: u64, frac: u64| (frac | 1 << (m - 1)) * (exp != 0) as u64 + (1 << (m - 1)) * (exp == 0) as u64;
+    let (ma, mb) = (mant(ea, fa) as u128, mant(eb, fb) as u128);
+
+    let product = ma * mb;
+    let hi = product >> (2 * m - 1) != 0;
+    let s = m - 1 + hi as usize;
+    let q = (product >> s) as u64;
+    let rem = product & ((1 << s) - 1);
+    let g = rem >> (s - 1) & 1 == 1;
+    let sticky = rem & ((1 << (s - 1)) - 1) != 0;
+
+    let mut adv = MulAdvice {
+        a_limbs: limbs(ma, num_limbs),
+        b_limbs: limbs(mb, num_limbs),
+        hi,
+        q_limbs: limbs(q as u128, num_limbs),
+        q_half: (q & ((1 << LIMB_BITS) - 1)) >> 1,
+        lsb: q & 1 == 1,
+        r_limbs: limbs(rem, k0),
+        g,
+        r_top: (rem >> (LIMB_BITS * k0)) as u64 & ((1 << (s - 1 - LIMB_BITS * k0)) - 1),
+        ..Default::default()
+    };
+
+    let sign = sa ^ sb;
+    if zero {
+        adv.bits = fmt.pack(sign, 0, 0);
+        return Some(adv);
+    }
+    let up = g && (sticky || adv.lsb);
+    let (rounded, ovf) = if q + up as u64 == 1 << m { (1 << (m - 1), true) } else { (q + up as u64, false) };
+    let exp = ea as i64 + eb as i64 - fmt.bias() as i64 + hi as i64 + ovf as i64;
+    if exp < 1 || exp > (1 << e) - 2 {
+        return None;
+    }
+    adv.bits = fmt.pack(sign, exp as u64, rounded - (1 << (m - 1)));
+    adv.inexact = g || sticky;
+    Some(adv)
+}
+
+/// Result bits and inexact flag of an arithmetic instruction on register values, or `None`
+/// when an operand or the result is not a normal number or zero.
+pub fn fp_arith(op: FpOp, fmt: FpFormat, a: u64, b: u64) -> Option<(u64, bool)> {
+    let (a, b) = match fmt {
+        FpFormat::Single => (a & 0xffff_ffff, b & 0xffff_ffff),
+        FpFormat::Double => (a, b),
+    };
+    match op {
+        FpOp::Add | FpOp::Sub => {
+            let adv = add_advice(fmt, a, b, op == FpOp::Sub)?;
+            let inexact = fmt.unpack(adv.bits).1 != 0 && adv.round.inexact();
+            Some((adv.bits, inexact))
+        }
+        FpOp::Mul => mul_advice(fmt, a, b).map(|adv| (adv.bits, adv.inexact)),
+        _ => None,
+    }
+}
+
+/// Sign, biased exponent, significand (hidden bit included, 0 for zero) and zero flag.
+struct Unpacked {
+    sign: BoolTarget,
+    exp: Target,
+    frac: Target,
+    mant: Target,
+    is_zero: BoolTarget,
+}
+
+/// Floating-point gadgets sharing one witness list.
+///
+/// Arithmetic handles normal numbers and zeros, rounding to nearest with ties to even using
+/// guard, round and sticky bits. Operands or results that are subnormal, infinite or NaN make
+/// the row unprovable; so does an exponent overflow or underflow. The only exception flag
+/// that can be raised is inexact (`NX`), which is accrued into `fcsr`.
+#[derive(Debug, Default)]
+pub struct FpUnit {
+    witness: Vec<(Target, GoldilocksField)>,
+}
+
+type Builder = CircuitBuilder<GoldilocksField, 2>;
+
+fn constant(builder: &mut Builder, v: u64) -> Target {
+    builder.constant(GoldilocksField::from_canonical_u64(v))
+}
+
+impl FpUnit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn advice(&mut self, builder: &mut Builder, value: u64) -> Target {
+        let t = builder.add_virtual_target();
+        self.witness.push((t, GoldilocksField::from_canonical_u64(value)));
+        t
+    }
+
+    fn advice_bool(&mut self, builder: &mut Builder, value: bool) -> BoolTarget {
+        let t = builder.add_virtual_bool_target_safe();
+        self.witness.push((t.target, GoldilocksField::from_bool(value)));
+        t
+    }
+
+    /// Adds the constraints of a floating-point row and returns `fcsr` after it.
+    ///
+    /// Fails when the row's operation has no opcode, or its operands are not ones
+    /// [`crate::zk`] converts (e.g. NaN or infinite inputs).
+    pub fn add_fp_row(
+        &mut self,
+        builder: &mut Builder,
+        tables: &mut LookupTables,
+        targets: &RowTargets,
+        row: &FpRow<GoldilocksField>,
+        values: (GoldilocksField, GoldilocksField),
+        fcsr_in: Target,
+    ) -> anyhow::Result<Target> {
+        let opcode_id = FP_OPS
+            .iter()
+            .find(|(_, op, fmt)| *op == row.op && *fmt == row.fmt)
+            .and_then(|(mnemonic, _, _)| fp_opcode_id(mnemonic))
+            .ok_or_else(|| anyhow::anyhow!("no opcode for {:?} on {:?}", row.op, row.fmt))?;
+        let opcode = constant(builder, opcode_id);
+        builder.connect(targets.opcode, opcode);
+        let fmt = row.fmt;
+
+        Ok(match row.op {
+            FpOp::Add | FpOp::Sub | FpOp::Mul => {
+                builder.assert_zero(targets.imm_flag.target);
+                let (a, b) = (fmt.bits_of(values.0), fmt.bits_of(values.1));
+                let x = unpack(builder, tables, fmt, targets.rs1);
+                let y = unpack(builder, tables, fmt, targets.rs2);
+                let (result, inexact) = if row.op == FpOp::Mul {
+                    let adv = mul_advice(fmt, a, b).ok_or_else(|| unmodeled(row, a, b))?;
+                    self.mul(builder, tables, fmt, &x, &y, &adv)
+                } else {
+                    let adv = add_advice(fmt, a, b, row.op == FpOp::Sub).ok_or_else(|| unmodeled(row, a, b))?;
+                    self.add(builder, tables, fmt, &x, &y, row.op == FpOp::Sub, &adv)
+                };
+                builder.connect(targets.rd, result);
+                accrue_inexact(builder, fcsr_in, inexact, row.dynamic_rm)
+            }
+            FpOp::MoveToInt | FpOp::MoveFromInt if fmt == FpFormat::Double => {
+                builder.connect(targets.rd, targets.rs1);
+                fcsr_in
+            }
+            FpOp::MoveToInt => {
+                let bits = builder.add_const(targets.rs1, GoldilocksField::ONE);
+                let sext = sign_extend_word(builder, tables, bits);
+                builder.connect(targets.rd, sext);
+                fcsr_in
+            }
+            FpOp::MoveFromInt => {
+                let bits = self.word_of(builder, tables, targets.rs1, values.0);
+                let boxed = builder.add_const(bits, GoldilocksField::NEG_ONE);
+                builder.connect(targets.rd, boxed);
+                fcsr_in
+            }
+            FpOp::ConvertFromWord => {
+                let bits = self.word_of(builder, tables, targets.rs1, values.0);
+                let result = self.convert_from_word(builder, tables, bits, values.0);
+                builder.connect(targets.rd, result);
+                fcsr_in
+            }
+            FpOp::ConvertFromSingle => {
+                let x = unpack(builder, tables, FpFormat::Single, targets.rs1);
+                let rebias = FpFormat::Double.bias() - FpFormat::Single.bias();
+                let exp = builder.add_const(x.exp, GoldilocksField::from_canonical_u64(rebias));
+                let exp = builder.select(x.is_zero, x.exp, exp);
+                let shift = FpFormat::Double.frac_bits() - FpFormat::Single.frac_bits();
+                let frac = builder.mul_const(GoldilocksField::from_canonical_u64(1 << shift), x.frac);
+                let result = pack(builder, FpFormat::Double, x.sign, exp, frac);
+                builder.connect(targets.rd, result);
+                fcsr_in
+            }
+            FpOp::Load | FpOp::Store => anyhow::bail!("{:?} rows are proven by the memory log", row.op),
+        })
+    }
+
+    /// Low 32 bits of an x-register holding a sign-extended word, as RV64 keeps them.
+    ///
+    /// In the field, `sext(w) = w - w[31]`, since `2^64 = 2^32 - 1`.
+    fn word_of(&mut self, builder: &mut Builder, tables: &mut LookupTables, reg: Target, value: GoldilocksField) -> Target {
+        let v = value.to_canonical_u64();
+        let word = if v < 1 << 31 { v } else { (v + 1) & 0xffff_ffff };
+        let word_t = self.advice(builder, word);
+        let sext = sign_extend_word(builder, tables, word_t);
+        builder.connect(sext, reg);
+        word_t
+    }
+
+    /// Exact `fcvt.d.w` of a 32-bit two's complement word.
+    fn convert_from_word(&mut self, builder: &mut Builder, tables: &mut LookupTables, word: Target, value: GoldilocksField) -> Target {
+        let fmt = FpFormat::Double;
+        let m = fmt.mant_bits();
+        let v = value.to_canonical_u64();
+        let int = if v < 1 << 31 { v as i64 } else { (v + 1) as u32 as i32 as i64 };
+        let magnitude = int.unsigned_abs();
+
+        let biased = builder.add_const(word, GoldilocksField::from_canonical_u64(1 << 31));
+        let (_, sign) = split_carry(builder, tables, biased);
+        let two_32 = constant(builder, 1 << 32);
+        let neg = builder.sub(two_32, word);
+        let abs = builder.select(sign, neg, word);
+        let zero = builder.zero();
+        let is_zero = builder.is_equal(abs, zero);
+
+        // abs << k puts the leading one at the hidden bit.
+        let k = if magnitude == 0 { 0 } else { (m as u64 - 1) - (63 - magnitude.leading_zeros() as u64) };
+        let k_t = self.advice(builder, k);
+        let hidden = constant(builder, 1 << (m - 1));
+        let abs_or_one = builder.select(is_zero, hidden, abs);
+        let two = constant(builder, 2);
+        let pow = builder.exp(two, k_t, 6);
+        let mant = builder.mul(abs_or_one, pow);
+        check_in_binade(builder, tables, mant, m);
+
+        let exp = constant(builder, fmt.bias() + m as u64 - 1);
+        let exp = builder.sub(exp, k_t);
+        let exp = builder.select(is_zero, zero, exp);
+        let frac = builder.sub(mant, hidden);
+        let frac = builder.select(is_zero, zero, frac);
+        pack(builder, fmt, sign, exp, frac)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn add(
+        &mut self,
+        builder: &mut Builder,
+        tables: &mut LookupTables,
+        fmt: FpFormat,
+        a: &Unpacked,
+        b: &Unpacked,
+        subtract: bool,
+        adv: &AddAdvice,
+    ) -> (Target, BoolTarget) {
+        let m = fmt.mant_bits();
+        let d_max = m as u64 + 3;
+        let width = d_max as usize;
+        let b_sign = if subtract { builder.not(b.sign) } else { b.sign };
+
+        // Order the operands by magnitude.
+        let swap = self.advice_bool(builder, adv.swap);
+        let key = |builder: &mut Builder, x: &Unpacked| {
+            builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << fmt.frac_bits()), x.exp, x.frac)
+        };
+        let (ka, kb) = (key(builder, a), key(builder, b));
+        let pick = |builder: &mut Builder, first: Target, second: Target| {
+            (builder.select(swap, second, first), builder.select(swap, first, second))
+        };
+        let (kx, ky) = pick(builder, ka, kb);
+        let key_diff = builder.sub(kx, ky);
+        tables.range_check_bits(builder, key_diff, fmt.frac_bits() + fmt.exp_bits());
+        let (sx, sy) = pick(builder, a.sign.target, b_sign.target);
+        let (ex, ey) = pick(builder, a.exp, b.exp);
+        let (mx, my) = pick(builder, a.mant, b.mant);
+
+        // Align the smaller significand, shifted left by three guard bits, with sticky.
+        let far = self.advice_bool(builder, adv.far);
+        let diff = builder.sub(ex, ey);
+        let d_max_t = constant(builder, d_max);
+        let beyond = builder.sub(diff, d_max_t);
+        let within = builder.sub(d_max_t, diff);
+        let within = builder.add_const(within, GoldilocksField::NEG_ONE);
+        let bound = builder.select(far, beyond, within);
+        tables.range_check_bits(builder, bound, fmt.exp_bits() + 1);
+        let d = builder.select(far, d_max_t, diff);
+
+        let two = constant(builder, 2);
+        let pow_d = builder.exp(two, d, 6);
+        let d_rest = builder.sub(d_max_t, d);
+        let pow_rest = builder.exp(two, d_rest, 6);
+        let hi = self.advice(builder, adv.hi);
+        let lo = self.advice(builder, adv.lo);
+        tables.range_check_bits(builder, hi, width);
+        tables.range_check_bits(builder, lo, width);
+        // hi < 2^(d_max - d) and lo < 2^d keep hi * 2^d + lo from wrapping.
+        let hi_slack = below(builder, pow_rest, hi);
+        tables.range_check_bits(builder, hi_slack, width);
+        let lo_slack = below(builder, pow_d, lo);
+        tables.range_check_bits(builder, lo_slack, width);
+        let y3 = builder.mul_const(GoldilocksField::from_canonical_u64(8), my);
+        let recombined = builder.mul_add(hi, pow_d, lo);
+        builder.connect(recombined, y3);
+
+        let hi_half = self.advice(builder, adv.hi_half);
+        tables.range_check_bits(builder, hi_half, width);
+        let hi_bit0 = builder.mul_const(GoldilocksField::TWO, hi_half);
+        let hi_bit0 = builder.sub(hi, hi_bit0);
+        let hi_bit0 = BoolTarget::new_unsafe(hi_bit0);
+        builder.assert_bool(hi_bit0);
+        let zero = builder.zero();
+        let lo_zero = builder.is_equal(lo, zero);
+        let sticky = builder.not(lo_zero);
+        let odd_hi = builder.not(hi_bit0);
+        let sticky_new = builder.and(sticky, odd_hi);
+        let aligned = builder.add(hi, sticky_new.target);
+
+        // Add or subtract; |x| >= |y| keeps the difference non-negative.
+        let x3 = builder.mul_const(GoldilocksField::from_canonical_u64(8), mx);
+        let eff_sub = builder.sub(sx, sy);
+        let eff_sub = builder.mul(eff_sub, eff_sub);
+        let signed = builder.mul_const(GoldilocksField::TWO, eff_sub);
+        let signed = builder.mul(signed, aligned);
+        let sum = builder.add(x3, aligned);
+        let sum = builder.sub(sum, signed);
+        let sum_zero = builder.is_equal(sum, zero);
+        let dummy = constant(builder, 1 << (d_max - 1));
+        let sum = builder.select(sum_zero, dummy, sum);
+
+        // Normalize to d_max bits: one right shift with sticky, or an exact left shift by k.
+        let carry = self.advice_bool(builder, adv.carry);
+        let k = self.advice(builder, adv.k);
+        let u = self.advice(builder, adv.u);
+        let s0 = self.advice_bool(builder, adv.s0);
+        let lost = self.advice_bool(builder, adv.lost);
+        tables.range_check_bits(builder, u, width);
+        let low = builder.mul_const_add(GoldilocksField::TWO, s0.target, lost.target);
+        let recombined = builder.mul_const_add(GoldilocksField::from_canonical_u64(4), u, low);
+        builder.connect(recombined, sum);
+        let sticky_bit = builder.or(s0, lost);
+        let shifted_right = builder.mul_const_add(GoldilocksField::TWO, u, sticky_bit.target);
+
+        let no_k = builder.mul(carry.target, k);
+        builder.assert_zero(no_k);
+        let bound_exp = builder.add_const(carry.target, GoldilocksField::from_canonical_u64(d_max));
+        let bound_exp = builder.sub(bound_exp, k);
+        let pow_bound = builder.exp(two, bound_exp, 7);
+        let sum_slack = below(builder, pow_bound, sum);
+        tables.range_check_bits(builder, sum_slack, width + 1);
+        let pow_k = builder.exp(two, k, 6);
+        let shifted_left = builder.mul(sum, pow_k);
+        let n = builder.select(carry, shifted_right, shifted_left);
+        check_in_binade(builder, tables, n, width);
+
+        let (mant, ovf, inexact) = self.round(builder, tables, n, m, &adv.round);
+        let exp = builder.add(ex, carry.target);
+        let exp = builder.sub(exp, k);
+        let exp = builder.add(exp, ovf.target);
+        check_normal_exp(builder, tables, fmt, exp, sum_zero);
+
+        let zero_sign = builder.and(a.sign, b_sign);
+        let sign = builder.select(sum_zero, zero_sign.target, sx);
+        let exp = builder.select(sum_zero, zero, exp);
+        let hidden = constant(builder, 1 << (m - 1));
+        let frac = builder.sub(mant, hidden);
+        let frac = builder.select(sum_zero, zero, frac);
+        let not_zero = builder.not(sum_zero);
+        let inexact = builder.and(inexact, not_zero);
+        (pack(builder, fmt, BoolTarget::new_unsafe(sign), exp, frac), inexact)
+    }
+
+    /// Rounds `n = mant·8 + grs` to nearest, ties to even. Returns the rounded significand,
+    /// whether it carried into the next binade, and whether any of `grs` was set.
+    fn round(
+        &mut self,
+        builder: &mut Builder,
+        tables: &mut LookupTables,
+        n: Target,
+        m: usize,
+        adv: &Rounding,
+    ) -> (Target, BoolTarget, BoolTarget) {
+        let mant_half = self.advice(builder, adv.mant_half);
+        tables.range_check_bits(builder, mant_half, m - 1);
+        let lsb = self.advice_bool(builder, adv.lsb);
+        let g = self.advice_bool(builder, adv.g);
+        let r = self.advice_bool(builder, adv.r);
+        let s = self.advice_bool(builder, adv.s);
+        let mut acc = mant_half;
+        for bit in [lsb, g, r, s] {
+            acc = builder.mul_const_add(GoldilocksField::TWO, acc, bit.target);
+        }
+        builder.connect(acc, n);
+
+        let mant = builder.mul_const_add(GoldilocksField::TWO, mant_half, lsb.target);
+        let rs = builder.or(r, s);
+        let (mant, ovf) = round_up(builder, mant, m, g, rs, lsb);
+        let grs = builder.or(g, rs);
+        (mant, ovf, grs)
+    }
+
+    fn mul(
+        &mut self,
+        builder: &mut Builder,
+        tables: &mut LookupTables,
+        fmt: FpFormat,
+        a: &Unpacked,
+        b: &Unpacked,
+        adv: &MulAdvice,
+    ) -> (Target, BoolTarget) {
+        let m = fmt.mant_bits();
+        let num_limbs = m.div_ceil(LIMB_BITS);
+        let k0 = (m - 1) / LIMB_BITS;
+        let off = (m - 1) % LIMB_BITS;
+        let top_bits = m - LIMB_BITS * (num_limbs - 1);
+        let base = GoldilocksField::from_canonical_u64(1 << LIMB_BITS);
+
+        let any_zero = builder.or(a.is_zero, b.is_zero);
+        let one = constant(builder, 1 << (m - 1));
+        let ma = builder.select(a.is_zero, one, a.mant);
+        let mb = builder.select(b.is_zero, one, b.mant);
+        let a_limbs = self.limbs(builder, tables, ma, &adv.a_limbs);
+        let b_limbs = self.limbs(builder, tables, mb, &adv.b_limbs);
+
+        // product = q·2^s + rem with s = m - 1 + hi and q in [2^(m-1), 2^m).
+        let hi = self.advice_bool(builder, adv.hi);
+        let q_limbs: Vec<Target> = adv.q_limbs.iter().map(|&l| self.advice(builder, l)).collect();
+        for &l in &q_limbs[..num_limbs - 1] {
+            tables.range_check_bits(builder, l, LIMB_BITS);
+        }
+        let q_top = q_limbs[num_limbs - 1];
+        let q_top_rest = builder.add_const(q_top, -GoldilocksField::from_canonical_u64(1 << (top_bits - 1)));
+        tables.range_check_bits(builder, q_top_rest, top_bits - 1);
+
+        let r_limbs: Vec<Target> = adv.r_limbs.iter().map(|&l| self.advice(builder, l)).collect();
+        for &l in &r_limbs {
+            tables.range_check_bits(builder, l, LIMB_BITS);
+        }
+        // The top remainder limb is g·2^(off-1+hi) + r_top with r_top < 2^(off-1+hi).
+        let g = self.advice_bool(builder, adv.g);
+        let r_top = self.advice(builder, adv.r_top);
+        let not_hi = builder.not(hi);
+        let scale = builder.add_const(not_hi.target, GoldilocksField::ONE);
+        let scaled = builder.mul(r_top, scale);
+        tables.range_check_bits(builder, scaled, off);
+        let g_weight = builder.add_const(hi.target, GoldilocksField::ONE);
+        let g_weight = builder.mul_const(GoldilocksField::from_canonical_u64(1 << (off - 1)), g_weight);
+        let rem_top = builder.mul_add(g.target, g_weight, r_top);
+
+        let q_factor = builder.add_const(hi.target, GoldilocksField::ONE);
+        let q_factor = builder.mul_const(GoldilocksField::from_canonical_u64(1 << off), q_factor);
+        let columns = 2 * num_limbs - 1;
+        let mut carry = builder.zero();
+        for k in 0..columns {
+            let mut diff = carry;
+            for i in 0..num_limbs {
+                if k >= i && k - i < num_limbs {
+                    diff = builder.mul_add(a_limbs[i], b_limbs[k - i], diff);
+                }
+            }
+            if k >= k0 && k - k0 < num_limbs {
+                let term = builder.mul(q_limbs[k - k0], q_factor);
+                diff = builder.sub(diff, term);
+            }
+            if k < k0 {
+                diff = builder.sub(diff, r_limbs[k]);
+            } else if k == k0 {
+                diff = builder.sub(diff, rem_top);
+            }
+            if k + 1 == columns {
+                builder.assert_zero(diff);
+            } else {
+                carry = builder.mul_const(base.inverse(), diff);
+                let biased = builder.add_const(carry, GoldilocksField::from_canonical_u64(1 << 21));
+                tables.range_check_bits(builder, biased, 22);
+            }
+        }
+
+        let q_half = self.advice(builder, adv.q_half);
+        tables.range_check_bits(builder, q_half, LIMB_BITS - 1);
+        let lsb = self.advice_bool(builder, adv.lsb);
+        let q0 = builder.mul_const_add(GoldilocksField::TWO, q_half, lsb.target);
+        builder.connect(q0, q_limbs[0]);
+
+        let mut sticky_sum = r_top;
+        for &l in &r_limbs {
+            sticky_sum = builder.add(sticky_sum, l);
+        }
+        let zero = builder.zero();
+        let sticky_zero = builder.is_equal(sticky_sum, zero);
+        let sticky = builder.not(sticky_zero);
+
+        let mut q = q_limbs[num_limbs - 1];
+        for &l in q_limbs[..num_limbs - 1].iter().rev() {
+            q = builder.mul_const_add(base, q, l);
+        }
+        let (mant, ovf) = round_up(builder, q, m, g, sticky, lsb);
+
+        let exp = builder.add(a.exp, b.exp);
+        let exp = builder.add_const(exp, -GoldilocksField::from_canonical_u64(fmt.bias()));
+        let exp = builder.add(exp, hi.target);
+        let exp = builder.add(exp, ovf.target);
+        check_normal_exp(builder, tables, fmt, exp, any_zero);
+
+        let sign = builder.sub(a.sign.target, b.sign.target);
+        let sign = BoolTarget::new_unsafe(builder.mul(sign, sign));
+        let exp = builder.select(any_zero, zero, exp);
+        let hidden = constant(builder, 1 << (m - 1));
+        let frac = builder.sub(mant, hidden);
+        let frac = builder.select(any_zero, zero, frac);
+        let inexact = builder.or(g, sticky);
+        let not_zero = builder.not(any_zero);
+        let inexact = builder.and(inexact, not_zero);
+        (pack(builder, fmt, sign, exp, frac), inexact)
+    }
+
+    /// Decomposes `x` into range-checked limbs of [`LIMB_BITS`] bits.
+    fn limbs(&mut self, builder: &mut Builder, tables: &mut LookupTables, x: Target, values: &[u64]) -> Vec<Target> {
+        let limbs: Vec<Target> = values.iter().map(|&v| self.advice(builder, v)).collect();
+        let mut acc = limbs[limbs.len() - 1];
+        for &l in limbs.iter().rev() {
+            tables.range_check_bits(builder, l, LIMB_BITS);
+        }
+        for &l in limbs[..limbs.len() - 1].iter().rev() {
+            acc = builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << LIMB_BITS), acc, l);
+        }
+        builder.connect(acc, x);
+        limbs
+    }
+
+    /// Assigns the advice values of all floating-point rows.
+    pub fn set_witness(&self, pw: &mut PartialWitness<GoldilocksField>) -> Result<(), anyhow::Error> {
+        for &(target, value) in &self.witness {
+            pw.set_target(target, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// `pow - 1 - x`, which is a small non-negative number exactly when `x < pow`.
+fn below(builder: &mut Builder, pow: Target, x: Target) -> Target {
+    let slack = builder.sub(pow, x);
+    builder.add_const(slack, GoldilocksField::NEG_ONE)
+}
+
+/// Constrains `2^(bits-1) <= x < 2^bits`.
+fn check_in_binade(builder: &mut Builder, tables: &mut LookupTables, x: Target, bits: usize) {
+    let rest = builder.add_const(x, -GoldilocksField::from_canonical_u64(1 << (bits - 1)));
+    tables.range_check_bits(builder, rest, bits - 1);
+}
+
+/// Constrains a result exponent to the normal range unless the result is zero.
+fn check_normal_exp(builder: &mut Builder, tables: &mut LookupTables, fmt: FpFormat, exp: Target, is_zero: BoolTarget) {
+    let one = builder.one();
+    let exp = builder.select(is_zero, one, exp);
+    let above_min = builder.add_const(exp, GoldilocksField::NEG_ONE);
+    tables.range_check_bits(builder, above_min, fmt.exp_bits());
+    let max_normal = constant(builder, fmt.max_exp() - 1);
+    let below_max = builder.sub(max_normal, exp);
+    tables.range_check_bits(builder, below_max, fmt.exp_bits());
+}
+
+/// Adds the round-to-nearest-even increment `g & (sticky | lsb)` to `mant`, renormalizing
+/// `2^m` to `2^(m-1)`. Returns the significand and whether it overflowed.
+fn round_up(
+    builder: &mut Builder,
+    mant: Target,
+    m: usize,
+    g: BoolTarget,
+    sticky: BoolTarget,
+    lsb: BoolTarget,
+) -> (Target, BoolTarget) {
+    let tie_break = builder.or(sticky, lsb);
+    let up = builder.and(g, tie_break);
+    let all_ones = constant(builder, (1 << m) - 1);
+    let saturated = builder.is_equal(mant, all_ones);
+    let ovf = builder.and(up, saturated);
+    let rounded = builder.add(mant, up.target);
+    let wrap = builder.mul_const(GoldilocksField::from_canonical_u64(1 << (m - 1)), ovf.target);
+    (builder.sub(rounded, wrap), ovf)
+}
+
+/// Splits `x < 2^33` into its low 32 bits and bit 32.
+fn split_carry(builder: &mut Builder, tables: &mut LookupTables, x: Target) -> (Target, BoolTarget) {
+    let limbs = tables.split_limbs(builder, x, 16, 3);
+    let carry = BoolTarget::new_unsafe(limbs[2]);
+    builder.assert_bool(carry);
+    let low = builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << 16), limbs[1], limbs[0]);
+    (low, carry)
+}
+
+/// Field value of the 64-bit sign extension of a 32-bit word.
+fn sign_extend_word(builder: &mut Builder, tables: &mut LookupTables, word: Target) -> Target {
+    tables.range_check_bits(builder, word, 32);
+    let biased = builder.add_const(word, GoldilocksField::from_canonical_u64(1 << 31));
+    let (_, sign) = split_carry(builder, tables, biased);
+    builder.sub(word, sign.target)
+}
+
+/// Decodes a register holding a value of `fmt`, rejecting subnormals, infinities and NaNs.
+fn unpack(builder: &mut Builder, tables: &mut LookupTables, fmt: FpFormat, reg: Target) -> Unpacked {
+    let (f, e) = (fmt.frac_bits(), fmt.exp_bits());
+    let limbs = match fmt {
+        FpFormat::Single => {
+            let bits = builder.add_const(reg, GoldilocksField::ONE);
+            tables.split_limbs(builder, bits, 16, 2)
+        }
+        FpFormat::Double => tables.split_u64_limbs(builder, reg).to_vec(),
+    };
+    let top = *limbs.last().unwrap();
+    let top_bits = builder.split_le(top, 16);
+    let frac_top = f - 16 * (limbs.len() - 1);
+
+    let mut frac = builder.le_sum(top_bits[..frac_top].iter());
+    for &l in limbs[..limbs.len() - 1].iter().rev() {
+        frac = builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << 16), frac, l);
+    }
+    let exp = builder.le_sum(top_bits[frac_top..frac_top + e].iter());
+    let sign = top_bits[15];
+
+    let max_exp = constant(builder, fmt.max_exp());
+    let special = builder.is_equal(exp, max_exp);
+    builder.assert_zero(special.target);
+    let zero = builder.zero();
+    let is_zero = builder.is_equal(exp, zero);
+    let subnormal = builder.mul(is_zero.target, frac);
+    builder.assert_zero(subnormal);
+
+    let not_zero = builder.not(is_zero);
+    let hidden = builder.mul_const(GoldilocksField::from_canonical_u64(1 << f), not_zero.target);
+    let mant = builder.add(frac, hidden);
+    Unpacked { sign, exp, frac, mant, is_zero }
+}
+
+/// Register value of the number with the given fields.
+fn pack(builder: &mut Builder, fmt: FpFormat, sign: BoolTarget, exp: Target, frac: Target) -> Target {
+    let f = fmt.frac_bits();
+    let value = builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << f), exp, frac);
+    let value = builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << (f + fmt.exp_bits())), sign.target, value);
+    match fmt {
+        FpFormat::Single => builder.add_const(value, GoldilocksField::NEG_ONE),
+        FpFormat::Double => value,
+    }
+}
+
+/// Accrues the inexact flag into `fcsr`; with a dynamic rounding mode, `frm` must be RNE.
+fn accrue_inexact(builder: &mut Builder, fcsr_in: Target, inexact: BoolTarget, dynamic_rm: bool) -> Target {
+    let bits = builder.split_le(fcsr_in, crate::csr::FCSR_BITS);
+    if dynamic_rm {
+        for bit in &bits[5..8] {
+            builder.assert_zero(bit.target);
+        }
+    }
+    let nx = builder.or(bits[0], inexact);
+    let delta = builder.sub(nx.target, bits[0].target);
+    builder.add(fcsr_in, delta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::prove_multi_instruction_constraint;
+    use crate::trace_parser::{RegisterFile, TraceEntry};
+    use crate::zk::{convert_trace_to_rows_with, ConversionMode, ConvertOptions, UnprovenSpan};
+
+    #[test]
+    fn test_native_rounding_matches_hardware() {
+        let singles = [1.5f32, -2.25, 3.0e-3, 1.0e7, 0.1, -0.0, 0.0, 16777215.0, 1.0e-30, 3.4e30];
+        for &x in &singles {
+            for &y in &singles {
+                let (a, b) = (x.to_bits() as u64, y.to_bits() as u64);
+                for (op, expected) in [(FpOp::Add, x + y), (FpOp::Sub, x - y), (FpOp::Mul, x * y)] {
+                    if let Some((bits, _)) = fp_arith(op, FpFormat::Single, a, b) {
+                        assert_eq!(bits, expected.to_bits() as u64, "{:?} {} {}", op, x, y);
+                    } else {
+                        assert!(!expected.is_normal(), "{:?} {} {}", op, x, y);
+                    }
+                }
+            }
+        }
+
+        let doubles = [1.5f64, -2.25, 1.0e-3, 1.0e300, 0.1, 0.2, -0.0, 9007199254740991.0, 1.0 / 3.0, 1.0e-300];
+        for &x in &doubles {
+            for &y in &doubles {
+                for (op, expected) in [(FpOp::Add, x + y), (FpOp::Sub, x - y), (FpOp::Mul, x * y)] {
+                    if let Some((bits, _)) = fp_arith(op, FpFormat::Double, x.to_bits(), y.to_bits()) {
+                        assert_eq!(bits, expected.to_bits(), "{:?} {} {}", op, x, y);
+                    } else {
+                        assert!(!expected.is_normal(), "{:?} {} {}", op, x, y);
+                    }
+                }
+            }
+        }
+        assert!(fp_arith(FpOp::Add, FpFormat::Double, 0.1f64.to_bits(), 0.2f64.to_bits()).unwrap().1);
+        assert!(!fp_arith(FpOp::Add, FpFormat::Double, 1.5f64.to_bits(), 2.0f64.to_bits()).unwrap().1);
+    }
+
+    fn fp(opcode: &str, rd: &str, rs1: (&str, u64), rs2: Option<(&str, u64)>, rd_after: u64) -> TraceEntry {
+        let before = RegisterFile::default().with(&[rs1]).with(rs2.as_slice());
+        TraceEntry {
+            opcode: opcode.to_string(),
+            rd: Some(rd.to_string()),
+            rs1: Some(rs1.0.to_string()),
+            rs2: rs2.map(|(r, _)| r.to_string()),
+            regs_before: before,
+            regs_after: before.with(&[(rd, rd_after)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fp_rows_round_to_nearest_even_and_accrue_inexact() {
+        let d = |x: f64| x.to_bits();
+        let s = |x: f32| FpFormat::Single.to_register(x.to_bits() as u64);
+        let (third, tenth) = (1.0f64 / 3.0, 0.1f32);
+        let mut trace = vec![
+            fp("fadd.d", "f3", ("f1", d(0.1)), Some(("f2", d(0.2))), d(0.1 + 0.2)),
+            fp("fsub.d", "f4", ("f3", d(0.5)), Some(("f3", d(0.5))), d(0.0)),
+            fp("fmul.d", "f5", ("f1", d(third)), Some(("f2", d(3.0))), d(third * 3.0)),
+            fp("fadd.s", "f6", ("f1", s(1.5)), Some(("f2", s(2.25))), s(3.75)),
+            fp("fmul.s", "f7", ("f1", s(tenth)), Some(("f2", s(0.2))), s(tenth * 0.2)),
+            fp("fsub.s", "f8", ("f1", s(1.0e-3)), Some(("f2", s(1.0e7))), s(1.0e-3 - 1.0e7)),
+            fp("fmul.d", "f9", ("f1", d(-0.0)), Some(("f2", d(2.5))), d(-0.0)),
+            fp("fcvt.d.w", "f10", ("x5", -7i64 as u64), None, d(-7.0)),
+            fp("fcvt.d.s", "f11", ("f6", s(3.75)), None, d(3.75)),
+            fp("fmv.x.d", "x10", ("f5", d(1.0)), None, d(1.0)),
+            fp("fmv.w.x", "f12", ("x11", 0x3fc0_0000), None, s(1.5)),
+            fp("fmv.x.w", "x12", ("f7", s(-2.0)), None, (-2.0f32).to_bits() as i32 as i64 as u64),
+            fp("fsub.d", "f13", ("f1", d(f64::MIN_POSITIVE / 2.0)), Some(("f2", d(0.0))), 0), // subnormal
+        ];
+        trace.push(TraceEntry { rounding: Some("rtz".to_string()), ..trace[0].clone() }); // skipped
+        let lenient = ConvertOptions { mode: ConversionMode::Lenient, ..Default::default() };
+        let rows = convert_trace_to_rows_with(&trace, &lenient).unwrap();
+        assert_eq!(rows.len(), 13);
+        assert_eq!(rows[12].unproven, Some(UnprovenSpan { first_pc: 0, last_pc: 0, count: 2 }));
+
+        let (proof, data) = prove_multi_instruction_constraint(&rows).unwrap();
+        let inputs = &proof.public_inputs;
+        assert_eq!(inputs[rows.len() * 6], GoldilocksField::ZERO);
+        assert_eq!(inputs[rows.len() * 6 + 1], GoldilocksField::ONE); // NX
+        data.verify(proof).unwrap();
+
+        // One ulp off the correctly rounded sum.
+        let mut bad = rows[..1].to_vec();
+        bad[0].rd_val = GoldilocksField::from_canonical_u64(d(0.1 + 0.2) - 1);
+        assert!(prove_multi_instruction_constraint(&bad).is_err());
+
+        // fadd with a dynamic rounding mode requires frm = RNE.
+        let options = ConvertOptions { initial_fcsr: 0b001_00000, ..Default::default() };
+        let err = convert_trace_to_rows_with(&trace[..1], &options).unwrap_err();
+        assert_eq!(err.0[0].mnemonic, "fadd.d");
+        assert!(err.0[0].reason.contains("frm"));
+    }
+}
@@ -0,0 +1,193 @@
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::Field;
+use plonky2::hash::hash_types::HashOut;
+use plonky2::hash::poseidon::PoseidonHash;
+use plonky2::plonk::config::Hasher;
+use serde::{Deserialize, Serialize};
+
+use crate::csr::InputVisibility;
+use crate::elf::ElfFile;
+
+/// Bytes placed in the program's memory before it runs, standing for a memory-mapped sensor.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SensorInput {
+    pub addr: u64,
+    pub data: Vec<u8>,
+}
+
+impl SensorInput {
+    /// Places `data` at a data symbol, such as `volatile uint8_t sensor[64]`, if it fits.
+    pub fn at_symbol(elf: &ElfFile, name: &str, data: Vec<u8>) -> Option<SensorInput> {
+        let symbol = elf.symbol(name).filter(|s| !s.is_function && data.len() as u64 <= s.size)?;
+        Some(SensorInput { addr: symbol.value, data })
+    }
+}
+
+/// Inputs given to a traced program.
+///
+/// Stdin is proven by the `read` ecalls that consume it, with the visibility set by
+/// [`crate::zk::ConvertOptions::stdin_inputs`]. The arguments, the environment and the sensor
+/// blob are only declared: the digest of those marked public is committed by the proof, see
+/// [`ProgramInputs::public_digest`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProgramInputs {
+    /// `argv[1..]`; `argv[0]` is the program path.
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub stdin: Vec<u8>,
+    pub sensor: Option<SensorInput>,
+    pub args_visibility: InputVisibility,
+    pub env_visibility: InputVisibility,
+    pub sensor_visibility: InputVisibility,
+}
+
+fn visibility_name(visibility: InputVisibility) -> &'static str {
+    match visibility {
+        InputVisibility::Public => "public",
+        InputVisibility::Private => "private",
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+impl ProgramInputs {
+    pub fn is_empty(&self) -> bool {
+        self.args.is_empty() && self.env.is_empty() && self.stdin.is_empty() && self.sensor.is_none()
+    }
+
+    /// `#` lines recording the inputs at the top of a cleaned trace. Values are hex encoded,
+    /// so arguments may contain spaces or newlines.
+    pub fn header(&self) -> String {
+        let mut header = String::new();
+        for arg in &self.args {
+            header.push_str(&format!("# arg {}\n", hex(arg.as_bytes())));
+        }
+        for (key, value) in &self.env {
+            header.push_str(&format!("# env {} {}\n", hex(key.as_bytes()), hex(value.as_bytes())));
+        }
+        if !self.stdin.is_empty() {
+            header.push_str(&format!("# stdin {}\n", hex(&self.stdin)));
+        }
+        if let Some(sensor) = &self.sensor {
+            header.push_str(&format!("# sensor 0x{:x} {}\n", sensor.addr, hex(&sensor.data)));
+        }
+        if !self.is_empty() {
+            header.push_str(&format!(
+                "# visibility args={} env={} sensor={}\n",
+                visibility_name(self.args_visibility),
+                visibility_name(self.env_visibility),
+                visibility_name(self.sensor_visibility)
+            ));
+        }
+        header
+    }
+
+    /// Reads the inputs recorded by [`ProgramInputs::header`]; other lines are ignored.
+    pub fn parse_header(trace: &str) -> Result<ProgramInputs, String> {
+        let mut inputs = ProgramInputs::default();
+        for line in trace.lines() {
+            let Some(fields) = line.strip_prefix("# ") else { continue };
+            let fields: Vec<&str> = fields.split_whitespace().collect();
+            let bad = || format!("bad input header line: {}", line);
+            let text = |s: &str| unhex(s).map(|b| String::from_utf8_lossy(&b).into_owned()).ok_or_else(bad);
+            match fields[..] {
+                ["arg", arg] => inputs.args.push(text(arg)?),
+                ["env", key, value] => inputs.env.push((text(key)?, text(value)?)),
+                ["stdin", data] => inputs.stdin = unhex(data).ok_or_else(bad)?,
+                ["sensor", addr, data] => {
+                    let addr = addr.strip_prefix("0x").and_then(|a| u64::from_str_radix(a, 16).ok()).ok_or_else(bad)?;
+                    inputs.sensor = Some(SensorInput { addr, data: unhex(data).ok_or_else(bad)? });
+                }
+                ["visibility", ref settings @ ..] => {
+                    for setting in settings {
+                        let visibility = match setting.split_once('=') {
+                            Some((_, "public")) => InputVisibility::Public,
+                            Some((_, "private")) => InputVisibility::Private,
+                            _ => return Err(bad()),
+                        };
+                        match setting.split('=').next() {
+                            Some("args") => inputs.args_visibility = visibility,
+                            Some("env") => inputs.env_visibility = visibility,
+                            Some("sensor") => inputs.sensor_visibility = visibility,
+                            _ => return Err(bad()),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(inputs)
+    }
+
+    /// Digest of the public arguments, environment and sensor blob.
+    ///
+    /// Each kind contributes a public flag; a public kind follows with its number of items,
+    /// then each item's byte length and bytes. The sensor's address comes before its bytes as
+    /// two 32-bit halves.
+    pub fn public_digest(&self) -> HashOut<GoldilocksField> {
+        let mut elements: Vec<u64> = Vec::new();
+        let mut kind = |visibility: InputVisibility, items: Vec<Vec<u8>>| {
+            elements.push((visibility == InputVisibility::Public) as u64);
+            if visibility == InputVisibility::Public {
+                elements.push(items.len() as u64);
+                for item in items {
+                    elements.push(item.len() as u64);
+                    elements.extend(item.iter().map(|&b| b as u64));
+                }
+            }
+        };
+        kind(self.args_visibility, self.args.iter().map(|a| a.as_bytes().to_vec()).collect());
+        let env = self.env.iter().map(|(k, v)| format!("{}={}", k, v).into_bytes()).collect();
+        kind(self.env_visibility, env);
+        let sensor = self.sensor.iter().map(|s| {
+            let addr = [s.addr & 0xffff_ffff, s.addr >> 32].map(|h| h as u32);
+            addr.iter().flat_map(|h| h.to_le_bytes()).chain(s.data.iter().copied()).collect()
+        });
+        kind(self.sensor_visibility, sensor.collect());
+        let elements: Vec<GoldilocksField> = elements.into_iter().map(GoldilocksField::from_canonical_u64).collect();
+        PoseidonHash::hash_no_pad(&elements)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip_and_digest() {
+        let inputs = ProgramInputs {
+            args: vec!["--rate".to_string(), "two words".to_string()],
+            env: vec![("MODE".to_string(), "fast".to_string())],
+            stdin: b"42\n".to_vec(),
+            sensor: Some(SensorInput { addr: 0x11f00, data: vec![1, 2, 3] }),
+            env_visibility: InputVisibility::Private,
+            ..Default::default()
+        };
+        let header = inputs.header();
+        assert!(header.lines().all(|l| l.starts_with("# ")));
+        let trace = format!("{}x2=0x10\n0x1000: addi x2, x2, -16\n", header);
+        assert_eq!(ProgramInputs::parse_header(&trace), Ok(inputs.clone()));
+        assert!(ProgramInputs::parse_header("# stdin 4").is_err());
+        assert_eq!(ProgramInputs::default().header(), "");
+
+        // Private inputs do not affect the digest; public ones do.
+        let other_env = ProgramInputs { env: vec![("MODE".to_string(), "slow".to_string())], ..inputs.clone() };
+        assert_eq!(other_env.public_digest(), inputs.public_digest());
+        let mut other_sensor = inputs.clone();
+        other_sensor.sensor.as_mut().unwrap().data[0] = 9;
+        assert_ne!(other_sensor.public_digest(), inputs.public_digest());
+
+        let elf = ElfFile::load("test.bin").unwrap();
+        assert!(SensorInput::at_symbol(&elf, "main", vec![0]).is_none());
+        assert!(SensorInput::at_symbol(&elf, "missing", vec![0]).is_none());
+    }
+}
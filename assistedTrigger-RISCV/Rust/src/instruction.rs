@@ -0,0 +1,457 @@
+use std::fmt;
+
+/// ABI names of `x0`..`x31`; `fp` is accepted as well for `x8`.
+const X_ABI: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// ABI names of `f0`..`f31`.
+const F_ABI: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1", "fa0", "fa1", "fa2", "fa3",
+    "fa4", "fa5", "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11",
+    "ft8", "ft9", "ft10", "ft11",
+];
+
+/// An integer or floating-point register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reg {
+    X(u8),
+    F(u8),
+}
+
+pub const ZERO: Reg = Reg::X(0);
+pub const RA: Reg = Reg::X(1);
+
+impl Reg {
+    /// Parses `x5`, `f3` or an ABI name (`a0`, `fp`, `ft0`, ...).
+    pub fn parse(name: &str) -> Option<Reg> {
+        if name == "fp" {
+            return Some(Reg::X(8));
+        }
+        if let Some(i) = X_ABI.iter().position(|&n| n == name) {
+            return Some(Reg::X(i as u8));
+        }
+        if let Some(i) = F_ABI.iter().position(|&n| n == name) {
+            return Some(Reg::F(i as u8));
+        }
+        let number = |digits: &str| digits.parse::<u8>().ok().filter(|&n| n < 32 && !digits.starts_with('+'));
+        match name.split_at_checked(1)? {
+            ("x", digits) => number(digits).map(Reg::X),
+            ("f", digits) => number(digits).map(Reg::F),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reg::X(n) => write!(f, "x{}", n),
+            Reg::F(n) => write!(f, "f{}", n),
+        }
+    }
+}
+
+/// Source operand of a canonical CSR instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsrSource {
+    Reg(Reg),
+    Imm(i64),
+}
+
+/// A decoded instruction, in canonical form.
+///
+/// Pseudo-instructions are expanded to the base instruction they stand for (`ret` is
+/// `jalr x0, 0(x1)`, `beqz a0, t` is `beq x10, x0, t`, `sext.w` is `addiw rd, rs, 0`), except
+/// `li` and `mv`, which the ALU proves under their own opcodes. Branch and jump targets are
+/// absolute addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    /// `op rd, rs1, rs2`: register-register ALU operations, including M, Zba, Zbb and Zbs.
+    RegReg { op: String, rd: Reg, rs1: Reg, rs2: Reg },
+    /// `op rd, rs1, imm`: register-immediate ALU operations and shifts.
+    RegImm { op: String, rd: Reg, rs1: Reg, imm: i64 },
+    /// `op rd, rs1`: Zbb unary operations and `mv`.
+    Unary { op: String, rd: Reg, rs1: Reg },
+    /// `op rd, imm`: `lui` and `auipc` (with the 20-bit immediate as written) and `li`.
+    Upper { op: String, rd: Reg, imm: i64 },
+    /// `op rd, offset(base)`, integer or floating-point.
+    Load { op: String, rd: Reg, base: Reg, offset: i64 },
+    /// `op src, offset(base)`, integer or floating-point.
+    Store { op: String, src: Reg, base: Reg, offset: i64 },
+    Branch { op: String, rs1: Reg, rs2: Reg, target: u64 },
+    Jal { rd: Reg, target: u64 },
+    Jalr { rd: Reg, base: Reg, offset: i64 },
+    /// `csrrw`/`csrrs`/`csrrc` and their `i` forms; the CSR pseudo-instructions are expanded.
+    Csr { op: String, rd: Reg, csr: String, src: CsrSource },
+    /// `lr`, `sc` and AMOs, `op rd, [src,] (base)`; `ordering` is `aq`, `rl` or `aqrl`.
+    Atomic { op: String, ordering: Option<String>, rd: Reg, src: Option<Reg>, base: Reg },
+    /// Floating-point computation, `op rd, rs1[, rs2[, rs3]][, rm]`.
+    Fp { op: String, rd: Reg, rs1: Reg, rs2: Option<Reg>, rs3: Option<Reg>, rounding: Option<String> },
+    /// `ecall`, `ebreak`, fences and other instructions without register operands.
+    System { op: String, args: String },
+    /// A mnemonic this grammar does not know, kept so that the trace has no holes.
+    Other { op: String, args: String },
+}
+
+const REG_REG_OPS: [&str; 44] = [
+    "add", "sub", "sll", "slt", "sltu", "xor", "srl", "sra", "or", "and",
+    "addw", "subw", "sllw", "srlw", "sraw",
+    "mul", "mulh", "mulhsu", "mulhu", "div", "divu", "rem", "remu",
+    "mulw", "divw", "divuw", "remw", "remuw",
+    "sh1add", "sh2add", "sh3add", "sh1add.uw", "sh2add.uw", "sh3add.uw", "add.uw",
+    "andn", "orn", "xnor", "max", "maxu", "min", "minu", "rol", "ror",
+];
+
+const REG_REG_OPS_EXTRA: [&str; 6] = ["rolw", "rorw", "bclr", "bext", "binv", "bset"];
+
+const REG_IMM_OPS: [&str; 20] = [
+    "addi", "slti", "sltiu", "xori", "ori", "andi", "slli", "srli", "srai",
+    "addiw", "slliw", "srliw", "sraiw", "slli.uw", "rori", "roriw", "bclri", "bexti", "binvi", "bseti",
+];
+
+const UNARY_OPS: [&str; 12] =
+    ["clz", "clzw", "ctz", "ctzw", "cpop", "cpopw", "rev8", "orc.b", "sext.b", "sext.h", "zext.h", "mv"];
+
+const LOAD_OPS: [&str; 10] = ["lb", "lh", "lw", "ld", "lbu", "lhu", "lwu", "flh", "flw", "fld"];
+
+const STORE_OPS: [&str; 7] = ["sb", "sh", "sw", "sd", "fsh", "fsw", "fsd"];
+
+const BRANCH_OPS: [&str; 6] = ["beq", "bne", "blt", "bge", "bltu", "bgeu"];
+
+const SYSTEM_OPS: [&str; 11] =
+    ["ecall", "ebreak", "fence", "fence.i", "fence.tso", "pause", "wfi", "mret", "sret", "sfence.vma", "unimp"];
+
+/// Rounding mode operands accepted after the registers of a floating-point instruction.
+const ROUNDING_MODES: [&str; 6] = ["rne", "rtz", "rdn", "rup", "rmm", "dyn"];
+
+/// Parses a decimal or `0x`-prefixed hexadecimal immediate, as printed by gdb.
+pub fn parse_imm(s: &str) -> Option<i64> {
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let val = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()? as i64,
+        None => digits.parse::<i64>().ok()?,
+    };
+    Some(if neg { val.wrapping_neg() } else { val })
+}
+
+/// Absolute address of a branch or jump target, `0x101f4 <main+40>` or just the number.
+fn parse_target(s: &str) -> Option<u64> {
+    parse_imm(s.split_whitespace().next()?).map(|addr| addr as u64)
+}
+
+/// `offset(base)` or `(base)`.
+fn parse_mem(s: &str) -> Option<(i64, Reg)> {
+    let (offset, base) = s.strip_suffix(')')?.split_once('(')?;
+    let offset = if offset.trim().is_empty() { 0 } else { parse_imm(offset.trim())? };
+    Some((offset, Reg::parse(base.trim())?))
+}
+
+/// Splits operands at commas outside `(...)` and `<...>`, so that symbol names such as
+/// `<f<int, int>+4>` stay whole.
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut operands = Vec::new();
+    let (mut depth, mut start) = (0i32, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' | '<' => depth += 1,
+            ')' | '>' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if !text[start..].trim().is_empty() || !operands.is_empty() {
+        operands.push(text[start..].trim());
+    }
+    operands
+}
+
+/// Whether `op` is a CSR instruction or one of its pseudo-instructions.
+fn is_csr_mnemonic(op: &str) -> bool {
+    let counter = |name: &str| matches!(name.trim_end_matches('h'), "cycle" | "time" | "instret");
+    let fp_csr = |name: &str| matches!(name.trim_end_matches('i'), "csr" | "rm" | "flags");
+    op.starts_with("csr")
+        || op.strip_prefix("rd").is_some_and(counter)
+        || op.strip_prefix("fr").or_else(|| op.strip_prefix("fs")).is_some_and(fp_csr)
+}
+
+/// CSR accessed by the floating-point CSR pseudo-instructions.
+fn fp_csr(op: &str) -> Option<String> {
+    let csr = match op.trim_end_matches('i') {
+        "frcsr" | "fscsr" => "fcsr",
+        "frrm" | "fsrm" => "frm",
+        "frflags" | "fsflags" => "fflags",
+        _ => return None,
+    };
+    Some(csr.to_string())
+}
+
+/// Rewrites a CSR instruction or one of its pseudo-instructions (`csrr`, `csrw`, `rdcycle`,
+/// `frflags`, `fsrmi`, ...) into its canonical form.
+fn parse_csr(op: &str, operands: &[&str]) -> Option<Instruction> {
+    let reg = |s: &str| Reg::parse(s).map(CsrSource::Reg);
+    let imm = |s: &str| parse_imm(s).map(CsrSource::Imm);
+    let rd = |s: &str| Reg::parse(s);
+    let zero = Some(CsrSource::Reg(ZERO));
+
+    let (canonical, rd, csr, src) = match (op, operands) {
+        ("csrrw" | "csrrs" | "csrrc", [d, csr, rs1]) => (op, rd(d)?, csr.to_string(), reg(rs1)),
+        ("csrrwi" | "csrrsi" | "csrrci", [d, csr, uimm]) => (op, rd(d)?, csr.to_string(), imm(uimm)),
+        ("csrr", [d, csr]) => ("csrrs", rd(d)?, csr.to_string(), zero),
+        ("csrw", [csr, rs1]) => ("csrrw", ZERO, csr.to_string(), reg(rs1)),
+        ("csrs", [csr, rs1]) => ("csrrs", ZERO, csr.to_string(), reg(rs1)),
+        ("csrc", [csr, rs1]) => ("csrrc", ZERO, csr.to_string(), reg(rs1)),
+        ("csrwi", [csr, uimm]) => ("csrrwi", ZERO, csr.to_string(), imm(uimm)),
+        ("csrsi", [csr, uimm]) => ("csrrsi", ZERO, csr.to_string(), imm(uimm)),
+        ("csrci", [csr, uimm]) => ("csrrci", ZERO, csr.to_string(), imm(uimm)),
+        (_, [d]) if op.starts_with("rd") => ("csrrs", rd(d)?, op[2..].to_string(), zero),
+        ("frcsr" | "frrm" | "frflags", [d]) => ("csrrs", rd(d)?, fp_csr(op)?, zero),
+        ("fscsr" | "fsrm" | "fsflags", [rs1]) => ("csrrw", ZERO, fp_csr(op)?, reg(rs1)),
+        ("fscsr" | "fsrm" | "fsflags", [d, rs1]) => ("csrrw", rd(d)?, fp_csr(op)?, reg(rs1)),
+        ("fsrmi" | "fsflagsi", [uimm]) => ("csrrwi", ZERO, fp_csr(op)?, imm(uimm)),
+        ("fsrmi" | "fsflagsi", [d, uimm]) => ("csrrwi", rd(d)?, fp_csr(op)?, imm(uimm)),
+        _ => return None,
+    };
+    Some(Instruction::Csr { op: canonical.to_string(), rd, csr, src: src? })
+}
+
+impl Instruction {
+    /// Parses an instruction as printed by gdb or objdump, `mnemonic operands`, with registers
+    /// by number or ABI name and targets as absolute addresses.
+    pub fn parse(text: &str) -> Result<Instruction, String> {
+        let text = text.trim();
+        let (mnemonic, args) = text.split_once(char::is_whitespace).map_or((text, ""), |(m, a)| (m, a.trim()));
+        let ops = split_operands(args);
+        let bad = || format!("invalid operands for `{}`: {:?}", mnemonic, args);
+        let reg = |i: usize| ops.get(i).and_then(|s| Reg::parse(s)).ok_or_else(bad);
+        let imm = |i: usize| ops.get(i).and_then(|s| parse_imm(s)).ok_or_else(bad);
+        let target = |i: usize| ops.get(i).and_then(|s| parse_target(s)).ok_or_else(bad);
+        let mem = |i: usize| ops.get(i).and_then(|s| parse_mem(s)).ok_or_else(bad);
+        let op = mnemonic.to_string();
+        let n = ops.len();
+
+        let reg_reg = |op: &str, rd, rs1, rs2| Ok(Instruction::RegReg { op: op.to_string(), rd, rs1, rs2 });
+        let reg_imm = |op: &str, rd, rs1, imm| Ok(Instruction::RegImm { op: op.to_string(), rd, rs1, imm });
+        let branch = |op: &str, rs1, rs2, target| Ok(Instruction::Branch { op: op.to_string(), rs1, rs2, target });
+        let sign_injection = |op: &str| {
+            let fmt = &mnemonic[mnemonic.len() - 1..];
+            let (rd, rs) = (reg(0)?, reg(1)?);
+            Ok(Instruction::Fp { op: format!("{}.{}", op, fmt), rd, rs1: rs, rs2: Some(rs), rs3: None, rounding: None })
+        };
+
+        // Pseudo-instructions.
+        match (mnemonic, n) {
+            ("nop", 0) => return reg_imm("addi", ZERO, ZERO, 0),
+            ("ret", 0) => return Ok(Instruction::Jalr { rd: ZERO, base: RA, offset: 0 }),
+            ("jr", 1) => return Ok(Instruction::Jalr { rd: ZERO, base: reg(0)?, offset: 0 }),
+            ("j", 1) => return Ok(Instruction::Jal { rd: ZERO, target: target(0)? }),
+            ("not", 2) => return reg_imm("xori", reg(0)?, reg(1)?, -1),
+            ("neg", 2) => return reg_reg("sub", reg(0)?, ZERO, reg(1)?),
+            ("negw", 2) => return reg_reg("subw", reg(0)?, ZERO, reg(1)?),
+            ("sext.w", 2) => return reg_imm("addiw", reg(0)?, reg(1)?, 0),
+            ("seqz", 2) => return reg_imm("sltiu", reg(0)?, reg(1)?, 1),
+            ("snez", 2) => return reg_reg("sltu", reg(0)?, ZERO, reg(1)?),
+            ("sltz", 2) => return reg_reg("slt", reg(0)?, reg(1)?, ZERO),
+            ("sgtz", 2) => return reg_reg("slt", reg(0)?, ZERO, reg(1)?),
+            ("zext.b", 2) => return reg_imm("andi", reg(0)?, reg(1)?, 0xff),
+            ("zext.w", 2) => return reg_reg("add.uw", reg(0)?, reg(1)?, ZERO),
+            ("beqz", 2) => return branch("beq", reg(0)?, ZERO, target(1)?),
+            ("bnez", 2) => return branch("bne", reg(0)?, ZERO, target(1)?),
+            ("blez", 2) => return branch("bge", ZERO, reg(0)?, target(1)?),
+            ("bgez", 2) => return branch("bge", reg(0)?, ZERO, target(1)?),
+            ("bltz", 2) => return branch("blt", reg(0)?, ZERO, target(1)?),
+            ("bgtz", 2) => return branch("blt", ZERO, reg(0)?, target(1)?),
+            ("bgt", 3) => return branch("blt", reg(1)?, reg(0)?, target(2)?),
+            ("ble", 3) => return branch("bge", reg(1)?, reg(0)?, target(2)?),
+            ("bgtu", 3) => return branch("bltu", reg(1)?, reg(0)?, target(2)?),
+            ("bleu", 3) => return branch("bgeu", reg(1)?, reg(0)?, target(2)?),
+            ("fmv.s" | "fmv.d", 2) => return sign_injection("fsgnj"),
+            ("fneg.s" | "fneg.d", 2) => return sign_injection("fsgnjn"),
+            ("fabs.s" | "fabs.d", 2) => return sign_injection("fsgnjx"),
+            _ => {}
+        }
+
+        let inst = if REG_REG_OPS.contains(&mnemonic) || REG_REG_OPS_EXTRA.contains(&mnemonic) {
+            Instruction::RegReg { op, rd: reg(0)?, rs1: reg(1)?, rs2: reg(2)? }
+        } else if REG_IMM_OPS.contains(&mnemonic) {
+            Instruction::RegImm { op, rd: reg(0)?, rs1: reg(1)?, imm: imm(2)? }
+        } else if UNARY_OPS.contains(&mnemonic) {
+            Instruction::Unary { op, rd: reg(0)?, rs1: reg(1)? }
+        } else if matches!(mnemonic, "lui" | "auipc" | "li") {
+            Instruction::Upper { op, rd: reg(0)?, imm: imm(1)? }
+        } else if LOAD_OPS.contains(&mnemonic) {
+            let (offset, base) = mem(1)?;
+            Instruction::Load { op, rd: reg(0)?, base, offset }
+        } else if STORE_OPS.contains(&mnemonic) {
+            let (offset, base) = mem(1)?;
+            Instruction::Store { op, src: reg(0)?, base, offset }
+        } else if BRANCH_OPS.contains(&mnemonic) {
+            Instruction::Branch { op, rs1: reg(0)?, rs2: reg(1)?, target: target(2)? }
+        } else if mnemonic == "jal" {
+            match n {
+                1 => Instruction::Jal { rd: RA, target: target(0)? },
+                _ => Instruction::Jal { rd: reg(0)?, target: target(1)? },
+            }
+        } else if mnemonic == "jalr" {
+            match n {
+                1 => match mem(0) {
+                    Ok((offset, base)) => Instruction::Jalr { rd: RA, base, offset },
+                    Err(_) => Instruction::Jalr { rd: RA, base: reg(0)?, offset: 0 },
+                },
+                2 => {
+                    let (offset, base) = mem(1)?;
+                    Instruction::Jalr { rd: reg(0)?, base, offset }
+                }
+                _ => Instruction::Jalr { rd: reg(0)?, base: reg(1)?, offset: imm(2)? },
+            }
+        } else if is_csr_mnemonic(mnemonic) {
+            parse_csr(mnemonic, &ops).ok_or_else(bad)?
+        } else if ["lr.", "sc.", "amo"].iter().any(|p| mnemonic.starts_with(p)) {
+            let (base_op, ordering) = match mnemonic.match_indices('.').nth(1) {
+                Some((i, _)) => (&mnemonic[..i], Some(mnemonic[i + 1..].to_string())),
+                None => (mnemonic, None),
+            };
+            let (src, address) = if base_op.starts_with("lr.") { (None, 1) } else { (Some(reg(1)?), 2) };
+            let (_, base) = mem(address)?;
+            Instruction::Atomic { op: base_op.to_string(), ordering, rd: reg(0)?, src, base }
+        } else if SYSTEM_OPS.contains(&mnemonic) {
+            Instruction::System { op, args: args.to_string() }
+        } else if mnemonic.starts_with('f') && mnemonic.contains('.') {
+            let (rounding, regs) = match ops.split_last() {
+                Some((last, rest)) if ROUNDING_MODES.contains(last) => (Some(last.to_string()), rest.len()),
+                _ => (None, n),
+            };
+            if !(2..=4).contains(&regs) {
+                return Err(bad());
+            }
+            let optional = |i: usize| if i < regs { reg(i).map(Some) } else { Ok(None) };
+            Instruction::Fp { op, rd: reg(0)?, rs1: reg(1)?, rs2: optional(2)?, rs3: optional(3)?, rounding }
+        } else {
+            Instruction::Other { op, args: args.to_string() }
+        };
+        Ok(inst)
+    }
+
+    /// Canonical mnemonic, without the ordering suffix of atomics.
+    pub fn mnemonic(&self) -> &str {
+        match self {
+            Instruction::Jal { .. } => "jal",
+            Instruction::Jalr { .. } => "jalr",
+            Instruction::RegReg { op, .. }
+            | Instruction::RegImm { op, .. }
+            | Instruction::Unary { op, .. }
+            | Instruction::Upper { op, .. }
+            | Instruction::Load { op, .. }
+            | Instruction::Store { op, .. }
+            | Instruction::Branch { op, .. }
+            | Instruction::Csr { op, .. }
+            | Instruction::Atomic { op, .. }
+            | Instruction::Fp { op, .. }
+            | Instruction::System { op, .. }
+            | Instruction::Other { op, .. } => op,
+        }
+    }
+}
+
+/// Canonical assembly, with registers by number; [`Instruction::parse`] reads it back.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::RegReg { op, rd, rs1, rs2 } => write!(f, "{} {}, {}, {}", op, rd, rs1, rs2),
+            Instruction::RegImm { op, rd, rs1, imm } => write!(f, "{} {}, {}, {}", op, rd, rs1, imm),
+            Instruction::Unary { op, rd, rs1 } => write!(f, "{} {}, {}", op, rd, rs1),
+            Instruction::Upper { op, rd, imm } => write!(f, "{} {}, {}", op, rd, imm),
+            Instruction::Load { op, rd, base, offset } => write!(f, "{} {}, {}({})", op, rd, offset, base),
+            Instruction::Store { op, src, base, offset } => write!(f, "{} {}, {}({})", op, src, offset, base),
+            Instruction::Branch { op, rs1, rs2, target } => write!(f, "{} {}, {}, 0x{:x}", op, rs1, rs2, target),
+            Instruction::Jal { rd, target } => write!(f, "jal {}, 0x{:x}", rd, target),
+            Instruction::Jalr { rd, base, offset } => write!(f, "jalr {}, {}({})", rd, offset, base),
+            Instruction::Csr { op, rd, csr, src: CsrSource::Reg(rs1) } => write!(f, "{} {}, {}, {}", op, rd, csr, rs1),
+            Instruction::Csr { op, rd, csr, src: CsrSource::Imm(imm) } => write!(f, "{} {}, {}, {}", op, rd, csr, imm),
+            Instruction::Atomic { op, ordering, rd, src, base } => {
+                write!(f, "{}", op)?;
+                if let Some(ordering) = ordering {
+                    write!(f, ".{}", ordering)?;
+                }
+                write!(f, " {}, ", rd)?;
+                if let Some(src) = src {
+                    write!(f, "{}, ", src)?;
+                }
+                write!(f, "({})", base)
+            }
+            Instruction::Fp { op, rd, rs1, rs2, rs3, rounding } => {
+                write!(f, "{} {}, {}", op, rd, rs1)?;
+                for operand in rs2.iter().chain(rs3).map(Reg::to_string).chain(rounding.clone()) {
+                    write!(f, ", {}", operand)?;
+                }
+                Ok(())
+            }
+            Instruction::System { op, args } | Instruction::Other { op, args } if args.is_empty() => write!(f, "{}", op),
+            Instruction::System { op, args } | Instruction::Other { op, args } => write!(f, "{} {}", op, args),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_operand_forms() {
+        let parse = |text: &str| Instruction::parse(text).unwrap();
+        let x = Reg::X;
+
+        assert_eq!(parse("sd\tra,56(sp)"), Instruction::Store { op: "sd".into(), src: x(1), base: x(2), offset: 56 });
+        assert_eq!(parse("ld x1, -8(x2)"), Instruction::Load { op: "ld".into(), rd: x(1), base: x(2), offset: -8 });
+        assert_eq!(parse("fsd fa5,-24(s0)"), Instruction::Store { op: "fsd".into(), src: Reg::F(15), base: x(8), offset: -24 });
+        assert_eq!(parse("addw\ta5,a5,a4"), Instruction::RegReg { op: "addw".into(), rd: x(15), rs1: x(15), rs2: x(14) });
+        assert_eq!(parse("slliw a5,a5,0x1"), Instruction::RegImm { op: "slliw".into(), rd: x(15), rs1: x(15), imm: 1 });
+        assert_eq!(parse("lui a5,0x10"), Instruction::Upper { op: "lui".into(), rd: x(15), imm: 0x10 });
+        assert_eq!(parse("bne a4,a5,0x101f4 <main+24>"), Instruction::Branch { op: "bne".into(), rs1: x(14), rs2: x(15), target: 0x101f4 });
+        assert_eq!(parse("jal 0x10300 <f<int, int>+4>"), Instruction::Jal { rd: RA, target: 0x10300 });
+        assert_eq!(parse("jal ra,0x10300"), Instruction::Jal { rd: RA, target: 0x10300 });
+        assert_eq!(parse("jalr a5"), Instruction::Jalr { rd: RA, base: x(15), offset: 0 });
+        assert_eq!(parse("jalr ra,8(a5)"), Instruction::Jalr { rd: RA, base: x(15), offset: 8 });
+        assert_eq!(parse("ecall"), Instruction::System { op: "ecall".into(), args: String::new() });
+        assert_eq!(parse("fence iorw,iorw").to_string(), "fence iorw,iorw");
+        assert_eq!(parse("vsetvli a0,a1,e8").mnemonic(), "vsetvli");
+
+        // Pseudo-instructions expand to their base instruction, except `li` and `mv`.
+        assert_eq!(parse("ret"), Instruction::Jalr { rd: ZERO, base: RA, offset: 0 });
+        assert_eq!(parse("nop").to_string(), "addi x0, x0, 0");
+        assert_eq!(parse("j 0x10200 <main+36>").to_string(), "jal x0, 0x10200");
+        assert_eq!(parse("sext.w a4,a5").to_string(), "addiw x14, x15, 0");
+        assert_eq!(parse("bgtz a0,0x1000").to_string(), "blt x0, x10, 0x1000");
+        assert_eq!(parse("ble a0,a1,0x1000").to_string(), "bge x11, x10, 0x1000");
+        assert_eq!(parse("snez a0,a1").to_string(), "sltu x10, x0, x11");
+        assert_eq!(parse("fneg.d fa0,fa1").to_string(), "fsgnjn.d f10, f11, f11");
+        assert_eq!(parse("li a5,-1").to_string(), "li x15, -1");
+        assert_eq!(parse("mv a0,a5").to_string(), "mv x10, x15");
+
+        // CSRs are operands of their own, even when named like registers.
+        assert_eq!(parse("csrw fcsr,a0").to_string(), "csrrw x0, fcsr, x10");
+        assert_eq!(parse("frflags t0").to_string(), "csrrs x5, fflags, x0");
+        assert_eq!(parse("amoswap.w.aqrl a0,a1,(a2)").to_string(), "amoswap.w.aqrl x10, x11, (x12)");
+        assert_eq!(parse("lr.d a0,(a2)").mnemonic(), "lr.d");
+        assert_eq!(parse("fmadd.d fa0,fa1,fa2,fa3,rne").to_string(), "fmadd.d f10, f11, f12, f13, rne");
+        assert_eq!(parse("fcvt.w.d a0,fa0,rtz").to_string(), "fcvt.w.d x10, f10, rtz");
+
+        // Canonical output parses back to the same instruction.
+        for text in ["sd x1, 56(x2)", "bne x14, x15, 0x101f4", "csrrwi x6, frm, 3", "amoadd.d x15, x14, (x10)"] {
+            assert_eq!(parse(text).to_string(), text);
+        }
+
+        assert!(Instruction::parse("sd x1, 56").is_err());
+        assert!(Instruction::parse("add x1, x2").is_err());
+        assert!(Instruction::parse("beq x1, x2, main").is_err());
+        assert_eq!(Reg::parse("x32"), None);
+        assert_eq!(Reg::parse("fcsr"), None);
+    }
+}
@@ -1,13 +1,42 @@
 pub mod trace_parser;
+pub mod instruction;
+pub mod decoder;
 pub mod zk;
 pub mod circuit;
+pub mod alu_gate;
+pub mod lookup;
+pub mod csr;
+pub mod syscall;
+pub mod memory;
+pub mod atomic;
+pub mod fp;
+pub mod bitmanip;
+pub mod elf;
+pub mod dwarf;
+pub mod emulator;
+pub mod rsp;
+pub mod qemu_log;
+pub mod window;
+pub mod inputs;
+pub mod trace_format;
+pub mod trace_source;
 pub mod verifier;
 pub mod store;
 pub mod program_runner;
+pub mod error;
 
-pub use trace_parser::{TraceEntry, parse_trace};
-pub use zk::{InstructionRow, convert_trace_to_rows};
-pub use circuit::prove_multi_instruction_constraint;
+pub use error::{Error, Result};
+pub use trace_parser::{TraceEntry, TraceReader, DecodeMismatch, parse_trace, parse_trace_checked};
+pub use instruction::{Instruction, Reg};
+pub use zk::{InstructionRow, ConvertOptions, ConversionMode, UnprovenSpan, UnsupportedInstructions, RowConverter, RowStream, convert_trace_to_rows, convert_trace_to_rows_with, stream_rows};
+pub use circuit::{prove_multi_instruction_constraint, prove_rows};
 pub use verifier::verify_instruction_proof;
-pub use store::{save_proof_and_circuit, load_proof_and_circuit};
-pub use program_runner::run_program;
+pub use store::{save_proof_and_circuit, load_proof_and_circuit, TraceGateSerializer, TraceGeneratorSerializer};
+pub use program_runner::{run_program, trace_program_rsp, Qemu, RunError, Termination};
+pub use qemu_log::{parse_qemu_log, run_qemu_log};
+pub use elf::ElfFile;
+pub use emulator::Emulator;
+pub use trace_source::{TraceSource, TraceFile, GdbRunner, RspRunner, QemuLogRunner, EmulatorRunner, prove_trace, prove_window};
+pub use window::{Window, WindowBoundary, WindowOutputs};
+pub use inputs::{ProgramInputs, SensorInput};
+pub use trace_format::{StructuredTrace, TraceEncoding, TraceHeader, TracerBackend, TRACE_FORMAT_VERSION};
@@ -0,0 +1,391 @@
+use std::sync::Arc;
+
+use plonky2::field::extension::Extendable;
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::Field;
+use plonky2::gates::lookup_table::LookupTable;
+use plonky2::hash::hash_types::RichField;
+use plonky2::iop::generator::{GeneratedValues, SimpleGenerator};
+use plonky2::iop::target::Target;
+use plonky2::iop::witness::{PartitionWitness, Witness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::circuit_data::CommonCircuitData;
+use plonky2::util::serialization::{Buffer, IoResult, Read, Write};
+
+/// Byte-wise bitwise operations backed by a 16-bit lookup table on `(a << 8) | b`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOp {
+    And,
+    Or,
+    Xor,
+}
+
+impl ByteOp {
+    fn apply(self, a: u16, b: u16) -> u16 {
+        match self {
+            ByteOp::And => a & b,
+            ByteOp::Or => a | b,
+            ByteOp::Xor => a ^ b,
+        }
+    }
+}
+
+/// Lookup tables shared by all gadgets of one circuit.
+///
+/// Tables are only added to the builder on first use, since plonky2 rejects circuits
+/// containing an unused lookup table.
+#[derive(Debug, Default)]
+pub struct LookupTables {
+    range8: Option<usize>,
+    range16: Option<usize>,
+    byte_ops: [Option<usize>; 3],
+}
+
+impl LookupTables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn range8_index(&mut self, builder: &mut CircuitBuilder<GoldilocksField, 2>) -> usize {
+        *self.range8.get_or_insert_with(|| {
+            let table: LookupTable = Arc::new((0..=u8::MAX as u16).map(|i| (i, i)).collect());
+            builder.add_lookup_table_from_pairs(table)
+        })
+    }
+
+    fn range16_index(&mut self, builder: &mut CircuitBuilder<GoldilocksField, 2>) -> usize {
+        *self.range16.get_or_insert_with(|| {
+            let table: LookupTable = Arc::new((0..=u16::MAX).map(|i| (i, i)).collect());
+            builder.add_lookup_table_from_pairs(table)
+        })
+    }
+
+    fn byte_op_index(&mut self, builder: &mut CircuitBuilder<GoldilocksField, 2>, op: ByteOp) -> usize {
+        *self.byte_ops[op as usize].get_or_insert_with(|| {
+            let table: LookupTable = Arc::new(
+                (0..=u16::MAX)
+                    .map(|i| (i, op.apply(i >> 8, i & 0xff)))
+                    .collect(),
+            );
+            builder.add_lookup_table_from_pairs(table)
+        })
+    }
+
+    /// Constrains `x < 2^8`.
+    pub fn range_check_8(&mut self, builder: &mut CircuitBuilder<GoldilocksField, 2>, x: Target) {
+        let lut = self.range8_index(builder);
+        builder.add_lookup_from_index(x, lut);
+    }
+
+    /// Constrains `x < 2^16`.
+    pub fn range_check_16(&mut self, builder: &mut CircuitBuilder<GoldilocksField, 2>, x: Target) {
+        let lut = self.range16_index(builder);
+        builder.add_lookup_from_index(x, lut);
+    }
+
+    /// Constrains `x < 2^bits`.
+    ///
+    /// Up to 16 bits this is two lookups: `x` and `x << (16 - bits)` must both fit in 16 bits.
+    /// Wider values are split into 16-bit limbs and only the top limb gets the shifted check.
+    pub fn range_check_bits(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        x: Target,
+        bits: usize,
+    ) {
+        assert!(bits > 0 && bits <= 64, "unsupported range check width: {}", bits);
+        if bits > 16 {
+            // The top limb check keeps the limbs below 2^64 - 2^32, so they cannot wrap.
+            let limbs = self.split_limbs_unchecked(builder, x, 16, bits.div_ceil(16));
+            let top = *limbs.last().unwrap();
+            let top_bits = bits - 16 * (limbs.len() - 1);
+            if top_bits < 16 {
+                self.range_check_bits(builder, top, top_bits);
+            }
+            return;
+        }
+
+        self.range_check_16(builder, x);
+        if bits < 16 {
+            let shifted = builder.mul_const(GoldilocksField::from_canonical_u64(1 << (16 - bits)), x);
+            self.range_check_16(builder, shifted);
+        }
+    }
+
+    /// Decomposes `x` into `num_limbs` little-endian limbs of `limb_bits` (8 or 16) bits each.
+    ///
+    /// Every limb is range checked through a lookup and the limbs must recombine to `x`.
+    /// The limb values are filled in by [`LimbSplitGenerator`]. Limbs covering all 64 bits
+    /// could recombine to `x + p`, so those splits go through [`Self::split_u64_limbs`].
+    pub fn split_limbs(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        x: Target,
+        limb_bits: usize,
+        num_limbs: usize,
+    ) -> Vec<Target> {
+        assert!(limb_bits * num_limbs < 64, "64-bit splits must use split_u64_limbs");
+        self.split_limbs_unchecked(builder, x, limb_bits, num_limbs)
+    }
+
+    /// [`Self::split_limbs`] without the bound on the width, for callers that rule out
+    /// limbs of `x + p` themselves.
+    fn split_limbs_unchecked(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        x: Target,
+        limb_bits: usize,
+        num_limbs: usize,
+    ) -> Vec<Target> {
+        assert!(limb_bits == 8 || limb_bits == 16, "limbs must be 8 or 16 bits wide");
+        assert!(limb_bits * num_limbs <= 64, "limbs cover more than 64 bits");
+
+        let limbs = builder.add_virtual_targets(num_limbs);
+        builder.add_simple_generator(LimbSplitGenerator {
+            source: x,
+            limbs: limbs.clone(),
+            limb_bits,
+        });
+
+        for &limb in &limbs {
+            if limb_bits == 8 {
+                self.range_check_8(builder, limb);
+            } else {
+                self.range_check_16(builder, limb);
+            }
+        }
+        let shift = GoldilocksField::from_canonical_u64(1 << limb_bits);
+        let mut acc = limbs[num_limbs - 1];
+        for &limb in limbs[..num_limbs - 1].iter().rev() {
+            acc = builder.mul_const_add(shift, acc, limb);
+        }
+        builder.connect(acc, x);
+        limbs
+    }
+
+    /// Decomposes a canonical field element into four 16-bit limbs.
+    ///
+    /// Since `p = 2^64 - 2^32 + 1`, a limb sum can wrap around the field: the upper 32 bits
+    /// being all ones forces the lower 32 bits to be zero, which rules out `x + p`.
+    pub fn split_u64_limbs(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        x: Target,
+    ) -> [Target; 4] {
+        let limbs = self.split_limbs_unchecked(builder, x, 16, 4);
+        let shift = GoldilocksField::from_canonical_u64(1 << 16);
+        let lo = builder.mul_const_add(shift, limbs[1], limbs[0]);
+        let hi = builder.mul_const_add(shift, limbs[3], limbs[2]);
+        let max_hi = builder.constant(GoldilocksField::from_canonical_u64(u32::MAX as u64));
+        let hi_is_max = builder.is_equal(hi, max_hi);
+        let wrapped = builder.mul(hi_is_max.target, lo);
+        builder.assert_zero(wrapped);
+        [limbs[0], limbs[1], limbs[2], limbs[3]]
+    }
+
+    /// Decomposes a canonical field element into eight bytes, through the 16-bit limbs of
+    /// [`Self::split_u64_limbs`].
+    pub fn split_u64_bytes(&mut self, builder: &mut CircuitBuilder<GoldilocksField, 2>, x: Target) -> Vec<Target> {
+        let limbs = self.split_u64_limbs(builder, x);
+        limbs.iter().flat_map(|&limb| self.split_limbs(builder, limb, 8, 2)).collect()
+    }
+
+    /// Applies `op` to two values that are already known to be bytes.
+    pub fn byte_op(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        op: ByteOp,
+        a: Target,
+        b: Target,
+    ) -> Target {
+        let lut = self.byte_op_index(builder, op);
+        let packed = builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << 8), a, b);
+        builder.add_lookup_from_index(packed, lut)
+    }
+
+    /// Applies `op` byte by byte to two 64-bit values given as canonical field elements.
+    ///
+    /// The result is recombined in the field, so outputs of `2^64 - 2^32 + 1` or more are
+    /// reduced like every other register value in the circuit.
+    pub fn bitwise_u64(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        op: ByteOp,
+        a: Target,
+        b: Target,
+    ) -> Target {
+        let a_bytes = self.split_u64_bytes(builder, a);
+        let b_bytes = self.split_u64_bytes(builder, b);
+        let mut acc = builder.zero();
+        for (&x, &y) in a_bytes.iter().zip(&b_bytes).rev() {
+            let byte = self.byte_op(builder, op, x, y);
+            acc = builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << 8), acc, byte);
+        }
+        acc
+    }
+}
+
+/// Splits `source` into little-endian limbs of `limb_bits` bits.
+#[derive(Debug, Clone, Default)]
+pub struct LimbSplitGenerator {
+    source: Target,
+    limbs: Vec<Target>,
+    limb_bits: usize,
+}
+
+impl<F: RichField + Extendable<D>, const D: usize> SimpleGenerator<F, D> for LimbSplitGenerator {
+    fn id(&self) -> String {
+        "LimbSplitGenerator".to_string()
+    }
+
+    fn dependencies(&self) -> Vec<Target> {
+        vec![self.source]
+    }
+
+    fn run_once(
+        &self,
+        witness: &PartitionWitness<F>,
+        out_buffer: &mut GeneratedValues<F>,
+    ) -> anyhow::Result<()> {
+        let mut value = witness.get_target(self.source).to_canonical_u64();
+        let mask = (1u64 << self.limb_bits) - 1;
+        for &limb in &self.limbs {
+            out_buffer.set_target(limb, F::from_canonical_u64(value & mask))?;
+            value >>= self.limb_bits;
+        }
+        Ok(())
+    }
+
+    fn serialize(&self, dst: &mut Vec<u8>, _common_data: &CommonCircuitData<F, D>) -> IoResult<()> {
+        dst.write_target(self.source)?;
+        dst.write_target_vec(&self.limbs)?;
+        dst.write_usize(self.limb_bits)
+    }
+
+    fn deserialize(src: &mut Buffer, _common_data: &CommonCircuitData<F, D>) -> IoResult<Self> {
+        let source = src.read_target()?;
+        let limbs = src.read_target_vec()?;
+        let limb_bits = src.read_usize()?;
+        Ok(Self { source, limbs, limb_bits })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use plonky2::field::types::{Field64, PrimeField64};
+    use plonky2::iop::generator::WitnessGeneratorRef;
+    use plonky2::iop::witness::PartialWitness;
+    use plonky2::plonk::circuit_data::CircuitConfig;
+    use plonky2::plonk::config::PoseidonGoldilocksConfig;
+
+    use super::*;
+
+    type F = GoldilocksField;
+
+    #[test]
+    fn test_range_checks_and_byte_ops() -> anyhow::Result<()> {
+        let mut builder = CircuitBuilder::<F, 2>::new(CircuitConfig::standard_recursion_config());
+        let mut tables = LookupTables::new();
+
+        let imm12 = builder.add_virtual_target();
+        tables.range_check_bits(&mut builder, imm12, 12);
+        let imm20 = builder.add_virtual_target();
+        tables.range_check_bits(&mut builder, imm20, 20);
+
+        let a = builder.add_virtual_target();
+        let b = builder.add_virtual_target();
+        let xor = tables.bitwise_u64(&mut builder, ByteOp::Xor, a, b);
+        let and = tables.bitwise_u64(&mut builder, ByteOp::And, a, b);
+        let limbs = tables.split_u64_limbs(&mut builder, a);
+        builder.register_public_input(xor);
+        builder.register_public_input(and);
+        builder.register_public_inputs(&limbs);
+
+        let data = builder.build::<PoseidonGoldilocksConfig>();
+        let (x, y) = (0x1234_5678_9abc_def0u64, 0x0f0f_0f0f_f0f0_f0f0u64);
+        let mut pw = PartialWitness::new();
+        pw.set_target(imm12, F::from_canonical_u64(4095))?;
+        pw.set_target(imm20, F::from_canonical_u64((1 << 20) - 1))?;
+        pw.set_target(a, F::from_canonical_u64(x))?;
+        pw.set_target(b, F::from_canonical_u64(y))?;
+        let proof = data.prove(pw)?;
+        assert_eq!(proof.public_inputs[0], F::from_canonical_u64(x ^ y));
+        assert_eq!(proof.public_inputs[1], F::from_canonical_u64(x & y));
+        assert_eq!(proof.public_inputs[2], F::from_canonical_u64(0xdef0));
+        data.verify(proof)?;
+
+        let mut pw = PartialWitness::new();
+        pw.set_target(imm12, F::from_canonical_u64(4096))?;
+        pw.set_target(imm20, F::ZERO)?;
+        pw.set_target(a, F::ZERO)?;
+        pw.set_target(b, F::ZERO)?;
+        assert!(data.prove(pw).is_err());
+        Ok(())
+    }
+
+    /// [`LimbSplitGenerator`] of a malicious prover, which gives the limbs of `x + p` for
+    /// 64-bit splits of `x < 2^32 - 1`.
+    #[derive(Debug)]
+    struct WrappingSplit(LimbSplitGenerator);
+
+    impl SimpleGenerator<F, 2> for WrappingSplit {
+        fn id(&self) -> String {
+            "WrappingSplit".to_string()
+        }
+
+        fn dependencies(&self) -> Vec<Target> {
+            vec![self.0.source]
+        }
+
+        fn run_once(&self, witness: &PartitionWitness<F>, out_buffer: &mut GeneratedValues<F>) -> anyhow::Result<()> {
+            let mut value = witness.get_target(self.0.source).to_canonical_u64();
+            if self.0.limbs.len() * self.0.limb_bits == 64 && value < u32::MAX as u64 {
+                value += F::ORDER;
+            }
+            let mask = (1u64 << self.0.limb_bits) - 1;
+            for &limb in &self.0.limbs {
+                out_buffer.set_target(limb, F::from_canonical_u64(value & mask))?;
+                value >>= self.0.limb_bits;
+            }
+            Ok(())
+        }
+
+        fn serialize(&self, dst: &mut Vec<u8>, common_data: &CommonCircuitData<F, 2>) -> IoResult<()> {
+            SimpleGenerator::<F, 2>::serialize(&self.0, dst, common_data)
+        }
+
+        fn deserialize(src: &mut Buffer, common_data: &CommonCircuitData<F, 2>) -> IoResult<Self> {
+            Ok(WrappingSplit(SimpleGenerator::<F, 2>::deserialize(src, common_data)?))
+        }
+    }
+
+    #[test]
+    fn test_bitwise_rejects_limbs_of_x_plus_p() -> anyhow::Result<()> {
+        let mut builder = CircuitBuilder::<F, 2>::new(CircuitConfig::standard_recursion_config());
+        let mut tables = LookupTables::new();
+        let a = builder.add_virtual_target();
+        let b = builder.add_virtual_target();
+        let xor = tables.bitwise_u64(&mut builder, ByteOp::Xor, a, b);
+        builder.register_public_input(xor);
+        let mut data = builder.build::<PoseidonGoldilocksConfig>();
+
+        let witness = |x: u64, y: u64| -> anyhow::Result<PartialWitness<F>> {
+            let mut pw = PartialWitness::new();
+            pw.set_target(a, F::from_canonical_u64(x))?;
+            pw.set_target(b, F::from_canonical_u64(y))?;
+            Ok(pw)
+        };
+        data.verify(data.prove(witness(5, 3)?)?)?;
+
+        for generator in &mut data.prover_only.generators {
+            if generator.0.id() == "LimbSplitGenerator" {
+                let mut bytes = Vec::new();
+                generator.0.serialize(&mut bytes, &data.common).unwrap();
+                let split = SimpleGenerator::<F, 2>::deserialize(&mut Buffer::new(&bytes), &data.common).unwrap();
+                *generator = WitnessGeneratorRef::new(WrappingSplit(split).adapter());
+            }
+        }
+        assert!(data.prove(witness(5, 3)?).is_err());
+        Ok(())
+    }
+}
@@ -1,34 +1,44 @@
-use riscv_trace_reader::{parse_trace, convert_trace_to_rows};
-use riscv_trace_reader::prove_multi_instruction_constraint;
-use riscv_trace_reader::{save_proof_and_circuit, load_proof_and_circuit};
-use riscv_trace_reader::run_program;
+use riscv_trace_reader::{prove_trace, ConvertOptions, GdbRunner, ProgramInputs, TraceSource};
+use riscv_trace_reader::{save_proof_and_circuit, load_proof_and_circuit, verify_instruction_proof};
 
 fn main() {
-    let bin = "./test.bin";
-    let trace = "./traces/trace_cleaned.log";
-    // let trace = "./traces/sample_trace.log";
+    let mut source = GdbRunner {
+        program: "./test.bin".to_string(),
+        inputs: ProgramInputs::default(),
+        trace_path: "./traces/trace_cleaned.log".to_string(),
+        max_steps: 1_000_000,
+    };
+    // let mut source = TraceFile { path: "./traces/sample_trace.log".to_string() };
 
-    run_program(bin, trace);
-    let parsed = parse_trace(trace);
-    let rows = convert_trace_to_rows(&parsed);
+    prove(&mut source);
+}
 
-    match prove_multi_instruction_constraint(&rows) {
+fn prove(source: &mut impl TraceSource) {
+    match prove_trace(source, &ConvertOptions::default()) {
         Ok((proof, circuit)) => {
             println!("✅ Generated Proof Successfully!");
-            println!("Generated proof for {} instructions", rows.len());
             println!("Public inputs: {:?}", proof.public_inputs);
 
-            save_proof_and_circuit(&proof, &circuit, "./proof.bin", "./circuit.bin");
+            if let Err(e) = save_proof_and_circuit(&proof, &circuit, "./proof.bin", "./circuit.bin") {
+                println!("❌ Failed to save the proof: {}", e);
+                std::process::exit(1);
+            }
             println!("Proof and circuit saved to files.");
 
-            let (proof_loaded, circuit_loaded) = load_proof_and_circuit("./proof.bin", "./circuit.bin");
-            match circuit_loaded.verify(proof_loaded) {
+            let (proof_loaded, circuit_loaded) = match load_proof_and_circuit("./proof.bin", "./circuit.bin") {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    println!("❌ Failed to load the proof: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            match verify_instruction_proof(proof_loaded, &circuit_loaded) {
                 Ok(_) => println!("✅ Proof verified!"),
-                Err(e) => println!("❌ Verification failed: {:?}", e),
+                Err(e) => println!("❌ {}", e),
             }
         }
         Err(e) => {
-            println!("❌ Failed to Generate Proof: {:?}", e);
+            println!("❌ Failed to Generate Proof: {}", e);
             std::process::exit(1);
         }
     }
@@ -0,0 +1,293 @@
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::Field;
+use plonky2::iop::target::Target;
+use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+
+use crate::alu_gate::IMM12_BIAS;
+use crate::atomic::sign_extend_into;
+use crate::bitmanip::wrapped;
+use crate::circuit::RowTargets;
+use crate::lookup::LookupTables;
+
+/// Addresses are below `2^48`, the user address space of Sv48, so that an address and the
+/// index of its word are canonical field elements.
+pub const ADDRESS_BITS: usize = 48;
+
+/// A plain load or store of an instruction row (`ld`, `lw`, `sd` and their FP forms
+/// `fld`, `flw`, `fsd`, `fsw`), whose address is `rs1 + imm`.
+///
+/// Stores carry the stored register in `rs2` and, in `rd`, whether its value is
+/// `2^64 - 2^32 + 1` or more, which tells it from its reduction in the field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRow {
+    pub opcode: u64,
+    pub store: bool,
+    /// Whether the access is to a doubleword rather than a word.
+    pub double: bool,
+    /// Whether the register is an f-register, into which words are NaN-boxed rather than
+    /// sign-extended.
+    pub float: bool,
+    /// Value loaded, zero-extended, or the whole register stored.
+    pub value: u64,
+    /// Memory before the access; equal to `value` for loads.
+    pub before: u64,
+}
+
+/// A memory access recorded in the log.
+#[derive(Debug, Clone, Copy)]
+struct Access {
+    /// Index of the 32-bit word, its address divided by 4.
+    word: Target,
+    /// Value held in the word after the access; equal to the value read for pure reads.
+    written: Target,
+}
+
+/// Memory consistency argument over the accesses of a trace, in program order.
+///
+/// Every access reads a value and leaves one behind. The value read must be the one left
+/// by the latest earlier access to the same word; the first access to a word reads a free
+/// initial value. The log is kept over aligned 32-bit words: a doubleword access is an
+/// access to each of its two words, so words and doublewords at overlapping addresses see
+/// each other's values. Narrower accesses are not modeled.
+///
+/// Each access is compared against all earlier ones, which is quadratic in the number of
+/// accesses but needs no sorting witness.
+#[derive(Debug, Default)]
+pub struct MemoryLog {
+    accesses: Vec<Access>,
+    witness: Vec<(Target, GoldilocksField)>,
+}
+
+impl MemoryLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of word accesses recorded so far.
+    pub fn len(&self) -> usize {
+        self.accesses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accesses.is_empty()
+    }
+
+    /// Records a naturally aligned word or doubleword access at `addr`, given by the 32-bit
+    /// halves it reads and writes, least significant first.
+    ///
+    /// The address is divided by its width in the field and range checked, so that only
+    /// aligned addresses below `2^ADDRESS_BITS` have a valid index.
+    pub fn access(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        tables: &mut LookupTables,
+        addr: Target,
+        read: &[Target],
+        written: &[Target],
+    ) {
+        let words = read.len();
+        let width = GoldilocksField::from_canonical_usize(4 * words);
+        let index = builder.mul_const(width.inverse(), addr);
+        tables.range_check_bits(builder, index, ADDRESS_BITS - 1 - words);
+        for (i, (&read, &written)) in read.iter().zip(written).enumerate() {
+            let first = builder.mul_const(GoldilocksField::from_canonical_usize(words), index);
+            let word = builder.add_const(first, GoldilocksField::from_canonical_usize(i));
+            self.access_word(builder, word, read, written);
+        }
+    }
+
+    /// Records an access to the word with index `word` that reads `read` and leaves
+    /// `written` in memory.
+    fn access_word(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        word: Target,
+        read: Target,
+        written: Target,
+    ) {
+        let mut expected = read;
+        for prev in &self.accesses {
+            let same = builder.is_equal(prev.word, word);
+            expected = builder.select(same, prev.written, expected);
+        }
+        builder.connect(expected, read);
+        self.accesses.push(Access { word, written });
+    }
+
+    /// Adds the constraints of a plain load or store row, and returns its address.
+    ///
+    /// The row has the opcode and 12-bit immediate of its instruction, as on the ALU. Loads
+    /// write the value read to `rd`, sign-extending or NaN-boxing words. Stores write the
+    /// low halves of `rs2`, which are witnessed as the register value rather than its
+    /// reduction.
+    pub fn add_load_store_row(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        tables: &mut LookupTables,
+        targets: &RowTargets,
+        row: &MemoryRow,
+    ) -> Target {
+        let opcode = builder.constant(GoldilocksField::from_canonical_u64(row.opcode));
+        builder.connect(targets.opcode, opcode);
+        let one = builder.one();
+        builder.connect(targets.imm_flag.target, one);
+        let biased = builder.add_const(targets.imm_val, GoldilocksField::from_canonical_u64(IMM12_BIAS));
+        tables.range_check_bits(builder, biased, 12);
+
+        let addr = builder.add(targets.rs1, targets.imm_val);
+        if row.store {
+            let before = self.halves(builder, tables, row.before, row.double);
+            let reg = self.halves(builder, tables, row.value, true);
+            let stored = builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << 32), reg[1], reg[0]);
+            builder.connect(targets.rs2, stored);
+            let flag = wrapped(builder, [reg[0], reg[1]]);
+            builder.connect(targets.rd, flag.target);
+            self.access(builder, tables, addr, &before, &reg[..before.len()]);
+        } else {
+            let value = self.halves(builder, tables, row.value, row.double);
+            if row.float && !row.double {
+                // The box fills the upper half with ones: w + 2^64 - 2^32 is w - 1 in the field.
+                let boxed = builder.add_const(value[0], GoldilocksField::NEG_ONE);
+                builder.connect(targets.rd, boxed);
+            } else {
+                sign_extend_into(builder, tables, &value, targets.rd);
+            }
+            self.access(builder, tables, addr, &value, &value);
+        }
+        addr
+    }
+
+    /// Witnesses the 32-bit halves of `value`, one for words and two for doublewords.
+    fn halves(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        tables: &mut LookupTables,
+        value: u64,
+        double: bool,
+    ) -> Vec<Target> {
+        let count = if double { 2 } else { 1 };
+        (0..count)
+            .map(|i| {
+                let half = builder.add_virtual_target();
+                tables.range_check_bits(builder, half, 32);
+                self.witness.push((half, GoldilocksField::from_canonical_u64((value >> (32 * i)) & 0xffff_ffff)));
+                half
+            })
+            .collect()
+    }
+
+    /// Assigns the values of the loads and stores.
+    pub fn set_witness(&self, pw: &mut PartialWitness<GoldilocksField>) -> Result<(), anyhow::Error> {
+        for &(target, value) in &self.witness {
+            pw.set_target(target, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::prove_multi_instruction_constraint;
+    use crate::trace_parser::{RegisterFile, TraceEntry};
+    use crate::zk::convert_trace_to_rows;
+
+    fn load_store(opcode: &str, base: u64, imm: i64, value: u64) -> TraceEntry {
+        let store = opcode.trim_start_matches('f').starts_with('s');
+        let reg = if opcode.starts_with('f') { "f11" } else { "x11" };
+        let before = RegisterFile::default().with(&[("x10", base), (reg, if store { value } else { 0 })]);
+        TraceEntry {
+            opcode: opcode.to_string(),
+            rd: (!store).then(|| reg.to_string()),
+            rs1: Some("x10".to_string()),
+            rs2: store.then(|| reg.to_string()),
+            imm: Some(imm),
+            regs_before: before,
+            regs_after: before.with(&[(reg, value)]),
+            ..Default::default()
+        }
+    }
+
+    fn atomic(opcode: &str, addr: u64, src: Option<u64>, rd_after: u64) -> TraceEntry {
+        let before = RegisterFile::default().with(&[("x10", addr), ("x14", src.unwrap_or(0))]);
+        TraceEntry {
+            opcode: opcode.to_string(),
+            rd: Some("x15".to_string()),
+            rs1: Some("x10".to_string()),
+            rs2: src.map(|_| "x14".to_string()),
+            regs_before: before,
+            regs_after: before.with(&[("x15", rd_after)]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_loads_and_stores_follow_memory() {
+        let a = 0x2000;
+        let trace = vec![
+            load_store("sd", a, 8, u64::MAX),
+            load_store("lw", a, 12, u64::MAX), // the high half of -1, sign-extended
+            load_store("ld", a + 16, -8, u64::MAX),
+            load_store("sd", a, 8, 5),
+            atomic("lr.d", a + 8, None, 5),
+            load_store("sd", a + 8, 0, 6), // breaks the reservation
+            atomic("sc.d", a + 8, Some(9), 1),
+            atomic("amoadd.d", a + 8, Some(9), 6),
+            load_store("lw", a, 8, 15),
+        ];
+        let rows = convert_trace_to_rows(&trace).unwrap();
+        // A store's rd says that -1 is p or more.
+        assert_eq!(rows[0].rs2_val, GoldilocksField::from_noncanonical_u64(u64::MAX));
+        assert_eq!(rows[0].rd_val, GoldilocksField::ONE);
+        let (proof, data) = prove_multi_instruction_constraint(&rows).unwrap();
+        data.verify(proof).unwrap();
+
+        // The load must read what the store left.
+        let mut bad = rows.clone();
+        bad[2].rd_val = GoldilocksField::from_canonical_u64(7);
+        bad[2].memory.as_mut().unwrap().value = 7;
+        assert!(prove_multi_instruction_constraint(&bad).is_err());
+
+        // sc cannot succeed once a plain store has written to the reserved address.
+        let mut bad = rows.clone();
+        bad[6].rd_val = GoldilocksField::ZERO;
+        assert!(prove_multi_instruction_constraint(&bad).is_err());
+
+        // A trace that reads something else is refused rather than proven.
+        let mut wrong = trace.clone();
+        wrong[2] = load_store("ld", a + 16, -8, 7);
+        let err = convert_trace_to_rows(&wrong).unwrap_err();
+        assert!(err.to_string().contains("ld x1 (first at 0x00000000: memory at 0x2008 holds"), "{}", err);
+    }
+
+    #[test]
+    fn test_fp_loads_and_stores_follow_memory() {
+        let a = 0x3000;
+        let one = 1.0f64.to_bits();
+        let boxed = 0xffff_ffff_0000_0000 | 2.0f32.to_bits() as u64;
+        let trace = vec![
+            load_store("fsd", a, 0, one),
+            load_store("ld", a, 0, one),
+            load_store("fsw", a, 4, boxed), // only the low word is stored
+            load_store("flw", a, 4, boxed),
+            load_store("fld", a, 0, one & 0xffff_ffff | (boxed << 32)),
+        ];
+        let rows = convert_trace_to_rows(&trace).unwrap();
+        assert!(rows.iter().all(|row| row.fp.is_none() && row.memory.is_some()));
+        assert_eq!(rows[2].rd_val, GoldilocksField::ONE);
+        let (proof, data) = prove_multi_instruction_constraint(&rows).unwrap();
+        data.verify(proof).unwrap();
+
+        // flw must read the word fsw left.
+        let mut bad = rows.clone();
+        bad[3].rd_val = GoldilocksField::from_noncanonical_u64(boxed + 1);
+        bad[3].memory.as_mut().unwrap().value += 1;
+        assert!(prove_multi_instruction_constraint(&bad).is_err());
+
+        let mut wrong = trace.clone();
+        wrong[3] = load_store("flw", a, 4, 2.0f32.to_bits() as u64);
+        let err = convert_trace_to_rows(&wrong).unwrap_err();
+        assert!(err.to_string().contains("is not a NaN-boxed word"), "{}", err);
+    }
+}
@@ -1,98 +1,560 @@
-use std::fs::{File, write};
-use std::io::Write;
-use std::process::{Command, Stdio};
+use std::fmt;
+use std::fs::write;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use crate::decoder::{decode, memory_operand, MemoryOperand};
+use crate::elf::ElfFile;
+use crate::inputs::ProgramInputs;
+use crate::rsp::{self, RspClient, RspError};
+use crate::trace_parser::TraceEntry;
+
+/// How a traced program run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// The traced function returned to its caller.
+    Returned,
+    /// The program exited with this code, through an `exit` ecall or otherwise.
+    Exited(u64),
+}
 
-pub fn run_program(program_path: &str, trace_path: &str) {
-    println!("[*] Starting QEMU...");
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunError {
+    Io(String),
+    /// A tool could not be started, e.g. because it is not installed.
+    Spawn { program: String, error: String },
+    /// QEMU exited before its gdbstub was listening, with its exit status and stderr.
+    QemuExited { status: String, stderr: String },
+    /// The gdbstub was not listening on `port` within the readiness timeout.
+    NotReady { port: u16, timeout: Duration },
+    /// The step budget ran out before the program finished; the trace would be truncated.
+    StepLimit(usize),
+    /// gdb stopped without reporting how the program ended, e.g. after a fault.
+    Incomplete(String),
+    Rsp(RspError),
+}
 
-    let mut qemu_child = Command::new("qemu-riscv64")
-        .arg("-g")
-        .arg("1234")
-        .arg("-singlestep")
-        .arg(program_path)
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .expect("Failed to start QEMU");
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Io(e) => write!(f, "cannot trace program: {}", e),
+            RunError::Spawn { program, error } => write!(f, "failed to start {}: {}", program, error),
+            RunError::QemuExited { status, stderr } => write!(f, "QEMU exited early ({}): {}", status, stderr),
+            RunError::NotReady { port, timeout } => {
+                write!(f, "QEMU's gdbstub was not listening on port {} after {:?}", port, timeout)
+            }
+            RunError::StepLimit(max) => write!(f, "the program did not finish within {} steps", max),
+            RunError::Incomplete(e) => write!(f, "tracing stopped early: {}", e),
+            RunError::Rsp(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+impl From<RspError> for RunError {
+    fn from(e: RspError) -> Self {
+        match e {
+            RspError::StepLimit(max) => RunError::StepLimit(max),
+            e => RunError::Rsp(e),
+        }
+    }
+}
+
+/// How long to wait for QEMU's gdbstub to listen before giving up.
+pub const QEMU_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A port nobody listens on at the moment, chosen by the OS.
+///
+/// Another process may take it before QEMU does; [`Qemu::spawn`] then fails with
+/// [`RunError::QemuExited`] rather than tracing someone else's program.
+pub fn free_port() -> Result<u16, RunError> {
+    let listener = TcpListener::bind("127.0.0.1:0").map_err(|e| RunError::Io(format!("no free port: {}", e)))?;
+    listener.local_addr().map(|a| a.port()).map_err(|e| RunError::Io(e.to_string()))
+}
+
+/// Whether a socket listens on `port`, from the kernel's socket tables. Probing by binding
+/// the port could make QEMU's own bind fail, and qemu-user only runs on Linux anyway.
+fn is_listening(port: u16) -> bool {
+    let local = format!(":{:04X}", port);
+    ["/proc/net/tcp", "/proc/net/tcp6"].iter().any(|table| {
+        std::fs::read_to_string(table).is_ok_and(|text| {
+            text.lines().skip(1).any(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                // `st` 0A is TCP_LISTEN.
+                fields.len() > 3 && fields[1].ends_with(&local) && fields[3] == "0A"
+            })
+        })
+    })
+}
+
+/// A `qemu-riscv64` command running `program_path` with the arguments and environment of
+/// `inputs`. The guest sees exactly `inputs.env` rather than inheriting the host environment,
+/// so that a run can be reproduced from its trace header.
+pub(crate) fn qemu_command(qemu_args: &[&str], program_path: &str, inputs: &ProgramInputs) -> Command {
+    let mut command = Command::new("qemu-riscv64");
+    command.env_clear().args(qemu_args);
+    for (key, value) in &inputs.env {
+        command.arg("-E").arg(format!("{}={}", key, value));
+    }
+    command.arg(program_path).args(&inputs.args);
+    command
+}
+
+/// Writes `stdin` to the child's piped stdin from a thread, then closes it so that the
+/// program reads end of file. A program exiting before reading everything breaks the pipe,
+/// which is ignored.
+pub(crate) fn feed_stdin(child: &mut Child, stdin: Vec<u8>) {
+    if let Some(mut pipe) = child.stdin.take() {
+        std::thread::spawn(move || {
+            let _ = pipe.write_all(&stdin);
+        });
+    }
+}
+
+/// A `qemu-riscv64` process waiting for a debugger on its gdbstub, killed when dropped so
+/// that no error path leaves it behind.
+#[derive(Debug)]
+pub struct Qemu {
+    child: Child,
+    port: u16,
+}
+
+impl Qemu {
+    /// Starts `program_path` under `qemu-riscv64 -g <port> -singlestep` on a free port, with
+    /// the arguments, environment and stdin of `inputs`, and waits up to `timeout` for the
+    /// gdbstub to listen. The sensor blob needs the debugger and is left to the caller.
+    ///
+    /// Readiness is checked without connecting, since QEMU accepts a single debugger
+    /// connection and would take a probe for it.
+    pub fn spawn(program_path: &str, inputs: &ProgramInputs, timeout: Duration) -> Result<Qemu, RunError> {
+        let port = free_port()?;
+        let command = qemu_command(&["-g", &port.to_string(), "-singlestep"], program_path, inputs);
+        Self::spawn_command(command, inputs.stdin.clone(), port, timeout)
+    }
 
-    sleep(Duration::from_secs(1));
+    fn spawn_command(mut command: Command, stdin: Vec<u8>, port: u16, timeout: Duration) -> Result<Qemu, RunError> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| RunError::Spawn { program: command.get_program().to_string_lossy().into_owned(), error: e.to_string() })?;
+        feed_stdin(&mut child, stdin);
+        let mut qemu = Qemu { child, port };
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = qemu.child.try_wait().map_err(|e| RunError::Io(e.to_string()))? {
+                let mut stderr = String::new();
+                if let Some(mut pipe) = qemu.child.stderr.take() {
+                    let _ = pipe.read_to_string(&mut stderr);
+                }
+                return Err(RunError::QemuExited { status: status.to_string(), stderr: stderr.trim().to_string() });
+            }
+            if is_listening(port) {
+                return Ok(qemu);
+            }
+            if Instant::now() >= deadline {
+                return Err(RunError::NotReady { port, timeout });
+            }
+            sleep(Duration::from_millis(20));
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// `host:port` of the gdbstub.
+    pub fn address(&self) -> String {
+        format!("localhost:{}", self.port)
+    }
+}
+
+impl Drop for Qemu {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
 
+/// Traces `program_path` under QEMU and gdb into `trace_path`, in the cleaned trace format.
+///
+/// The program gets `inputs`, which are recorded as the header of the trace (see
+/// [`ProgramInputs::header`]). Tracing starts at `main` (or the entry point) and stops when
+/// that function returns, when the program calls `exit`, or when QEMU reports that the
+/// process is gone. Running more than `max_steps` instructions is an error rather than a
+/// silently partial trace.
+pub fn run_program(
+    program_path: &str,
+    inputs: &ProgramInputs,
+    trace_path: &str,
+    max_steps: usize,
+) -> Result<Termination, RunError> {
+    let elf = ElfFile::load(program_path).map_err(|e| RunError::Io(e.to_string()))?;
+    // Start tracing at main, or at the entry point of binaries without one.
+    let start = elf.symbol("main").map_or(elf.entry, |main| main.value);
+
+    println!("[*] Starting QEMU...");
+    let qemu = Qemu::spawn(program_path, inputs, QEMU_READY_TIMEOUT)?;
+
+    // Files are named after the port, so that concurrent runs do not share them.
     println!("[*] Writing GDB script...");
-    let mut gdb_script = File::create("./trace.gdb").unwrap();
-    writeln!(gdb_script, "target remote localhost:1234").unwrap();
-    writeln!(gdb_script, "set pagination off").unwrap();
-    writeln!(gdb_script, "set confirm off").unwrap();
-    writeln!(gdb_script, "set disassemble-next-line on").unwrap();
-    writeln!(gdb_script, "set $pc = *main").unwrap();
-    writeln!(gdb_script, "set $done = 0").unwrap();
-    writeln!(gdb_script, "set $i = 0").unwrap();
-    writeln!(gdb_script, "define do_step").unwrap();
-    writeln!(gdb_script, "  si").unwrap();
-    writeln!(gdb_script, "  printf \"\\nPC: 0x%x\\n\", $pc").unwrap();
-    writeln!(gdb_script, "  x/i $pc").unwrap();
-    writeln!(gdb_script, "  info registers").unwrap();
-    writeln!(gdb_script, "  set $i = $i + 1").unwrap();
-    writeln!(gdb_script, "  if $i >= 1000").unwrap();
-    writeln!(gdb_script, "    set $done = 1").unwrap();
-    writeln!(gdb_script, "  end").unwrap();
-    writeln!(gdb_script, "end").unwrap();
-    writeln!(gdb_script, "while !$done").unwrap();
-    writeln!(gdb_script, "  do_step").unwrap();
-    writeln!(gdb_script, "end").unwrap();
-    writeln!(gdb_script, "quit").unwrap();
+    let temp_path = |name: &str| std::env::temp_dir().join(format!("{}-{}", name, qemu.port()));
+    let sensor_path = temp_path("sensor.bin");
+    let sensor = match &inputs.sensor {
+        Some(sensor) => {
+            write(&sensor_path, &sensor.data).map_err(|e| RunError::Io(format!("{}: {}", sensor_path.display(), e)))?;
+            Some((sensor_path.to_string_lossy().into_owned(), sensor.addr))
+        }
+        None => None,
+    };
+    let script_path = temp_path("trace.gdb");
+    write(&script_path, gdb_script(&qemu.address(), start, max_steps, sensor.as_ref(), &memory_operands(&elf)))
+        .map_err(|e| RunError::Io(format!("{}: {}", script_path.display(), e)))?;
 
     println!("[*] Running GDB...");
     let output = Command::new("riscv64-unknown-elf-gdb")
         .arg("-q")
+        .arg("-batch")
         .arg(program_path)
         .arg("-x")
-        .arg("./trace.gdb")
+        .arg(&script_path)
+        .stdin(Stdio::null())
         .output()
-        .expect("Failed to run GDB");
-
-    if !output.status.success() {
-        eprintln!("GDB exited with error:\n{}", String::from_utf8_lossy(&output.stderr));
-    }
+        .map_err(|e| RunError::Spawn { program: "riscv64-unknown-elf-gdb".to_string(), error: e.to_string() });
+    let _ = std::fs::remove_file(&script_path);
+    let _ = std::fs::remove_file(&sensor_path);
+    let output = output?;
 
     let trace = String::from_utf8_lossy(&output.stdout);
-    write("./traces/trace_raw.log", trace.as_bytes()).unwrap();
+    let raw_path = Path::new(trace_path).with_extension("raw.log");
+    write(&raw_path, trace.as_bytes()).map_err(|e| RunError::Io(format!("{}: {}", raw_path.display(), e)))?;
 
     println!("[*] Parsing trace...");
-    let parsed = parse_trace(&trace);
-    write(trace_path, parsed).unwrap();
+    let (parsed, end) = parse_trace(&trace);
+    write(trace_path, inputs.header() + &parsed).map_err(|e| RunError::Io(format!("{}: {}", trace_path, e)))?;
 
     println!("[*] Cleaning up...");
-    let _ = qemu_child.kill();
-    let _ = qemu_child.wait();
+    drop(qemu);
 
     println!("[*] Done. Output written to {}", trace_path);
+    match end {
+        Some(TraceEnd::Finished(termination)) => Ok(termination),
+        Some(TraceEnd::StepLimit) => Err(RunError::StepLimit(max_steps)),
+        None => Err(RunError::Incomplete(format!(
+            "gdb exited with {}, raw output in {}: {}",
+            output.status,
+            raw_path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
+
+/// gdb commands reading the data access `mem_decode` found, before and after the step.
+///
+/// `mem_load` reads `$mem_len` bytes at `$mem_addr` into `$mem_val`. After the step,
+/// `dump_memory` prints a `mem=<addr>,<width>,<read>[,<written>]` line, the last field only
+/// for writes.
+const MEMORY_ACCESS_GDB: &str = r#"define mem_load
+  if $mem_len == 1
+    set $mem_val = *(unsigned char *)$mem_addr
+  end
+  if $mem_len == 2
+    set $mem_val = *(unsigned short *)$mem_addr
+  end
+  if $mem_len == 4
+    set $mem_val = *(unsigned int *)$mem_addr
+  end
+  if $mem_len == 8
+    set $mem_val = *(unsigned long *)$mem_addr
+  end
+end
+define dump_memory
+  if $mem_len > 0
+    mem_load
+    printf "mem=0x%lx,%d,0x%lx", $mem_addr, $mem_len, $mem_read
+    if $mem_store
+      printf ",0x%lx", $mem_val
+    end
+    printf "\n"
+  end
+end
+"#;
+
+/// Loads, stores and AMOs in the executable segments of `elf`, by address, decoded with
+/// [`decode`]. Every halfword is tried, so that data in the code cannot misalign the sweep;
+/// the extra entries are at addresses the program never executes.
+fn memory_operands(elf: &ElfFile) -> Vec<(u64, MemoryOperand)> {
+    let mut operands = Vec::new();
+    for segment in elf.segments.iter().filter(|s| s.is_executable()) {
+        for offset in (0..segment.data.len().saturating_sub(1)).step_by(2) {
+            let bytes = &segment.data[offset..segment.data.len().min(offset + 4)];
+            let mut word = [0; 4];
+            word[..bytes.len()].copy_from_slice(bytes);
+            let pc = segment.vaddr + offset as u64;
+            if let Some(operand) = decode(pc, u32::from_le_bytes(word)).as_ref().and_then(memory_operand) {
+                operands.push((pc, operand));
+            }
+        }
+    }
+    operands
+}
+
+/// gdb command decoding the data access of the instruction at `$pc`, before it runs.
+///
+/// `mem_decode` looks `$pc` up in `operands` with a binary search, and sets `$mem_len` (0
+/// without access), `$mem_reg`, `$mem_off` and `$mem_store`, then `$mem_addr` and `$mem_read`
+/// from the base register and memory. The instructions are decoded here rather than in gdb,
+/// so the trace sees them exactly as the other backends do.
+fn mem_decode_gdb(operands: &[(u64, MemoryOperand)]) -> String {
+    fn lookup(script: &mut String, operands: &[(u64, MemoryOperand)], depth: usize) {
+        let indent = "  ".repeat(depth);
+        if operands.len() <= 4 {
+            for (pc, op) in operands {
+                script.push_str(&format!(
+                    "{indent}if $pc == 0x{:x}\n{indent}  set $mem_reg = {}\n{indent}  set $mem_off = {}\n\
+                     {indent}  set $mem_len = {}\n{indent}  set $mem_store = {}\n{indent}end\n",
+                    pc, op.base, op.offset, op.width, op.writes as u8
+                ));
+            }
+            return;
+        }
+        let (low, high) = operands.split_at(operands.len() / 2);
+        script.push_str(&format!("{indent}if $pc < 0x{:x}\n", high[0].0));
+        lookup(script, low, depth + 1);
+        script.push_str(&format!("{indent}else\n"));
+        lookup(script, high, depth + 1);
+        script.push_str(&format!("{indent}end\n"));
+    }
+    let mut script = String::from("define mem_decode\n  set $mem_len = 0\n");
+    lookup(&mut script, operands, 1);
+    script.push_str("  if $mem_len > 0\n    mem_base\n    set $mem_addr = $mem_base + $mem_off\n");
+    script.push_str("    mem_load\n    set $mem_read = $mem_val\n  end\nend\n");
+    script
+}
+
+/// gdb command setting `$mem_base` to the register numbered `$mem_reg`.
+fn mem_base_gdb() -> String {
+    const ABI_NAMES: [&str; 31] = [
+        "ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6",
+        "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+    ];
+    let mut script = String::from("define mem_base\n  set $mem_base = 0\n");
+    for (i, name) in ABI_NAMES.iter().enumerate() {
+        script.push_str(&format!("  if $mem_reg == {}\n    set $mem_base = (unsigned long)${}\n  end\n", i + 1, name));
+    }
+    script.push_str("end\n");
+    script
+}
+
+/// gdb script tracing from `start`. Each instruction is printed as `PC:`, its disassembly and
+/// the registers before it runs. A `TRACE_END` line with the reason and one more register
+/// dump, the state after the last instruction, close the trace.
+///
+/// `sensor` names a file whose bytes are written at the given address before the program
+/// starts. The program then runs to `start`, so that `_start` has set up `argc` and `argv`.
+/// `operands` are the program's data accesses, see [`memory_operands`].
+fn gdb_script(
+    address: &str,
+    start: u64,
+    max_steps: usize,
+    sensor: Option<&(String, u64)>,
+    operands: &[(u64, MemoryOperand)],
+) -> String {
+    let mut script = String::new();
+    let mut line = |l: &str| {
+        script.push_str(l);
+        script.push('\n');
+    };
+    line(&format!("target remote {}", address));
+    line("set pagination off");
+    line("set confirm off");
+    line("set disassemble-next-line on");
+    if let Some((path, addr)) = sensor {
+        line(&format!("restore {} binary 0x{:x}", path, addr));
+    }
+    line(&format!("if $pc != 0x{:x}", start));
+    line(&format!("  tbreak *0x{:x}", start));
+    line("  continue");
+    line("end");
+    // The traced function has returned once the pc reaches its return address with the
+    // stack pointer restored; recursive calls returning there have a lower `sp`.
+    line("set $ret = $ra");
+    line("set $ret_sp = $sp");
+    line("set $done = 0");
+    line("set $i = 0");
+    // Prints `$len` bytes at `$buf` as a `data=` line, used for the buffers of read/write ecalls.
+    line("define dump_data");
+    line("  printf \"data=\"");
+    line("  set $k = 0");
+    line("  while $k < $len");
+    line("    printf \"%02x\", *(unsigned char *)($buf + $k)");
+    line("    set $k = $k + 1");
+    line("  end");
+    line("  printf \"\\n\"");
+    line("end");
+    // gdb commands must be defined before the commands that use them.
+    script.push_str(&mem_base_gdb());
+    script.push_str(MEMORY_ACCESS_GDB);
+    script.push_str(&mem_decode_gdb(operands));
+    let mut line = |l: &str| {
+        script.push_str(l);
+        script.push('\n');
+    };
+    line("define dump_registers");
+    line("  info registers");
+    line("  info registers float");
+    line("end");
+    line("define trace_end");
+    line("  set $done = 1");
+    line("  dump_registers");
+    line("end");
+    line("define do_step");
+    line("  printf \"\\nPC: 0x%x\\n\", $pc");
+    line("  x/i $pc");
+    line("  dump_registers");
+    line("  printf \"insn=0x%08x\\n\", *(unsigned int *)$pc");
+    line("  if *(unsigned int *)$pc == 0x73 && $a7 == 64 && $a2 > 0");
+    line("    set $buf = $a1");
+    line("    set $len = $a2");
+    line("    dump_data");
+    line("  end");
+    // exit and exit_group end the process, after which gdb has no registers to show.
+    line("  if *(unsigned int *)$pc == 0x73 && ($a7 == 93 || $a7 == 94)");
+    line("    printf \"TRACE_END exit %d\\n\", $a0");
+    line("    trace_end");
+    line("  else");
+    // read(0, buf, n) fills its buffer during the step, so remember it and dump it afterwards.
+    line("    set $read_buf = 0");
+    line("    if *(unsigned int *)$pc == 0x73 && $a7 == 63");
+    line("      set $read_buf = $a1");
+    line("    end");
+    line("    mem_decode");
+    line("    si");
+    line("    dump_memory");
+    line("    if $read_buf != 0 && (long)$a0 > 0");
+    line("      set $buf = $read_buf");
+    line("      set $len = $a0");
+    line("      dump_data");
+    line("    end");
+    line("    set $i = $i + 1");
+    line("  end");
+    line("end");
+    line("while !$done");
+    line("  if $pc == $ret && $sp == $ret_sp");
+    line("    printf \"TRACE_END returned\\n\"");
+    line("    trace_end");
+    line("  else");
+    line(&format!("    if $i >= {}", max_steps));
+    line("      printf \"TRACE_END step_limit\\n\"");
+    line("      trace_end");
+    line("    else");
+    line("      do_step");
+    line("    end");
+    line("  end");
+    line("end");
+    line("quit");
+    script
+}
+
+/// Traces `program_path` under `qemu-riscv64` by talking to its gdbstub directly, without gdb.
+///
+/// The program gets `inputs`, the sensor blob being written through the stub before it
+/// starts. Tracing starts at `main` (or the entry point) and ends when it returns or the
+/// program exits; see [`rsp::trace`] for the `max_steps` bound.
+pub fn trace_program_rsp(
+    program_path: &str,
+    inputs: &ProgramInputs,
+    max_steps: usize,
+) -> Result<Vec<TraceEntry>, RunError> {
+    let elf = ElfFile::load(program_path).map_err(|e| RunError::Io(e.to_string()))?;
+    let start = elf.symbol("main").map_or(elf.entry, |main| main.value);
+
+    let qemu = Qemu::spawn(program_path, inputs, QEMU_READY_TIMEOUT)?;
+    let mut client = RspClient::connect(&qemu.address())?;
+    if let Some(sensor) = &inputs.sensor {
+        client.write_memory(sensor.addr, &sensor.data)?;
+    }
+    Ok(rsp::trace(&mut client, Some(start), max_steps)?)
+}
+
+/// How the gdb output says tracing ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TraceEnd {
+    Finished(Termination),
+    StepLimit,
 }
 
+/// Reads a `TRACE_END` line of the gdb script, or gdb's own notice that the process is gone
+/// (`[Inferior 1 (process N) exited with code 01]`, the code being octal).
+fn parse_trace_end(line: &str) -> Option<TraceEnd> {
+    if let Some(reason) = line.strip_prefix("TRACE_END ") {
+        let mut words = reason.split_whitespace();
+        return match (words.next(), words.next()) {
+            (Some("returned"), _) => Some(TraceEnd::Finished(Termination::Returned)),
+            (Some("exit"), Some(code)) => Some(TraceEnd::Finished(Termination::Exited(code.parse::<i64>().ok()? as u64))),
+            (Some("step_limit"), _) => Some(TraceEnd::StepLimit),
+            _ => None,
+        };
+    }
+    let status = line.strip_prefix("[Inferior ")?.split(") exited ").nth(1)?.trim_end_matches(']');
+    let code = match status.strip_prefix("with code ") {
+        Some(code) => u64::from_str_radix(code, 8).ok()?,
+        None if status == "normally" => 0,
+        None => return None,
+    };
+    Some(TraceEnd::Finished(Termination::Exited(code)))
+}
 
-fn parse_trace(raw: &str) -> String {
+/// Turns gdb output into the cleaned trace format: for each instruction, a line with the
+/// registers before it and a `pc: disassembly` line, then the registers after the last one.
+fn parse_trace(raw: &str) -> (String, Option<TraceEnd>) {
     use std::collections::HashMap;
 
     let mut result = String::new();
     let mut lines = raw.lines().peekable();
     let mut current_instr = None;
+    let mut end = None;
 
     while let Some(line) = lines.next() {
         let trimmed = line.trim();
 
+        if let Some(trace_end) = parse_trace_end(trimmed) {
+            end = Some(trace_end);
+            // `si` printed the next instruction, which does not run.
+            current_instr = None;
+        // Syscall buffers, memory accesses and instruction bits belong to the instruction
+        // emitted last: the bits and a write's buffer are dumped right after its registers,
+        // the others right after it ran.
+        } else if trimmed.starts_with("data=") || trimmed.starts_with("mem=") || trimmed.starts_with("insn=") {
+            result.push_str(trimmed);
+            result.push('\n');
         // Detect instruction line (e.g., starts with address + tab)
-        if trimmed.contains(':') && trimmed.contains('\t') {
+        } else if trimmed.contains(':') && trimmed.contains('\t') {
             current_instr = Some(trimmed.to_string());
         } else if trimmed.starts_with("x0") || trimmed.starts_with("ra") || trimmed.starts_with("sp") {
             let mut xregs: HashMap<String, String> = HashMap::new();
 
-            // Collect ~32 lines of register values
+            // Collect the register lines, up to and including `pc`
+            let mut reg_lines = vec![trimmed];
+            while let Some(reg_line) = lines.peek() {
+                let name = reg_line.split_whitespace().next().unwrap_or("");
+                if map_to_x_register(name).is_none() && name != "pc" {
+                    break;
+                }
+                reg_lines.push(lines.next().unwrap().trim());
+            }
             xregs.extend(
-                std::iter::once(trimmed)
-                    .chain(lines.by_ref().take(33))
+                reg_lines
+                    .into_iter()
                     .filter_map(|reg_line| {
-                        let parts = reg_line.trim().split_whitespace().collect::<Vec<_>>();
+                        let parts = reg_line.split_whitespace().collect::<Vec<_>>();
                         if parts.len() >= 2 {
                             let reg = parts[0];
                             let val = parts[1].trim_start_matches("0x");
@@ -104,11 +566,49 @@ fn parse_trace(raw: &str) -> String {
                     }),
             );
 
+            // `info registers float` follows with one `name {float = ..., double = ...} (raw 0x...)`
+            // line per f-register, then fflags, frm and fcsr.
+            let mut fregs: HashMap<String, String> = HashMap::new();
+            while let Some(reg_line) = lines.peek() {
+                let name = reg_line.split_whitespace().next().unwrap_or("");
+                if let Some(fname) = map_to_f_register(name) {
+                    let raw = reg_line.split("(raw 0x").nth(1).and_then(|r| r.split(')').next());
+                    if let Some(val) = raw.and_then(|r| u64::from_str_radix(r, 16).ok()) {
+                        fregs.insert(fname, format!("0x{:08x}", val));
+                    }
+                } else if !matches!(name, "fflags" | "frm" | "fcsr") {
+                    break;
+                }
+                lines.next();
+            }
+
+            if current_instr.is_none() && end.is_none() {
+                continue;
+            }
+
+            // First dump registers
+            for i in 0..32 {
+                let reg = format!("x{}", i);
+                let val = if reg == "x0" {
+                    "0x00000000".to_string()
+                } else {
+                    xregs.get(&reg).cloned().unwrap_or_else(|| "--------".to_string())
+                };
+                result.push_str(&format!("{}={} ", reg, val));
+            }
+            for i in 0..32 {
+                let reg = format!("f{}", i);
+                if let Some(val) = fregs.get(&reg) {
+                    result.push_str(&format!("{}={} ", reg, val));
+                }
+            }
+            result.push('\n');
+
             if let Some(instr) = &current_instr {
                 // Clean and normalize instruction line:
                 let raw_instr = instr.trim_start_matches("=>").trim();
                 let parts: Vec<&str> = raw_instr.splitn(2, ':').collect();
-                let pc_with_label = parts.get(0).map(|s| s.trim()).unwrap_or("");
+                let pc_with_label = parts.first().map(|s| s.trim()).unwrap_or("");
                 let disasm = parts.get(1).map(|s| s.trim()).unwrap_or("");
 
                 // Strip anything after the PC (like <main()+2>)
@@ -118,18 +618,6 @@ fn parse_trace(raw: &str) -> String {
                 // Replace register aliases with xN form
                 let clean_disasm = replace_aliases_with_x(disasm);
 
-                // First dump registers
-                for i in 0..32 {
-                    let reg = format!("x{}", i);
-                    let val = if reg == "x0" {
-                        "0x00000000".to_string()
-                    } else {
-                        xregs.get(&reg).cloned().unwrap_or_else(|| "--------".to_string())
-                    };
-                    result.push_str(&format!("{}={} ", reg, val));
-                }
-                result.push('\n');
-
                 // Add PC line (normalize to 32-bit or full 64-bit)
                 result.push_str(&format!("{}: {}\n", pc, clean_disasm));
             }
@@ -138,17 +626,7 @@ fn parse_trace(raw: &str) -> String {
         }
     }
 
-    let mut lines: Vec<&str> = result.lines().collect();
-
-    // Remove last two lines if they exist
-    if lines.len() >= 1 {
-        lines.truncate(lines.len() - 1);
-    }
-
-    // Reconstruct result and add final empty line
-    result = lines.join("\n") + "\n";
-
-    result
+    (result, end)
 }
 
 
@@ -164,8 +642,18 @@ fn replace_aliases_with_x(instr: &str) -> String {
         ("t5", "x30"), ("t6", "x31"),
     ];
 
+    // f-register aliases go first and longer aliases before their prefixes, so that `fa0`
+    // does not turn into `fx10` nor `s10` into `x910`.
+    let mut aliases: Vec<(&str, String)> = F_ALIASES
+        .iter()
+        .enumerate()
+        .map(|(i, alias)| (*alias, format!("f{}", i)))
+        .chain(reg_map.iter().map(|(alias, xname)| (*alias, xname.to_string())))
+        .collect();
+    aliases.sort_by_key(|(alias, _)| std::cmp::Reverse(alias.len()));
+
     let mut replaced = instr.to_string();
-    for (alias, xname) in reg_map {
+    for (alias, xname) in aliases {
         replaced = replaced.replace(&format!("{}(", alias), &format!("{}(", xname));
         replaced = replaced.replace(&format!(", {}", alias), &format!(", {}", xname));
         replaced = replaced.replace(&format!(" {}", alias), &format!(" {}", xname));
@@ -176,6 +664,24 @@ fn replace_aliases_with_x(instr: &str) -> String {
     replaced
 }
 
+/// ABI names of `f0`..`f31`.
+const F_ALIASES: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7", "fs0", "fs1",
+    "fa0", "fa1", "fa2", "fa3", "fa4", "fa5", "fa6", "fa7", "fs2", "fs3",
+    "fs4", "fs5", "fs6", "fs7", "fs8", "fs9", "fs10", "fs11", "ft8", "ft9",
+    "ft10", "ft11",
+];
+
+fn map_to_f_register(name: &str) -> Option<String> {
+    if let Some(i) = F_ALIASES.iter().position(|&alias| alias == name) {
+        return Some(format!("f{}", i));
+    }
+    match name.strip_prefix('f')?.parse::<usize>() {
+        Ok(i) if i < 32 => Some(name.to_string()),
+        _ => None,
+    }
+}
+
 fn map_to_x_register(name: &str) -> Option<String> {
     let reg_map = [
         ("zero", 0), ("ra", 1), ("sp", 2), ("gp", 3), ("tp", 4),
@@ -199,3 +705,129 @@ fn map_to_x_register(name: &str) -> Option<String> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace_parser::MemoryAccess;
+
+    /// gdb output for one stop: `x/i` and the registers, with `a0` and `pc` as given.
+    fn stop(pc: u64, disasm: &str, a0: u64) -> String {
+        let mut out = format!("\nPC: 0x{:x}\n=> 0x{:x} <main()+0>:\t{}\n", pc, pc, disasm);
+        for name in ["ra", "sp", "gp", "tp", "t0", "t1", "t2", "fp", "s1", "a0"] {
+            let value = if name == "a0" { a0 } else { 0 };
+            out.push_str(&format!("{:<15}0x{:x}\t{}\n", name, value, value));
+        }
+        for name in ["a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7"] {
+            out.push_str(&format!("{:<15}0x0\t0\n", name));
+        }
+        for name in ["s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6"] {
+            out.push_str(&format!("{:<15}0x0\t0\n", name));
+        }
+        out.push_str(&format!("pc             0x{:x}\t0x{:x}\n", pc, pc));
+        out
+    }
+
+    #[test]
+    fn test_parse_gdb_output_until_return() {
+        let mut raw = stop(0x101d8, "sd\ta0,8(sp)", 0);
+        raw.push_str("insn=0x0001e42a\n");
+        raw.push_str("mem=0x7ff8,8,0x2a,0x0\n");
+        raw.push_str(&stop(0x101dc, "addi\ta0,a0,5", 0));
+        // `si` with disassemble-next-line shows the next instruction before the script stops.
+        raw.push_str("=> 0x0000000000010200 <main()+36>:\t8082\tret\nTRACE_END returned\n");
+        raw.push_str(stop(0x0, "", 5).split_once("ra").map(|(_, regs)| format!("ra{}", regs)).unwrap().as_str());
+
+        let (cleaned, end) = parse_trace(&raw);
+        assert_eq!(end, Some(TraceEnd::Finished(Termination::Returned)));
+        let lines: Vec<&str> = cleaned.lines().collect();
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[2], "insn=0x0001e42a");
+        assert_eq!(lines[3], "mem=0x7ff8,8,0x2a,0x0");
+        assert!(lines[4].contains("x10=0x00000000"));
+        assert_eq!(lines[5], "0x101dc: addi\tx10,x10,5");
+        assert!(lines[6].contains("x10=0x00000005"));
+
+        let path = std::env::temp_dir().join("gdb_cleaned.log");
+        write(&path, &cleaned).unwrap();
+        let (entries, mismatches) = crate::trace_parser::parse_trace_checked(path.to_str().unwrap(), None).unwrap();
+        assert_eq!(mismatches, vec![]);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].memory, Some(MemoryAccess { addr: 0x7ff8, width: 8, read: 0x2a, written: Some(0) }));
+        assert_eq!(entries[1].after("x10"), Some(5));
+    }
+
+    #[test]
+    fn test_parse_trace_end() {
+        assert_eq!(parse_trace_end("TRACE_END exit 3"), Some(TraceEnd::Finished(Termination::Exited(3))));
+        assert_eq!(parse_trace_end("TRACE_END step_limit"), Some(TraceEnd::StepLimit));
+        assert_eq!(
+            parse_trace_end("[Inferior 1 (process 42) exited with code 012]"),
+            Some(TraceEnd::Finished(Termination::Exited(10)))
+        );
+        assert_eq!(
+            parse_trace_end("[Inferior 1 (process 42) exited normally]"),
+            Some(TraceEnd::Finished(Termination::Exited(0)))
+        );
+        assert_eq!(parse_trace_end("PC: 0x101dc"), None);
+        let operands = memory_operands(&ElfFile::load("test.bin").unwrap());
+        assert!(operands.len() > 300 && operands.windows(2).all(|w| w[0].0 < w[1].0));
+        let sensor = ("/tmp/sensor.bin".to_string(), 0x11f00);
+        let script = gdb_script("localhost:4321", 0x101dc, 500, Some(&sensor), &operands);
+        assert!(script.starts_with("target remote localhost:4321\n"));
+        let restore = script.find("restore /tmp/sensor.bin binary 0x11f00\n").unwrap();
+        assert!(restore < script.find("if $pc != 0x101dc\n  tbreak *0x101dc\n  continue\nend\n").unwrap());
+        assert!(script.contains("    if $i >= 500\n"));
+        let defined = |command: &str| script.find(&format!("define {}\n", command)).unwrap();
+        assert!(defined("mem_base") < defined("mem_decode") && defined("mem_load") < defined("mem_decode"));
+        let (pc, op) = operands[operands.len() / 3];
+        let lookup = format!("if $pc == 0x{:x}\n", pc);
+        let body = &script[script.find(&lookup).unwrap() + lookup.len()..];
+        assert!(body.trim_start().starts_with(&format!("set $mem_reg = {}\n", op.base)));
+        assert!(script.contains("    mem_decode\n    si\n    dump_memory\n"));
+        assert!(script.contains("  if $mem_reg == 2\n    set $mem_base = (unsigned long)$sp\n"));
+    }
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn test_qemu_readiness_and_cleanup() {
+        // A process that dies before listening is reported with its stderr.
+        let port = free_port().unwrap();
+        let early = Qemu::spawn_command(shell("echo 'bad option' >&2; exit 3"), Vec::new(), port, Duration::from_secs(5));
+        assert!(matches!(early, Err(RunError::QemuExited { ref stderr, .. }) if stderr == "bad option"));
+        let echo = Qemu::spawn_command(shell("cat >&2; exit 1"), b"sensor 42".to_vec(), port, Duration::from_secs(5));
+        assert!(matches!(echo, Err(RunError::QemuExited { ref stderr, .. }) if stderr == "sensor 42"));
+
+        let inputs = ProgramInputs {
+            args: vec!["--rate".to_string(), "2".to_string()],
+            env: vec![("MODE".to_string(), "fast".to_string())],
+            ..Default::default()
+        };
+        let command = qemu_command(&["-singlestep"], "prog", &inputs);
+        let args: Vec<_> = command.get_args().map(|a| a.to_string_lossy().into_owned()).collect();
+        assert_eq!(args, ["-singlestep", "-E", "MODE=fast", "prog", "--rate", "2"]);
+        assert_eq!(command.get_envs().count(), 0);
+
+        let slow = Qemu::spawn_command(shell("sleep 30"), Vec::new(), port, Duration::from_millis(100));
+        assert_eq!(slow.unwrap_err(), RunError::NotReady { port, timeout: Duration::from_millis(100) });
+
+        // Once the port is listening the process is ready; dropping the guard kills it.
+        let listener = TcpListener::bind(("0.0.0.0", port)).unwrap();
+        let qemu = Qemu::spawn_command(shell("sleep 30"), Vec::new(), port, Duration::from_secs(5)).unwrap();
+        assert_eq!(qemu.address(), format!("localhost:{}", port));
+        let pid = qemu.child.id();
+        assert!(Path::new(&format!("/proc/{}", pid)).exists());
+        drop(qemu);
+        assert!(!Path::new(&format!("/proc/{}", pid)).exists());
+        drop(listener);
+
+        let missing = Qemu::spawn_command(Command::new("no-such-qemu"), Vec::new(), port, Duration::from_secs(1));
+        assert!(matches!(missing, Err(RunError::Spawn { ref program, .. }) if program == "no-such-qemu"));
+        assert_ne!(free_port().unwrap(), 0);
+    }
+}
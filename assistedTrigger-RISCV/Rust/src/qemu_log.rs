@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::process::Stdio;
+
+use regex::Regex;
+
+use crate::decoder::decode;
+use crate::inputs::ProgramInputs;
+use crate::program_runner::{feed_stdin, qemu_command};
+use crate::trace_parser::{RegisterFile, TraceEntry};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QemuLogError {
+    Io(String),
+    /// A line of the log that cannot be understood, counted from 1.
+    Malformed { line: usize, reason: String },
+}
+
+impl fmt::Display for QemuLogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QemuLogError::Io(e) => write!(f, "cannot produce QEMU log: {}", e),
+            QemuLogError::Malformed { line, reason } => write!(f, "QEMU log line {}: {}", line, reason),
+        }
+    }
+}
+
+impl std::error::Error for QemuLogError {}
+
+/// Register state printed by `-d cpu` before an instruction executes.
+#[derive(Debug, Clone, Copy)]
+struct CpuState {
+    pc: u64,
+    x: [u64; 32],
+    /// Log line of the `pc` field, for error messages.
+    line: usize,
+}
+
+/// Parses a log written by `qemu-riscv64 -d in_asm,cpu,nochain -singlestep`.
+///
+/// With single-instruction blocks and no chaining, QEMU dumps the CPU state before every
+/// instruction, while `in_asm` prints each instruction once, when it is first translated.
+/// Each dump becomes an entry whose registers after are the next dump's. Operands come from
+/// decoding the instruction bits of the `in_asm` line, not from its disassembly. Instructions
+/// outside RV64IMC are recorded as `.insn 0x<bits>` without operands.
+///
+/// The log holds no memory contents, so entries have no `MemoryAccess` and `ecall`s have no
+/// `syscall_data`. The last instruction is kept only if it is an `ecall`, which is taken to be
+/// the exit, and its registers after are those before.
+pub fn parse_qemu_log(log: &str) -> Result<Vec<TraceEntry>, QemuLogError> {
+    let re_insn = Regex::new(r"^0x([0-9a-f]+):\s+([0-9a-f]{4}|[0-9a-f]{8})\s").unwrap();
+    let re_pc = Regex::new(r"^\s*pc\s+([0-9a-f]+)\s*$").unwrap();
+    let re_reg = Regex::new(r"x(\d+)/\w+\s+([0-9a-f]{16})").unwrap();
+
+    let mut insns: HashMap<u64, u32> = HashMap::new();
+    let mut states: Vec<CpuState> = Vec::new();
+    let mut current: Option<(CpuState, u32)> = None; // state and the registers seen so far
+
+    for (index, line) in log.lines().enumerate() {
+        let malformed = |reason: String| QemuLogError::Malformed { line: index + 1, reason };
+        if let Some(caps) = re_insn.captures(line) {
+            let pc = u64::from_str_radix(&caps[1], 16).map_err(|e| malformed(e.to_string()))?;
+            let bits = u32::from_str_radix(&caps[2], 16).map_err(|e| malformed(e.to_string()))?;
+            insns.insert(pc, bits);
+        } else if let Some(caps) = re_pc.captures(line) {
+            if let Some((state, seen)) = current.take() {
+                return Err(malformed(format!("dump at line {} has only {} registers", state.line, seen)));
+            }
+            let pc = u64::from_str_radix(&caps[1], 16).map_err(|e| malformed(e.to_string()))?;
+            current = Some((CpuState { pc, x: [0; 32], line: index + 1 }, 0));
+        } else if let Some((state, seen)) = current.as_mut() {
+            for caps in re_reg.captures_iter(line) {
+                let reg: usize = caps[1].parse().map_err(|_| malformed(format!("bad register x{}", &caps[1])))?;
+                if reg != *seen as usize {
+                    return Err(malformed(format!("expected x{}, found x{}", seen, reg)));
+                }
+                state.x[reg] = u64::from_str_radix(&caps[2], 16).map_err(|e| malformed(e.to_string()))?;
+                *seen += 1;
+            }
+            if *seen == 32 {
+                states.push(current.take().unwrap().0);
+            }
+        }
+    }
+
+    let mut entries = Vec::new();
+    for (i, before) in states.iter().enumerate() {
+        let bits = *insns.get(&before.pc).ok_or_else(|| QemuLogError::Malformed {
+            line: before.line,
+            reason: format!("no in_asm line for pc 0x{:x}", before.pc),
+        })?;
+        let inst = decode(before.pc, bits);
+        let after = match states.get(i + 1) {
+            Some(after) => after,
+            None if inst.as_ref().is_some_and(|inst| inst.mnemonic() == "ecall") => before,
+            None => break,
+        };
+        entries.push(match inst {
+            Some(inst) => TraceEntry {
+                regs_before: RegisterFile { x: before.x, ..Default::default() },
+                regs_after: RegisterFile { x: after.x, ..Default::default() },
+                ..TraceEntry::from_instruction(before.pc, &inst)
+            },
+            None => TraceEntry { pc: before.pc, opcode: format!(".insn 0x{:08x}", bits), ..Default::default() },
+        });
+    }
+    Ok(entries)
+}
+
+/// Runs `program_path` under `qemu-riscv64` with instruction and CPU logging to `log_path`,
+/// then parses the log. The program runs at full speed apart from logging, with no debugger
+/// round-trips, and is traced from its entry point.
+///
+/// The program gets the arguments, environment and stdin of `inputs`. Without a debugger
+/// there is no way to write a sensor blob into its memory, so one is refused.
+pub fn run_qemu_log(program_path: &str, inputs: &ProgramInputs, log_path: &str) -> Result<Vec<TraceEntry>, QemuLogError> {
+    if inputs.sensor.is_some() {
+        return Err(QemuLogError::Io("a sensor input needs the gdb or RSP runner".to_string()));
+    }
+    let mut child = qemu_command(&["-d", "in_asm,cpu,nochain", "-singlestep", "-D", log_path], program_path, inputs)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| QemuLogError::Io(format!("failed to start QEMU: {}", e)))?;
+    feed_stdin(&mut child, inputs.stdin.clone());
+    let status = child.wait().map_err(|e| QemuLogError::Io(e.to_string()))?;
+    if status.code().is_none() {
+        return Err(QemuLogError::Io(format!("QEMU was killed: {}", status)));
+    }
+    let log = std::fs::read_to_string(log_path).map_err(|e| QemuLogError::Io(format!("{}: {}", log_path, e)))?;
+    parse_qemu_log(&log)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::ElfFile;
+    use crate::emulator::Emulator;
+
+    #[test]
+    fn test_parse_qemu_log_fixture() {
+        let log = std::fs::read_to_string("traces/qemu_exec.log").unwrap();
+        let trace = parse_qemu_log(&log).unwrap();
+
+        // The fixture covers test.bin from main to its return.
+        let elf = ElfFile::load("test.bin").unwrap();
+        let main = elf.symbol("main").unwrap();
+        assert_eq!(trace[0].pc, main.value);
+        assert!(trace.iter().all(|e| main.contains(e.pc)));
+        assert_eq!(trace.last().unwrap().opcode, "jalr");
+
+        // Operands and register files agree with the emulator, which also records memory.
+        let native = Emulator::from_elf(&elf).run(10_000).unwrap();
+        let from_main = native.iter().position(|e| e.pc == main.value).unwrap();
+        for (logged, native) in trace.iter().zip(&native[from_main..]) {
+            assert_eq!(logged, &TraceEntry { memory: None, syscall_data: None, ..native.clone() });
+        }
+        let add = trace.iter().find(|e| e.opcode == "addw").unwrap();
+        assert_eq!(add.after(add.rd.as_ref().unwrap()), Some(7));
+    }
+
+    #[test]
+    fn test_parse_qemu_log_errors() {
+        let log = std::fs::read_to_string("traces/qemu_exec.log").unwrap();
+        // Without in_asm lines the instructions are unknown.
+        let no_asm: String = log.lines().filter(|l| !l.starts_with("0x")).map(|l| format!("{}\n", l)).collect();
+        assert!(matches!(parse_qemu_log(&no_asm), Err(QemuLogError::Malformed { reason, .. }) if reason.starts_with("no in_asm")));
+
+        let truncated = " pc       0000000000010184\n x0/zero  0000000000000000\n pc       0000000000010186\n";
+        assert_eq!(
+            parse_qemu_log(truncated),
+            Err(QemuLogError::Malformed { line: 3, reason: "dump at line 1 has only 1 registers".to_string() })
+        );
+    }
+}
@@ -0,0 +1,446 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use crate::decoder::{decode, memory_operand};
+use crate::emulator::MAX_SYSCALL_DATA;
+use crate::trace_parser::{MemoryAccess, RegisterFile, TraceEntry};
+
+/// Retransmissions of a packet the stub answers with `-` before giving up.
+const MAX_RETRIES: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RspError {
+    Io(String),
+    /// A malformed or unexpected reply.
+    Protocol(String),
+    /// An `E NN` error reply.
+    Remote(u8),
+    /// The program was still running after this many steps.
+    StepLimit(usize),
+}
+
+impl fmt::Display for RspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RspError::Io(e) => write!(f, "gdbstub connection failed: {}", e),
+            RspError::Protocol(e) => write!(f, "unexpected gdbstub reply: {}", e),
+            RspError::Remote(code) => write!(f, "gdbstub returned error {:02x}", code),
+            RspError::StepLimit(max) => write!(f, "the program did not finish within {} steps", max),
+        }
+    }
+}
+
+impl std::error::Error for RspError {}
+
+impl From<std::io::Error> for RspError {
+    fn from(e: std::io::Error) -> Self {
+        RspError::Io(e.to_string())
+    }
+}
+
+/// Why the target stopped after `s` or `c`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// `S`/`T` reply: stopped with a signal, 5 (`SIGTRAP`) after a step or breakpoint.
+    Signal(u8),
+    /// `W` reply: the program exited with this status.
+    Exited(u8),
+    /// `X` reply: the program was killed by this signal.
+    Terminated(u8),
+}
+
+/// The `g` packet contents of a riscv64 target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub x: [u64; 32],
+    pub pc: u64,
+}
+
+/// `$payload#checksum` framing of a packet.
+fn frame(payload: &str) -> Vec<u8> {
+    let checksum = payload.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+    format!("${}#{:02x}", payload, checksum).into_bytes()
+}
+
+/// Undoes `}` escaping and `*` run-length encoding in a received payload.
+fn decode_payload(raw: &[u8]) -> Result<String, RspError> {
+    let mut out: Vec<u8> = Vec::with_capacity(raw.len());
+    let mut bytes = raw.iter().copied();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'}' => {
+                let escaped = bytes.next().ok_or_else(|| RspError::Protocol("dangling escape".to_string()))?;
+                out.push(escaped ^ 0x20);
+            }
+            b'*' => {
+                let count = bytes.next().ok_or_else(|| RspError::Protocol("dangling run length".to_string()))?;
+                let last = *out.last().ok_or_else(|| RspError::Protocol("run length without a byte".to_string()))?;
+                out.extend(std::iter::repeat_n(last, count.saturating_sub(29) as usize));
+            }
+            _ => out.push(b),
+        }
+    }
+    String::from_utf8(out).map_err(|_| RspError::Protocol("payload is not ASCII".to_string()))
+}
+
+fn hex_bytes(hex: &str) -> Result<Vec<u8>, RspError> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(RspError::Protocol(format!("odd-length hex: {}", hex)));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| RspError::Protocol(format!("bad hex: {}", hex))))
+        .collect()
+}
+
+fn error_code(reply: &str) -> Option<u8> {
+    let code = reply.strip_prefix('E')?;
+    (code.len() == 2).then(|| u8::from_str_radix(code, 16).ok()).flatten()
+}
+
+/// Client for the GDB Remote Serial Protocol, as served by `qemu-riscv64 -g <port>`.
+///
+/// Packets are acknowledged (no `QStartNoAckMode`), and console output (`O`) packets sent
+/// while the target runs are skipped.
+pub struct RspClient<S: Read + Write> {
+    stream: S,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl RspClient<TcpStream> {
+    pub fn connect(addr: &str) -> Result<Self, RspError> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(RspClient::new(stream))
+    }
+}
+
+impl<S: Read + Write> RspClient<S> {
+    pub fn new(stream: S) -> Self {
+        RspClient { stream, buf: Vec::new(), pos: 0 }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, RspError> {
+        if self.pos == self.buf.len() {
+            self.buf.resize(4096, 0);
+            let n = self.stream.read(&mut self.buf)?;
+            if n == 0 {
+                return Err(RspError::Io("connection closed".to_string()));
+            }
+            self.buf.truncate(n);
+            self.pos = 0;
+        }
+        self.pos += 1;
+        Ok(self.buf[self.pos - 1])
+    }
+
+    fn send(&mut self, payload: &str) -> Result<(), RspError> {
+        let packet = frame(payload);
+        for _ in 0..MAX_RETRIES {
+            self.stream.write_all(&packet)?;
+            self.stream.flush()?;
+            match self.read_byte()? {
+                b'+' => return Ok(()),
+                b'-' => continue,
+                other => return Err(RspError::Protocol(format!("expected an ack, got {:?}", other as char))),
+            }
+        }
+        Err(RspError::Protocol(format!("packet {} rejected {} times", payload, MAX_RETRIES)))
+    }
+
+    fn receive(&mut self) -> Result<String, RspError> {
+        loop {
+            while self.read_byte()? != b'$' {}
+            let mut raw = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    b => raw.push(b),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+            if expected != Some(raw.iter().fold(0u8, |acc, &b| acc.wrapping_add(b))) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+            self.stream.write_all(b"+")?;
+            return decode_payload(&raw);
+        }
+    }
+
+    /// Sends a packet and returns its reply, turning `E NN` into [`RspError::Remote`].
+    fn request(&mut self, payload: &str) -> Result<String, RspError> {
+        self.send(payload)?;
+        let reply = self.receive()?;
+        match error_code(&reply) {
+            Some(code) => Err(RspError::Remote(code)),
+            None => Ok(reply),
+        }
+    }
+
+    fn expect_ok(&mut self, payload: &str) -> Result<(), RspError> {
+        match self.request(payload)?.as_str() {
+            "OK" => Ok(()),
+            other => Err(RspError::Protocol(format!("{} answered {:?}", payload, other))),
+        }
+    }
+
+    fn resume(&mut self, payload: &str) -> Result<StopReason, RspError> {
+        self.send(payload)?;
+        loop {
+            let reply = self.receive()?;
+            if reply.starts_with('O') && reply != "OK" {
+                continue; // console output
+            }
+            let code = reply.get(1..3).and_then(|c| u8::from_str_radix(c, 16).ok());
+            return match (reply.chars().next(), code) {
+                (Some('S' | 'T'), Some(signal)) => Ok(StopReason::Signal(signal)),
+                (Some('W'), Some(status)) => Ok(StopReason::Exited(status)),
+                (Some('X'), Some(signal)) => Ok(StopReason::Terminated(signal)),
+                _ => Err(RspError::Protocol(format!("bad stop reply {:?}", reply))),
+            };
+        }
+    }
+
+    /// Executes one instruction (`s`).
+    pub fn step(&mut self) -> Result<StopReason, RspError> {
+        self.resume("s")
+    }
+
+    /// Runs until a breakpoint or exit (`c`).
+    pub fn cont(&mut self) -> Result<StopReason, RspError> {
+        self.resume("c")
+    }
+
+    /// Reads `x0`-`x31` and `pc` (`g`).
+    pub fn read_registers(&mut self) -> Result<Registers, RspError> {
+        let bytes = hex_bytes(&self.request("g")?)?;
+        if bytes.len() < 33 * 8 {
+            return Err(RspError::Protocol(format!("g reply has {} bytes", bytes.len())));
+        }
+        let reg = |i: usize| u64::from_le_bytes(bytes[8 * i..8 * i + 8].try_into().unwrap());
+        Ok(Registers { x: std::array::from_fn(reg), pc: reg(32) })
+    }
+
+    /// Reads `len` bytes at `addr` (`m`).
+    pub fn read_memory(&mut self, addr: u64, len: usize) -> Result<Vec<u8>, RspError> {
+        let bytes = hex_bytes(&self.request(&format!("m{:x},{:x}", addr, len))?)?;
+        if bytes.len() != len {
+            return Err(RspError::Protocol(format!("asked for {} bytes at 0x{:x}, got {}", len, addr, bytes.len())));
+        }
+        Ok(bytes)
+    }
+
+    /// Writes `bytes` at `addr` (`M`), in pieces that fit the stub's packet buffer.
+    pub fn write_memory(&mut self, addr: u64, bytes: &[u8]) -> Result<(), RspError> {
+        for (i, chunk) in bytes.chunks(1024).enumerate() {
+            let hex: String = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            self.expect_ok(&format!("M{:x},{:x}:{}", addr + 1024 * i as u64, chunk.len(), hex))?;
+        }
+        Ok(())
+    }
+
+    /// Inserts a software breakpoint (`Z0`).
+    pub fn set_breakpoint(&mut self, addr: u64) -> Result<(), RspError> {
+        self.expect_ok(&format!("Z0,{:x},4", addr))
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u64) -> Result<(), RspError> {
+        self.expect_ok(&format!("z0,{:x},4", addr))
+    }
+}
+
+fn le_value(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64)
+}
+
+/// Single-steps the target and records a [`TraceEntry`] per instruction.
+///
+/// When `start` is given, the target first runs to a breakpoint there. Each step reads the
+/// registers and the instruction bytes, so operands come from the decoded encoding rather
+/// than from disassembly. Loads and stores get their [`MemoryAccess`] by reading the bytes
+/// before and after the step, and `read`/`write` `ecall`s get their buffer. Instructions
+/// outside RV64IMC are recorded as `.insn 0x<bits>` without operands.
+///
+/// Tracing ends when the program exits or, with a `start`, when the function there returns:
+/// the pc reaches the return address held in `ra` at the start, with `sp` back to its value
+/// there. Stepping more than `max_steps` instructions is a [`RspError::StepLimit`] error.
+pub fn trace<S: Read + Write>(
+    client: &mut RspClient<S>,
+    start: Option<u64>,
+    max_steps: usize,
+) -> Result<Vec<TraceEntry>, RspError> {
+    if let Some(addr) = start {
+        client.set_breakpoint(addr)?;
+        let stop = client.cont()?;
+        client.remove_breakpoint(addr)?;
+        if stop != StopReason::Signal(5) {
+            return Err(RspError::Protocol(format!("did not reach 0x{:x}: {:?}", addr, stop)));
+        }
+    }
+
+    let mut entries = Vec::new();
+    let mut regs = client.read_registers()?;
+    let caller = start.map(|_| (regs.x[1], regs.x[2]));
+    loop {
+        if caller == Some((regs.pc, regs.x[2])) {
+            break;
+        }
+        if entries.len() == max_steps {
+            return Err(RspError::StepLimit(max_steps));
+        }
+        let bits = le_value(&client.read_memory(regs.pc, 4)?) as u32;
+        let inst = decode(regs.pc, bits);
+        let access = inst.as_ref().and_then(memory_operand).map(|op| (op.addr(&regs.x), op.width, op.writes));
+        let read = match access {
+            Some((addr, width, _)) => Some(le_value(&client.read_memory(addr, width as usize)?)),
+            None => None,
+        };
+        let is_ecall = inst.as_ref().is_some_and(|i| i.mnemonic() == "ecall");
+        let (number, buf) = (regs.x[17], regs.x[11]);
+
+        let stop = client.step()?;
+        let exited = !matches!(stop, StopReason::Signal(_));
+        // Registers cannot be read once the process is gone; exit leaves them as they were.
+        let after = if exited { regs } else { client.read_registers()? };
+
+        let mut entry = match &inst {
+            Some(inst) => TraceEntry {
+                regs_before: RegisterFile { x: regs.x, ..Default::default() },
+                regs_after: RegisterFile { x: after.x, ..Default::default() },
+                ..TraceEntry::from_instruction(regs.pc, inst)
+            },
+            None => TraceEntry { pc: regs.pc, opcode: format!(".insn 0x{:08x}", bits), ..Default::default() },
+        };
+        if let (Some((addr, width, writes)), Some(read)) = (access, read) {
+            let written = if writes { Some(le_value(&client.read_memory(addr, width as usize)?)) } else { None };
+            entry.memory = Some(MemoryAccess { addr, width, read, written });
+        }
+        // The bytes moved by `read`/`write` are in the buffer after the call; `a0` says how many.
+        let transferred = after.x[10];
+        if is_ecall && (number == 63 || number == 64) && (transferred as i64) > 0 {
+            if transferred > MAX_SYSCALL_DATA {
+                return Err(RspError::Protocol(format!("syscall at 0x{:x} transferred {} bytes", regs.pc, transferred)));
+            }
+            entry.syscall_data = Some(client.read_memory(buf, transferred as usize)?);
+        }
+        entries.push(entry);
+
+        if exited {
+            break;
+        }
+        regs = after;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::elf::ElfFile;
+    use crate::emulator::{Emulator, EmulatorError};
+
+    /// Minimal gdbstub answering from an [`Emulator`].
+    fn serve(mut emu: Emulator, listener: TcpListener) {
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut conn = RspClient::new(stream);
+        let mut breakpoints = HashSet::new();
+        loop {
+            let Ok(packet) = conn.receive() else { return };
+            let reply = match packet.as_bytes()[0] {
+                b'g' => emu.regs.iter().chain([&emu.pc]).map(|r| format!("{:016x}", r.swap_bytes())).collect(),
+                b'm' => {
+                    let (addr, len) = packet[1..].split_once(',').unwrap();
+                    let addr = u64::from_str_radix(addr, 16).unwrap();
+                    if addr == 0 {
+                        "E14".to_string()
+                    } else {
+                        let bytes = emu.memory.read_bytes(addr, usize::from_str_radix(len, 16).unwrap());
+                        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+                    }
+                }
+                b'M' => {
+                    let (location, hex) = packet[1..].split_once(':').unwrap();
+                    let addr = u64::from_str_radix(location.split(',').next().unwrap(), 16).unwrap();
+                    emu.memory.write_bytes(addr, &hex_bytes(hex).unwrap());
+                    "OK".to_string()
+                }
+                b'Z' | b'z' => {
+                    let addr = u64::from_str_radix(packet[3..].split(',').next().unwrap(), 16).unwrap();
+                    if packet.starts_with('Z') { breakpoints.insert(addr) } else { breakpoints.remove(&addr) };
+                    "OK".to_string()
+                }
+                b's' | b'c' => loop {
+                    match emu.step() {
+                        Ok(_) if emu.exit_code().is_some() => break format!("W{:02x}", emu.exit_code().unwrap()),
+                        Ok(_) if packet == "s" || breakpoints.contains(&emu.pc) => break "T05".to_string(),
+                        Ok(_) => continue,
+                        Err(EmulatorError::Exited) => break "X09".to_string(),
+                        Err(_) => break "S04".to_string(),
+                    }
+                },
+                _ => String::new(),
+            };
+            conn.stream.write_all(&frame(&reply)).unwrap();
+            if conn.read_byte().is_err() {
+                return;
+            }
+        }
+    }
+
+    fn mock(emu: Emulator) -> RspClient<TcpStream> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(emu, listener));
+        RspClient::connect(&addr).unwrap()
+    }
+
+    #[test]
+    fn test_decode_payload_and_framing() {
+        assert_eq!(frame("OK"), b"$OK#9a");
+        assert_eq!(decode_payload(b"0* 1").unwrap(), "00001");
+        assert_eq!(decode_payload(b"a}\x03b").unwrap(), "a#b");
+        assert_eq!(error_code("E14"), Some(0x14));
+        assert_eq!(error_code("OK"), None);
+    }
+
+    #[test]
+    fn test_trace_over_rsp_matches_emulator() {
+        let elf = ElfFile::load("test.bin").unwrap();
+        let main = elf.symbol("main").unwrap().value;
+        let native = Emulator::from_elf(&elf).run(10_000).unwrap();
+
+        // From the entry point, tracing runs to exit.
+        let mut client = mock(Emulator::from_elf(&elf));
+        assert_eq!(client.read_memory(0, 4), Err(RspError::Remote(0x14)));
+        let sensor: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        client.write_memory(0x2000_0000, &sensor).unwrap();
+        assert_eq!(client.read_memory(0x2000_0000, 2000).unwrap(), sensor);
+        let trace = trace(&mut client, None, 10_000).unwrap();
+        assert_eq!(trace.len(), native.len());
+        let (exit, steps) = trace.split_last().unwrap();
+        assert_eq!(steps, &native[..native.len() - 1]);
+        // The exit ecall cannot read the registers of the finished process.
+        assert_eq!(exit.opcode, "ecall");
+        assert_eq!(exit.regs_after, native.last().unwrap().regs_before);
+        assert!(trace.iter().any(|e| e.memory.is_some_and(|m| m.written.is_some())));
+
+        // From main, it stops when main returns to _start.
+        let from_main = native.iter().position(|e| e.pc == main).unwrap();
+        let trace = super::trace(&mut mock(Emulator::from_elf(&elf)), Some(main), 10_000).unwrap();
+        assert_eq!(trace, native[from_main..from_main + trace.len()]);
+        assert_eq!(trace.last().unwrap().opcode, "jalr");
+        assert!(!elf.symbol("main").unwrap().contains(native[from_main + trace.len()].pc));
+
+        let truncated = super::trace(&mut mock(Emulator::from_elf(&elf)), Some(main), 10);
+        assert_eq!(truncated, Err(RspError::StepLimit(10)));
+    }
+}
@@ -4,44 +4,143 @@ use plonky2::field::goldilocks_field::GoldilocksField;
 use plonky2::plonk::circuit_data::CircuitData;
 use plonky2::plonk::config::PoseidonGoldilocksConfig;
 use plonky2::plonk::proof::ProofWithPublicInputs;
-use plonky2::util::serialization::{DefaultGateSerializer, DefaultGeneratorSerializer};
+use plonky2::util::serialization::{GateSerializer, WitnessGeneratorSerializer};
+use plonky2::{get_gate_tag_impl, get_generator_tag_impl, impl_gate_serializer, impl_generator_serializer, read_gate_impl, read_generator_impl};
 
+use plonky2::field::extension::Extendable;
+use plonky2::gadgets::arithmetic::EqualityGenerator;
+use plonky2::gadgets::arithmetic_extension::QuotientGeneratorExtension;
+use plonky2::gadgets::range_check::LowHighGenerator;
+use plonky2::gadgets::split_base::BaseSumGenerator;
+use plonky2::gadgets::split_join::{SplitGenerator, WireSplitGenerator};
+use plonky2::gates::arithmetic_base::{ArithmeticBaseGenerator, ArithmeticGate};
+use plonky2::gates::arithmetic_extension::{ArithmeticExtensionGate, ArithmeticExtensionGenerator};
+use plonky2::gates::base_sum::{BaseSplitGenerator, BaseSumGate};
+use plonky2::gates::constant::ConstantGate;
+use plonky2::gates::coset_interpolation::{CosetInterpolationGate, InterpolationGenerator};
+use plonky2::gates::exponentiation::{ExponentiationGate, ExponentiationGenerator};
+use plonky2::gates::lookup::{LookupGate, LookupGenerator};
+use plonky2::gates::lookup_table::{LookupTableGate, LookupTableGenerator};
+use plonky2::gates::multiplication_extension::{MulExtensionGate, MulExtensionGenerator};
+use plonky2::gates::noop::NoopGate;
+use plonky2::gates::poseidon::{PoseidonGate, PoseidonGenerator};
+use plonky2::gates::poseidon_mds::{PoseidonMdsGate, PoseidonMdsGenerator};
+use plonky2::gates::public_input::PublicInputGate;
+use plonky2::gates::random_access::{RandomAccessGate, RandomAccessGenerator};
+use plonky2::gates::reducing::{ReducingGate, ReducingGenerator};
+use plonky2::gates::reducing_extension::{
+    ReducingExtensionGate, ReducingGenerator as ReducingExtensionGenerator,
+};
+use plonky2::hash::hash_types::RichField;
+use plonky2::iop::generator::{ConstantGenerator, CopyGenerator, NonzeroTestGenerator, RandomValueGenerator};
+
+use crate::alu_gate::{AluGate, AluGenerator};
+use crate::error::{Error, Result};
+use crate::lookup::LimbSplitGenerator;
+
+/// Gate serializer for the instruction circuit: plonky2's default gates plus [`AluGate`].
+#[derive(Debug)]
+pub struct TraceGateSerializer;
+
+impl<F: RichField + Extendable<D>, const D: usize> GateSerializer<F, D> for TraceGateSerializer {
+    impl_gate_serializer! {
+        TraceGateSerializer,
+        ArithmeticGate,
+        ArithmeticExtensionGate<D>,
+        BaseSumGate<2>,
+        ConstantGate,
+        CosetInterpolationGate<F, D>,
+        ExponentiationGate<F, D>,
+        LookupGate,
+        LookupTableGate,
+        MulExtensionGate<D>,
+        NoopGate,
+        PoseidonMdsGate<F, D>,
+        PoseidonGate<F, D>,
+        PublicInputGate,
+        RandomAccessGate<F, D>,
+        ReducingExtensionGate<D>,
+        ReducingGate<D>,
+        AluGate
+    }
+}
+
+/// Generator serializer matching [`TraceGateSerializer`].
+#[derive(Debug, Default)]
+pub struct TraceGeneratorSerializer;
+
+impl<F: RichField + Extendable<D>, const D: usize> WitnessGeneratorSerializer<F, D>
+    for TraceGeneratorSerializer
+{
+    impl_generator_serializer! {
+        TraceGeneratorSerializer,
+        ArithmeticBaseGenerator<F, D>,
+        ArithmeticExtensionGenerator<F, D>,
+        BaseSplitGenerator<2>,
+        BaseSumGenerator<2>,
+        ConstantGenerator<F>,
+        CopyGenerator,
+        EqualityGenerator,
+        ExponentiationGenerator<F, D>,
+        InterpolationGenerator<F, D>,
+        LookupGenerator,
+        LookupTableGenerator,
+        LowHighGenerator,
+        MulExtensionGenerator<F, D>,
+        NonzeroTestGenerator,
+        PoseidonGenerator<F, D>,
+        PoseidonMdsGenerator<D>,
+        QuotientGeneratorExtension<D>,
+        RandomAccessGenerator<F, D>,
+        RandomValueGenerator,
+        ReducingGenerator<D>,
+        ReducingExtensionGenerator<D>,
+        SplitGenerator,
+        WireSplitGenerator,
+        AluGenerator,
+        LimbSplitGenerator
+    }
+}
+
+/// Writes a proof and the circuit data needed to verify it.
 pub fn save_proof_and_circuit(
     proof: &ProofWithPublicInputs<GoldilocksField, PoseidonGoldilocksConfig, 2>,
     data: &CircuitData<GoldilocksField, PoseidonGoldilocksConfig, 2>,
     proof_path: &str,
     circuit_path: &str,
-) {
-    let gate_serializer = DefaultGateSerializer;
-    let generator_serializer = DefaultGeneratorSerializer::<PoseidonGoldilocksConfig, 2>::default();
+) -> Result<()> {
+    let gate_serializer = TraceGateSerializer;
+    let generator_serializer = TraceGeneratorSerializer;
     let proof_bytes = proof.to_bytes();
     let data_bytes = data
         .to_bytes(&gate_serializer, &generator_serializer)
-        .unwrap();
+        .map_err(|e| Error::Serialization(format!("circuit: {:?}", e)))?;
 
-    fs::write(proof_path, proof_bytes).unwrap();
-    fs::write(circuit_path, data_bytes).unwrap();
+    fs::write(proof_path, proof_bytes).map_err(|e| Error::Io(format!("{}: {}", proof_path, e)))?;
+    fs::write(circuit_path, data_bytes).map_err(|e| Error::Io(format!("{}: {}", circuit_path, e)))
 }
 
+/// Reads back what [`save_proof_and_circuit`] wrote.
 pub fn load_proof_and_circuit(
     proof_path: &str,
     circuit_path: &str,
-) -> (
+) -> Result<(
     ProofWithPublicInputs<GoldilocksField, PoseidonGoldilocksConfig, 2>,
     CircuitData<GoldilocksField, PoseidonGoldilocksConfig, 2>,
-) {
-    let gate_serializer = DefaultGateSerializer;
-    let generator_serializer = DefaultGeneratorSerializer::<PoseidonGoldilocksConfig, 2>::default();
-    let circuit_bytes = fs::read(circuit_path).unwrap();
+)> {
+    let gate_serializer = TraceGateSerializer;
+    let generator_serializer = TraceGeneratorSerializer;
+    let circuit_bytes = fs::read(circuit_path).map_err(|e| Error::Io(format!("{}: {}", circuit_path, e)))?;
     let circuit = CircuitData::from_bytes(
         &circuit_bytes,
         &gate_serializer,
         &generator_serializer,
     )
-    .unwrap();
+    .map_err(|e| Error::Serialization(format!("{}: {:?}", circuit_path, e)))?;
 
-    let proof_bytes = fs::read(proof_path).unwrap();
-    let proof = ProofWithPublicInputs::from_bytes(proof_bytes, &circuit.common).unwrap();
+    let proof_bytes = fs::read(proof_path).map_err(|e| Error::Io(format!("{}: {}", proof_path, e)))?;
+    let proof = ProofWithPublicInputs::from_bytes(proof_bytes, &circuit.common)
+        .map_err(|e| Error::Serialization(format!("{}: {}", proof_path, e)))?;
 
-    (proof, circuit)
+    Ok((proof, circuit))
 }
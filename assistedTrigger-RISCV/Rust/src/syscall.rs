@@ -0,0 +1,350 @@
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::Field;
+use plonky2::hash::hash_types::HashOut;
+use plonky2::hash::poseidon::PoseidonHash;
+use plonky2::iop::target::Target;
+use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::config::Hasher;
+
+use crate::atomic::AtomicUnit;
+use crate::circuit::RowTargets;
+use crate::csr::InputVisibility;
+use crate::lookup::LookupTables;
+use crate::memory::MemoryLog;
+use crate::zk::opcode_to_id;
+
+/// Linux/newlib system calls understood by the circuit, identified by the number in `a7`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall {
+    /// `read(fd, buf, count)`: bytes from stdin (fd 0) become program input.
+    Read,
+    /// `write(fd, buf, count)`: bytes written to stdout (fd 1) become committed output.
+    Write,
+    /// `exit(code)` and `exit_group(code)`: the code becomes a public value.
+    Exit,
+    /// `brk(addr)`: moves the program break, or queries it when `addr` is 0.
+    Brk,
+}
+
+impl Syscall {
+    pub fn from_number(number: u64) -> Option<Syscall> {
+        match number {
+            63 => Some(Syscall::Read),
+            64 => Some(Syscall::Write),
+            93 | 94 => Some(Syscall::Exit),
+            214 => Some(Syscall::Brk),
+            _ => None,
+        }
+    }
+}
+
+/// Position of the buffer address in the `imm_val` of `read`/`write` rows.
+pub const BUFFER_SHIFT: u32 = 12;
+
+/// System call made by an `ecall` row.
+///
+/// The row's operands are `rs1 = a0` (fd, exit code or break address), `rs2 = a2` (byte
+/// count), `imm_val = a7` (syscall number) and `rd = a0` after the call. For `read` and
+/// `write`, `imm_val` also carries the buffer address in `a1`, as
+/// `a7 + a1 * 2^BUFFER_SHIFT`, and the bytes transferred are accesses to the words of the
+/// buffer in the [`MemoryLog`].
+#[derive(Debug, Clone)]
+pub struct SyscallRow<F: Field> {
+    pub syscall: Syscall,
+    pub number: u64,
+    /// Bytes transferred by `read`/`write`.
+    pub data: Vec<u8>,
+    /// Buffer address of `read`/`write`, in `a1`.
+    pub buf: u64,
+    /// Aligned words covering the buffer, before the call.
+    pub words_before: Vec<u32>,
+    /// Whether bytes read from stdin are committed in the public inputs.
+    pub visibility: InputVisibility,
+    /// Program break before a `brk` call. Only the first `brk` row needs it in the witness.
+    pub brk_before: F,
+}
+
+/// Number of public inputs appended by [`IoChannel::register_public_inputs`].
+pub const IO_PUBLIC_INPUTS: usize = 10;
+
+/// Digest committing to a byte stream, as found in the public inputs.
+///
+/// The stream is hashed with Poseidon, prefixed by its length.
+pub fn io_digest(bytes: &[u8]) -> HashOut<GoldilocksField> {
+    let inputs: Vec<GoldilocksField> = std::iter::once(bytes.len() as u64)
+        .chain(bytes.iter().map(|&b| b as u64))
+        .map(GoldilocksField::from_canonical_u64)
+        .collect();
+    PoseidonHash::hash_no_pad(&inputs)
+}
+
+/// Program I/O decoded from the last [`IO_PUBLIC_INPUTS`] public inputs of a proof.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IoOutputs {
+    pub stdout_digest: HashOut<GoldilocksField>,
+    /// `None` when stdin was a private input, which is committed as an all-zero digest.
+    pub stdin_digest: Option<HashOut<GoldilocksField>>,
+    pub exit_code: Option<GoldilocksField>,
+}
+
+impl IoOutputs {
+    /// Reads the I/O block at the end of the public inputs of a trace containing `ecall` rows.
+    pub fn from_public_inputs(public_inputs: &[GoldilocksField]) -> Option<IoOutputs> {
+        let io = public_inputs.get(public_inputs.len().checked_sub(IO_PUBLIC_INPUTS)?..)?;
+        let digest = |s: &[GoldilocksField]| HashOut { elements: [s[0], s[1], s[2], s[3]] };
+        Some(IoOutputs {
+            stdout_digest: digest(&io[0..4]),
+            stdin_digest: (io[4..8] != [GoldilocksField::ZERO; 4]).then(|| digest(&io[4..8])),
+            exit_code: io[8].is_one().then_some(io[9]),
+        })
+    }
+}
+
+/// Circuit state threaded through the `ecall` rows of a trace.
+#[derive(Debug, Default)]
+pub struct IoChannel {
+    stdout: Vec<Target>,
+    stdin: Vec<Target>,
+    stdin_public: bool,
+    exit_code: Option<Target>,
+    brk: Option<Target>,
+    witness: Vec<(Target, GoldilocksField)>,
+}
+
+impl IoChannel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the constraints of an `ecall` row.
+    ///
+    /// `write` reads its bytes from memory and `read` stores them there, which ends a
+    /// reservation on any word of the buffer.
+    pub fn add_syscall_row(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        tables: &mut LookupTables,
+        memory: &mut MemoryLog,
+        atomics: &mut AtomicUnit,
+        targets: &RowTargets,
+        row: &SyscallRow<GoldilocksField>,
+    ) -> anyhow::Result<()> {
+        let constant = |b: &mut CircuitBuilder<GoldilocksField, 2>, v: u64| {
+            b.constant(GoldilocksField::from_canonical_u64(v))
+        };
+        let opcode = constant(builder, opcode_to_id("ecall").expect("ecall has an id"));
+        builder.connect(targets.opcode, opcode);
+        let number = constant(builder, row.number);
+        builder.assert_zero(targets.imm_flag.target);
+
+        match row.syscall {
+            Syscall::Read | Syscall::Write => {
+                let buf = self.advice(builder, GoldilocksField::from_canonical_u64(row.buf));
+                let imm = builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << BUFFER_SHIFT), buf, number);
+                builder.connect(targets.imm_val, imm);
+                let fd = constant(builder, if row.syscall == Syscall::Read { 0 } else { 1 });
+                builder.connect(targets.rs1, fd);
+
+                // a0 returns the number of bytes transferred, at most the requested count.
+                let len = constant(builder, row.data.len() as u64);
+                builder.connect(targets.rd, len);
+                let slack = builder.sub(targets.rs2, len);
+                tables.range_check_bits(builder, slack, 32);
+
+                let bytes = self.transfer(builder, tables, memory, atomics, buf, row);
+                if row.syscall == Syscall::Read {
+                    self.stdin.extend(bytes);
+                    if row.visibility == InputVisibility::Public {
+                        self.stdin_public = true;
+                    }
+                } else {
+                    self.stdout.extend(bytes);
+                }
+            }
+            Syscall::Exit => {
+                builder.connect(targets.imm_val, number);
+                if self.exit_code.is_some() {
+                    anyhow::bail!("trace exits twice");
+                }
+                self.exit_code = Some(targets.rs1);
+            }
+            Syscall::Brk => {
+                builder.connect(targets.imm_val, number);
+                // brk(0) returns the current break; otherwise the break moves to `addr`.
+                let current = *self.brk.get_or_insert_with(|| {
+                    let t = builder.add_virtual_target();
+                    self.witness.push((t, row.brk_before));
+                    t
+                });
+                let zero = builder.zero();
+                let is_query = builder.is_equal(targets.rs1, zero);
+                let new_brk = builder.select(is_query, current, targets.rs1);
+                builder.connect(targets.rd, new_brk);
+                self.brk = Some(new_brk);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the memory accesses of the bytes a `read` or `write` transfers at `buf`, one per
+    /// word of the buffer, and returns the bytes in order.
+    fn transfer(
+        &mut self,
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        tables: &mut LookupTables,
+        memory: &mut MemoryLog,
+        atomics: &mut AtomicUnit,
+        buf: Target,
+        row: &SyscallRow<GoldilocksField>,
+    ) -> Vec<Target> {
+        let offset = (row.buf % 4) as usize;
+        let first = builder.add_const(buf, -GoldilocksField::from_canonical_usize(offset));
+        let mut bytes = Vec::with_capacity(row.data.len());
+        for (i, &word) in row.words_before.iter().enumerate() {
+            let mut read = Vec::with_capacity(4);
+            let mut written = Vec::with_capacity(4);
+            for j in 0..4 {
+                let before = self.byte(builder, tables, (word >> (8 * j)) as u8);
+                read.push(before);
+                let after = match (4 * i + j).checked_sub(offset).and_then(|k| row.data.get(k)) {
+                    Some(&byte) if row.syscall == Syscall::Read => self.byte(builder, tables, byte),
+                    Some(_) => before,
+                    None => {
+                        written.push(before);
+                        continue;
+                    }
+                };
+                bytes.push(after);
+                written.push(after);
+            }
+            let addr = builder.add_const(first, GoldilocksField::from_canonical_usize(4 * i));
+            let read = compose_word(builder, &read);
+            let written = compose_word(builder, &written);
+            memory.access(builder, tables, addr, &[read], &[written]);
+            if row.syscall == Syscall::Read {
+                atomics.add_store(builder, addr);
+            }
+        }
+        bytes
+    }
+
+    fn byte(&mut self, builder: &mut CircuitBuilder<GoldilocksField, 2>, tables: &mut LookupTables, value: u8) -> Target {
+        let t = self.advice(builder, GoldilocksField::from_canonical_u8(value));
+        tables.range_check_8(builder, t);
+        t
+    }
+
+    fn advice(&mut self, builder: &mut CircuitBuilder<GoldilocksField, 2>, value: GoldilocksField) -> Target {
+        let t = builder.add_virtual_target();
+        self.witness.push((t, value));
+        t
+    }
+
+    /// Appends the I/O block to the public inputs: the stdout digest, the stdin digest
+    /// (zero when stdin is private), an exit flag and the exit code (zero without exit).
+    pub fn register_public_inputs(&self, builder: &mut CircuitBuilder<GoldilocksField, 2>) {
+        let digest = |b: &mut CircuitBuilder<GoldilocksField, 2>, bytes: &[Target]| {
+            let len = b.constant(GoldilocksField::from_canonical_usize(bytes.len()));
+            let inputs = std::iter::once(len).chain(bytes.iter().copied()).collect();
+            b.hash_n_to_hash_no_pad::<PoseidonHash>(inputs).elements
+        };
+
+        let stdout = digest(builder, &self.stdout);
+        builder.register_public_inputs(&stdout);
+        let stdin = if self.stdin_public {
+            digest(builder, &self.stdin)
+        } else {
+            [builder.zero(); 4]
+        };
+        builder.register_public_inputs(&stdin);
+
+        let exited = builder.constant_bool(self.exit_code.is_some());
+        builder.register_public_input(exited.target);
+        let exit_code = self.exit_code.unwrap_or_else(|| builder.zero());
+        builder.register_public_input(exit_code);
+    }
+
+    /// Assigns the buffers, the transferred bytes and the initial program break.
+    pub fn set_witness(&self, pw: &mut PartialWitness<GoldilocksField>) -> Result<(), anyhow::Error> {
+        for &(target, value) in &self.witness {
+            pw.set_target(target, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Little-endian word of four bytes.
+fn compose_word(builder: &mut CircuitBuilder<GoldilocksField, 2>, bytes: &[Target]) -> Target {
+    bytes.iter().rev().fold(builder.zero(), |acc, &byte| {
+        builder.mul_const_add(GoldilocksField::from_canonical_u64(256), acc, byte)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::prove_multi_instruction_constraint;
+    use crate::trace_parser::{RegisterFile, TraceEntry};
+    use crate::zk::{convert_trace_to_rows, convert_trace_to_rows_with, ConversionMode, ConvertOptions};
+
+    fn ecall(number: u64, a0: u64, count: u64, ret: u64, data: Option<&[u8]>) -> TraceEntry {
+        let buf = 0x7002;
+        let before = RegisterFile::default().with(&[("x17", number), ("x10", a0), ("x11", buf), ("x12", count)]);
+        TraceEntry {
+            opcode: "ecall".to_string(),
+            rd: Some("x10".to_string()),
+            rs1: Some("x10".to_string()),
+            rs2: Some("x12".to_string()),
+            regs_before: before,
+            regs_after: before.with(&[("x10", ret)]),
+            syscall_data: data.map(<[u8]>::to_vec),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_syscalls_commit_io_and_exit_code() {
+        let trace = vec![
+            ecall(214, 0, 0, 0x5000, None),
+            ecall(214, 0x6000, 0, 0x6000, None),
+            ecall(63, 0, 16, 5, Some(b"hi\n42")),
+            ecall(64, 1, 3, 3, Some(b"hi\n")), // what read left in the buffer
+            ecall(64, 2, 1, 1, Some(b"!")), // stderr is not modeled
+            ecall(93, 7, 0, 7, None),
+        ];
+        let options = ConvertOptions {
+            stdin_inputs: InputVisibility::Private,
+            mode: ConversionMode::Lenient,
+            ..Default::default()
+        };
+        let rows = convert_trace_to_rows_with(&trace, &options).unwrap();
+        assert_eq!(rows.len(), 6);
+        assert!(rows[4].unproven.is_some());
+        assert_eq!(rows[3].imm_val, GoldilocksField::from_canonical_u64(64 + (0x7002 << BUFFER_SHIFT)));
+
+        let (proof, data) = prove_multi_instruction_constraint(&rows).unwrap();
+        let io = IoOutputs::from_public_inputs(&proof.public_inputs).unwrap();
+        assert_eq!(io.stdout_digest, io_digest(b"hi\n"));
+        assert_eq!(io.stdin_digest, None);
+        assert_eq!(io.exit_code, Some(GoldilocksField::from_canonical_u64(7)));
+        data.verify(proof).unwrap();
+
+        // brk(0) must return the break set by the previous call.
+        let mut rows = convert_trace_to_rows_with(&[trace[1].clone(), trace[0].clone()], &options).unwrap();
+        assert_eq!(rows.len(), 2);
+        rows[1].rd_val = GoldilocksField::from_canonical_u64(0x5000);
+        assert!(prove_multi_instruction_constraint(&rows).is_err());
+
+        // write outputs the bytes in memory at a1, not others.
+        let mut rows = convert_trace_to_rows_with(&trace[2..4], &options).unwrap();
+        rows[1].syscall.as_mut().unwrap().words_before[0] ^= 0x06 << 24; // "hi" -> "ho"
+        assert!(prove_multi_instruction_constraint(&rows).is_err());
+        let wrong = [trace[2].clone(), ecall(64, 1, 3, 3, Some(b"ho\n"))];
+        let err = convert_trace_to_rows(&wrong).unwrap_err();
+        assert!(err.to_string().contains("memory at 0x7003 holds 0x69 but write outputs 0x6f"), "{}", err);
+
+        let err = convert_trace_to_rows(&[trace[5].clone(), trace[5].clone()]).unwrap_err();
+        assert!(err.to_string().contains("the trace has already exited"), "{}", err);
+    }
+}
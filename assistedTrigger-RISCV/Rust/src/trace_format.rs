@@ -0,0 +1,426 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+use bincode::Options;
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::{Field, PrimeField64};
+use plonky2::hash::poseidon::PoseidonHash;
+use plonky2::plonk::config::Hasher;
+use serde::{Deserialize, Serialize};
+
+use crate::inputs::ProgramInputs;
+use crate::trace_parser::{TraceEntry, TraceReader};
+use crate::trace_source::TraceSource;
+
+/// Version of the schema below, bumped on any incompatible change.
+pub const TRACE_FORMAT_VERSION: u32 = 2;
+
+/// ISA string of the traces this crate produces and proves.
+pub const TRACE_ISA: &str = "rv64imafdc_zicsr_zba_zbb";
+
+/// First bytes of a bincode trace, followed by the version as a little-endian `u32`.
+pub const BINCODE_MAGIC: &[u8; 8] = b"RVTRACE\0";
+
+/// bincode settings of the binary encoding: variable-length integers keep register values
+/// and addresses small.
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+/// Tracer that produced a trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TracerBackend {
+    Gdb,
+    Rsp,
+    QemuLog,
+    Emulator,
+    /// Imported from the legacy `trace_cleaned.log` text, whose producer is not recorded.
+    LegacyText,
+    Synthetic,
+}
+
+/// What a trace is a trace of.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceHeader {
+    pub version: u32,
+    pub isa: String,
+    pub xlen: u32,
+    /// [`program_hash`] of the executable, when known.
+    pub program_hash: Option<[u64; 4]>,
+    pub inputs: ProgramInputs,
+    pub backend: TracerBackend,
+}
+
+impl TraceHeader {
+    /// Header of the current version for a trace of an unidentified program.
+    pub fn new(backend: TracerBackend, inputs: ProgramInputs) -> Self {
+        TraceHeader {
+            version: TRACE_FORMAT_VERSION,
+            isa: TRACE_ISA.to_string(),
+            xlen: 64,
+            program_hash: None,
+            inputs,
+            backend,
+        }
+    }
+
+    /// Identifies the program by hashing the executable at `path`.
+    pub fn with_program(mut self, path: &str) -> Result<Self, TraceFormatError> {
+        let bytes = std::fs::read(path).map_err(|e| TraceFormatError::Io(format!("{}: {}", path, e)))?;
+        self.program_hash = Some(program_hash(&bytes));
+        Ok(self)
+    }
+}
+
+/// Poseidon digest of an executable: its length, then its bytes as little-endian 32-bit words
+/// (the last one zero-padded).
+pub fn program_hash(bytes: &[u8]) -> [u64; 4] {
+    let words = bytes.chunks(4).map(|w| {
+        let mut word = [0; 4];
+        word[..w.len()].copy_from_slice(w);
+        GoldilocksField::from_canonical_u32(u32::from_le_bytes(word))
+    });
+    let elements: Vec<GoldilocksField> =
+        std::iter::once(GoldilocksField::from_canonical_u64(bytes.len() as u64)).chain(words).collect();
+    PoseidonHash::hash_no_pad(&elements).elements.map(|e| e.to_canonical_u64())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceFormatError {
+    Io(String),
+    /// A JSON Lines record that does not parse, counted from 1.
+    Json { line: usize, error: String },
+    Bincode(String),
+    /// A trace written by a newer (or unknown) version of the schema.
+    UnsupportedVersion(u32),
+    /// A legacy text trace that does not parse.
+    Legacy(String),
+}
+
+impl fmt::Display for TraceFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceFormatError::Io(e) => write!(f, "cannot access trace: {}", e),
+            TraceFormatError::Json { line, error } => write!(f, "trace line {}: {}", line, error),
+            TraceFormatError::Bincode(e) => write!(f, "bad binary trace: {}", e),
+            TraceFormatError::UnsupportedVersion(v) => {
+                write!(f, "trace format version {} is not supported (expected {})", v, TRACE_FORMAT_VERSION)
+            }
+            TraceFormatError::Legacy(e) => write!(f, "bad legacy trace: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TraceFormatError {}
+
+fn io_error(e: std::io::Error) -> TraceFormatError {
+    TraceFormatError::Io(e.to_string())
+}
+
+fn open(path: &str) -> Result<BufReader<File>, TraceFormatError> {
+    let file = File::open(path).map_err(|e| TraceFormatError::Io(format!("{}: {}", path, e)))?;
+    Ok(BufReader::new(file))
+}
+
+/// Tells the encodings apart by their first bytes; anything else is taken for legacy text.
+fn detect(reader: &mut impl BufRead) -> Result<Option<TraceEncoding>, TraceFormatError> {
+    let start = reader.fill_buf().map_err(io_error)?;
+    Ok(if start.starts_with(BINCODE_MAGIC) {
+        Some(TraceEncoding::Bincode)
+    } else if start.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{') {
+        Some(TraceEncoding::JsonLines)
+    } else {
+        None
+    })
+}
+
+fn jsonl_header(n: usize, line: &str) -> Result<TraceHeader, TraceFormatError> {
+    let json = |e: serde_json::Error| TraceFormatError::Json { line: n + 1, error: e.to_string() };
+    let value: serde_json::Value = serde_json::from_str(line).map_err(json)?;
+    let version = value.get("version").and_then(serde_json::Value::as_u64).unwrap_or(0) as u32;
+    if version != TRACE_FORMAT_VERSION {
+        return Err(TraceFormatError::UnsupportedVersion(version));
+    }
+    serde_json::from_value(value).map_err(json)
+}
+
+fn bincode_header(reader: &mut impl Read) -> Result<TraceHeader, TraceFormatError> {
+    let mut prefix = [0; 12];
+    reader.read_exact(&mut prefix).map_err(io_error)?;
+    if &prefix[..8] != BINCODE_MAGIC {
+        return Err(TraceFormatError::Bincode("missing magic bytes".to_string()));
+    }
+    let version = u32::from_le_bytes(prefix[8..].try_into().unwrap());
+    if version != TRACE_FORMAT_VERSION {
+        return Err(TraceFormatError::UnsupportedVersion(version));
+    }
+    bincode_options().deserialize_from(reader).map_err(|e| TraceFormatError::Bincode(e.to_string()))
+}
+
+fn legacy_header(inputs_text: &str) -> Result<TraceHeader, TraceFormatError> {
+    let inputs = ProgramInputs::parse_header(inputs_text).map_err(TraceFormatError::Legacy)?;
+    Ok(TraceHeader::new(TracerBackend::LegacyText, inputs))
+}
+
+/// How a [`StructuredTrace`] is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEncoding {
+    /// The header on the first line, then one step per line; readable and diffable.
+    JsonLines,
+    /// [`BINCODE_MAGIC`], the version, then the header and the steps; compact.
+    Bincode,
+}
+
+/// A trace in the versioned schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructuredTrace {
+    pub header: TraceHeader,
+    pub steps: Vec<TraceEntry>,
+}
+
+impl StructuredTrace {
+
+    /// Runs `source` and records its trace, with the inputs it reports.
+    pub fn record(
+        source: &mut impl TraceSource,
+        backend: TracerBackend,
+        program: Option<&str>,
+    ) -> crate::error::Result<Self> {
+        let entries = source.trace()?;
+        let mut header = TraceHeader::new(backend, source.inputs()?);
+        if let Some(program) = program {
+            header = header.with_program(program)?;
+        }
+        Ok(StructuredTrace { header, steps: entries })
+    }
+
+    pub fn write_jsonl(&self, mut writer: impl Write) -> Result<(), TraceFormatError> {
+        let json = |e: serde_json::Error| TraceFormatError::Json { line: 0, error: e.to_string() };
+        serde_json::to_writer(&mut writer, &self.header).map_err(json)?;
+        writeln!(writer).map_err(io_error)?;
+        for step in &self.steps {
+            serde_json::to_writer(&mut writer, step).map_err(json)?;
+            writeln!(writer).map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    /// Reads JSON Lines, checking the header's version before the rest of its fields.
+    pub fn read_jsonl(reader: impl BufRead) -> Result<Self, TraceFormatError> {
+        let mut lines = reader.lines().enumerate().filter(|(_, l)| l.as_ref().map_or(true, |l| !l.trim().is_empty()));
+        let json = |line: usize| move |e: serde_json::Error| TraceFormatError::Json { line: line + 1, error: e.to_string() };
+        let (n, first) = lines.next().ok_or_else(|| TraceFormatError::Json { line: 1, error: "no header".to_string() })?;
+        let header = jsonl_header(n, &first.map_err(io_error)?)?;
+        let steps = lines
+            .map(|(n, line)| serde_json::from_str(&line.map_err(io_error)?).map_err(json(n)))
+            .collect::<Result<_, _>>()?;
+        Ok(StructuredTrace { header, steps })
+    }
+
+    pub fn write_bincode(&self, mut writer: impl Write) -> Result<(), TraceFormatError> {
+        writer.write_all(BINCODE_MAGIC).map_err(io_error)?;
+        writer.write_all(&self.header.version.to_le_bytes()).map_err(io_error)?;
+        let bincode = |e: bincode::Error| TraceFormatError::Bincode(e.to_string());
+        bincode_options().serialize_into(&mut writer, &self.header).map_err(bincode)?;
+        bincode_options().serialize_into(&mut writer, &self.steps).map_err(bincode)
+    }
+
+    pub fn read_bincode(mut reader: impl Read) -> Result<Self, TraceFormatError> {
+        let header = bincode_header(&mut reader)?;
+        let bincode = |e: bincode::Error| TraceFormatError::Bincode(e.to_string());
+        let steps = bincode_options().deserialize_from(&mut reader).map_err(bincode)?;
+        Ok(StructuredTrace { header, steps })
+    }
+
+    pub fn write_file(&self, path: &str, encoding: TraceEncoding) -> Result<(), TraceFormatError> {
+        let file = File::create(path).map_err(|e| TraceFormatError::Io(format!("{}: {}", path, e)))?;
+        let mut writer = BufWriter::new(file);
+        match encoding {
+            TraceEncoding::JsonLines => self.write_jsonl(&mut writer)?,
+            TraceEncoding::Bincode => self.write_bincode(&mut writer)?,
+        }
+        writer.flush().map_err(io_error)
+    }
+
+    /// Reads a trace in either encoding or in the legacy text format, told apart by their
+    /// first bytes.
+    pub fn read_file(path: &str) -> Result<Self, TraceFormatError> {
+        let mut reader = open(path)?;
+        match detect(&mut reader)? {
+            Some(TraceEncoding::Bincode) => StructuredTrace::read_bincode(reader),
+            Some(TraceEncoding::JsonLines) => StructuredTrace::read_jsonl(reader),
+            None => read_legacy(reader, path),
+        }
+    }
+
+    /// Like [`Self::read_file`], but reads the steps one at a time as they are consumed.
+    pub fn stream_file(path: &str) -> Result<StepReader, TraceFormatError> {
+        let header = StructuredTrace::read_header(path)?;
+        let mut reader = open(path)?;
+        let steps = match detect(&mut reader)? {
+            Some(TraceEncoding::Bincode) => {
+                bincode_header(&mut reader)?;
+                let bincode = |e: bincode::Error| TraceFormatError::Bincode(e.to_string());
+                let remaining = bincode_options().deserialize_from(&mut reader).map_err(bincode)?;
+                Steps::Bincode { reader, remaining }
+            }
+            Some(TraceEncoding::JsonLines) => {
+                let mut lines = reader.lines().enumerate();
+                // Skip up to and including the header line.
+                for (_, line) in lines.by_ref() {
+                    if !line.map_err(io_error)?.trim().is_empty() {
+                        break;
+                    }
+                }
+                Steps::JsonLines(lines)
+            }
+            None => Steps::Legacy(Box::new(TraceReader::new(reader, path))),
+        };
+        Ok(StepReader { header, steps })
+    }
+
+    /// Reads only the header of a trace file in any of the formats of [`Self::read_file`],
+    /// leaving its steps unread.
+    pub fn read_header(path: &str) -> Result<TraceHeader, TraceFormatError> {
+        let mut reader = open(path)?;
+        match detect(&mut reader)? {
+            Some(TraceEncoding::Bincode) => bincode_header(&mut reader),
+            Some(TraceEncoding::JsonLines) => {
+                let mut first = String::new();
+                while first.trim().is_empty() {
+                    if reader.read_line(&mut first).map_err(io_error)? == 0 {
+                        return Err(TraceFormatError::Json { line: 1, error: "no header".to_string() });
+                    }
+                }
+                jsonl_header(0, &first)
+            }
+            None => {
+                // The inputs are the `#` lines at the top; the first instruction ends them.
+                let mut text = String::new();
+                for line in reader.lines() {
+                    let line = line.map_err(io_error)?;
+                    if !line.starts_with('#') {
+                        break;
+                    }
+                    text.push_str(&line);
+                    text.push('\n');
+                }
+                legacy_header(&text)
+            }
+        }
+    }
+}
+
+/// Steps of a trace file, read as they are consumed; see [`StructuredTrace::stream_file`].
+pub struct StepReader {
+    pub header: TraceHeader,
+    steps: Steps,
+}
+
+enum Steps {
+    JsonLines(std::iter::Enumerate<std::io::Lines<BufReader<File>>>),
+    Bincode { reader: BufReader<File>, remaining: u64 },
+    Legacy(Box<TraceReader<'static, BufReader<File>>>),
+}
+
+impl Iterator for StepReader {
+    type Item = Result<TraceEntry, TraceFormatError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.steps {
+            Steps::JsonLines(lines) => lines.find(|(_, l)| l.as_ref().map_or(true, |l| !l.trim().is_empty())).map(|(n, line)| {
+                let json = |e: serde_json::Error| TraceFormatError::Json { line: n + 1, error: e.to_string() };
+                serde_json::from_str(&line.map_err(io_error)?).map_err(json)
+            }),
+            Steps::Bincode { reader, remaining } => {
+                if *remaining == 0 {
+                    return None;
+                }
+                *remaining -= 1;
+                let step = bincode_options().deserialize_from(reader).map_err(|e| TraceFormatError::Bincode(e.to_string()));
+                if step.is_err() {
+                    *remaining = 0;
+                }
+                Some(step)
+            }
+            Steps::Legacy(entries) => entries.next().map(|e| e.map_err(|e| TraceFormatError::Legacy(e.to_string()))),
+        }
+    }
+}
+
+/// Imports a `trace_cleaned.log` text trace, with the inputs of its `#` header.
+pub fn import_legacy(path: &str) -> Result<StructuredTrace, TraceFormatError> {
+    read_legacy(open(path)?, path)
+}
+
+fn read_legacy(mut reader: impl Read, path: &str) -> Result<StructuredTrace, TraceFormatError> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text).map_err(io_error)?;
+    let header = legacy_header(&text)?;
+    let steps = TraceReader::new(text.as_bytes(), path)
+        .collect::<crate::error::Result<_>>()
+        .map_err(|e| TraceFormatError::Legacy(e.to_string()))?;
+    Ok(StructuredTrace { header, steps })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inputs::SensorInput;
+    use crate::trace_source::EmulatorRunner;
+
+    #[test]
+    fn test_round_trip_both_encodings() {
+        let inputs = ProgramInputs {
+            args: vec!["--rate".to_string()],
+            sensor: Some(SensorInput { addr: 0x2000_0000, data: vec![1, 2, 3] }),
+            ..Default::default()
+        };
+        let mut runner = EmulatorRunner { program: "test.bin".to_string(), inputs: inputs.clone(), max_steps: 10_000 };
+        let trace = StructuredTrace::record(&mut runner, TracerBackend::Emulator, Some("test.bin")).unwrap();
+        assert_eq!(trace.header.inputs, inputs);
+        assert_eq!(trace.header.program_hash, Some(program_hash(&std::fs::read("test.bin").unwrap())));
+        assert_eq!(trace.steps, runner.trace().unwrap());
+        assert_ne!(trace.steps[0].regs_before.x[2], 0); // sp
+
+        let dir = std::env::temp_dir();
+        let jsonl = dir.join("trace_format.jsonl").to_string_lossy().into_owned();
+        let bin = dir.join("trace_format.bin").to_string_lossy().into_owned();
+        trace.write_file(&jsonl, TraceEncoding::JsonLines).unwrap();
+        trace.write_file(&bin, TraceEncoding::Bincode).unwrap();
+        assert_eq!(StructuredTrace::read_file(&jsonl).unwrap(), trace);
+        assert_eq!(StructuredTrace::read_file(&bin).unwrap(), trace);
+        assert_eq!(StructuredTrace::read_header(&jsonl).unwrap(), trace.header);
+        assert_eq!(StructuredTrace::read_header(&bin).unwrap(), trace.header);
+        for path in [&jsonl, &bin] {
+            let steps = StructuredTrace::stream_file(path).unwrap();
+            assert_eq!(steps.header, trace.header);
+            assert_eq!(steps.collect::<Result<Vec<_>, _>>().unwrap(), trace.steps);
+        }
+        let text = std::fs::read_to_string(&jsonl).unwrap();
+        assert_eq!(text.lines().count(), 1 + trace.steps.len());
+        let size = std::fs::metadata(&bin).unwrap().len();
+        assert!(size < text.len() as u64);
+
+        // Other versions are refused before their fields are read.
+        let newer = text.replacen("\"version\":2", "\"version\":3", 1);
+        assert_eq!(StructuredTrace::read_jsonl(newer.as_bytes()), Err(TraceFormatError::UnsupportedVersion(3)));
+        let mut bytes = std::fs::read(&bin).unwrap();
+        bytes[8] = 7;
+        assert_eq!(StructuredTrace::read_bincode(&bytes[..]), Err(TraceFormatError::UnsupportedVersion(7)));
+        let bad_step = format!("{}\n{{\"pc\":1}}\n", text.lines().next().unwrap());
+        assert!(matches!(StructuredTrace::read_jsonl(bad_step.as_bytes()), Err(TraceFormatError::Json { line: 2, .. })));
+    }
+
+    #[test]
+    fn test_import_legacy_text() {
+        let imported = StructuredTrace::read_file("traces/sample_trace.log").unwrap();
+        assert_eq!(imported.header.backend, TracerBackend::LegacyText);
+        assert_eq!(imported.header.version, TRACE_FORMAT_VERSION);
+        assert_eq!(StructuredTrace::read_header("traces/sample_trace.log").unwrap(), imported.header);
+        let streamed = StructuredTrace::stream_file("traces/sample_trace.log").unwrap();
+        assert_eq!(streamed.collect::<Result<Vec<_>, _>>().unwrap(), imported.steps);
+        assert_eq!(imported.steps, crate::trace_parser::parse_trace("traces/sample_trace.log").unwrap());
+    }
+}
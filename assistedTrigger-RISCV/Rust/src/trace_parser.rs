@@ -1,9 +1,18 @@
-use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq)]
+use crate::decoder::{decode, equivalent, instruction_len};
+use crate::elf::ElfFile;
+use crate::error::{Error, Result};
+use crate::instruction::{CsrSource, Instruction, Reg};
+
+/// One executed instruction: its operands, the register state around it and its effects.
+///
+/// This is also the step record of [`crate::trace_format`], so the field order is part of its
+/// binary encoding.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub struct TraceEntry {
     pub pc: u64,
     pub opcode: String,
@@ -11,115 +20,627 @@ pub struct TraceEntry {
     pub rs1: Option<String>,
     pub rs2: Option<String>,
     pub imm: Option<i64>,
-    pub reg_values_before: HashMap<String, u64>, // rs1, rs2 values before instruction
-    pub reg_values_after: HashMap<String, u64>,  // rd value after instruction
-}
-
-pub fn parse_trace(trace_path: &str) -> Vec<TraceEntry> {
-    let file = File::open(trace_path).expect("Cannot open trace log");
-    let reader = BufReader::new(file);
-
-    let re_instr = Regex::new(
-        r"^\s*(0x[0-9a-fA-F]+):\s+([a-z0-9]+)\s+([x][0-9]+)(?:,\s*([x][0-9]+))?(?:,\s*(-?\d+|[x][0-9]+))?"
-    ).unwrap();
-    let re_reg = Regex::new(r"(x[0-9]+)=0x([0-9a-fA-F]+)").unwrap();
-
-    let mut entries = Vec::new();
-    let mut last_regs: HashMap<String, u64> = HashMap::new();
-    let mut pending_regs: Option<HashMap<String, u64>> = None;
-    let mut last_reg_dump: HashMap<String, u64> = HashMap::new();
-    let mut current_entry: Option<TraceEntry> = None;
-
-    for line in reader.lines().flatten() {
-        if re_instr.is_match(&line) {
-            // Push previous instruction before processing new one
-            if let Some(entry) = current_entry.take() {
-                entries.push(entry);
-            }
+    /// CSR operand of `csrr*` instructions, which are stored in canonical form
+    /// (`csrrw`/`csrrs`/`csrrc` and their `i` variants, whose 5-bit source is in `imm`).
+    pub csr: Option<String>,
+    /// Static rounding mode operand of a floating-point instruction (`rne`, `rtz`, ...).
+    pub rounding: Option<String>,
+    /// Every register before the instruction. Registers a backend does not observe, such as
+    /// the `f` registers of the emulator, are zero.
+    pub regs_before: RegisterFile,
+    /// Every register after the instruction.
+    pub regs_after: RegisterFile,
+    /// Data memory access of a load or store, when the trace source observes it.
+    pub memory: Option<MemoryAccess>,
+    /// Bytes transferred by a `read`/`write` `ecall`, from the `data=` line that follows it.
+    pub syscall_data: Option<Vec<u8>>,
+}
 
-            // Update last_regs with the pending register dump (pre-instruction state)
-            if let Some(new_regs) = pending_regs.take() {
-                last_regs = new_regs;
-            }
+/// Bytes accessed by a load or store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryAccess {
+    pub addr: u64,
+    /// Access width in bytes.
+    pub width: u8,
+    /// Little-endian contents of the accessed bytes before the instruction.
+    pub read: u64,
+    /// Value stored, truncated to the access width.
+    pub written: Option<u64>,
+}
 
-            let caps = re_instr.captures(&line).unwrap();
-            let pc = u64::from_str_radix(&caps[1][2..], 16).unwrap();
-            let opcode = caps[2].to_string();
-            let rd = Some(caps[3].to_string());
-            let rs1 = caps.get(4).map(|m| m.as_str().to_string());
-            let third = caps.get(5).map(|m| m.as_str().to_string());
-
-            let (rs2, imm) = match (&opcode[..], third) {
-                ("addi", Some(s)) => {
-                    if let Ok(val) = s.parse::<i64>() {
-                        (None, Some(val))
-                    } else {
-                        panic!("❌ Invalid `addi` syntax: expected immediate but got {}", s);
-                    }
-                }
-                (_, Some(s)) if s.starts_with('x') => (Some(s), None),
-                (_, Some(s)) => (None, s.parse::<i64>().ok()),
-                (_, None) => (None, None),
-            };
-
-            // Prepare reg_values_before from last_regs for rs1, rs2
-            let mut reg_values_before = HashMap::new();
-            if let Some(rs1) = &rs1 {
-                if let Some(val) = last_regs.get(rs1) {
-                    reg_values_before.insert(rs1.clone(), *val);
-                }
+/// The integer and floating-point register files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct RegisterFile {
+    pub x: [u64; 32],
+    /// Raw bits of `f0`..`f31`; single-precision values are NaN-boxed.
+    pub f: [u64; 32],
+}
+
+impl RegisterFile {
+    pub fn read(&self, reg: Reg) -> u64 {
+        match reg {
+            Reg::X(n) => self.x[n as usize],
+            Reg::F(n) => self.f[n as usize],
+        }
+    }
+
+    /// Sets `reg`; writes to `x0` are ignored.
+    pub fn write(&mut self, reg: Reg, value: u64) {
+        match reg {
+            Reg::X(0) => {}
+            Reg::X(n) => self.x[n as usize] = value,
+            Reg::F(n) => self.f[n as usize] = value,
+        }
+    }
+
+    /// Value of a register given by name (`x5`, `t0`, `f14`, ...).
+    pub fn get(&self, name: &str) -> Option<u64> {
+        Reg::parse(name).map(|reg| self.read(reg))
+    }
+
+    /// Copy with the named registers set, for building synthetic traces.
+    #[cfg(test)]
+    pub(crate) fn with(mut self, values: &[(&str, u64)]) -> Self {
+        for &(name, value) in values {
+            self.write(Reg::parse(name).unwrap_or_else(|| panic!("unknown register {}", name)), value);
+        }
+        self
+    }
+
+    /// Registers whose values differ between `self` and `other`.
+    pub fn changed(&self, other: &RegisterFile) -> Vec<Reg> {
+        let x = (0..32).filter(|&n| self.x[n] != other.x[n]).map(|n| Reg::X(n as u8));
+        let f = (0..32).filter(|&n| self.f[n] != other.f[n]).map(|n| Reg::F(n as u8));
+        x.chain(f).collect()
+    }
+}
+
+/// Decodes the hex byte string of a `data=` line.
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses the `addr,width,read[,written]` operand of a `mem=` line, numbers in hex with `0x`.
+fn parse_memory_access(text: &str) -> Option<MemoryAccess> {
+    let hex = |s: &str| u64::from_str_radix(s.trim().strip_prefix("0x")?, 16).ok();
+    let fields: Vec<&str> = text.split(',').collect();
+    let (addr, width, read, written) = match fields[..] {
+        [addr, width, read] => (addr, width, read, None),
+        [addr, width, read, written] => (addr, width, read, Some(hex(written)?)),
+        _ => return None,
+    };
+    let width = width.trim().parse::<u8>().ok().filter(|w| [1, 2, 4, 8].contains(w))?;
+    Some(MemoryAccess { addr: hex(addr)?, width, read: hex(read)?, written })
+}
+
+impl TraceEntry {
+    /// Entry of `inst` at `pc`, without register values.
+    ///
+    /// Operands follow the emulator's layout: loads, stores, `jalr` and atomics put the base
+    /// register in `rs1`, stores their source in `rs2`, and branches and `jal` carry their
+    /// target as an offset from `pc` in `imm`.
+    pub fn from_instruction(pc: u64, inst: &Instruction) -> TraceEntry {
+        let name = |r: &Reg| Some(r.to_string());
+        let mut entry = TraceEntry { pc, opcode: inst.mnemonic().to_string(), ..Default::default() };
+        let relative = |target: u64| Some(target.wrapping_sub(pc) as i64);
+        match inst {
+            Instruction::RegReg { rd, rs1, rs2, .. } => (entry.rd, entry.rs1, entry.rs2) = (name(rd), name(rs1), name(rs2)),
+            Instruction::RegImm { rd, rs1, imm, .. } => (entry.rd, entry.rs1, entry.imm) = (name(rd), name(rs1), Some(*imm)),
+            Instruction::Unary { rd, rs1, .. } => (entry.rd, entry.rs1) = (name(rd), name(rs1)),
+            Instruction::Upper { rd, imm, .. } => (entry.rd, entry.imm) = (name(rd), Some(*imm)),
+            Instruction::Load { rd, base, offset, .. } | Instruction::Jalr { rd, base, offset } => {
+                (entry.rd, entry.rs1, entry.imm) = (name(rd), name(base), Some(*offset))
+            }
+            Instruction::Store { src, base, offset, .. } => {
+                (entry.rs1, entry.rs2, entry.imm) = (name(base), name(src), Some(*offset))
             }
-            if let Some(rs2) = &rs2 {
-                if let Some(val) = last_regs.get(rs2) {
-                    reg_values_before.insert(rs2.clone(), *val);
+            Instruction::Branch { rs1, rs2, target, .. } => {
+                (entry.rs1, entry.rs2, entry.imm) = (name(rs1), name(rs2), relative(*target))
+            }
+            Instruction::Jal { rd, target } => (entry.rd, entry.imm) = (name(rd), relative(*target)),
+            Instruction::Csr { rd, csr, src, .. } => {
+                entry.rd = name(rd);
+                entry.csr = Some(csr.clone());
+                match src {
+                    CsrSource::Reg(rs1) => entry.rs1 = name(rs1),
+                    CsrSource::Imm(imm) => entry.imm = Some(*imm),
                 }
             }
-
-            current_entry = Some(TraceEntry {
-                pc,
-                opcode,
-                rd,
-                rs1,
-                rs2,
-                imm,
-                reg_values_before,
-                reg_values_after: HashMap::new(), // fill after parsing register dump
-            });
-        } else if line.starts_with('x') {
-            let mut regs = HashMap::new();
-            for cap in re_reg.captures_iter(&line) {
-                let reg = cap[1].to_string();
-                let val = u64::from_str_radix(&cap[2], 16).unwrap_or(0);
-                regs.insert(reg, val);
+            Instruction::Atomic { rd, src, base, .. } => {
+                (entry.rd, entry.rs1, entry.rs2) = (name(rd), name(base), src.as_ref().and_then(name))
             }
+            Instruction::Fp { rd, rs1, rs2, rounding, .. } => {
+                (entry.rd, entry.rs1, entry.rs2) = (name(rd), name(rs1), rs2.as_ref().and_then(name));
+                entry.rounding = rounding.clone();
+            }
+            Instruction::System { op, .. } if op == "ecall" => {
+                entry.rd = Some("x10".to_string());
+                entry.rs1 = Some("x10".to_string());
+                entry.rs2 = Some("x12".to_string());
+            }
+            Instruction::System { .. } | Instruction::Other { .. } => {}
+        }
+        entry
+    }
+
+    /// Value of the register named `name` before the instruction.
+    pub fn before(&self, name: &str) -> Option<u64> {
+        self.regs_before.get(name)
+    }
+
+    /// Value of the register named `name` after the instruction.
+    pub fn after(&self, name: &str) -> Option<u64> {
+        self.regs_after.get(name)
+    }
+
+    /// Registers the instruction changed other than `rd`, which no instruction does.
+    pub fn side_effects(&self) -> Vec<Reg> {
+        let rd = self.rd.as_deref().and_then(Reg::parse);
+        self.regs_before.changed(&self.regs_after).into_iter().filter(|&reg| Some(reg) != rd).collect()
+    }
+}
+
+/// An instruction whose disassembly in the trace disagrees with the decoding of its bits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeMismatch {
+    pub pc: u64,
+    pub bits: u32,
+    pub disassembly: Instruction,
+    /// `None` when the bits are not a known RV64GC instruction.
+    pub decoded: Option<Instruction>,
+}
+
+/// Bits of the instruction at `pc` in the program image.
+fn image_bits(elf: &ElfFile, pc: u64) -> Option<u32> {
+    let low = u16::from_le_bytes(elf.read(pc, 2)?.try_into().ok()?) as u32;
+    match instruction_len(low) {
+        2 => Some(low),
+        _ => Some(u32::from_le_bytes(elf.read(pc, 4)?.try_into().ok()?)),
+    }
+}
+
+/// Parses a trace in the cleaned format, logging a warning for each [`DecodeMismatch`].
+/// Use [`parse_trace_checked`] to get the mismatches.
+pub fn parse_trace(trace_path: &str) -> Result<Vec<TraceEntry>> {
+    let (entries, mismatches) = parse_trace_checked(trace_path, None)?;
+    for m in &mismatches {
+        match &m.decoded {
+            Some(decoded) => log::warn!(
+                "instruction at 0x{:x} is traced as `{}` but its bits 0x{:x} decode as `{}`",
+                m.pc, m.disassembly, m.bits, decoded
+            ),
+            None => log::warn!(
+                "instruction at 0x{:x} is traced as `{}` but its bits 0x{:x} are not a known instruction",
+                m.pc, m.disassembly, m.bits
+            ),
+        }
+    }
+    Ok(entries)
+}
+
+/// Parses a trace in the cleaned format, decoding each instruction from its bits.
+///
+/// The bits come from the `insn=` line that follows the instruction, or else from `elf`.
+/// Entries are built from the decoded instruction, so the disassembly text is only trusted
+/// when the bits are unknown. Where the two disagree, the instruction is returned as a
+/// [`DecodeMismatch`]. Lines that do not parse are reported with their line number.
+pub fn parse_trace_checked(trace_path: &str, elf: Option<&ElfFile>) -> Result<(Vec<TraceEntry>, Vec<DecodeMismatch>)> {
+    let mut reader = TraceReader::open(trace_path)?;
+    if let Some(elf) = elf {
+        reader = reader.with_elf(elf);
+    }
+    let entries = reader.by_ref().collect::<Result<_>>()?;
+    Ok((entries, reader.take_mismatches()))
+}
+
+/// Streaming parser of the cleaned trace format: an iterator over the entries of a trace
+/// that reads one instruction ahead, so memory does not grow with the length of the trace.
+///
+/// [`parse_trace_checked`] is this reader collected. The first error ends the iteration.
+pub struct TraceReader<'a, R> {
+    lines: std::iter::Enumerate<std::io::Lines<R>>,
+    path: String,
+    elf: Option<&'a ElfFile>,
+    re_instr: Regex,
+    re_reg: Regex,
+    mismatches: Vec<DecodeMismatch>,
+    /// Registers as of the latest dump. Dumps may list only some registers, e.g. no `f`
+    /// registers, so the others keep their earlier values.
+    regs: RegisterFile,
+    current_entry: Option<TraceEntry>,
+    // Disassembly of the current entry and the image bits it was checked against, if any.
+    disassembly: Option<(Instruction, Option<u32>)>,
+    done: bool,
+}
+
+impl TraceReader<'_, BufReader<File>> {
+    pub fn open(trace_path: &str) -> Result<Self> {
+        let file = File::open(trace_path).map_err(|e| Error::Io(format!("{}: {}", trace_path, e)))?;
+        Ok(TraceReader::new(BufReader::new(file), trace_path))
+    }
+}
+
+impl<'a, R: BufRead> TraceReader<'a, R> {
+    /// Reads a trace from `reader`; `path` names it in errors.
+    pub fn new(reader: R, path: &str) -> Self {
+        TraceReader {
+            lines: reader.lines().enumerate(),
+            path: path.to_string(),
+            elf: None,
+            re_instr: Regex::new(r"^\s*0x([0-9a-fA-F]+):\s+(\S.*?)\s*$").unwrap(),
+            re_reg: Regex::new(r"([xf][0-9]+)=0x([0-9a-fA-F]+)").unwrap(),
+            mismatches: Vec::new(),
+            regs: RegisterFile::default(),
+            current_entry: None,
+            disassembly: None,
+            done: false,
+        }
+    }
 
-            // Update rd value (after-instruction) for current entry if present
-            if let Some(entry) = current_entry.as_mut() {
-                if let Some(rd) = &entry.rd {
-                    if let Some(val) = regs.get(rd) {
-                        entry.reg_values_after.insert(rd.clone(), *val);
+    /// Checks instructions without an `insn=` line against the program image.
+    pub fn with_elf(mut self, elf: &'a ElfFile) -> Self {
+        self.elf = Some(elf);
+        self
+    }
+
+    /// The disagreements found so far, see [`parse_trace_checked`].
+    pub fn mismatches(&self) -> &[DecodeMismatch] {
+        &self.mismatches
+    }
+
+    /// Takes the disagreements found so far, so a long trace does not accumulate them.
+    pub fn take_mismatches(&mut self) -> Vec<DecodeMismatch> {
+        std::mem::take(&mut self.mismatches)
+    }
+
+    /// Reads lines up to the next instruction and returns the entry it completes, if any.
+    fn read_entry(&mut self) -> Result<Option<TraceEntry>> {
+        while let Some((index, line)) = self.lines.next() {
+            let line = line.map_err(|e| Error::Io(format!("{}: {}", self.path, e)))?;
+            let parse_error = |message: String| Error::Parse { path: self.path.clone(), line: index + 1, message };
+            // The header recording the program's inputs, see `ProgramInputs::header`.
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some(hex) = line.trim().strip_prefix("data=") {
+                let data = parse_hex_bytes(hex).ok_or_else(|| parse_error(format!("invalid syscall data: {}", hex)))?;
+                if let Some(entry) = self.current_entry.as_mut() {
+                    entry.syscall_data = Some(data);
+                }
+            } else if let Some(text) = line.trim().strip_prefix("mem=") {
+                let access = parse_memory_access(text).ok_or_else(|| parse_error(format!("invalid memory access: {}", text)))?;
+                if let Some(entry) = self.current_entry.as_mut() {
+                    entry.memory = Some(access);
+                }
+            } else if let Some(hex) = line.trim().strip_prefix("insn=") {
+                let bits = hex.strip_prefix("0x").and_then(|h| u32::from_str_radix(h, 16).ok());
+                let bits = bits.ok_or_else(|| parse_error(format!("invalid instruction bits: {}", hex)))?;
+                // The bits after a compressed instruction belong to the next one.
+                let bits = if instruction_len(bits) == 2 { bits & 0xffff } else { bits };
+                if let (Some(entry), Some((text, checked))) = (self.current_entry.as_mut(), self.disassembly.take()) {
+                    if checked != Some(bits) {
+                        check(&mut self.mismatches, entry, text, bits);
                     }
                 }
+            } else if let Some(caps) = self.re_instr.captures(&line) {
+                let pc = u64::from_str_radix(&caps[1], 16).map_err(|e| parse_error(format!("invalid pc: {}", e)))?;
+                let inst = Instruction::parse(&caps[2]).map_err(parse_error)?;
+                // The registers after it stay as they were until a dump follows.
+                let regs = self.regs;
+                let mut entry = TraceEntry { regs_before: regs, regs_after: regs, ..TraceEntry::from_instruction(pc, &inst) };
+                let image_bits = self.elf.and_then(|elf| image_bits(elf, pc));
+                if let Some(bits) = image_bits {
+                    check(&mut self.mismatches, &mut entry, inst.clone(), bits);
+                }
+                self.disassembly = Some((inst, image_bits));
+                // The previous instruction is complete once the next one starts.
+                if let Some(previous) = self.current_entry.replace(entry) {
+                    return Ok(Some(previous));
+                }
+            } else if line.starts_with('x') {
+                for cap in self.re_reg.captures_iter(&line) {
+                    let val = u64::from_str_radix(&cap[2], 16).unwrap_or(0);
+                    if let Some(reg) = Reg::parse(&cap[1]) {
+                        self.regs.write(reg, val);
+                    }
+                }
+
+                // A dump follows the instruction it completes and precedes the next one.
+                if let Some(entry) = self.current_entry.as_mut() {
+                    entry.regs_after = self.regs;
+                }
             }
+        }
+        Ok(self.current_entry.take())
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<'_, R> {
+    type Item = Result<TraceEntry>;
 
-            // Store current register dump for next instruction's use (before-instruction state)
-            pending_regs = Some(regs.clone());
-            // Also update last_reg_dump (for final instruction fix)
-            last_reg_dump = regs;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
         }
+        let entry = self.read_entry().transpose();
+        self.done = !matches!(entry, Some(Ok(_)));
+        entry
     }
+}
 
-    // Push final entry if exists
-    if let Some(mut entry) = current_entry {
-        // It's possible the final instruction has not had reg_values_after fully updated if no trailing reg dump
-        // So merge from last_reg_dump here as a safety net
-        if let Some(rd) = &entry.rd {
-            if let Some(val) = last_reg_dump.get(rd) {
-                entry.reg_values_after.insert(rd.clone(), *val);
-            }
+/// Rebuilds `entry` from `bits` if they decode, and records a disagreement with `text`.
+fn check(mismatches: &mut Vec<DecodeMismatch>, entry: &mut TraceEntry, text: Instruction, bits: u32) {
+    let decoded = decode(entry.pc, bits);
+    let agrees = match &decoded {
+        Some(inst) => equivalent(&text, inst),
+        None => matches!(text, Instruction::Other { .. }),
+    };
+    if let Some(inst) = &decoded {
+        *entry = TraceEntry {
+            regs_before: entry.regs_before,
+            regs_after: entry.regs_after,
+            syscall_data: entry.syscall_data.take(),
+            memory: entry.memory,
+            ..TraceEntry::from_instruction(entry.pc, inst)
+        };
+    }
+    if !agrees {
+        mismatches.push(DecodeMismatch { pc: entry.pc, bits, disassembly: text, decoded });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Vec<TraceEntry>> {
+        TraceReader::new(text.as_bytes(), "inline").collect()
+    }
+
+    /// [`parse_trace_checked`] on an inline trace.
+    fn parse_checked(text: &str, elf: Option<&ElfFile>) -> Result<(Vec<TraceEntry>, Vec<DecodeMismatch>)> {
+        let mut reader = TraceReader::new(text.as_bytes(), "inline");
+        if let Some(elf) = elf {
+            reader = reader.with_elf(elf);
+        }
+        let entries = reader.by_ref().collect::<Result<_>>()?;
+        Ok((entries, reader.take_mismatches()))
+    }
+
+    #[test]
+    fn test_parse_csr_pseudo_instructions() {
+        let entries = parse(
+            "0x1000:\trdcycle\tx5\n\
+             x5=0x2a\n\
+             0x1004:\tcsrw\tfcsr, x5\n\
+             x5=0x2a\n\
+             0x1008:\tfsrmi\tx6, 3\n\
+             x6=0x1\n",
+        )
+        .unwrap();
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].opcode, "csrrs");
+        assert_eq!(entries[0].csr.as_deref(), Some("cycle"));
+        assert_eq!(entries[0].rs1.as_deref(), Some("x0"));
+        assert_eq!(entries[0].after("x5"), Some(0x2a));
+
+        assert_eq!(entries[1].opcode, "csrrw");
+        assert_eq!(entries[1].rd.as_deref(), Some("x0"));
+        assert_eq!(entries[1].before("x5"), Some(0x2a));
+
+        assert_eq!(entries[2].opcode, "csrrwi");
+        assert_eq!(entries[2].csr.as_deref(), Some("frm"));
+        assert_eq!(entries[2].imm, Some(3));
+        assert_eq!(entries[2].after("x6"), Some(1));
+    }
+
+    #[test]
+    fn test_parse_ecall_with_data() {
+        let entries = parse(
+            "x10=0x1 x11=0x2000 x12=0x3 x17=0x40\n\
+             0x1000: ecall\n\
+             data=686921\n\
+             x10=0x3 x11=0x2000 x12=0x3 x17=0x40\n",
+        )
+        .unwrap();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].opcode, "ecall");
+        assert_eq!(entries[0].before("x17"), Some(64));
+        assert_eq!(entries[0].before("x12"), Some(3));
+        assert_eq!(entries[0].after("x10"), Some(3));
+        assert_eq!(entries[0].syscall_data.as_deref(), Some(&b"hi!"[..]));
+    }
+
+    #[test]
+    fn test_parse_memory_accesses() {
+        let entries = parse(
+            "x2=0x7ff0 x5=0x37\n\
+             0x1000: sb x5, 8(x2)\n\
+             mem=0x7ff8,1,0x0,0x37\n\
+             x2=0x7ff0 x5=0x37\n\
+             0x1004: lw x6, 8(x2)\n\
+             mem=0x7ff8,4,0x37\n\
+             x2=0x7ff0 x5=0x37 x6=0x37\n",
+        )
+        .unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].memory, Some(MemoryAccess { addr: 0x7ff8, width: 1, read: 0, written: Some(0x37) }));
+        assert_eq!(entries[1].memory, Some(MemoryAccess { addr: 0x7ff8, width: 4, read: 0x37, written: None }));
+        assert_eq!(parse_memory_access("0x10,3,0x0"), None);
+        assert_eq!(parse_memory_access("0x10,8"), None);
+
+        // Malformed lines are errors that name the line, not panics.
+        match parse("x2=0x7ff0\n0x1000: sb x5, 8(x2)\nmem=0x7ff8,3,0x0\n") {
+            Err(Error::Parse { line, message, .. }) => assert_eq!((line, message.as_str()), (3, "invalid memory access: 0x7ff8,3,0x0")),
+            other => panic!("unexpected {:?}", other),
         }
-        entries.push(entry);
+        assert!(matches!(parse("0x1000: sd x1, 56\n"), Err(Error::Parse { line: 1, .. })));
+        assert!(matches!(parse_trace("traces/missing.log"), Err(Error::Io(_))));
     }
 
-    entries
+    #[test]
+    fn test_parse_atomics() {
+        let entries = parse(
+            "x10=0x2000 x14=0x5\n\
+             0x1000: lr.w.aq\tx15,(x10)\n\
+             x10=0x2000 x14=0x5 x15=0x1\n\
+             0x1004: amoadd.d.aqrl\tx15,x14,(x10)\n\
+             x10=0x2000 x14=0x5 x15=0x1\n",
+        )
+        .unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].opcode, "lr.w");
+        assert_eq!(entries[0].rs1.as_deref(), Some("x10"));
+        assert_eq!(entries[0].rs2, None);
+        assert_eq!(entries[1].opcode, "amoadd.d");
+        assert_eq!(entries[1].rs2.as_deref(), Some("x14"));
+        assert_eq!(entries[1].before("x14"), Some(5));
+        assert_eq!(entries[1].before("x10"), Some(0x2000));
+    }
+
+    #[test]
+    fn test_parse_fp_instructions_and_registers() {
+        let entries = parse(
+            "x2=0x3000 f14=0x3ff8000000000000 f15=0x4000000000000000\n\
+             0x1000:\tfadd.d\tf15,f14,f15,rne\n\
+             x2=0x3000 f14=0x3ff8000000000000 f15=0x400c000000000000\n\
+             0x1004:\tfsd\tf15,-8(x2)\n\
+             x2=0x3000 f14=0x3ff8000000000000 f15=0x400c000000000000\n\
+             0x1008:\tfmv.x.d\tx10,f15\n\
+             x2=0x3000 x10=0x400c000000000000 f15=0x400c000000000000\n\
+             0x100c:\tfence.i\n",
+        )
+        .unwrap();
+
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].opcode, "fadd.d");
+        assert_eq!(entries[0].rounding.as_deref(), Some("rne"));
+        assert_eq!(entries[0].before("f14"), Some(0x3ff8000000000000));
+        assert_eq!(entries[0].after("f15"), Some(0x400c000000000000));
+
+        assert_eq!(entries[1].rd, None);
+        assert_eq!(entries[1].rs1.as_deref(), Some("x2"));
+        assert_eq!(entries[1].rs2.as_deref(), Some("f15"));
+        assert_eq!(entries[1].imm, Some(-8));
+
+        assert_eq!(entries[2].rs1.as_deref(), Some("f15"));
+        assert_eq!(entries[2].after("x10"), Some(0x400c000000000000));
+        assert_eq!(entries[3].opcode, "fence.i");
+    }
+
+    #[test]
+    fn test_parse_gdb_operand_forms() {
+        let entries = parse(
+            "x1=0x10100 x2=0x7ff0 x15=0x5\n\
+             0x101dc:\tsd\tra,56(sp)\n\
+             x1=0x10100 x2=0x7ff0 x15=0x5\n\
+             0x101e0:\tsext.w\ta4,a5\n\
+             x1=0x10100 x2=0x7ff0 x14=0x5 x15=0x5\n\
+             0x101e4:\tbne\ta4,a5,0x101f4 <main+24>\n\
+             x1=0x10100 x2=0x7ff0 x14=0x5 x15=0x5\n\
+             0x101e8:\tret\n",
+        )
+        .unwrap();
+
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].opcode, "sd");
+        assert_eq!((entries[0].rs1.as_deref(), entries[0].rs2.as_deref()), (Some("x2"), Some("x1")));
+        assert_eq!(entries[0].imm, Some(56));
+        assert_eq!(entries[0].before("x1"), Some(0x10100));
+
+        assert_eq!(entries[1].opcode, "addiw");
+        assert_eq!(entries[1].before("x15"), Some(5));
+        assert_eq!(entries[1].after("x14"), Some(5));
+
+        assert_eq!(entries[2].opcode, "bne");
+        assert_eq!(entries[2].rd, None);
+        assert_eq!(entries[2].imm, Some(0x10));
+
+        assert_eq!(entries[3].opcode, "jalr");
+        assert_eq!((entries[3].rd.as_deref(), entries[3].rs1.as_deref()), (Some("x0"), Some("x1")));
+    }
+
+    #[test]
+    fn test_decoded_bits_override_disassembly() {
+        let text = "x1=0x10100 x2=0x7ff0 x8=0x0\n\
+             0x101de: sd\tx8,48(x2)\n\
+             insn=0xf822fc06\n\
+             x1=0x10100 x2=0x7ff0 x8=0x0\n\
+             0x101e0: sd\tx8,48(x2)\n\
+             x1=0x10100 x2=0x7ff0 x8=0x0\n";
+        let (entries, mismatches) = parse_checked(text, None).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].rs2.as_deref(), entries[0].imm), (Some("x1"), Some(56)));
+        assert_eq!(entries[0].before("x1"), Some(0x10100));
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches[0].pc, 0x101de);
+        assert_eq!(mismatches[0].bits, 0xfc06);
+        assert_eq!(mismatches[0].disassembly.to_string(), "sd x8, 48(x2)");
+
+        // Without an `insn=` line, the bits are read from the program image.
+        let elf = ElfFile::load("test.bin").unwrap();
+        let (entries, mismatches) = parse_checked(text, Some(&elf)).unwrap();
+        assert_eq!(entries[1].imm, Some(48));
+        assert_eq!(mismatches.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_bitmanip() {
+        let entries = parse(
+            "x10=0x80 x11=0x3\n\
+             0x1000:\tsext.b\tx12,x10\n\
+             x10=0x80 x11=0x3 x12=0xffffffffffffff80\n\
+             0x1004:\tsh2add\tx13,x11,x10\n\
+             x10=0x80 x11=0x3 x12=0xffffffffffffff80 x13=0x8c\n",
+        )
+        .unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].opcode, "sext.b");
+        assert_eq!(entries[0].rs1.as_deref(), Some("x10"));
+        assert_eq!(entries[0].after("x12"), Some(0xffffffffffffff80));
+        assert_eq!(entries[1].rs2.as_deref(), Some("x10"));
+        assert_eq!(entries[1].before("x11"), Some(3));
+    }
+
+    #[test]
+    fn test_trace_reader_streams_entries_until_an_error() {
+        let text = "0x1000:\taddi\tx1,x0,1\nx1=0x1\n0x1004:\taddi\tx2,x1,2\nx2=0x3\nmem=zz\n0x1008:\taddi\tx3,x0,3\n";
+        let mut reader = TraceReader::new(text.as_bytes(), "inline");
+        let first = reader.next().unwrap().unwrap();
+        assert_eq!((first.pc, first.after("x1").unwrap()), (0x1000, 1));
+        match reader.next() {
+            Some(Err(Error::Parse { line, .. })) => assert_eq!(line, 5),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+        assert!(reader.next().is_none());
+
+        let path = "traces/trace_cleaned.log";
+        let streamed: Vec<_> = TraceReader::open(path).unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(streamed, parse_trace(path).unwrap());
+    }
+
+    #[test]
+    fn test_entries_keep_full_register_files() {
+        let text = "x1=0x1 x2=0x2\n0x1000:\taddi\tx3,x1,5\nx1=0x1 x2=0x2 x3=0x6 x4=0x9\n0x1004:\tadd\tx5,x3,x4\n";
+        let entries: Vec<_> = TraceReader::new(text.as_bytes(), "inline").collect::<Result<_>>().unwrap();
+        assert_eq!(entries[0].before("x2"), Some(2));
+        assert_eq!(entries[0].after("sp"), Some(2));
+        assert_eq!(entries[0].regs_after.x[..5], [0, 1, 2, 6, 9]);
+        assert_eq!(entries[0].side_effects(), [Reg::X(4)]);
+        assert_eq!(entries[1].regs_before, entries[0].regs_after);
+        // Without a dump after it, the last instruction leaves the registers as they were.
+        assert_eq!(entries[1].regs_after, entries[1].regs_before);
+        assert_eq!(entries[0].regs_before.changed(&entries[1].regs_after), [Reg::X(3), Reg::X(4)]);
+    }
 }
@@ -0,0 +1,243 @@
+use crate::circuit::{prove_rows, InstructionCircuit, InstructionProof};
+use crate::elf::ElfFile;
+use crate::emulator::Emulator;
+use crate::error::{Error, Result};
+use crate::inputs::ProgramInputs;
+use crate::program_runner::{run_program, trace_program_rsp};
+use crate::qemu_log::run_qemu_log;
+use crate::trace_format::StructuredTrace;
+use crate::trace_parser::{parse_trace, TraceEntry, TraceReader};
+use crate::window::{Window, WindowBoundary};
+use crate::zk::{convert_window, stream_rows, ConvertOptions};
+
+/// Entries of a trace as [`TraceSource::entries`] yields them.
+pub type EntryStream<'a> = Box<dyn Iterator<Item = Result<TraceEntry>> + 'a>;
+
+/// Anything that yields the executed instructions of a program.
+pub trait TraceSource {
+    fn trace(&mut self) -> Result<Vec<TraceEntry>>;
+
+    /// The trace one entry at a time. Sources that read it from a file override this so the
+    /// whole trace is never in memory; the default collects [`TraceSource::trace`].
+    fn entries(&mut self) -> Result<EntryStream<'_>> {
+        Ok(Box::new(self.trace()?.into_iter().map(Ok)))
+    }
+
+    /// Inputs the program was run with, committed by the proof when there are any.
+    fn inputs(&self) -> Result<ProgramInputs> {
+        Ok(ProgramInputs::default())
+    }
+}
+
+/// Synthetic traces, mostly for tests.
+impl TraceSource for Vec<TraceEntry> {
+    fn trace(&mut self) -> Result<Vec<TraceEntry>> {
+        Ok(self.clone())
+    }
+}
+
+/// Replays a trace written earlier, as a [`StructuredTrace`] in either encoding or in the
+/// legacy `trace_cleaned.log` format, with the inputs recorded in its header.
+#[derive(Debug, Clone)]
+pub struct TraceFile {
+    pub path: String,
+}
+
+impl TraceSource for TraceFile {
+    fn trace(&mut self) -> Result<Vec<TraceEntry>> {
+        Ok(StructuredTrace::read_file(&self.path)?.steps)
+    }
+
+    fn entries(&mut self) -> Result<EntryStream<'_>> {
+        Ok(Box::new(StructuredTrace::stream_file(&self.path)?.map(|step| step.map_err(Error::from))))
+    }
+
+    fn inputs(&self) -> Result<ProgramInputs> {
+        Ok(StructuredTrace::read_header(&self.path)?.inputs)
+    }
+}
+
+/// Runs the program under QEMU and gdb with [`run_program`], then replays the cleaned trace
+/// it writes to `trace_path`.
+#[derive(Debug, Clone)]
+pub struct GdbRunner {
+    pub program: String,
+    pub inputs: ProgramInputs,
+    pub trace_path: String,
+    pub max_steps: usize,
+}
+
+impl TraceSource for GdbRunner {
+    fn trace(&mut self) -> Result<Vec<TraceEntry>> {
+        run_program(&self.program, &self.inputs, &self.trace_path, self.max_steps)?;
+        parse_trace(&self.trace_path)
+    }
+
+    fn entries(&mut self) -> Result<EntryStream<'_>> {
+        run_program(&self.program, &self.inputs, &self.trace_path, self.max_steps)?;
+        Ok(Box::new(TraceReader::open(&self.trace_path)?))
+    }
+
+    fn inputs(&self) -> Result<ProgramInputs> {
+        Ok(self.inputs.clone())
+    }
+}
+
+/// Steps the program through QEMU's gdbstub, see [`trace_program_rsp`].
+#[derive(Debug, Clone)]
+pub struct RspRunner {
+    pub program: String,
+    pub inputs: ProgramInputs,
+    pub max_steps: usize,
+}
+
+impl TraceSource for RspRunner {
+    fn trace(&mut self) -> Result<Vec<TraceEntry>> {
+        Ok(trace_program_rsp(&self.program, &self.inputs, self.max_steps)?)
+    }
+
+    fn inputs(&self) -> Result<ProgramInputs> {
+        Ok(self.inputs.clone())
+    }
+}
+
+/// Runs the program under QEMU with execution logging, see [`run_qemu_log`].
+#[derive(Debug, Clone)]
+pub struct QemuLogRunner {
+    pub program: String,
+    pub inputs: ProgramInputs,
+    pub log_path: String,
+}
+
+impl TraceSource for QemuLogRunner {
+    fn trace(&mut self) -> Result<Vec<TraceEntry>> {
+        Ok(run_qemu_log(&self.program, &self.inputs, &self.log_path)?)
+    }
+
+    fn inputs(&self) -> Result<ProgramInputs> {
+        Ok(self.inputs.clone())
+    }
+}
+
+/// Runs the program in the built-in [`Emulator`].
+#[derive(Debug, Clone)]
+pub struct EmulatorRunner {
+    pub program: String,
+    pub inputs: ProgramInputs,
+    pub max_steps: usize,
+}
+
+impl TraceSource for EmulatorRunner {
+    fn trace(&mut self) -> Result<Vec<TraceEntry>> {
+        let mut emulator = Emulator::from_elf(&ElfFile::load(&self.program)?);
+        emulator.set_inputs(&self.program, &self.inputs);
+        Ok(emulator.run(self.max_steps)?)
+    }
+
+    fn inputs(&self) -> Result<ProgramInputs> {
+        Ok(self.inputs.clone())
+    }
+}
+
+/// Inputs to commit, if the program was given any.
+fn committed_inputs(source: &impl TraceSource) -> Result<Option<ProgramInputs>> {
+    let inputs = source.inputs()?;
+    Ok((!inputs.is_empty()).then_some(inputs))
+}
+
+/// Traces, converts and proves: the whole pipeline over any [`TraceSource`].
+///
+/// Entries are converted as [`TraceSource::entries`] yields them, so only the rows are held
+/// in memory, and a strict conversion stops at the first unsupported instruction.
+pub fn prove_trace(
+    source: &mut impl TraceSource,
+    options: &ConvertOptions,
+) -> Result<(InstructionProof, InstructionCircuit)> {
+    let inputs = committed_inputs(source)?;
+    let rows = stream_rows(source.entries()?, options).collect::<Result<Vec<_>>>()?;
+    if rows.is_empty() {
+        return Err(Error::EmptyTrace);
+    }
+    prove_rows(&rows, inputs.as_ref(), None)
+}
+
+/// Like [`prove_trace`], proving only the part of the execution selected by `window`. The
+/// rest still runs, and the register state at the window's boundary is committed.
+pub fn prove_window(
+    source: &mut impl TraceSource,
+    elf: &ElfFile,
+    window: &Window,
+    options: &ConvertOptions,
+) -> Result<(InstructionProof, InstructionCircuit, WindowBoundary)> {
+    let trace = source.trace()?;
+    let selection = window.select(elf, &trace)?;
+    let rows = convert_window(&trace, &selection.in_window, options)?;
+    if rows.is_empty() {
+        return Err(Error::EmptyTrace);
+    }
+    let (proof, data) = prove_rows(&rows, committed_inputs(source)?.as_ref(), Some(&selection.boundary))?;
+    Ok((proof, data, selection.boundary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace_parser::RegisterFile;
+    use crate::zk::ConversionMode;
+
+    #[test]
+    fn test_sources_feed_the_pipeline() {
+        let entry = |opcode: &str, rd: &str, rs1: (&str, u64), imm: i64, after: u64| {
+            let before = RegisterFile::default().with(&[rs1]);
+            TraceEntry {
+                opcode: opcode.to_string(),
+                rd: Some(rd.to_string()),
+                rs1: Some(rs1.0.to_string()),
+                imm: Some(imm),
+                regs_before: before,
+                regs_after: before.with(&[(rd, after)]),
+                ..Default::default()
+            }
+        };
+        let mut synthetic = vec![entry("addi", "x5", ("x0", 0), 5, 5), entry("addi", "x6", ("x5", 5), -2, 3)];
+        let (proof, data) = prove_trace(&mut synthetic, &ConvertOptions::default()).unwrap();
+        data.verify(proof).unwrap();
+
+        let replayed = TraceFile { path: "traces/sample_trace.log".to_string() }.trace().unwrap();
+        assert_eq!(replayed, parse_trace("traces/sample_trace.log").unwrap());
+
+        let mut emulated =
+            EmulatorRunner { program: "test.bin".to_string(), inputs: ProgramInputs::default(), max_steps: 10_000 };
+        assert_eq!(emulated.trace().unwrap().last().unwrap().opcode, "ecall");
+        assert!(prove_trace(&mut emulated, &ConvertOptions::default()).is_err()); // branches are not provable
+        let lenient = ConvertOptions { mode: ConversionMode::Lenient, ..Default::default() };
+        assert!(prove_trace(&mut Vec::new(), &lenient).is_err());
+    }
+
+    /// A legacy trace with its input header, replayed from memory like [`TraceFile`] does.
+    struct Recorded(String);
+
+    impl TraceSource for Recorded {
+        fn trace(&mut self) -> Result<Vec<TraceEntry>> {
+            TraceReader::new(self.0.as_bytes(), "inline").collect()
+        }
+
+        fn inputs(&self) -> Result<ProgramInputs> {
+            Ok(ProgramInputs::parse_header(&self.0).unwrap())
+        }
+    }
+
+    #[test]
+    fn test_recorded_inputs_are_committed() {
+        let inputs = ProgramInputs { args: vec!["--rate".to_string()], ..Default::default() };
+        let body = "x5=0x0 x6=0x0\n0x1000: addi x5, x0, 5\nx5=0x5 x6=0x0\n0x1004: addi x6, x5, -2\nx5=0x5 x6=0x3\n";
+        let mut replayed = Recorded(inputs.header() + body);
+        assert_eq!(replayed.inputs().unwrap(), inputs);
+        assert_eq!(replayed.trace().unwrap().len(), 2);
+
+        let (proof, data) = prove_trace(&mut replayed, &ConvertOptions::default()).unwrap();
+        assert_eq!(proof.public_inputs.len(), 2 * 6 + 4);
+        assert_eq!(proof.public_inputs[12..], inputs.public_digest().elements);
+        data.verify(proof).unwrap();
+    }
+}
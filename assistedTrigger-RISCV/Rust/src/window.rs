@@ -0,0 +1,373 @@
+use std::fmt;
+
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::field::types::Field;
+use plonky2::hash::hash_types::HashOut;
+use plonky2::hash::poseidon::PoseidonHash;
+use plonky2::iop::target::Target;
+use plonky2::iop::witness::{PartialWitness, WitnessWrite};
+use plonky2::plonk::circuit_builder::CircuitBuilder;
+use plonky2::plonk::config::Hasher;
+
+use crate::circuit::RowTargets;
+use crate::dwarf::line_ranges;
+use crate::elf::{ElfError, ElfFile};
+use crate::lookup::LookupTables;
+use crate::trace_parser::TraceEntry;
+
+/// The part of an execution to prove.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Window {
+    /// A function, from its entry to the return of that call.
+    Function(String),
+    /// Instructions whose pc lies in `[start, end)`.
+    AddressRange { start: u64, end: u64 },
+    /// Instructions generated from source lines `first_line..=last_line`, as found in the
+    /// DWARF line table. `file` matches the end of the file name, or any file when `None`.
+    CodeBlock { file: Option<String>, first_line: u64, last_line: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WindowError {
+    UnknownSymbol(String),
+    Elf(ElfError),
+    /// The window covers no code in the binary.
+    NoCode,
+    /// No instruction of the trace falls in the window.
+    NotExecuted,
+    Config(String),
+    /// [`crate::zk::convert_window`] was given a different number of flags than entries.
+    FlagCount { entries: usize, flags: usize },
+}
+
+impl fmt::Display for WindowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowError::UnknownSymbol(name) => write!(f, "no symbol named {}", name),
+            WindowError::Elf(e) => write!(f, "{}", e),
+            WindowError::NoCode => write!(f, "the window covers no code"),
+            WindowError::NotExecuted => write!(f, "the window was not executed by the trace"),
+            WindowError::Config(e) => write!(f, "bad window configuration: {}", e),
+            WindowError::FlagCount { entries, flags } => {
+                write!(f, "{} window flags for {} trace entries", flags, entries)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WindowError {}
+
+impl From<ElfError> for WindowError {
+    fn from(e: ElfError) -> Self {
+        WindowError::Elf(e)
+    }
+}
+
+/// Register state on either side of a window: the state before its first instruction and
+/// after its last one, with the pc of the instruction that follows (0 if the trace ends).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowBoundary {
+    pub entry_pc: u64,
+    pub entry_regs: [u64; 32],
+    pub exit_pc: u64,
+    pub exit_regs: [u64; 32],
+}
+
+/// Trace entries selected by a [`Window`], with the state at its boundary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    /// One flag per trace entry.
+    pub in_window: Vec<bool>,
+    pub boundary: WindowBoundary,
+}
+
+impl Window {
+    /// Reads the `code_block` of a `device_config.json` or `class.json`, as written for the
+    /// C++ embeddedZKP flow: `"code_block": [first_line, last_line]`, at any depth.
+    pub fn from_config(path: &str) -> Result<Window, WindowError> {
+        std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| Self::parse_config(&text))
+            .map_err(|e| WindowError::Config(format!("{}: {}", path, e)))
+    }
+
+    /// [`Window::from_config`] on the text of the configuration.
+    fn parse_config(text: &str) -> Result<Window, String> {
+        let json: serde_json::Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let block = find_key(&json, "code_block").ok_or("no code_block")?;
+        match block.as_array().map(|a| a.iter().map(serde_json::Value::as_u64).collect::<Vec<_>>()).as_deref() {
+            Some([Some(first), Some(last)]) if first <= last => {
+                Ok(Window::CodeBlock { file: None, first_line: *first, last_line: *last })
+            }
+            _ => Err("code_block must be [first_line, last_line]".to_string()),
+        }
+    }
+
+    /// Address ranges of an [`Window::AddressRange`] or [`Window::CodeBlock`] window.
+    fn ranges(&self, elf: &ElfFile) -> Result<Vec<(u64, u64)>, WindowError> {
+        let ranges = match self {
+            Window::Function(name) => {
+                let symbol = elf.symbol(name).ok_or_else(|| WindowError::UnknownSymbol(name.clone()))?;
+                vec![(symbol.value, symbol.value + symbol.size)]
+            }
+            Window::AddressRange { start, end } => vec![(*start, *end)],
+            Window::CodeBlock { file, first_line, last_line } => {
+                line_ranges(&elf.line_rows()?, file.as_deref(), *first_line, *last_line)
+            }
+        };
+        match ranges.iter().any(|(start, end)| start < end) {
+            true => Ok(ranges),
+            false => Err(WindowError::NoCode),
+        }
+    }
+
+    /// Selects the entries of `trace` to prove.
+    ///
+    /// A function window runs from the first entry at the function's address to the first
+    /// later entry at the return address with the stack pointer restored, which excludes
+    /// recursive calls returning to the same place. Other windows run from the first entry in
+    /// their ranges to the last one, leaving out the entries in between that are outside them,
+    /// such as calls to other functions.
+    pub fn select(&self, elf: &ElfFile, trace: &[TraceEntry]) -> Result<Selection, WindowError> {
+        let ranges = self.ranges(elf)?;
+        let in_ranges = |pc: u64| ranges.iter().any(|&(start, end)| (start..end).contains(&pc));
+
+        let mut in_window = vec![false; trace.len()];
+        match self {
+            Window::Function(_) => {
+                let start = ranges[0].0;
+                let first = trace.iter().position(|e| e.pc == start).ok_or(WindowError::NotExecuted)?;
+                let (ra, sp) = (trace[first].regs_before.x[1], trace[first].regs_before.x[2]);
+                let last = (first + 1..trace.len())
+                    .find(|&i| trace[i].pc == ra && trace[i].regs_before.x[2] == sp)
+                    .unwrap_or(trace.len());
+                in_window[first..last].fill(true);
+            }
+            _ => {
+                for (flag, entry) in in_window.iter_mut().zip(trace) {
+                    *flag = in_ranges(entry.pc);
+                }
+            }
+        }
+
+        let first = in_window.iter().position(|&w| w).ok_or(WindowError::NotExecuted)?;
+        let last = in_window.iter().rposition(|&w| w).expect("the window is not empty");
+        let boundary = WindowBoundary {
+            entry_pc: trace[first].pc,
+            entry_regs: trace[first].regs_before.x,
+            exit_pc: trace.get(last + 1).map_or(0, |e| e.pc),
+            exit_regs: trace[last].regs_after.x,
+        };
+        Ok(Selection { in_window, boundary })
+    }
+}
+
+fn find_key<'a>(json: &'a serde_json::Value, key: &str) -> Option<&'a serde_json::Value> {
+    match json {
+        serde_json::Value::Object(map) => map.get(key).or_else(|| map.values().find_map(|v| find_key(v, key))),
+        serde_json::Value::Array(values) => values.iter().find_map(|v| find_key(v, key)),
+        _ => None,
+    }
+}
+
+/// Number of public inputs appended by [`WindowTargets::register_public_inputs`].
+pub const WINDOW_PUBLIC_INPUTS: usize = 10;
+
+fn halves(regs: &[u64; 32]) -> impl Iterator<Item = GoldilocksField> + '_ {
+    regs.iter()
+        .flat_map(|&r| [r & 0xffff_ffff, r >> 32])
+        .map(GoldilocksField::from_canonical_u64)
+}
+
+/// Digest committing to an integer register file: Poseidon over the low and high 32-bit
+/// halves of `x0`..`x31`.
+pub fn register_digest(regs: &[u64; 32]) -> HashOut<GoldilocksField> {
+    PoseidonHash::hash_no_pad(&halves(regs).collect::<Vec<_>>())
+}
+
+/// Boundary state decoded from the last [`WINDOW_PUBLIC_INPUTS`] public inputs of a proof.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowOutputs {
+    pub entry_pc: GoldilocksField,
+    pub entry_digest: HashOut<GoldilocksField>,
+    pub exit_pc: GoldilocksField,
+    pub exit_digest: HashOut<GoldilocksField>,
+}
+
+impl WindowOutputs {
+    /// Reads the window block at the end of the public inputs of a windowed proof.
+    pub fn from_public_inputs(public_inputs: &[GoldilocksField]) -> Option<WindowOutputs> {
+        let w = public_inputs.get(public_inputs.len().checked_sub(WINDOW_PUBLIC_INPUTS)?..)?;
+        let digest = |s: &[GoldilocksField]| HashOut { elements: [s[0], s[1], s[2], s[3]] };
+        Some(WindowOutputs { entry_pc: w[0], entry_digest: digest(&w[1..5]), exit_pc: w[5], exit_digest: digest(&w[6..10]) })
+    }
+
+    /// The outputs a proof of the window with `boundary` must have.
+    pub fn expected(boundary: &WindowBoundary) -> WindowOutputs {
+        WindowOutputs {
+            entry_pc: GoldilocksField::from_noncanonical_u64(boundary.entry_pc),
+            entry_digest: register_digest(&boundary.entry_regs),
+            exit_pc: GoldilocksField::from_noncanonical_u64(boundary.exit_pc),
+            exit_digest: register_digest(&boundary.exit_regs),
+        }
+    }
+}
+
+/// Circuit targets of a window boundary.
+///
+/// The register files are committed so that proofs of consecutive windows can be chained
+/// by comparing digests. The register operands of the first row are read from the entry
+/// state, and the `rd` of the last row is in the exit state; registers in between are not
+/// tracked.
+#[derive(Debug)]
+pub struct WindowTargets {
+    entry_pc: Target,
+    entry_halves: Vec<Target>,
+    exit_pc: Target,
+    exit_halves: Vec<Target>,
+    boundary: WindowBoundary,
+}
+
+impl WindowTargets {
+    /// Appends the window block to the public inputs: the entry pc, the entry register
+    /// digest, the exit pc and the exit register digest.
+    pub fn register_public_inputs(
+        builder: &mut CircuitBuilder<GoldilocksField, 2>,
+        tables: &mut LookupTables,
+        boundary: &WindowBoundary,
+    ) -> WindowTargets {
+        let mut state = |b: &mut CircuitBuilder<GoldilocksField, 2>| {
+            let pc = b.add_virtual_target();
+            let halves = b.add_virtual_targets(64);
+            for &half in &halves {
+                tables.range_check_bits(b, half, 32);
+            }
+            let digest = b.hash_n_to_hash_no_pad::<PoseidonHash>(halves.clone()).elements;
+            b.register_public_input(pc);
+            b.register_public_inputs(&digest);
+            (pc, halves)
+        };
+        let (entry_pc, entry_halves) = state(builder);
+        let (exit_pc, exit_halves) = state(builder);
+        WindowTargets { entry_pc, entry_halves, exit_pc, exit_halves, boundary: boundary.clone() }
+    }
+
+    /// Connects the `rs1` and `rs2` operands of the first row of the window to the entry
+    /// state, for those that are the x-registers in `x_regs`.
+    pub fn connect_entry(&self, builder: &mut CircuitBuilder<GoldilocksField, 2>, targets: &RowTargets, x_regs: [Option<u8>; 3]) {
+        for (operand, reg) in [targets.rs1, targets.rs2].into_iter().zip(x_regs) {
+            if let Some(reg) = reg {
+                let value = register(builder, &self.entry_halves, reg);
+                builder.connect(operand, value);
+            }
+        }
+    }
+
+    /// Connects the `rd` operand of the last row of the window to the exit state, if it is
+    /// the x-register `x_regs[2]`.
+    pub fn connect_exit(&self, builder: &mut CircuitBuilder<GoldilocksField, 2>, targets: &RowTargets, x_regs: [Option<u8>; 3]) {
+        if let Some(reg) = x_regs[2] {
+            let value = register(builder, &self.exit_halves, reg);
+            builder.connect(targets.rd, value);
+        }
+    }
+
+    pub fn set_witness(&self, pw: &mut PartialWitness<GoldilocksField>) -> Result<(), anyhow::Error> {
+        let b = &self.boundary;
+        pw.set_target(self.entry_pc, GoldilocksField::from_noncanonical_u64(b.entry_pc))?;
+        pw.set_target(self.exit_pc, GoldilocksField::from_noncanonical_u64(b.exit_pc))?;
+        for (&target, value) in self.entry_halves.iter().zip(halves(&b.entry_regs)) {
+            pw.set_target(target, value)?;
+        }
+        for (&target, value) in self.exit_halves.iter().zip(halves(&b.exit_regs)) {
+            pw.set_target(target, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Register `reg` of a committed state, from its halves.
+fn register(builder: &mut CircuitBuilder<GoldilocksField, 2>, halves: &[Target], reg: u8) -> Target {
+    let i = 2 * reg as usize;
+    builder.mul_const_add(GoldilocksField::from_canonical_u64(1 << 32), halves[i + 1], halves[i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::prove_rows;
+    use crate::emulator::Emulator;
+    use crate::zk::{convert_window, ConversionMode, ConvertOptions};
+
+    #[test]
+    fn test_select_windows_of_newlib_binary() {
+        let elf = ElfFile::load("test.bin").unwrap();
+        let main = elf.symbol("main").unwrap().clone();
+        let trace = Emulator::from_elf(&elf).run(10_000).unwrap();
+
+        let function = Window::Function("main".to_string()).select(&elf, &trace).unwrap();
+        let selected: Vec<&TraceEntry> = trace.iter().zip(&function.in_window).filter(|(_, &w)| w).map(|(e, _)| e).collect();
+        assert_eq!(selected.len(), 59);
+        assert!(selected.iter().all(|e| main.contains(e.pc)));
+        assert_eq!(selected.last().unwrap().opcode, "jalr");
+        let b = &function.boundary;
+        assert_eq!(b.entry_pc, main.value);
+        assert_eq!(b.exit_pc, b.entry_regs[1]); // back in _start
+        assert_eq!(b.exit_regs[2], b.entry_regs[2]);
+        assert_eq!(b.exit_regs[10], 0); // return 0
+
+        // Lines 5 and 6 hold the first two assignments.
+        let block = Window::CodeBlock { file: Some("test.cpp".to_string()), first_line: 5, last_line: 6 };
+        let lines = block.select(&elf, &trace).unwrap();
+        let range = Window::AddressRange { start: main.value + 8, end: main.value + 20 };
+        assert_eq!(lines, range.select(&elf, &trace).unwrap());
+        assert_eq!(lines.boundary.entry_pc, main.value + 8);
+        assert_eq!(lines.boundary.exit_pc, main.value + 20);
+
+        assert_eq!(Window::Function("nope".to_string()).select(&elf, &trace), Err(WindowError::UnknownSymbol("nope".to_string())));
+        assert_eq!(Window::AddressRange { start: 4, end: 8 }.select(&elf, &trace), Err(WindowError::NotExecuted));
+        let far = Window::CodeBlock { file: None, first_line: 1000, last_line: 2000 };
+        assert_eq!(far.select(&elf, &trace), Err(WindowError::NoCode));
+    }
+
+    #[test]
+    fn test_window_from_config() {
+        assert_eq!(
+            Window::parse_config(r#"{"device": {"name": "pump", "code_block": [5, 9]}}"#),
+            Ok(Window::CodeBlock { file: None, first_line: 5, last_line: 9 })
+        );
+        assert!(Window::parse_config(r#"{"code_block": [9, 5]}"#).is_err());
+        match Window::from_config("traces/missing.json") {
+            Err(WindowError::Config(e)) => assert!(e.starts_with("traces/missing.json: "), "{}", e),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_window_proof_commits_boundary() {
+        let elf = ElfFile::load("test.bin").unwrap();
+        let main = elf.symbol("main").unwrap().value;
+        let trace = Emulator::from_elf(&elf).run(10_000).unwrap();
+
+        // `addi sp, sp, -64` alone; everything around it runs but is not proven.
+        let selection = Window::AddressRange { start: main, end: main + 2 }.select(&elf, &trace).unwrap();
+        let options = ConvertOptions { mode: ConversionMode::Lenient, ..Default::default() };
+        let rows = convert_window(&trace, &selection.in_window, &options).unwrap();
+        assert_eq!(rows.len(), 1);
+        let (proof, data) = prove_rows(&rows, None, Some(&selection.boundary)).unwrap();
+        let outputs = WindowOutputs::from_public_inputs(&proof.public_inputs).unwrap();
+        data.verify(proof).unwrap();
+
+        let b = &selection.boundary;
+        assert_eq!(b.exit_regs[2], b.entry_regs[2] - 64);
+        assert_eq!(outputs, WindowOutputs::expected(b));
+
+        // The committed states must hold the sp the row reads and writes.
+        let mut wrong = b.clone();
+        wrong.entry_regs[2] += 8;
+        assert!(prove_rows(&rows, None, Some(&wrong)).is_err());
+        let mut wrong = b.clone();
+        wrong.exit_regs[2] += 8;
+        assert!(prove_rows(&rows, None, Some(&wrong)).is_err());
+    }
+}
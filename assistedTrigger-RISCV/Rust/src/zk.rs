@@ -1,8 +1,16 @@
 use plonky2::field::goldilocks_field::GoldilocksField;
 use plonky2::field::types::Field;
 use plonky2::field::types::Field64;
-use crate::trace_parser::TraceEntry;
-use std::collections::HashMap;
+use crate::atomic::{AtomicKind, AtomicOp, AtomicRow};
+use crate::bitmanip::{BitOp, BitmanipRow};
+use crate::csr::{csr_address, csr_kind, CsrKind, CsrOp, CsrRow, InputVisibility};
+use crate::memory::{MemoryRow, ADDRESS_BITS};
+use crate::fp::{fp_arith, fp_opcode_id, parse_fp_op, FpFormat, FpOp, FpRow};
+use crate::syscall::{Syscall, SyscallRow, BUFFER_SHIFT};
+use crate::instruction::Reg;
+use crate::trace_parser::{RegisterFile, TraceEntry};
+use crate::window::WindowError;
+use std::collections::{HashMap, VecDeque};
 
 #[derive(Debug, Clone)]
 pub struct InstructionRow<F: Field> {
@@ -13,15 +21,32 @@ pub struct InstructionRow<F: Field> {
     pub imm_flag: F,
     pub imm_val: F,
     pub rd_val: F,
+    /// Indices of the x-registers whose values are `rs1`, `rs2` and `rd`, for the operands
+    /// that are one.
+    pub x_regs: [Option<u8>; 3],
+    /// Set for CSR instructions, which are proven by [`crate::csr`] instead of the ALU.
+    pub csr: Option<CsrRow<F>>,
+    /// Set for `ecall`s, which are proven by [`crate::syscall`].
+    pub syscall: Option<SyscallRow<F>>,
+    /// Set for A-extension instructions, which are proven by [`crate::atomic`].
+    pub atomic: Option<AtomicRow<F>>,
+    /// Set for F/D instructions, which are proven by [`crate::fp`].
+    pub fp: Option<FpRow<F>>,
+    /// Set for Zba/Zbb instructions, which are proven by [`crate::bitmanip`].
+    pub bitmanip: Option<BitmanipRow>,
+    /// Set for plain loads and stores, whose accesses join the [`crate::memory::MemoryLog`].
+    pub memory: Option<MemoryRow>,
+    /// Set for the marker rows that lenient conversion puts in place of skipped entries.
+    pub unproven: Option<UnprovenSpan>,
 }
 
-fn reg_name_to_index(reg: &str) -> Option<usize> {
-    if reg.starts_with('x') {
-        reg[1..].parse::<usize>().ok()
-    } else {
-        None
-    }
-}
+/// Number of opcodes understood by the ALU; ids run from 1 to `NUM_OPCODES`.
+///
+/// CSR instructions, `ecall`, the A extension, floating point and Zba/Zbb get the ids after
+/// it and are proven by [`crate::csr::add_csr_row`], [`crate::syscall::IoChannel`],
+/// [`crate::atomic::AtomicUnit`], [`crate::fp::FpUnit`] and
+/// [`crate::bitmanip::add_bitmanip_row`].
+pub const NUM_OPCODES: usize = 18;
 
 pub fn opcode_to_id(op: &str) -> Option<u64> {
     match op {
@@ -42,113 +67,813 @@ pub fn opcode_to_id(op: &str) -> Option<u64> {
         "or" => Some(15),
         "and" => Some(16),
         "mv" => Some(17), // move
-        _ => None,
+        "lui" => Some(18),
+        "csrrw" => Some(19),
+        "csrrs" => Some(20),
+        "csrrc" => Some(21),
+        "csrrwi" => Some(22),
+        "csrrsi" => Some(23),
+        "csrrci" => Some(24),
+        "ecall" => Some(25),
+        _ => AtomicOp::parse(op)
+            .map(AtomicOp::opcode_id)
+            .or_else(|| fp_opcode_id(op))
+            .or_else(|| BitOp::parse(op).map(BitOp::opcode_id)),
     }
 }
 
-pub fn convert_trace_to_rows(entries: &[TraceEntry]) -> Vec<InstructionRow<GoldilocksField>> {
-    let mut rows = Vec::new();
-    let mut registers: HashMap<usize, u64> = (0..32).map(|i| (i, 0)).collect();
+/// Options for [`convert_trace_to_rows_with`].
+#[derive(Debug, Clone, Default)]
+pub struct ConvertOptions {
+    /// Whether counter reads (`rdcycle`, `rdtime`, ...) are public or private inputs.
+    pub counter_inputs: InputVisibility,
+    /// Value of `fcsr` when the trace starts.
+    pub initial_fcsr: u64,
+    /// Whether bytes read from stdin are public or private inputs.
+    pub stdin_inputs: InputVisibility,
+    /// What to do with instructions that cannot be proven.
+    pub mode: ConversionMode,
+}
 
-    for entry in entries {
-        println!("0x{:08x}: {}", entry.pc, entry.opcode);
+/// How [`convert_trace_to_rows_with`] treats trace entries it cannot prove.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConversionMode {
+    /// Fail with [`UnsupportedInstructions`], so that a proof never silently omits part of
+    /// the execution.
+    #[default]
+    Strict,
+    /// Replace each run of such entries by an [`UnprovenSpan`] row.
+    Lenient,
+}
+
+/// Opcode id of [`UnprovenSpan`] rows; real instructions start at 1.
+pub const UNPROVEN_SPAN_OPCODE: u64 = 0;
 
-        if let Some(rd) = &entry.rd {
-            let val = entry.reg_values_after.get(rd).unwrap_or(&0);
-            println!("  rd:  {} = {}", rd, val);
+/// A run of consecutive trace entries left out of a proof.
+///
+/// The gap stays visible to the verifier as a marker row whose public inputs are
+/// [`UNPROVEN_SPAN_OPCODE`], the pcs of the first and last skipped entries in `rs1` and
+/// `rs2`, no immediate flag, the number of skipped entries in `imm_val`, and a zero `rd`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnprovenSpan {
+    pub first_pc: u64,
+    pub last_pc: u64,
+    pub count: u64,
+}
+
+impl UnprovenSpan {
+    fn row(self) -> InstructionRow<GoldilocksField> {
+        let f = |v: u64| GoldilocksField::from_canonical_u64(v % GoldilocksField::ORDER);
+        InstructionRow {
+            pc: f(self.first_pc),
+            opcode: f(UNPROVEN_SPAN_OPCODE),
+            rs1_val: f(self.first_pc),
+            rs2_val: f(self.last_pc),
+            imm_flag: GoldilocksField::ZERO,
+            imm_val: f(self.count),
+            rd_val: GoldilocksField::ZERO,
+            x_regs: [None; 3],
+            csr: None,
+            syscall: None,
+            atomic: None,
+            fp: None,
+            bitmanip: None,
+            memory: None,
+            unproven: Some(self),
         }
+    }
+}
+
+/// A mnemonic that [`convert_trace_to_rows_with`] could not prove, with the pc and the
+/// reason of its first occurrence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unsupported {
+    pub mnemonic: String,
+    pub first_pc: u64,
+    pub count: usize,
+    pub reason: String,
+}
 
-        if let Some(rs1) = &entry.rs1 {
-            let val = entry.reg_values_before.get(rs1).unwrap_or(&0);
-            println!("  rs1: {} = {}", rs1, val);
+/// Error of a strict conversion: every mnemonic that could not be proven, in order of
+/// first occurrence.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedInstructions(pub Vec<Unsupported>);
+
+impl std::fmt::Display for UnsupportedInstructions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "trace contains instructions that cannot be proven:")?;
+        for u in &self.0 {
+            write!(f, "\n  {} x{} (first at 0x{:08x}: {})", u.mnemonic, u.count, u.first_pc, u.reason)?;
         }
+        Ok(())
+    }
+}
 
-        if let Some(rs2) = &entry.rs2 {
-            let val = entry.reg_values_before.get(rs2).unwrap_or(&0);
-            println!("  rs2: {} = {}", rs2, val);
+impl std::error::Error for UnsupportedInstructions {}
+
+/// Converts a trace in [`ConversionMode::Strict`] mode, with public counter reads and stdin.
+pub fn convert_trace_to_rows(
+    entries: &[TraceEntry],
+) -> Result<Vec<InstructionRow<GoldilocksField>>, UnsupportedInstructions> {
+    convert_trace_to_rows_with(entries, &ConvertOptions::default())
+}
+
+/// Builds the CSR part of a row and updates the modeled `fcsr`, or explains why the
+/// instruction cannot be proven.
+fn convert_csr(
+    entry: &TraceEntry,
+    csr: &str,
+    src: u64,
+    rd_val: u64,
+    fcsr: &mut u64,
+    options: &ConvertOptions,
+) -> Result<CsrRow<GoldilocksField>, String> {
+    let addr = csr_address(csr).ok_or_else(|| format!("unknown CSR {}", csr))?;
+    let kind = csr_kind(addr).ok_or_else(|| format!("unmodeled CSR {}", csr))?;
+    let (op, uimm) = CsrOp::from_mnemonic(&entry.opcode)
+        .ok_or_else(|| format!("unknown CSR instruction {}", entry.opcode))?;
+    let rd_is_x0 = entry.rd.as_deref() == Some("x0");
+
+    let fcsr_before = *fcsr;
+    let nondet = match kind {
+        CsrKind::Counter if op.writes(src) => {
+            return Err(format!("write to read-only counter {}", csr));
         }
+        CsrKind::Counter => Some(options.counter_inputs),
+        CsrKind::Fcsr { lo, width } => {
+            let mask = (1u64 << width) - 1;
+            let old = (*fcsr >> lo) & mask;
+            if !rd_is_x0 && old != rd_val {
+                log::warn!("{} reads {} but the modeled value is {}", csr, rd_val, old);
+            }
+            let new = op.apply(old, src & mask) & mask;
+            *fcsr = (*fcsr & !(mask << lo)) | (new << lo);
+            None
+        }
+    };
+
+    Ok(CsrRow {
+        addr,
+        op,
+        uimm,
+        rd_is_x0,
+        nondet,
+        fcsr_before: GoldilocksField::from_canonical_u64(fcsr_before),
+    })
+}
 
-        if let Some(imm) = entry.imm {
-            println!("  imm: {}", imm);
+/// Builds the syscall part of an `ecall` row, or explains why it cannot be proven.
+fn convert_syscall(
+    entry: &TraceEntry,
+    number: u64,
+    a0: u64,
+    ret: u64,
+    state: &mut ConvertState,
+    options: &ConvertOptions,
+) -> Result<SyscallRow<GoldilocksField>, String> {
+    let syscall = Syscall::from_number(number).ok_or_else(|| format!("unsupported syscall {}", number))?;
+    let data = entry.syscall_data.clone().unwrap_or_default();
+    let buf = entry.regs_before.x[11];
+
+    let mut brk_before = 0;
+    let mut words_before = Vec::new();
+    match syscall {
+        Syscall::Read | Syscall::Write => {
+            let fd = if syscall == Syscall::Read { 0 } else { 1 };
+            if a0 != fd {
+                return Err(format!("{:?} on fd {} is not modeled", syscall, a0));
+            }
+            if data.len() as u64 != ret {
+                return Err(format!("{:?} returned {} but the trace has {} bytes", syscall, ret, data.len()));
+            }
+            words_before = state.memory.transfer(buf, &data, syscall == Syscall::Read)?;
+        }
+        Syscall::Exit => {
+            if std::mem::replace(&mut state.exited, true) {
+                return Err("the trace has already exited".to_string());
+            }
+        }
+        Syscall::Brk => {
+            if a0 != 0 && ret != a0 {
+                return Err(format!("failed brk to 0x{:x}", a0));
+            }
+            brk_before = state.brk.unwrap_or(ret);
+            state.brk = Some(ret);
         }
     }
 
-    for entry in entries {
-        // Update registers AFTER instruction execution
-        for (reg_name, val) in &entry.reg_values_after {
-            if let Some(idx) = reg_name_to_index(reg_name) {
-                registers.insert(idx, *val);
+    Ok(SyscallRow {
+        syscall,
+        number,
+        data,
+        buf,
+        words_before,
+        visibility: options.stdin_inputs,
+        brk_before: GoldilocksField::from_canonical_u64(brk_before),
+    })
+}
+
+/// Single-hart memory and reservation state seen by the loads, stores and atomics of a
+/// trace, as aligned 32-bit words like the [`crate::memory::MemoryLog`].
+#[derive(Debug, Default)]
+struct MemoryShadow {
+    words: HashMap<u64, u32>,
+    reservation: Option<u64>,
+}
+
+impl MemoryShadow {
+    /// Checks a naturally aligned access of a word or `double`word at `addr`.
+    fn check(addr: u64, double: bool) -> Result<(), String> {
+        let width = if double { 8 } else { 4 };
+        if !addr.is_multiple_of(width) || addr >> ADDRESS_BITS != 0 {
+            return Err(format!("access to 0x{:x} is misaligned or above 2^{}", addr, ADDRESS_BITS));
+        }
+        Ok(())
+    }
+
+    /// The value at `addr` before an access that the trace says read `seen`. Words the trace
+    /// has not touched yet take the value seen, or 0, and are remembered; a known word that
+    /// differs from the value seen means the trace disagrees with the model.
+    fn read(&mut self, addr: u64, double: bool, seen: Option<u64>) -> Result<u64, String> {
+        let mut value = 0;
+        for i in 0..if double { 2 } else { 1 } {
+            let seen_half = seen.map(|v| (v >> (32 * i)) as u32);
+            let word = self.words.entry(addr / 4 + i).or_insert(seen_half.unwrap_or(0));
+            if seen_half.is_some_and(|half| half != *word) {
+                return Err(format!(
+                    "memory at 0x{:x} holds 0x{:x} but the trace reads 0x{:x}",
+                    4 * (addr / 4 + i),
+                    word,
+                    seen_half.unwrap()
+                ));
             }
+            value |= (*word as u64) << (32 * i);
         }
+        Ok(value)
+    }
 
-        let Some(opcode_id) = opcode_to_id(&entry.opcode) else {
-            println!("⚠️ Skipping unsupported opcode: {}", entry.opcode);
-            continue;
-        };
+    /// The words covering `data` at `addr`, before a `read` stores it there or a `write`
+    /// outputs it. A `write` of bytes other than the ones the model holds cannot be proven.
+    fn transfer(&mut self, addr: u64, data: &[u8], stores: bool) -> Result<Vec<u32>, String> {
+        let end = addr.checked_add(data.len() as u64).filter(|end| end >> ADDRESS_BITS == 0);
+        let end = end.ok_or_else(|| format!("buffer at 0x{:x} is above 2^{}", addr, ADDRESS_BITS))?;
+        let byte_at = |a: u64| (addr..end).contains(&a).then(|| data[(a - addr) as usize]);
+        let mut words = Vec::new();
+        for index in addr / 4..end.div_ceil(4) {
+            let known = self.words.get(&index).copied();
+            let mut before = known.unwrap_or(0);
+            let mut after = before;
+            for j in 0..4 {
+                let Some(byte) = byte_at(4 * index + j) else { continue };
+                let held = (before >> (8 * j)) as u8;
+                if known.is_none() && !stores {
+                    before |= (byte as u32) << (8 * j);
+                } else if !stores && held != byte {
+                    return Err(format!(
+                        "memory at 0x{:x} holds 0x{:x} but write outputs 0x{:x}",
+                        4 * index + j,
+                        held,
+                        byte
+                    ));
+                }
+                after = after & !(0xff << (8 * j)) | (byte as u32) << (8 * j);
+            }
+            self.words.insert(index, after);
+            if stores && self.reservation == Some(4 * index) {
+                self.reservation = None;
+            }
+            words.push(before);
+        }
+        Ok(words)
+    }
+
+    /// Stores `value` at `addr`, which ends a reservation there.
+    fn write(&mut self, addr: u64, double: bool, value: u64) {
+        self.words.insert(addr / 4, value as u32);
+        if double {
+            self.words.insert(addr / 4 + 1, (value >> 32) as u32);
+        }
+        if self.reservation == Some(addr) {
+            self.reservation = None;
+        }
+    }
+}
+
+/// Builds the memory part of a plain load or store row at `addr` and updates the shadow
+/// state, or explains why the trace cannot be proven. `value` is the register loaded or
+/// stored.
+fn convert_load_store(
+    entry: &TraceEntry,
+    opcode: u64,
+    addr: u64,
+    value: u64,
+    shadow: &mut MemoryShadow,
+) -> Result<MemoryRow, String> {
+    let float = entry.opcode.starts_with('f');
+    let store = entry.opcode.trim_start_matches('f').starts_with('s');
+    let double = entry.opcode.ends_with('d');
+    MemoryShadow::check(addr, double)?;
+    if let Some(access) = entry.memory.as_ref().filter(|access| access.addr != addr) {
+        return Err(format!("the trace accesses 0x{:x} but rs1 + imm is 0x{:x}", access.addr, addr));
+    }
+    if store {
+        let before = shadow.read(addr, double, entry.memory.as_ref().map(|access| access.read))?;
+        shadow.write(addr, double, value);
+        return Ok(MemoryRow { opcode, store, double, float, value, before });
+    }
+    if !double && float && !is_boxed(value) {
+        return Err(format!("flw result 0x{:x} is not a NaN-boxed word", value));
+    }
+    if !double && !float && !is_word(value) {
+        return Err(format!("lw result 0x{:x} is not a sign-extended word", value));
+    }
+    let loaded = if double { value } else { value & 0xffff_ffff };
+    let value = shadow.read(addr, double, Some(loaded))?;
+    Ok(MemoryRow { opcode, store, double, float, value, before: value })
+}
+
+/// Builds the atomic part of a row and updates the shadow state, or explains why the trace
+/// cannot be proven.
+///
+/// Memory values come from the trace: `lr` and AMOs reveal the old value through `rd`, and
+/// `sc` stores `rs2` when `rd` reports success.
+fn convert_atomic(
+    op: AtomicOp,
+    addr: u64,
+    src: u64,
+    rd_val: u64,
+    shadow: &mut MemoryShadow,
+) -> Result<AtomicRow<GoldilocksField>, String> {
+    MemoryShadow::check(addr, op.double)?;
+    // Sources and doubleword operands are split as field elements.
+    let canonical = |what: &str, v: u64| {
+        if v < GoldilocksField::ORDER { Ok(()) } else { Err(format!("{} 0x{:x} is not a canonical field element", what, v)) }
+    };
+    if op.kind != AtomicKind::LoadReserved {
+        canonical("source", src)?;
+    }
+    let seen = match op.kind {
+        AtomicKind::StoreConditional => None,
+        _ => Some(rd_val & op.mask()),
+    };
+    let old = shadow.read(addr, op.double, seen)?;
+    if op.double {
+        canonical("memory operand", old)?;
+    }
+
+    match op.kind {
+        AtomicKind::LoadReserved => shadow.reservation = Some(addr),
+        AtomicKind::StoreConditional => {
+            let success = rd_val == 0;
+            if success != (shadow.reservation == Some(addr)) {
+                return Err(format!("sc at 0x{:x} disagrees with the modeled reservation", addr));
+            }
+            if success {
+                shadow.write(addr, op.double, src & op.mask());
+            }
+            shadow.reservation = None;
+        }
+        AtomicKind::Amo(amo) => {
+            let new = op.apply(amo, old, src);
+            if op.double {
+                canonical("result", new)?;
+            }
+            shadow.write(addr, op.double, new);
+        }
+    }
+
+    Ok(AtomicRow { op, old: GoldilocksField::from_canonical_u64(old) })
+}
+
+/// Whether a register holds a NaN-boxed single.
+fn is_boxed(reg: u64) -> bool {
+    reg >> 32 == 0xffff_ffff
+}
+
+/// Whether a register holds a sign-extended 32-bit word.
+fn is_word(reg: u64) -> bool {
+    reg as i32 as i64 as u64 == reg
+}
+
+/// Builds the floating-point part of a row and accrues its flags into `fcsr`, or explains
+/// why the instruction cannot be proven.
+fn convert_fp(
+    entry: &TraceEntry,
+    (op, fmt): (FpOp, FpFormat),
+    (rs1_val, rs2_val, rd_val): (u64, u64, u64),
+    fcsr: &mut u64,
+) -> Result<FpRow<GoldilocksField>, String> {
+    let fcsr_before = *fcsr;
+    let single = fmt == FpFormat::Single;
+    let expected = match op {
+        FpOp::Add | FpOp::Sub | FpOp::Mul => {
+            match entry.rounding.as_deref() {
+                None | Some("dyn") if fcsr_before >> 5 != 0 => {
+                    return Err(format!("rounding mode {} in frm is not modeled", fcsr_before >> 5));
+                }
+                None | Some("dyn") | Some("rne") => {}
+                Some(rm) => return Err(format!("rounding mode {} is not modeled", rm)),
+            }
+            if single && !(is_boxed(rs1_val) && is_boxed(rs2_val)) {
+                return Err("operand is not a NaN-boxed single".to_string());
+            }
+            let (bits, inexact) = fp_arith(op, fmt, rs1_val, rs2_val)
+                .ok_or("operand or result is not a normal number or zero")?;
+            *fcsr |= inexact as u64;
+            fmt.to_register(bits)
+        }
+        FpOp::MoveToInt if single => rs1_val as u32 as i32 as i64 as u64,
+        FpOp::MoveFromInt | FpOp::ConvertFromWord if !is_word(rs1_val) => {
+            return Err(format!("x-register value 0x{:x} is not a sign-extended word", rs1_val));
+        }
+        FpOp::MoveFromInt if single => fmt.to_register(rs1_val & 0xffff_ffff),
+        FpOp::MoveToInt | FpOp::MoveFromInt => rs1_val,
+        FpOp::ConvertFromWord => (rs1_val as i32 as f64).to_bits(),
+        FpOp::ConvertFromSingle => {
+            let x = f32::from_bits(rs1_val as u32);
+            if !is_boxed(rs1_val) || !(x.is_normal() || x == 0.0) {
+                return Err("operand is not a NaN-boxed normal single or zero".to_string());
+            }
+            (x as f64).to_bits()
+        }
+        FpOp::Load | FpOp::Store => return Err("FP loads and stores are memory rows".to_string()),
+    };
+    if expected != rd_val {
+        log::warn!("{} writes 0x{:x} but the modeled result is 0x{:x}", entry.opcode, rd_val, expected);
+    }
+
+    Ok(FpRow {
+        op,
+        fmt,
+        dynamic_rm: entry.rounding.as_deref() != Some("rne"),
+        fcsr_before: GoldilocksField::from_canonical_u64(fcsr_before),
+    })
+}
+
+/// Modeled machine state threaded through the entries of a trace.
+#[derive(Debug)]
+struct ConvertState {
+    fcsr: u64,
+    brk: Option<u64>,
+    memory: MemoryShadow,
+    exited: bool,
+}
+
+/// Builds the row of one trace entry, or explains why it cannot be proven.
+fn convert_entry(
+    entry: &TraceEntry,
+    state: &mut ConvertState,
+    options: &ConvertOptions,
+) -> Result<InstructionRow<GoldilocksField>, String> {
+    let opcode_id = opcode_to_id(&entry.opcode).ok_or("unsupported opcode")?;
+
+    let opcode = GoldilocksField::from_canonical_u64(opcode_id % GoldilocksField::ORDER);
 
-        let opcode = GoldilocksField::from_canonical_u64(opcode_id % GoldilocksField::ORDER);
-
-        // rs1 value from BEFORE instruction (reg_values_before)
-        let rs1_val = entry.rs1
-            .as_ref()
-            .and_then(|r| {
-                let idx = reg_name_to_index(r)?;
-                entry.reg_values_before.get(r)
-                    .copied()
-                    .or_else(|| registers.get(&idx).copied())
-            })
-            .unwrap_or(0);
-
-        // rs2 or imm value
-        let (rs2_val, imm_flag, imm_val) = if let Some(imm) = entry.imm {
-            (imm as u64, GoldilocksField::ONE, GoldilocksField::from_canonical_i64(imm))
-        } else {
-            let val = entry.rs2
-                .as_ref()
-                .and_then(|r| {
-                    let idx = reg_name_to_index(r)?;
-                    entry.reg_values_before.get(r)
-                        .copied()
-                        .or_else(|| registers.get(&idx).copied())
-                })
-                .unwrap_or(0);
-            (val, GoldilocksField::ZERO, GoldilocksField::ZERO)
+    // Sources are read from the register file BEFORE the instruction, rd from the one AFTER.
+    let before = |r: &Option<String>| r.as_deref().and_then(|r| entry.regs_before.get(r)).unwrap_or(0);
+    let rs1_val = before(&entry.rs1);
+
+    // rs2 or imm value
+    let (rs2_val, imm_flag, imm_val) = if let Some(imm) = entry.imm {
+        (imm as u64, GoldilocksField::ONE, GoldilocksField::from_noncanonical_i64(imm))
+    } else {
+        (before(&entry.rs2), GoldilocksField::ZERO, GoldilocksField::ZERO)
+    };
+
+    let rd_val = entry.rd.as_deref().and_then(|rd| entry.regs_after.get(rd)).unwrap_or(0);
+
+    // CSR rows carry their source in rs1 and the CSR address in imm_val.
+    let (csr, rs1_val, rs2_val, imm_flag, imm_val) = match &entry.csr {
+        Some(name) => {
+            let src = entry.imm.map(|imm| imm as u64).unwrap_or(rs1_val);
+            let csr = convert_csr(entry, name, src, rd_val, &mut state.fcsr, options)?;
+            let imm_flag = if csr.uimm { GoldilocksField::ONE } else { GoldilocksField::ZERO };
+            let addr = GoldilocksField::from_canonical_u16(csr.addr);
+            (Some(csr), src, 0, imm_flag, addr)
+        }
+        None => (None, rs1_val, rs2_val, imm_flag, imm_val),
+    };
+
+    // ecall rows carry the syscall number (a7) in imm_val.
+    // read/write rows also carry the buffer address (a1) above bit BUFFER_SHIFT.
+    let (syscall, imm_val) = if entry.opcode == "ecall" {
+        let number = entry.regs_before.x[17];
+        let syscall = convert_syscall(entry, number, rs1_val, rd_val, state, options)?;
+        let buf = match syscall.syscall {
+            Syscall::Read | Syscall::Write => syscall.buf << BUFFER_SHIFT,
+            Syscall::Exit | Syscall::Brk => 0,
         };
+        (Some(syscall), GoldilocksField::from_canonical_u64(number + buf))
+    } else {
+        (None, imm_val)
+    };
+
+    let atomic = match AtomicOp::parse(&entry.opcode) {
+        Some(op) => Some(convert_atomic(op, rs1_val, rs2_val, rd_val, &mut state.memory)?),
+        None => None,
+    };
+
+    // Stores carry the stored register in rs2, and whether it is p or more in rd.
+    let (memory, rs2_val, rd_val) = match entry.opcode.as_str() {
+        "ld" | "lw" | "fld" | "flw" => {
+            let addr = rs1_val.wrapping_add(entry.imm.unwrap_or(0) as u64);
+            (Some(convert_load_store(entry, opcode_id, addr, rd_val, &mut state.memory)?), rs2_val, rd_val)
+        }
+        "sd" | "fsd" | "fsw" => {
+            let addr = rs1_val.wrapping_add(entry.imm.unwrap_or(0) as u64);
+            let stored = before(&entry.rs2);
+            let memory = convert_load_store(entry, opcode_id, addr, stored, &mut state.memory)?;
+            (Some(memory), stored, (stored >= GoldilocksField::ORDER) as u64)
+        }
+        _ => (None, rs2_val, rd_val),
+    };
 
-        // rd value from AFTER instruction (reg_values_after)
-        let rd_val = entry.rd
-            .as_ref()
-            .and_then(|rd| {
-                let idx = reg_name_to_index(rd)?;
-                entry.reg_values_after.get(rd)
-                    .copied()
-                    .or_else(|| registers.get(&idx).copied())
-            })
-            .unwrap_or(0);
+    let fp = match parse_fp_op(&entry.opcode) {
+        Some((FpOp::Load | FpOp::Store, _)) | None => None,
+        Some(op) => Some(convert_fp(entry, op, (rs1_val, rs2_val, rd_val), &mut state.fcsr)?),
+    };
 
-        rows.push(InstructionRow {
-            pc: GoldilocksField::from_canonical_u64(entry.pc % GoldilocksField::ORDER),
-            opcode,
-            rs1_val: GoldilocksField::from_canonical_u64(rs1_val % GoldilocksField::ORDER),
-            rs2_val: GoldilocksField::from_canonical_u64(rs2_val % GoldilocksField::ORDER),
-            imm_flag,
-            imm_val,
-            rd_val: GoldilocksField::from_canonical_u64(rd_val % GoldilocksField::ORDER),
-        });
+    // Zba/Zbb rows carry which of their values wrap around the field in imm_val.
+    let bitmanip = BitOp::parse(&entry.opcode).map(|op| BitmanipRow { op, rs1: rs1_val, rs2: rs2_val });
+    let imm_val = match &bitmanip {
+        Some(row) => {
+            let expected = row.op.apply(rs1_val, rs2_val);
+            if expected != rd_val {
+                log::warn!("{} writes 0x{:x} but the modeled result is 0x{:x}", entry.opcode, rd_val, expected);
+            }
+            GoldilocksField::from_canonical_u64(row.wrapped_flags())
+        }
+        None => imm_val,
+    };
+
+    // CSR rows read an immediate or nothing in place of registers, and stores have a flag in rd.
+    let x_reg = |r: &Option<String>| match r.as_deref().and_then(Reg::parse) {
+        Some(Reg::X(n)) => Some(n),
+        _ => None,
+    };
+    let store = memory.is_some_and(|m| m.store);
+    let x_regs = [
+        if csr.as_ref().is_some_and(|csr| csr.uimm) { None } else { x_reg(&entry.rs1) },
+        if csr.is_some() || (entry.imm.is_some() && !store) { None } else { x_reg(&entry.rs2) },
+        if store { None } else { x_reg(&entry.rd) },
+    ];
+
+    Ok(InstructionRow {
+        pc: GoldilocksField::from_canonical_u64(entry.pc % GoldilocksField::ORDER),
+        opcode,
+        rs1_val: GoldilocksField::from_canonical_u64(rs1_val % GoldilocksField::ORDER),
+        rs2_val: GoldilocksField::from_canonical_u64(rs2_val % GoldilocksField::ORDER),
+        imm_flag,
+        imm_val,
+        rd_val: GoldilocksField::from_canonical_u64(rd_val % GoldilocksField::ORDER),
+        x_regs,
+        csr,
+        syscall,
+        atomic,
+        fp,
+        bitmanip,
+        memory,
+        unproven: None,
+    })
+}
+
+/// Like [`convert_trace_to_rows`], with control over how CSR reads and stdin are exposed and
+/// over instructions that cannot be proven.
+pub fn convert_trace_to_rows_with(
+    entries: &[TraceEntry],
+    options: &ConvertOptions,
+) -> Result<Vec<InstructionRow<GoldilocksField>>, UnsupportedInstructions> {
+    convert_flagged(entries, &vec![true; entries.len()], options)
+}
+
+/// Converts the entries flagged in `in_window`, one flag per entry, as selected by
+/// [`crate::window::Window::select`].
+///
+/// Entries before the first and after the last flagged one are left out, and those in between
+/// become [`UnprovenSpan`] rows in either mode, since they were deliberately excluded. Every
+/// entry still updates the modeled register file. Fails with [`WindowError::FlagCount`]
+/// when the flags do not match the entries.
+pub fn convert_window(
+    entries: &[TraceEntry],
+    in_window: &[bool],
+    options: &ConvertOptions,
+) -> crate::error::Result<Vec<InstructionRow<GoldilocksField>>> {
+    if entries.len() != in_window.len() {
+        return Err(WindowError::FlagCount { entries: entries.len(), flags: in_window.len() }.into());
     }
+    Ok(convert_flagged(entries, in_window, options)?)
+}
 
-    rows
+fn convert_flagged(
+    entries: &[TraceEntry],
+    in_window: &[bool],
+    options: &ConvertOptions,
+) -> Result<Vec<InstructionRow<GoldilocksField>>, UnsupportedInstructions> {
+    let mut converter = RowConverter::new(options);
+    let mut rows = Vec::new();
+    for (entry, &in_window) in entries.iter().zip(in_window) {
+        converter.push(entry, in_window, &mut rows);
+    }
+    converter.finish(&mut rows)?;
+    Ok(rows)
+}
+
+/// Incremental form of [`convert_window`]: converts one entry at a time, keeping only the
+/// modeled machine state and the pending [`UnprovenSpan`] between entries.
+#[derive(Debug)]
+pub struct RowConverter {
+    options: ConvertOptions,
+    state: ConvertState,
+    unsupported: Vec<Unsupported>,
+    /// Entries left out since the last row.
+    span: Option<UnprovenSpan>,
+    /// Entries outside the window since the last one inside it, dropped if none follows.
+    excluded: Option<UnprovenSpan>,
+    started: bool,
+    /// Registers after the previous entry, which the next one must start from.
+    regs: Option<RegisterFile>,
+}
+
+impl RowConverter {
+    pub fn new(options: &ConvertOptions) -> Self {
+        RowConverter {
+            options: options.clone(),
+            state: ConvertState {
+                fcsr: options.initial_fcsr,
+                brk: None,
+                memory: MemoryShadow::default(),
+                exited: false,
+            },
+            unsupported: Vec::new(),
+            span: None,
+            excluded: None,
+            started: false,
+            regs: None,
+        }
+    }
+
+    /// Converts the next entry of the trace, appending the rows it completes to `rows`:
+    /// none while a span of left-out entries grows, otherwise the span and the entry's row.
+    pub fn push(
+        &mut self,
+        entry: &TraceEntry,
+        in_window: bool,
+        rows: &mut impl Extend<InstructionRow<GoldilocksField>>,
+    ) {
+        log_entry(entry);
+        check_registers(entry, self.regs.replace(entry.regs_after));
+
+        if !in_window {
+            if self.started {
+                extend_span(&mut self.excluded, entry.pc, 1);
+            }
+            return;
+        }
+        self.started = true;
+        if let Some(excluded) = self.excluded.take() {
+            extend_span(&mut self.span, excluded.first_pc, 0);
+            extend_span(&mut self.span, excluded.last_pc, excluded.count);
+        }
+
+        match convert_entry(entry, &mut self.state, &self.options) {
+            Ok(row) => rows.extend(self.span.take().map(UnprovenSpan::row).into_iter().chain([row])),
+            Err(reason) => {
+                log::warn!("Skipping {} at 0x{:08x}: {}", entry.opcode, entry.pc, reason);
+                match self.unsupported.iter_mut().find(|u| u.mnemonic == entry.opcode) {
+                    Some(u) => u.count += 1,
+                    None => self.unsupported.push(Unsupported {
+                        mnemonic: entry.opcode.clone(),
+                        first_pc: entry.pc,
+                        count: 1,
+                        reason,
+                    }),
+                }
+                extend_span(&mut self.span, entry.pc, 1);
+            }
+        }
+    }
+
+    /// In [`ConversionMode::Strict`] mode, fails as soon as an entry could not be proven, with
+    /// the instructions found so far.
+    pub fn check(&self) -> Result<(), UnsupportedInstructions> {
+        match self.options.mode {
+            ConversionMode::Strict if !self.unsupported.is_empty() => Err(UnsupportedInstructions(self.unsupported.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Appends the trailing span, if any. In [`ConversionMode::Strict`] mode, fails if any
+    /// entry could not be proven.
+    pub fn finish(
+        self,
+        rows: &mut impl Extend<InstructionRow<GoldilocksField>>,
+    ) -> Result<(), UnsupportedInstructions> {
+        rows.extend(self.span.map(UnprovenSpan::row));
+        match self.options.mode {
+            ConversionMode::Strict if !self.unsupported.is_empty() => Err(UnsupportedInstructions(self.unsupported)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Grows `span` to end at `pc`, creating it if needed, by `count` entries.
+fn extend_span(span: &mut Option<UnprovenSpan>, pc: u64, count: u64) {
+    let span = span.get_or_insert(UnprovenSpan { first_pc: pc, last_pc: pc, count: 0 });
+    span.last_pc = pc;
+    span.count += count;
+}
+
+/// Warns about registers that the entry does not account for: values that changed since
+/// `previous` (the registers after the entry before it) and writes other than `rd`.
+fn check_registers(entry: &TraceEntry, previous: Option<RegisterFile>) {
+    let names = |regs: Vec<Reg>| regs.iter().map(Reg::to_string).collect::<Vec<_>>().join(", ");
+    let stale = previous.map_or(Vec::new(), |previous| previous.changed(&entry.regs_before));
+    if !stale.is_empty() {
+        log::warn!("0x{:08x}: {} changed between this and the previous entry", entry.pc, names(stale));
+    }
+    let side_effects = entry.side_effects();
+    if !side_effects.is_empty() {
+        log::warn!("0x{:08x}: {} also writes {}", entry.pc, entry.opcode, names(side_effects));
+    }
+}
+
+fn log_entry(entry: &TraceEntry) {
+    if !log::log_enabled!(log::Level::Debug) {
+        return;
+    }
+    log::debug!("0x{:08x}: {}", entry.pc, entry.opcode);
+    if let Some(rd) = &entry.rd {
+        log::debug!("  rd:  {} = {}", rd, entry.after(rd).unwrap_or(0));
+    }
+    if let Some(rs1) = &entry.rs1 {
+        log::debug!("  rs1: {} = {}", rs1, entry.before(rs1).unwrap_or(0));
+    }
+    if let Some(rs2) = &entry.rs2 {
+        log::debug!("  rs2: {} = {}", rs2, entry.before(rs2).unwrap_or(0));
+    }
+    if let Some(imm) = entry.imm {
+        log::debug!("  imm: {}", imm);
+    }
+}
+
+/// Converts a stream of entries, such as a [`crate::trace_parser::TraceReader`], as it is
+/// read. Rows come out as soon as they are complete, so a long trace can be split into
+/// segments without holding it in memory. A strict conversion stops at the first entry it
+/// cannot prove and reports it as [`UnsupportedInstructions`], without reading further.
+pub fn stream_rows<I>(entries: I, options: &ConvertOptions) -> RowStream<I::IntoIter>
+where
+    I: IntoIterator<Item = crate::error::Result<TraceEntry>>,
+{
+    RowStream { entries: entries.into_iter(), converter: Some(RowConverter::new(options)), rows: VecDeque::new() }
+}
+
+/// Iterator returned by [`stream_rows`].
+pub struct RowStream<I> {
+    entries: I,
+    converter: Option<RowConverter>,
+    rows: VecDeque<InstructionRow<GoldilocksField>>,
+}
+
+impl<I: Iterator<Item = crate::error::Result<TraceEntry>>> Iterator for RowStream<I> {
+    type Item = crate::error::Result<InstructionRow<GoldilocksField>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.rows.pop_front() {
+                return Some(Ok(row));
+            }
+            let converter = self.converter.as_mut()?;
+            match self.entries.next() {
+                Some(Ok(entry)) => {
+                    converter.push(&entry, true, &mut self.rows);
+                    if let Err(e) = converter.check() {
+                        self.converter = None;
+                        self.rows.clear();
+                        return Some(Err(e.into()));
+                    }
+                }
+                Some(Err(e)) => {
+                    self.converter = None;
+                    return Some(Err(e));
+                }
+                None => {
+                    let converter = self.converter.take()?;
+                    if let Err(e) = converter.finish(&mut self.rows) {
+                        self.rows.clear();
+                        return Some(Err(e.into()));
+                    }
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
 
-    fn map_from(pairs: &[(&str, u64)]) -> HashMap<String, u64> {
-        pairs.iter()
-            .map(|(k, v)| (k.to_string(), *v))
-            .collect()
+    fn regs(pairs: &[(&str, u64)]) -> RegisterFile {
+        RegisterFile::default().with(pairs)
     }
 
     #[test]
@@ -161,8 +886,9 @@ mod tests {
                 rs1: Some("x0".to_string()),
                 rs2: None,
                 imm: Some(5),
-                reg_values_before: map_from(&[("x0", 0), ("x1", 0)]),
-                reg_values_after: map_from(&[("x0", 0), ("x1", 5)]),
+                regs_before: regs(&[("x0", 0), ("x1", 0)]),
+                regs_after: regs(&[("x0", 0), ("x1", 5)]),
+                ..Default::default()
             },
             TraceEntry {
                 pc: 0x1004,
@@ -171,8 +897,9 @@ mod tests {
                 rs1: Some("x0".to_string()),
                 rs2: None,
                 imm: Some(10),
-                reg_values_before: map_from(&[("x0", 0), ("x1", 5), ("x2", 0)]),
-                reg_values_after: map_from(&[("x0", 0), ("x1", 5), ("x2", 10)]),
+                regs_before: regs(&[("x0", 0), ("x1", 5), ("x2", 0)]),
+                regs_after: regs(&[("x0", 0), ("x1", 5), ("x2", 10)]),
+                ..Default::default()
             },
             TraceEntry {
                 pc: 0x1008,
@@ -181,8 +908,9 @@ mod tests {
                 rs1: Some("x1".to_string()),
                 rs2: Some("x2".to_string()),
                 imm: None,
-                reg_values_before: map_from(&[("x0", 0), ("x1", 5), ("x2", 10), ("x3", 0)]),
-                reg_values_after: map_from(&[("x0", 0), ("x1", 5), ("x2", 10), ("x3", 15)]),
+                regs_before: regs(&[("x0", 0), ("x1", 5), ("x2", 10), ("x3", 0)]),
+                regs_after: regs(&[("x0", 0), ("x1", 5), ("x2", 10), ("x3", 15)]),
+                ..Default::default()
             },
             TraceEntry {
                 pc: 0x100C,
@@ -191,12 +919,13 @@ mod tests {
                 rs1: Some("x1".to_string()),
                 rs2: Some("x2".to_string()),
                 imm: None,
-                reg_values_before: map_from(&[("x0", 0), ("x1", 5), ("x2", 10), ("x3", 15), ("x4", 0)]),
-                reg_values_after: map_from(&[("x0", 0), ("x1", 5), ("x2", 10), ("x3", 15), ("x4", 50)]),
+                regs_before: regs(&[("x0", 0), ("x1", 5), ("x2", 10), ("x3", 15), ("x4", 0)]),
+                regs_after: regs(&[("x0", 0), ("x1", 5), ("x2", 10), ("x3", 15), ("x4", 50)]),
+                ..Default::default()
             },
         ];
 
-        let rows = convert_trace_to_rows(&trace);
+        let rows = convert_trace_to_rows(&trace).unwrap();
 
         assert_eq!(rows.len(), 4);
 
@@ -211,4 +940,75 @@ mod tests {
         assert_eq!(rows[3].opcode, GoldilocksField::from_canonical_u64(3)); // mul
         assert_eq!(rows[3].rd_val, GoldilocksField::from_canonical_u64(50));
     }
+
+    #[test]
+    fn test_strict_mode_lists_unsupported_and_lenient_marks_spans() {
+        let entry = |pc: u64, opcode: &str| TraceEntry {
+            pc,
+            opcode: opcode.to_string(),
+            rd: Some("x1".to_string()),
+            rs1: Some("x0".to_string()),
+            imm: Some(1),
+            regs_after: regs(&[("x1", 1)]),
+            ..Default::default()
+        };
+        let trace = vec![
+            entry(0x1000, "jal"),
+            entry(0x1004, "addi"),
+            entry(0x1008, "slti"),
+            entry(0x100c, "jal"),
+            entry(0x1010, "addi"),
+        ];
+
+        let err = convert_trace_to_rows(&trace).unwrap_err();
+        let listed: Vec<_> = err.0.iter().map(|u| (u.mnemonic.as_str(), u.first_pc, u.count)).collect();
+        assert_eq!(listed, [("jal", 0x1000, 2), ("slti", 0x1008, 1)]);
+        assert!(err.to_string().contains("jal x2 (first at 0x00001000: unsupported opcode)"));
+
+        let options = ConvertOptions { mode: ConversionMode::Lenient, ..Default::default() };
+        let rows = convert_trace_to_rows_with(&trace, &options).unwrap();
+        let spans: Vec<_> = rows.iter().map(|row| row.unproven).collect();
+        assert_eq!(spans, [
+            Some(UnprovenSpan { first_pc: 0x1000, last_pc: 0x1000, count: 1 }),
+            None,
+            Some(UnprovenSpan { first_pc: 0x1008, last_pc: 0x100c, count: 2 }),
+            None,
+        ]);
+        assert_eq!(rows[2].opcode, GoldilocksField::from_canonical_u64(UNPROVEN_SPAN_OPCODE));
+        assert_eq!(rows[2].imm_val, GoldilocksField::from_canonical_u64(2));
+
+        let flags = convert_window(&trace, &[true], &options);
+        assert!(matches!(flags, Err(crate::error::Error::Window(WindowError::FlagCount { entries: 5, flags: 1 }))));
+    }
+
+    #[test]
+    fn test_streamed_rows_match_batch_conversion() {
+        use crate::error::Error;
+        use crate::trace_parser::{parse_trace, TraceReader};
+
+        let key = |row: &InstructionRow<GoldilocksField>| {
+            (row.pc, row.opcode, row.rs1_val, row.rs2_val, row.imm_flag, row.imm_val, row.rd_val, row.unproven)
+        };
+        let options = ConvertOptions { mode: ConversionMode::Lenient, ..Default::default() };
+        let path = "traces/sample_trace.log";
+        let batch = convert_trace_to_rows_with(&parse_trace(path).unwrap(), &options).unwrap();
+        let streamed: Vec<_> = stream_rows(TraceReader::open(path).unwrap(), &options)
+            .collect::<crate::error::Result<_>>()
+            .unwrap();
+        assert_eq!(streamed.iter().map(key).collect::<Vec<_>>(), batch.iter().map(key).collect::<Vec<_>>());
+
+        // A strict conversion yields the rows it could build, then the error at the first
+        // unsupported entry, without reading the rest of the trace.
+        let entry = |pc: u64, opcode: &str| Ok(TraceEntry { pc, opcode: opcode.to_string(), ..Default::default() });
+        let read = std::cell::Cell::new(0);
+        let trace = [entry(0x1000, "addi"), entry(0x1004, "jal"), entry(0x1008, "addi"), entry(0x100c, "jal")];
+        let mut stream = stream_rows(trace.into_iter().inspect(|_| read.set(read.get() + 1)), &ConvertOptions::default());
+        assert!(stream.next().unwrap().is_ok());
+        match stream.next() {
+            Some(Err(Error::Unsupported(e))) => assert_eq!(e.0.len(), 1),
+            other => panic!("expected the unsupported jal, got {:?}", other.map(|r| r.map(|row| row.pc))),
+        }
+        assert!(stream.next().is_none());
+        assert_eq!(read.get(), 2);
+    }
 }